@@ -0,0 +1,145 @@
+//! Timing of the withdrawal proof verification, stage by stage
+//!
+//! Run with `cargo bench --features bench`. Each stage of `verify_tornado_proof`
+//! is timed natively, for both the ark path used off-chain and the alt_bn128
+//! syscall path used on-chain (emulated off-chain by `solana-program`), and
+//! printed next to the baseline below. On-chain compute units are logged by
+//! building the program with `--features log_cu`.
+
+use std::time::{Duration, Instant};
+
+use ark_bn254::{Bn254, Fr};
+use ark_ff::{BigInteger, PrimeField};
+use ark_groth16::Groth16;
+use ark_relations::{
+    lc,
+    r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError, Variable},
+};
+use ark_serialize::CanonicalSerialize;
+use borsh::BorshSerialize;
+use solana_program::{
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    pubkey::Pubkey,
+};
+
+use tornado_svm::{
+    state::VerifierState,
+    verifier::{bench, hash_verifying_key, verify_tornado_proof, WithdrawPublicInputs, CURVE_BN254, NUM_PUBLIC_INPUTS, PROOF_SYSTEM_GROTH16},
+};
+
+/// Iterations averaged for each stage
+const ITERATIONS: u32 = 50;
+
+/// Baseline mean per stage in microseconds (bench profile, x86_64 Linux, single thread)
+///
+/// Update these alongside any change to the verifier that moves them.
+const BASELINE: &[(&str, u64)] = &[
+    ("deserialize proof", 2),
+    ("deserialize public inputs", 2),
+    ("load verifying key", 310),
+    ("prepare verifying key (ark)", 2_550),
+    ("verify prepared (ark)", 3_500),
+    ("verify (alt_bn128 syscalls)", 11_000),
+    ("verify_tornado_proof", 6_200),
+];
+
+// Circuit with one public input per withdraw input, each constrained by `x * 1 = x`
+#[derive(Clone)]
+struct PublicInputsCircuit {
+    inputs: [Fr; NUM_PUBLIC_INPUTS],
+}
+
+impl ConstraintSynthesizer<Fr> for PublicInputsCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        for input in self.inputs {
+            let x = cs.new_input_variable(|| Ok(input))?;
+            cs.enforce_constraint(lc!() + x, lc!() + Variable::One, lc!() + x)?;
+        }
+        Ok(())
+    }
+}
+
+// Mean duration of `f` over `ITERATIONS` runs
+fn time<T>(mut f: impl FnMut() -> T) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        std::hint::black_box(f());
+    }
+    start.elapsed() / ITERATIONS
+}
+
+// Syscall stubs that drop the program logs, which would otherwise be printed every iteration
+struct SilentSyscallStubs;
+
+impl SyscallStubs for SilentSyscallStubs {
+    fn sol_log(&self, _message: &str) {}
+}
+
+fn main() {
+    set_syscall_stubs(Box::new(SilentSyscallStubs));
+
+    // A real proof for representative withdrawal inputs
+    let public_inputs = WithdrawPublicInputs {
+        root: [7u8; 32],
+        nullifier_hash: [9u8; 32],
+        recipient: Pubkey::new_unique(),
+        relayer: Pubkey::new_unique(),
+        fee: 1_000,
+        fee_in_sol: false,
+        refund: 0,
+        instance: Pubkey::new_unique(),
+    }
+    .to_bytes()
+    .unwrap();
+    let inputs: [Fr; NUM_PUBLIC_INPUTS] =
+        core::array::from_fn(|i| Fr::from_be_bytes_mod_order(&public_inputs[i * 32..(i + 1) * 32]));
+    let mut rng = ark_std::test_rng();
+    let circuit = PublicInputsCircuit { inputs };
+    let pk = Groth16::<Bn254>::generate_random_parameters_with_reduction(circuit.clone(), &mut rng).unwrap();
+    let proof = Groth16::<Bn254>::create_random_proof_with_reduction(circuit, &pk, &mut rng).unwrap();
+    let proof_data: Vec<u8> = [
+        proof.a.x,
+        proof.a.y,
+        proof.b.x.c0,
+        proof.b.x.c1,
+        proof.b.y.c0,
+        proof.b.y.c1,
+        proof.c.x,
+        proof.c.y,
+    ]
+    .iter()
+    .flat_map(|f| f.into_bigint().to_bytes_le())
+    .collect();
+    let mut verifying_key = Vec::new();
+    pk.vk.serialize_compressed(&mut verifying_key).unwrap();
+    let vk_hash = hash_verifying_key(&verifying_key);
+    let verifier_data = VerifierState {
+        is_initialized: true,
+        verifying_key,
+    }
+    .try_to_vec()
+    .unwrap();
+
+    // Inputs of the later stages
+    let proof = bench::deserialize_proof(&proof_data).unwrap();
+    let inputs = bench::deserialize_public_inputs(&public_inputs).unwrap();
+    let vk = bench::load_verifying_key(&verifier_data, NUM_PUBLIC_INPUTS).unwrap();
+    let pvk = bench::prepare(&vk);
+    assert!(bench::verify_prepared(&pvk, &proof, &inputs));
+    assert!(bench::verify_syscalls(&vk, &proof, &inputs));
+
+    let results = [
+        time(|| bench::deserialize_proof(&proof_data).unwrap()),
+        time(|| bench::deserialize_public_inputs(&public_inputs).unwrap()),
+        time(|| bench::load_verifying_key(&verifier_data, NUM_PUBLIC_INPUTS).unwrap()),
+        time(|| bench::prepare(&vk)),
+        time(|| bench::verify_prepared(&pvk, &proof, &inputs)),
+        time(|| bench::verify_syscalls(&vk, &proof, &inputs)),
+        time(|| verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, Some(&vk_hash), &verifier_data, &proof_data, &public_inputs).unwrap()),
+    ];
+
+    println!("{:<30} {:>12} {:>12}", "stage", "mean (us)", "baseline");
+    for ((stage, baseline), mean) in BASELINE.iter().zip(results) {
+        println!("{:<30} {:>12} {:>12}", stage, mean.as_micros(), baseline);
+    }
+}
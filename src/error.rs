@@ -1,79 +1,187 @@
 //! Error types for the Tornado Cash Privacy Solution
 
 use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
 use solana_program::{decode_error::DecodeError, program_error::ProgramError};
 use thiserror::Error;
 
 /// Errors that may be returned by the Tornado Cash program
+///
+/// Each error is returned as `ProgramError::Custom` with its discriminant as
+/// the code. Codes are part of the program's interface: existing variants are
+/// never renumbered or removed, and new ones are appended with the next code.
 #[derive(Clone, Debug, Eq, Error, FromPrimitive, PartialEq)]
 pub enum TornadoError {
     /// Invalid instruction data
     #[error("Invalid instruction data")]
-    InvalidInstructionData,
+    InvalidInstructionData = 0,
 
     /// Invalid account data
     #[error("Invalid account data")]
-    InvalidAccountData,
+    InvalidAccountData = 1,
 
     /// Account not initialized
     #[error("Account not initialized")]
-    AccountNotInitialized,
+    AccountNotInitialized = 2,
 
     /// Account already initialized
     #[error("Account already initialized")]
-    AccountAlreadyInitialized,
+    AccountAlreadyInitialized = 3,
 
     /// Invalid Merkle tree state
     #[error("Invalid Merkle tree state")]
-    InvalidMerkleTreeState,
+    InvalidMerkleTreeState = 4,
 
     /// Merkle tree is full
     #[error("Merkle tree is full")]
-    MerkleTreeFull,
+    MerkleTreeFull = 5,
 
     /// Invalid commitment
     #[error("Invalid commitment")]
-    InvalidCommitment,
+    InvalidCommitment = 6,
 
     /// Commitment already exists
     #[error("Commitment already exists")]
-    CommitmentAlreadyExists,
+    CommitmentAlreadyExists = 7,
 
     /// Invalid nullifier hash
     #[error("Invalid nullifier hash")]
-    InvalidNullifierHash,
+    InvalidNullifierHash = 8,
 
     /// Nullifier already spent
     #[error("Nullifier already spent")]
-    NullifierAlreadySpent,
+    NullifierAlreadySpent = 9,
 
     /// Invalid Merkle root
     #[error("Invalid Merkle root")]
-    InvalidMerkleRoot,
+    InvalidMerkleRoot = 10,
 
     /// Invalid proof
     #[error("Invalid proof")]
-    InvalidProof,
+    InvalidProof = 11,
 
-    /// Invalid fee
+    /// The fee exceeds the instance's cap, or the cap itself is out of range
     #[error("Invalid fee")]
-    InvalidFee,
+    InvalidFee = 12,
 
     /// Invalid recipient
     #[error("Invalid recipient")]
-    InvalidRecipient,
+    InvalidRecipient = 13,
 
     /// Invalid relayer
     #[error("Invalid relayer")]
-    InvalidRelayer,
+    InvalidRelayer = 14,
 
     /// Invalid amount
     #[error("Invalid amount")]
-    InvalidAmount,
+    InvalidAmount = 15,
 
     /// Insufficient funds
     #[error("Insufficient funds")]
-    InsufficientFunds,
+    InsufficientFunds = 16,
+
+    /// Token mint does not match the instance
+    #[error("Invalid token mint")]
+    InvalidTokenMint = 17,
+
+    /// Token account does not match the expected mint or owner
+    #[error("Invalid token account")]
+    InvalidTokenAccount = 18,
+
+    /// Verifying key is malformed or does not match the circuit
+    #[error("Invalid verifying key")]
+    InvalidVerifyingKey = 19,
+
+    /// Note string is malformed
+    #[error("Invalid note")]
+    InvalidNote = 20,
+
+    /// An instance with the same denomination and mint is already registered
+    ///
+    /// No longer returned: the registry lists every instance, and clients
+    /// filter entries by admin and verifying key.
+    #[error("Duplicate instance")]
+    DuplicateInstance = 21,
+
+    /// The instance is paused
+    #[error("Instance paused")]
+    InstancePaused = 22,
+
+    /// Signer is not the instance admin
+    #[error("Unauthorized")]
+    Unauthorized = 23,
+
+    /// Denomination is zero
+    #[error("Invalid denomination")]
+    InvalidDenomination = 24,
+
+    /// Merkle tree height is zero or above the maximum
+    #[error("Invalid Merkle tree height")]
+    InvalidMerkleTreeHeight = 25,
+
+    /// Account data has a layout version this program can't read
+    #[error("Unsupported state version")]
+    UnsupportedStateVersion = 26,
+
+    /// Relayer fee quote signature is missing or doesn't match the withdrawal
+    #[error("Invalid relayer fee quote")]
+    InvalidRelayerFeeQuote = 27,
+
+    /// Instance still holds deposits that haven't been withdrawn
+    #[error("Instance not empty")]
+    InstanceNotEmpty = 28,
+
+    /// Unknown Merkle tree hash algorithm
+    #[error("Unsupported hash algorithm")]
+    UnsupportedHashAlgorithm = 29,
+
+    /// Unknown proof curve
+    #[error("Unsupported curve")]
+    UnsupportedCurve = 30,
+
+    /// Merkle root is in the history but older than the instance accepts
+    #[error("Root too old")]
+    RootTooOld = 31,
+
+    /// Merkle path can't be rebuilt from the on-chain state alone
+    #[error("Cannot reconstruct path")]
+    CannotReconstructPath = 32,
+
+    /// Transaction has instructions besides the withdrawal of a strict transaction instance
+    #[error("Unexpected instructions")]
+    UnexpectedInstructions = 33,
+
+    /// Recipient is on the instance's compliance list
+    #[error("Recipient blocked")]
+    RecipientBlocked = 34,
+
+    /// Proof system isn't one the program implements
+    #[error("Unsupported proof system")]
+    UnsupportedProofSystem = 35,
+
+    /// An instance failed one of the `SelfCheck` invariants
+    #[error("Inconsistent state")]
+    InconsistentState = 36,
+
+    /// The deposit would not take the leaf index the depositor expected
+    #[error("Unexpected leaf index")]
+    UnexpectedLeafIndex = 37,
+
+    /// An account the instruction writes to was not passed as writable
+    #[error("Account not writable")]
+    AccountNotWritable = 38,
+
+    /// The verifying key in the verifier account doesn't match the instance's `vk_hash`
+    #[error("Verifier key mismatch")]
+    VerifierKeyMismatch = 39,
+
+    /// The Merkle tree holds fewer deposits than the instance's `min_anonymity_set`
+    #[error("Anonymity set too small")]
+    AnonymitySetTooSmall = 40,
+
+    /// The withdrawal's root was inserted less than the instance's `withdraw_delay_secs` ago
+    #[error("Withdraw too early")]
+    WithdrawTooEarly = 41,
 }
 
 impl From<TornadoError> for ProgramError {
@@ -82,8 +190,168 @@ impl From<TornadoError> for ProgramError {
     }
 }
 
+impl TornadoError {
+    /// Get the error with the given `ProgramError::Custom` code
+    pub fn from_u32(code: u32) -> Option<Self> {
+        FromPrimitive::from_u32(code)
+    }
+
+    /// The message of the error, as shown by its `Display` implementation
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::InvalidInstructionData => "Invalid instruction data",
+            Self::InvalidAccountData => "Invalid account data",
+            Self::AccountNotInitialized => "Account not initialized",
+            Self::AccountAlreadyInitialized => "Account already initialized",
+            Self::InvalidMerkleTreeState => "Invalid Merkle tree state",
+            Self::MerkleTreeFull => "Merkle tree is full",
+            Self::InvalidCommitment => "Invalid commitment",
+            Self::CommitmentAlreadyExists => "Commitment already exists",
+            Self::InvalidNullifierHash => "Invalid nullifier hash",
+            Self::NullifierAlreadySpent => "Nullifier already spent",
+            Self::InvalidMerkleRoot => "Invalid Merkle root",
+            Self::InvalidProof => "Invalid proof",
+            Self::InvalidFee => "Invalid fee",
+            Self::InvalidRecipient => "Invalid recipient",
+            Self::InvalidRelayer => "Invalid relayer",
+            Self::InvalidAmount => "Invalid amount",
+            Self::InsufficientFunds => "Insufficient funds",
+            Self::InvalidTokenMint => "Invalid token mint",
+            Self::InvalidTokenAccount => "Invalid token account",
+            Self::InvalidVerifyingKey => "Invalid verifying key",
+            Self::InvalidNote => "Invalid note",
+            Self::DuplicateInstance => "Duplicate instance",
+            Self::InstancePaused => "Instance paused",
+            Self::Unauthorized => "Unauthorized",
+            Self::InvalidDenomination => "Invalid denomination",
+            Self::InvalidMerkleTreeHeight => "Invalid Merkle tree height",
+            Self::UnsupportedStateVersion => "Unsupported state version",
+            Self::InvalidRelayerFeeQuote => "Invalid relayer fee quote",
+            Self::InstanceNotEmpty => "Instance not empty",
+            Self::UnsupportedHashAlgorithm => "Unsupported hash algorithm",
+            Self::UnsupportedCurve => "Unsupported curve",
+            Self::RootTooOld => "Root too old",
+            Self::CannotReconstructPath => "Cannot reconstruct path",
+            Self::UnexpectedInstructions => "Unexpected instructions",
+            Self::RecipientBlocked => "Recipient blocked",
+            Self::UnsupportedProofSystem => "Unsupported proof system",
+            Self::InconsistentState => "Inconsistent state",
+            Self::UnexpectedLeafIndex => "Unexpected leaf index",
+            Self::AccountNotWritable => "Account not writable",
+            Self::VerifierKeyMismatch => "Verifier key mismatch",
+            Self::AnonymitySetTooSmall => "Anonymity set too small",
+            Self::WithdrawTooEarly => "Withdraw too early",
+        }
+    }
+
+    /// The name of the variant, as relayers and other clients log it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::InvalidInstructionData => "InvalidInstructionData",
+            Self::InvalidAccountData => "InvalidAccountData",
+            Self::AccountNotInitialized => "AccountNotInitialized",
+            Self::AccountAlreadyInitialized => "AccountAlreadyInitialized",
+            Self::InvalidMerkleTreeState => "InvalidMerkleTreeState",
+            Self::MerkleTreeFull => "MerkleTreeFull",
+            Self::InvalidCommitment => "InvalidCommitment",
+            Self::CommitmentAlreadyExists => "CommitmentAlreadyExists",
+            Self::InvalidNullifierHash => "InvalidNullifierHash",
+            Self::NullifierAlreadySpent => "NullifierAlreadySpent",
+            Self::InvalidMerkleRoot => "InvalidMerkleRoot",
+            Self::InvalidProof => "InvalidProof",
+            Self::InvalidFee => "InvalidFee",
+            Self::InvalidRecipient => "InvalidRecipient",
+            Self::InvalidRelayer => "InvalidRelayer",
+            Self::InvalidAmount => "InvalidAmount",
+            Self::InsufficientFunds => "InsufficientFunds",
+            Self::InvalidTokenMint => "InvalidTokenMint",
+            Self::InvalidTokenAccount => "InvalidTokenAccount",
+            Self::InvalidVerifyingKey => "InvalidVerifyingKey",
+            Self::InvalidNote => "InvalidNote",
+            Self::DuplicateInstance => "DuplicateInstance",
+            Self::InstancePaused => "InstancePaused",
+            Self::Unauthorized => "Unauthorized",
+            Self::InvalidDenomination => "InvalidDenomination",
+            Self::InvalidMerkleTreeHeight => "InvalidMerkleTreeHeight",
+            Self::UnsupportedStateVersion => "UnsupportedStateVersion",
+            Self::InvalidRelayerFeeQuote => "InvalidRelayerFeeQuote",
+            Self::InstanceNotEmpty => "InstanceNotEmpty",
+            Self::UnsupportedHashAlgorithm => "UnsupportedHashAlgorithm",
+            Self::UnsupportedCurve => "UnsupportedCurve",
+            Self::RootTooOld => "RootTooOld",
+            Self::CannotReconstructPath => "CannotReconstructPath",
+            Self::UnexpectedInstructions => "UnexpectedInstructions",
+            Self::RecipientBlocked => "RecipientBlocked",
+            Self::UnsupportedProofSystem => "UnsupportedProofSystem",
+            Self::InconsistentState => "InconsistentState",
+            Self::UnexpectedLeafIndex => "UnexpectedLeafIndex",
+            Self::AccountNotWritable => "AccountNotWritable",
+            Self::VerifierKeyMismatch => "VerifierKeyMismatch",
+            Self::AnonymitySetTooSmall => "AnonymitySetTooSmall",
+            Self::WithdrawTooEarly => "WithdrawTooEarly",
+        }
+    }
+}
+
+/// Explain a `ProgramError::Custom` code returned by the Tornado Cash program
+pub fn explain(code: u32) -> &'static str {
+    TornadoError::from_u32(code).map_or("Unknown error", |e| e.message())
+}
+
+/// Name the `TornadoError` variant of a `ProgramError::Custom` code
+///
+/// Returns `None` for codes the program doesn't define, such as the codes of
+/// another program's errors.
+pub fn decode_error(code: u32) -> Option<&'static str> {
+    TornadoError::from_u32(code).map(|e| e.name())
+}
+
 impl<T> DecodeError<T> for TornadoError {
     fn type_of() -> &'static str {
         "TornadoError"
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        // Every code up to the last variant round-trips through `ProgramError`
+        let last = TornadoError::WithdrawTooEarly as u32;
+        for code in 0..=last {
+            let error = TornadoError::from_u32(code).unwrap();
+            assert_eq!(error.clone() as u32, code);
+            assert_eq!(ProgramError::from(error.clone()), ProgramError::Custom(code));
+            assert_eq!(explain(code), error.to_string());
+        }
+        assert_eq!(TornadoError::from_u32(last + 1), None);
+        assert_eq!(explain(last + 1), "Unknown error");
+
+        // Codes clients already rely on
+        assert_eq!(TornadoError::from_u32(5), Some(TornadoError::MerkleTreeFull));
+        assert_eq!(TornadoError::from_u32(9), Some(TornadoError::NullifierAlreadySpent));
+        assert_eq!(explain(11), "Invalid proof");
+    }
+
+    #[test]
+    fn test_decode_error() {
+        assert_eq!(decode_error(0), Some("InvalidInstructionData"));
+        assert_eq!(decode_error(5), Some("MerkleTreeFull"));
+        assert_eq!(decode_error(9), Some("NullifierAlreadySpent"));
+        assert_eq!(decode_error(11), Some("InvalidProof"));
+        assert_eq!(decode_error(TornadoError::WithdrawTooEarly as u32), Some("WithdrawTooEarly"));
+
+        // Every name is the variant's `Debug` form
+        for code in 0..=TornadoError::WithdrawTooEarly as u32 {
+            let error = TornadoError::from_u32(code).unwrap();
+            assert_eq!(decode_error(code), Some(format!("{:?}", error).as_str()));
+        }
+
+        // Unknown codes have no name
+        for code in [TornadoError::WithdrawTooEarly as u32 + 1, 1000, u32::MAX] {
+            assert_eq!(decode_error(code), None);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Event types for the Tornado Cash Privacy Solution
+//!
+//! Events are Borsh-encoded `TornadoEvent`s written with `sol_log_data`, so they
+//! show up base64-encoded as `Program data: ...` in the transaction logs. The
+//! first byte of the data is the variant discriminator (0 = deposit,
+//! 1 = withdrawal, 2 = root, 3 = nullifier status, 4 = stealth announcement);
+//! new variants are only ever appended.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{log::sol_log_data, program_error::ProgramError, pubkey::Pubkey};
+
+/// Emitted when a commitment is inserted into the Merkle tree
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct DepositEvent {
+    /// The deposited commitment
+    pub commitment: [u8; 32],
+    /// The leaf index of the commitment in the Merkle tree
+    pub leaf_index: u32,
+    /// The Unix timestamp of the deposit
+    pub timestamp: i64,
+}
+
+/// Emitted when a note is withdrawn
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct WithdrawEvent {
+    /// The nullifier hash of the spent note
+    pub nullifier_hash: [u8; 32],
+    /// The recipient address
+    pub recipient: Pubkey,
+    /// The relayer address
+    pub relayer: Pubkey,
+    /// The fee paid to the relayer
+    pub fee: u64,
+}
+
+/// Emitted by `GetRoot` with the latest root of an instance's Merkle tree
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct RootEvent {
+    /// The latest Merkle root
+    pub root: [u8; 32],
+    /// The index of the root in the root history
+    pub root_index: u8,
+}
+
+/// Emitted by `IsSpent` with whether a nullifier hash has been spent
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct NullifierStatusEvent {
+    /// The queried nullifier hash
+    pub nullifier_hash: [u8; 32],
+    /// Whether a withdrawal has spent the nullifier hash
+    pub spent: bool,
+}
+
+/// Emitted after the withdrawal event of a `WithdrawStealth`
+///
+/// Recipients scan these with their viewing key to find the stealth addresses
+/// they can spend from (see `stealth`).
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct StealthAnnouncementEvent {
+    /// The one-time stealth address paid
+    pub recipient: Pubkey,
+    /// The ephemeral pubkey the stealth address was derived with
+    pub ephemeral_pubkey: [u8; 32],
+}
+
+/// Events emitted by the Tornado Cash program
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub enum TornadoEvent {
+    /// A deposit (discriminator 0)
+    Deposit(DepositEvent),
+    /// A withdrawal (discriminator 1)
+    Withdraw(WithdrawEvent),
+    /// The latest root (discriminator 2)
+    Root(RootEvent),
+    /// The status of a nullifier hash (discriminator 3)
+    NullifierStatus(NullifierStatusEvent),
+    /// A stealth withdrawal's ephemeral pubkey (discriminator 4)
+    StealthAnnouncement(StealthAnnouncementEvent),
+}
+
+impl TornadoEvent {
+    /// Encode the event as log data
+    pub fn to_log_data(&self) -> Result<Vec<u8>, ProgramError> {
+        Ok(self.try_to_vec()?)
+    }
+
+    /// Decode an event from log data
+    pub fn from_log_data(data: &[u8]) -> Result<Self, ProgramError> {
+        Ok(Self::try_from_slice(data)?)
+    }
+
+    /// Write the event to the program logs
+    pub fn emit(&self) -> Result<(), ProgramError> {
+        sol_log_data(&[&self.to_log_data()?]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_encoding() {
+        let deposit = TornadoEvent::Deposit(DepositEvent {
+            commitment: [1u8; 32],
+            leaf_index: 7,
+            timestamp: 1_700_000_000,
+        });
+        let data = deposit.to_log_data().unwrap();
+        assert_eq!(data[0], 0);
+        assert_eq!(data.len(), 1 + 32 + 4 + 8);
+        assert_eq!(TornadoEvent::from_log_data(&data).unwrap(), deposit);
+
+        let withdraw = TornadoEvent::Withdraw(WithdrawEvent {
+            nullifier_hash: [2u8; 32],
+            recipient: Pubkey::new_unique(),
+            relayer: Pubkey::new_unique(),
+            fee: 1_000,
+        });
+        let data = withdraw.to_log_data().unwrap();
+        assert_eq!(data[0], 1);
+        assert_eq!(data.len(), 1 + 32 + 32 + 32 + 8);
+        assert_eq!(TornadoEvent::from_log_data(&data).unwrap(), withdraw);
+
+        let root = TornadoEvent::Root(RootEvent {
+            root: [3u8; 32],
+            root_index: 5,
+        });
+        let data = root.to_log_data().unwrap();
+        assert_eq!(data[0], 2);
+        assert_eq!(data.len(), 1 + 32 + 1);
+        assert_eq!(TornadoEvent::from_log_data(&data).unwrap(), root);
+
+        let status = TornadoEvent::NullifierStatus(NullifierStatusEvent {
+            nullifier_hash: [4u8; 32],
+            spent: true,
+        });
+        let data = status.to_log_data().unwrap();
+        assert_eq!(data[0], 3);
+        assert_eq!(data.len(), 1 + 32 + 1);
+        assert_eq!(TornadoEvent::from_log_data(&data).unwrap(), status);
+
+        let announcement = TornadoEvent::StealthAnnouncement(StealthAnnouncementEvent {
+            recipient: Pubkey::new_unique(),
+            ephemeral_pubkey: [5u8; 32],
+        });
+        let data = announcement.to_log_data().unwrap();
+        assert_eq!(data[0], 4);
+        assert_eq!(data.len(), 1 + 32 + 32);
+        assert_eq!(TornadoEvent::from_log_data(&data).unwrap(), announcement);
+
+        // Unknown discriminators and truncated data are rejected
+        assert!(TornadoEvent::from_log_data(&[5u8; 65]).is_err());
+        assert!(TornadoEvent::from_log_data(&data[..data.len() - 1]).is_err());
+    }
+}
@@ -2,11 +2,68 @@
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
     instruction::{AccountMeta, Instruction},
+    program::invoke_signed,
     program_error::ProgramError,
     pubkey::Pubkey,
-    system_program,
+    system_program, sysvar,
 };
+use spl_associated_token_account::get_associated_token_address;
+
+use crate::utils::{
+    compute_amount_commitment, find_commitment_address, find_compliance_list_address, find_encrypted_note_address, find_merkle_tree_address,
+    find_nullifier_address, find_partial_verifier_address, find_registry_address, find_vault_authority_address, find_verifier_address,
+    get_vault_address,
+};
+
+/// The maximum number of commitments in a `DepositBatch`
+///
+/// Each commitment adds 32 bytes of instruction data and a 32-byte marker
+/// account key, so a batch of this size still fits in a single transaction.
+pub const MAX_DEPOSIT_BATCH_SIZE: usize = 8;
+
+/// The maximum number of withdrawals in a `WithdrawBatch`
+///
+/// Each withdrawal carries a proof and three account keys, so a batch of this
+/// size only fits in a transaction with compressed proofs and its accounts in
+/// an address lookup table. Each proof verification also takes a large share
+/// of the compute budget.
+pub const MAX_WITHDRAW_BATCH_SIZE: usize = 4;
+
+/// The maximum size of the encrypted note stored with a `Deposit`, in bytes
+pub const MAX_ENCRYPTED_NOTE_SIZE: usize = 256;
+
+/// The return data of a `Deposit` or `DepositVariable`
+///
+/// Set with `set_return_data`, so a program depositing through a cross-program
+/// invocation can read the new leaf and root without reading the Merkle tree
+/// account. It is borsh-encoded: the leaf index in little-endian, then the root.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DepositReturnData {
+    /// The index of the inserted leaf
+    pub leaf_index: u32,
+    /// The Merkle root after the insertion
+    pub root: [u8; 32],
+}
+
+/// One withdrawal of a `WithdrawBatch`, with the arguments of a `Withdraw`
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct WithdrawRequest {
+    /// The Groth16 proof (`PROOF_SIZE` bytes uncompressed or `COMPRESSED_PROOF_SIZE` compressed over BN254)
+    pub proof: Vec<u8>,
+    /// The Merkle root
+    pub root: [u8; 32],
+    /// The nullifier hash
+    pub nullifier_hash: [u8; 32],
+    /// The recipient address
+    pub recipient: Pubkey,
+    /// The relayer address
+    pub relayer: Pubkey,
+    /// The fee to pay to the relayer
+    pub fee: u64,
+}
 
 /// Instructions supported by the Tornado Cash program
 #[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
@@ -14,39 +71,121 @@ pub enum TornadoInstruction {
     /// Initialize a new Tornado instance
     ///
     /// Accounts expected:
-    /// 0. `[signer]` The account that will pay for the initialization
+    /// 0. `[signer, writable]` The account that will pay for the initialization (becomes the instance admin)
     /// 1. `[writable]` The Tornado instance account to initialize
-    /// 2. `[]` System program
+    /// 2. `[writable]` The Merkle tree account (PDA of `[b"merkle_tree", instance, &[0]]`)
+    /// 3. `[writable]` The verifier account (PDA of `[b"verifier", instance, &[0]]`)
+    /// 4. `[writable]` The instance registry account (PDA of `[b"registry"]`, created on first use)
+    /// 5. `[]` System program
+    ///
+    /// The instance is added to the registry with its admin and verifying key
+    /// hash. Other instances may pool the same denomination and mint, so
+    /// clients should only use entries with an admin and key they trust.
     Initialize {
         /// The denomination amount for this instance
         denomination: u64,
-        /// The height of the Merkle tree
+        /// The height of the Merkle tree (its account must fit in `MAX_PERMITTED_DATA_LENGTH`)
         merkle_tree_height: u8,
+        /// The number of recent roots accepted by withdrawals (1 to `MAX_ROOT_HISTORY_SIZE`)
+        root_history_size: u8,
+        /// The oldest root a withdrawal may use, as the number of roots added since
+        ///
+        /// 0 accepts the whole root history; otherwise it must be below `root_history_size`.
+        max_root_age: u8,
+        /// The SPL token mint to pool (`None` for native SOL)
+        token_mint: Option<Pubkey>,
+        /// Accept arbitrary amounts up to `denomination` (native SOL only)
+        variable_amount: bool,
+        /// The largest relayer fee, in basis points of the amount withdrawn (at most `MAX_FEE_BPS`)
+        max_fee_bps: u16,
+        /// The hash algorithm of the Merkle tree (`HASH_ALGO_MIMC` or `HASH_ALGO_POSEIDON`)
+        ///
+        /// It must match the hash the withdrawal circuit uses for the tree.
+        hash_algo: u8,
+        /// The curve of the withdrawal proofs (`CURVE_BN254` or `CURVE_BLS12_381`)
+        curve: u8,
+        /// Require each withdrawal to be the only instruction of its transaction, besides compute budget instructions
+        ///
+        /// This stops a relayer from bundling other instructions with it (native SOL only).
+        strict_tx: bool,
+        /// The proof system of the withdrawal proofs (`PROOF_SYSTEM_GROTH16`)
+        ///
+        /// `PROOF_SYSTEM_PLONK` is reserved and rejected until it is implemented.
+        proof_system: u8,
+        /// The compressed `VerifyingKey` of `proof_system` over `curve` from the trusted setup
+        ///
+        /// The circuit has 7 public inputs, or 8 for a variable-amount instance.
+        verifying_key: Vec<u8>,
     },
 
     /// Deposit funds into the Tornado instance
     ///
     /// Accounts expected:
-    /// 0. `[signer]` The account that will deposit funds
+    /// 0. `[signer, writable]` The account that pays for the commitment marker
     /// 1. `[writable]` The Tornado instance account
     /// 2. `[writable]` The Merkle tree account
-    /// 3. `[]` System program
+    /// 3. `[writable]` The commitment marker account (PDA of `[b"commitment", instance, commitment]`)
+    /// 4. `[]` System program
+    /// 5. `[signer, writable]` The account that funds the deposit (account 0 again unless the deposit is sponsored)
+    /// 6. `[writable]` The encrypted note account (PDA of `[b"note", instance, commitment]`, only with `encrypted_note`)
+    /// 7. `[writable]` The instance's treasury (only when the instance charges a `deposit_fee`)
+    ///
+    /// Accounts 6 and 7 are each omitted when not needed, shifting the one after them.
+    ///
+    /// A sponsored deposit lets one account pay the transaction fees and rent
+    /// while another provides the denomination. The rent of the encrypted note
+    /// account is paid by account 0. The deposit fee is paid by whichever
+    /// account funds the deposit.
+    ///
+    /// A depositor that precomputed its Merkle path can pass `expected_index`
+    /// so the deposit fails rather than landing at another leaf. Likewise, a
+    /// depositor passing `max_deposit_fee` fails with `InvalidFee` rather than
+    /// paying a fee the admin raised after it signed.
+    ///
+    /// The leaf index and new root are returned as `DepositReturnData`.
     Deposit {
         /// The commitment to deposit
         commitment: [u8; 32],
+        /// The note encrypted by the depositor (at most `MAX_ENCRYPTED_NOTE_SIZE` bytes), stored for recovery
+        encrypted_note: Option<Vec<u8>>,
+        /// The leaf index the depositor expects the commitment to take, if any
+        expected_index: Option<u32>,
+        /// The largest `deposit_fee` the depositor agrees to pay, if any
+        max_deposit_fee: Option<u64>,
     },
 
     /// Withdraw funds from the Tornado instance
     ///
     /// Accounts expected:
-    /// 0. `[signer]` The account that will pay for the transaction (can be the relayer)
+    /// 0. `[signer, writable]` The account that will pay for the transaction (must be the relayer when `fee > 0`)
     /// 1. `[writable]` The Tornado instance account
     /// 2. `[writable]` The Merkle tree account
-    /// 3. `[writable]` The recipient account
-    /// 4. `[writable, optional]` The relayer account
-    /// 5. `[]` System program
+    /// 3. `[]` The verifier account
+    /// 4. `[writable]` The nullifier marker account (PDA of `[b"nullifier", instance, nullifier_hash]`)
+    /// 5. `[writable]` The recipient account
+    /// 6. `[writable]` The relayer account (the recipient again for a self-withdrawal)
+    /// 7. `[]` System program
+    /// 8. `[]` Instructions sysvar (only needed with `relayer_fee_signature` or on a `strict_tx` instance)
+    /// 9. `[]` The compliance list account (only needed when the instance has a `compliance_list`)
+    ///
+    /// A self-withdrawal sets `relayer` to `recipient` and must have `fee == 0`.
+    /// A recipient on the instance's compliance list is rejected.
+    ///
+    /// On a `strict_tx` instance, the withdrawal must be the only instruction of
+    /// its transaction besides compute budget instructions and a fee quote's
+    /// ed25519 program instruction.
+    ///
+    /// With `relayer_fee_signature`, the instruction immediately before this one
+    /// must be an ed25519 program instruction verifying the relayer's signature
+    /// over `relayer_fee_quote_message(nullifier_hash, fee, recipient)`.
+    ///
+    /// With `root_index`, the root must be at that index of the root history,
+    /// which is checked directly rather than searched for: the `root_index` of
+    /// the `RootEvent` that reported it, or the tree's `current_root_index`
+    /// when it was read. A root that has since been overwritten or an index
+    /// outside the history fails with `InvalidMerkleRoot`.
     Withdraw {
-        /// The proof data
+        /// The Groth16 proof (`PROOF_SIZE` bytes uncompressed or `COMPRESSED_PROOF_SIZE` compressed over BN254)
         proof: Vec<u8>,
         /// The Merkle root
         root: [u8; 32],
@@ -60,26 +199,498 @@ pub enum TornadoInstruction {
         fee: u64,
         /// The refund amount (for token instances)
         refund: u64,
+        /// The relayer's signature over its fee quote, if the fee was quoted
+        relayer_fee_signature: Option<[u8; 64]>,
+        /// The index of `root` in the root history, if known, to look it up without searching
+        root_index: Option<u8>,
+    },
+
+    /// Deposit SPL tokens into a token Tornado instance
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The account that will deposit tokens
+    /// 1. `[writable]` The Tornado instance account
+    /// 2. `[writable]` The Merkle tree account
+    /// 3. `[writable]` The commitment marker account (PDA of `[b"commitment", instance, commitment]`)
+    /// 4. `[writable]` The depositor's token account
+    /// 5. `[writable]` The instance token vault (associated token account of the vault authority)
+    /// 6. `[]` SPL Token program
+    /// 7. `[]` System program
+    DepositToken {
+        /// The commitment to deposit
+        commitment: [u8; 32],
+    },
+
+    /// Withdraw SPL tokens from a token Tornado instance
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The account that will pay for the transaction and the refund (must be the relayer when `fee > 0`)
+    /// 1. `[writable]` The Tornado instance account
+    /// 2. `[writable]` The Merkle tree account
+    /// 3. `[]` The verifier account
+    /// 4. `[writable]` The nullifier marker account (PDA of `[b"nullifier", instance, nullifier_hash]`)
+    /// 5. `[writable]` The recipient account (receives the SOL refund)
+    /// 6. `[writable]` The recipient's associated token account
+    /// 7. `[writable]` The relayer's token account (the recipient's again for a self-withdrawal)
+    /// 8. `[writable]` The instance token vault
+    /// 9. `[]` The vault authority PDA
+    /// 10. `[]` SPL Token program
+    /// 11. `[]` System program
+    /// 12. `[]` The token mint
+    /// 13. `[]` SPL Associated Token Account program
+    ///
+    /// A self-withdrawal sets `relayer` to `recipient` and must have `fee == 0`.
+    /// If the recipient's associated token account doesn't exist yet, it is
+    /// created and its rent is taken out of the refund.
+    ///
+    /// With `fee_in_sol`, the recipient receives the whole denomination in tokens
+    /// and the relayer (the payer) is paid `fee` lamports from the instance
+    /// account's balance above its rent-exempt minimum. The fee may be at most
+    /// the instance's `max_sol_fee` (see `SetMaxSolFee`). The proof commits to
+    /// the fee's unit (see `SOL_FEE_TAG`), so the flag must match the proof.
+    WithdrawToken {
+        /// The Groth16 proof (`PROOF_SIZE` bytes uncompressed or `COMPRESSED_PROOF_SIZE` compressed over BN254)
+        proof: Vec<u8>,
+        /// The Merkle root
+        root: [u8; 32],
+        /// The nullifier hash
+        nullifier_hash: [u8; 32],
+        /// The recipient address
+        recipient: Pubkey,
+        /// The relayer address
+        relayer: Pubkey,
+        /// The fee to pay to the relayer (in tokens, or in lamports with `fee_in_sol`)
+        fee: u64,
+        /// The SOL refund sent from the payer to the recipient
+        refund: u64,
+        /// Pay the fee in SOL from the instance account instead of in tokens
+        fee_in_sol: bool,
+    },
+
+    /// Halt deposits and withdrawals of a Tornado instance
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The instance admin
+    /// 1. `[writable]` The Tornado instance account
+    Pause,
+
+    /// Resume deposits and withdrawals of a paused Tornado instance
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The instance admin
+    /// 1. `[writable]` The Tornado instance account
+    Unpause,
+
+    /// Deposit an arbitrary amount into a variable-amount Tornado instance
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The account that pays for the commitment marker
+    /// 1. `[writable]` The Tornado instance account
+    /// 2. `[writable]` The Merkle tree account
+    /// 3. `[writable]` The commitment marker account (PDA of `[b"commitment", instance, leaf]`)
+    /// 4. `[]` System program
+    /// 5. `[signer, writable]` The account that funds the deposit (account 0 again unless the deposit is sponsored)
+    /// 6. `[writable]` The instance's treasury (only when the instance charges a `deposit_fee`)
+    ///
+    /// The leaf inserted into the tree is `Poseidon(commitment, amount)`. The
+    /// leaf index and new root are returned as `DepositReturnData`.
+    DepositVariable {
+        /// The commitment to deposit
+        commitment: [u8; 32],
+        /// The amount to deposit (at most the instance denomination)
+        amount: u64,
+    },
+
+    /// Withdraw from a variable-amount Tornado instance
+    ///
+    /// Accounts expected: as for `Withdraw`
+    ///
+    /// The proof has the withdrawn `amount` as an extra, last public input.
+    WithdrawVariable {
+        /// The Groth16 proof (`PROOF_SIZE` bytes uncompressed or `COMPRESSED_PROOF_SIZE` compressed over BN254)
+        proof: Vec<u8>,
+        /// The Merkle root
+        root: [u8; 32],
+        /// The nullifier hash
+        nullifier_hash: [u8; 32],
+        /// The recipient address
+        recipient: Pubkey,
+        /// The relayer address
+        relayer: Pubkey,
+        /// The fee to pay to the relayer
+        fee: u64,
+        /// The refund amount (must be 0)
+        refund: u64,
+        /// The amount committed in the deposit
+        amount: u64,
+    },
+
+    /// Deposit several commitments into a fixed-denomination Tornado instance
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The account that will deposit funds
+    /// 1. `[writable]` The Tornado instance account
+    /// 2. `[writable]` The Merkle tree account
+    /// 3. `[]` System program
+    /// 4. `[writable]` One commitment marker account per commitment, in order
+    /// 5. `[writable]` The instance's treasury, after the markers (only when the instance charges a `deposit_fee`)
+    ///
+    /// The instance receives `denomination * commitments.len()` in one transfer,
+    /// and the treasury `deposit_fee * commitments.len()` in another. As with
+    /// `Deposit`, `max_deposit_fee` bounds the fee per commitment.
+    DepositBatch {
+        /// The commitments to deposit (at most `MAX_DEPOSIT_BATCH_SIZE`)
+        commitments: Vec<[u8; 32]>,
+        /// The largest `deposit_fee` the depositor agrees to pay per commitment, if any
+        max_deposit_fee: Option<u64>,
+    },
+
+    /// Log the latest root of the Merkle tree and its index as a `RootEvent`
+    ///
+    /// Accounts expected:
+    /// 0. `[]` The Tornado instance account
+    /// 1. `[]` The Merkle tree account
+    GetRoot,
+
+    /// Upgrade an instance and its Merkle tree to the current account layouts
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The account that pays for any extra rent
+    /// 1. `[writable]` The Tornado instance account
+    /// 2. `[writable]` The Merkle tree account
+    /// 3. `[]` System program
+    /// 4. `[]` The verifier account (only needed for an unversioned instance)
+    ///
+    /// Accounts already at the current version are left unchanged. Migrating
+    /// an instance records the hash of the verifying key it has been using as
+    /// its `vk_hash`.
+    Migrate,
+
+    /// Close an emptied Tornado instance, reclaiming the rent of its accounts
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The instance admin
+    /// 1. `[writable]` The Tornado instance account
+    /// 2. `[writable]` The Merkle tree account
+    /// 3. `[writable]` The verifier account
+    /// 4. `[writable]` The account receiving the reclaimed lamports
+    /// 5. `[writable]` The instance registry account
+    /// 6. `[writable]` The instance token vault (token instances only)
+    /// 7. `[]` The vault authority PDA (token instances only)
+    /// 8. `[]` SPL Token program (token instances only)
+    ///
+    /// Every deposit must have been withdrawn. Any lamports the instance still
+    /// holds, such as dust or plain transfers, go to the receiver with its
+    /// rent. The vault of a token pool must be empty and is closed too. The instance is removed from the registry.
+    CloseInstance,
+
+    /// Log whether a nullifier hash has been spent as a `NullifierStatusEvent`
+    ///
+    /// Accounts expected:
+    /// 0. `[]` The Tornado instance account
+    /// 1. `[]` The Merkle tree account
+    /// 2. `[]` The nullifier marker PDA of the nullifier hash
+    ///
+    /// Nullifier hashes spent before nullifier markers existed are looked up
+    /// in the Merkle tree. Nothing is modified, so this can be simulated.
+    IsSpent {
+        /// The nullifier hash to look up
+        nullifier_hash: [u8; 32],
+    },
+
+    /// Remove a root from the root history, so withdrawals can no longer use it
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The instance admin
+    /// 1. `[]` The Tornado instance account
+    /// 2. `[writable]` The Merkle tree account
+    ///
+    /// Notes proven against other roots in the history can still be withdrawn.
+    InvalidateRoot {
+        /// The root to invalidate
+        root: [u8; 32],
+    },
+
+    /// Run every check of a SOL withdrawal without performing it
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The account that would pay for the withdrawal (must be the relayer when `fee > 0`)
+    /// 1. `[]` The Tornado instance account
+    /// 2. `[]` The Merkle tree account
+    /// 3. `[]` The verifier account
+    /// 4. `[]` The nullifier marker PDA of the nullifier hash
+    /// 5. `[]` The compliance list account (only needed when the instance has a `compliance_list`)
+    ///
+    /// Fails with the error the withdrawal would fail with. Nothing is
+    /// modified, so a relayer can simulate this to vet a withdrawal request
+    /// before paying for it. Neither a relayer fee quote nor the transaction of
+    /// a `strict_tx` instance is checked.
+    SimulateWithdraw {
+        /// The Groth16 proof (`PROOF_SIZE` bytes uncompressed or `COMPRESSED_PROOF_SIZE` compressed over BN254)
+        proof: Vec<u8>,
+        /// The Merkle root
+        root: [u8; 32],
+        /// The nullifier hash
+        nullifier_hash: [u8; 32],
+        /// The recipient address
+        recipient: Pubkey,
+        /// The relayer address
+        relayer: Pubkey,
+        /// The fee to pay to the relayer
+        fee: u64,
+        /// The refund amount (must be 0)
+        refund: u64,
+        /// The amount committed in the deposit of a variable-amount instance (`None` otherwise)
+        amount: Option<u64>,
+    },
+
+    /// Set or clear the compliance list of a SOL Tornado instance
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The instance admin (pays for the compliance list account)
+    /// 1. `[writable]` The Tornado instance account
+    /// 2. `[writable]` The compliance list account (PDA of `[b"compliance_list", instance]`, created on first use)
+    /// 3. `[]` System program
+    ///
+    /// Withdrawals to a listed recipient fail with `RecipientBlocked`. Clearing
+    /// the list makes the instance permissionless again; the account is kept,
+    /// so it can be set again later.
+    SetComplianceList {
+        /// The blocked recipients in strictly ascending order (`None` to disable the list)
+        recipients: Option<Vec<Pubkey>>,
+    },
+
+    /// Store the verifying key of the partial withdrawal circuit of a variable-amount SOL instance
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The instance admin (pays for the verifier account)
+    /// 1. `[]` The Tornado instance account
+    /// 2. `[]` The Merkle tree account
+    /// 3. `[writable]` The partial withdrawal verifier account (PDA of `[b"verifier", instance, &[1]]`)
+    /// 4. `[]` System program
+    ///
+    /// The key can only be set once, like the instance's own verifying key, and
+    /// only before the first deposit, so depositors can vet it. Later, it fails
+    /// with `InstanceNotEmpty`.
+    SetPartialVerifier {
+        /// The compressed verifying key, with `NUM_PARTIAL_WITHDRAW_PUBLIC_INPUTS` public inputs
+        verifying_key: Vec<u8>,
+    },
+
+    /// Withdraw part of a note from a variable-amount Tornado instance, re-depositing the remainder
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The account that will pay for the transaction (must be the relayer when `fee > 0`)
+    /// 1. `[writable]` The Tornado instance account
+    /// 2. `[writable]` The Merkle tree account
+    /// 3. `[]` The partial withdrawal verifier account (PDA of `[b"verifier", instance, &[1]]`)
+    /// 4. `[writable]` The nullifier marker account (PDA of `[b"nullifier", instance, nullifier_hash]`)
+    /// 5. `[writable]` The commitment marker account of the remainder (PDA of `[b"commitment", instance, leaf]`)
+    /// 6. `[writable]` The recipient account
+    /// 7. `[writable]` The relayer account (the recipient again for a self-withdrawal)
+    /// 8. `[]` System program
+    /// 9. `[]` Instructions sysvar (only needed on a `strict_tx` instance)
+    /// 10. `[]` The compliance list account (only needed when the instance has a `compliance_list`)
+    ///
+    /// The input note is spent, `input_amount - output_amount` is paid out (less
+    /// the fee) and `Poseidon(output_commitment, output_amount)` is inserted into
+    /// the tree, as by a `DepositVariable` of the remainder. The spend and the
+    /// insertion happen in the same instruction, so either both take effect or
+    /// neither does. The new leaf's index and root are returned as `DepositReturnData`.
+    WithdrawPartial {
+        /// The Groth16 proof (`PROOF_SIZE` bytes uncompressed or `COMPRESSED_PROOF_SIZE` compressed over BN254)
+        proof: Vec<u8>,
+        /// The Merkle root
+        root: [u8; 32],
+        /// The nullifier hash of the input note
+        nullifier_hash: [u8; 32],
+        /// The recipient address
+        recipient: Pubkey,
+        /// The relayer address
+        relayer: Pubkey,
+        /// The fee to pay to the relayer, out of the amount withdrawn
+        fee: u64,
+        /// The refund amount (must be 0)
+        refund: u64,
+        /// The amount committed in the input note's deposit
+        input_amount: u64,
+        /// The remainder re-deposited (more than 0 and less than `input_amount`)
+        output_amount: u64,
+        /// The commitment of the note holding the remainder
+        output_commitment: [u8; 32],
+    },
+
+    /// Check the invariants of an instance's state, logging whether each holds
+    ///
+    /// Accounts expected:
+    /// 0. `[]` The Tornado instance account
+    /// 1. `[]` The Merkle tree account
+    /// 2. `[]` The verifier account
+    ///
+    /// Fails with `InconsistentState` if any check fails, after running them
+    /// all. Nothing is modified, so this can be simulated.
+    SelfCheck,
+
+    /// Withdraw several notes from a fixed-denomination Tornado instance
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The account that will pay for the transaction (must be the relayer of every withdrawal with `fee > 0`)
+    /// 1. `[writable]` The Tornado instance account
+    /// 2. `[writable]` The Merkle tree account
+    /// 3. `[]` The verifier account
+    /// 4. `[]` System program
+    /// 5. `[]` Instructions sysvar (only checked on a `strict_tx` instance)
+    /// 6. `[]` The compliance list account (only checked when the instance has a `compliance_list`)
+    /// 7. For each withdrawal, in order: `[writable]` its nullifier marker
+    ///    account, `[writable]` its recipient and `[writable]` its relayer
+    ///
+    /// Every withdrawal is checked as a `Withdraw` before any is settled, so
+    /// the batch succeeds or fails as a whole. A failing batch logs the index
+    /// of the first invalid withdrawal and sets it as the return data, a
+    /// little-endian `u32`.
+    WithdrawBatch {
+        /// The withdrawals (at most `MAX_WITHDRAW_BATCH_SIZE`)
+        withdrawals: Vec<WithdrawRequest>,
+    },
+
+    /// Set the deposit fee of a SOL Tornado instance and the treasury it is paid to
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The instance admin
+    /// 1. `[writable]` The Tornado instance account
+    ///
+    /// Every later `Deposit`, `DepositVariable` and `DepositBatch` pays
+    /// `deposit_fee` lamports per commitment to the treasury, on top of the
+    /// amount deposited. A fee of 0 turns it off. The fee may be at most
+    /// `MAX_DEPOSIT_FEE_BPS` of the denomination; a larger one fails with
+    /// `InvalidFee`.
+    SetDepositFee {
+        /// The lamports charged per deposit
+        deposit_fee: u64,
+        /// The account the fee is paid to
+        treasury: Pubkey,
+    },
+
+    /// Set the number of deposits a Tornado instance must hold before withdrawals are allowed
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The instance admin
+    /// 1. `[writable]` The Tornado instance account
+    ///
+    /// Until the Merkle tree's `next_index` reaches `min_anonymity_set`, every
+    /// withdrawal fails with `AnonymitySetTooSmall`. This keeps a depositor from
+    /// withdrawing out of a pool too small to hide in. 0 turns it off.
+    SetMinAnonymitySet {
+        /// The number of deposits required
+        min_anonymity_set: u32,
+    },
+
+    /// Set how long a Tornado instance's withdrawals are time-locked for
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The instance admin
+    /// 1. `[writable]` The Tornado instance account
+    ///
+    /// A withdrawal fails with `WithdrawTooEarly` unless the root it proves
+    /// against was inserted at least `withdraw_delay_secs` ago. The lock is on
+    /// roots rather than notes, so a withdrawal proves against an older root
+    /// and hides among the deposits made before it. 0 turns it off.
+    SetWithdrawDelay {
+        /// The seconds a root must have been in the tree for
+        withdraw_delay_secs: u32,
+    },
+
+    /// Withdraw funds from the Tornado instance to a one-time stealth address
+    ///
+    /// Accounts expected: the same as `Withdraw`, with the stealth address as
+    /// the recipient.
+    ///
+    /// The withdrawer derives `recipient` from the recipient's published
+    /// stealth meta-address and an ephemeral key (see `stealth`), so the
+    /// address paid can't be linked to the recipient. The proof's `recipient`
+    /// signal is `stealth_recipient(recipient, ephemeral_pubkey)`, binding
+    /// both. The ephemeral pubkey is announced in a `StealthAnnouncement`
+    /// event, which the recipient scans to find its payments.
+    ///
+    /// Only fixed-denomination SOL instances support stealth withdrawals.
+    WithdrawStealth {
+        /// The Groth16 proof (`PROOF_SIZE` bytes uncompressed or `COMPRESSED_PROOF_SIZE` compressed over BN254)
+        proof: Vec<u8>,
+        /// The Merkle root
+        root: [u8; 32],
+        /// The nullifier hash
+        nullifier_hash: [u8; 32],
+        /// The one-time stealth address
+        recipient: Pubkey,
+        /// The ephemeral pubkey the stealth address was derived with
+        ephemeral_pubkey: [u8; 32],
+        /// The relayer address
+        relayer: Pubkey,
+        /// The fee to pay to the relayer
+        fee: u64,
+    },
+
+    /// Set the largest relayer fee a token Tornado instance's withdrawals may pay in SOL
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The instance admin
+    /// 1. `[writable]` The Tornado instance account
+    ///
+    /// A `WithdrawToken` with `fee_in_sol` and a fee above `max_sol_fee`
+    /// lamports fails with `InvalidFee`, however much SOL the instance holds.
+    /// This keeps one withdrawal from draining the SOL every other withdrawal's
+    /// fee is paid from. 0, the default, allows no fees in SOL.
+    SetMaxSolFee {
+        /// The largest fee in lamports
+        max_sol_fee: u64,
     },
 }
 
 /// Create an Initialize instruction
+#[allow(clippy::too_many_arguments)]
 pub fn initialize(
     program_id: &Pubkey,
     payer: &Pubkey,
     tornado_instance: &Pubkey,
     denomination: u64,
     merkle_tree_height: u8,
+    root_history_size: u8,
+    max_root_age: u8,
+    token_mint: Option<Pubkey>,
+    variable_amount: bool,
+    max_fee_bps: u16,
+    hash_algo: u8,
+    curve: u8,
+    strict_tx: bool,
+    proof_system: u8,
+    verifying_key: Vec<u8>,
 ) -> Result<Instruction, ProgramError> {
     let data = TornadoInstruction::Initialize {
         denomination,
         merkle_tree_height,
+        root_history_size,
+        max_root_age,
+        token_mint,
+        variable_amount,
+        max_fee_bps,
+        hash_algo,
+        curve,
+        strict_tx,
+        proof_system,
+        verifying_key,
     }
     .try_to_vec()?;
 
+    let (merkle_tree, _) = find_merkle_tree_address(program_id, tornado_instance);
+    let (verifier, _) = find_verifier_address(program_id, tornado_instance);
+    let (registry, _) = find_registry_address(program_id);
+
     let accounts = vec![
         AccountMeta::new(*payer, true),
         AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(merkle_tree, false),
+        AccountMeta::new(verifier, false),
+        AccountMeta::new(registry, false),
         AccountMeta::new_readonly(system_program::id(), false),
     ];
 
@@ -91,21 +702,52 @@ pub fn initialize(
 }
 
 /// Create a Deposit instruction
+///
+/// `encrypted_note` is stored with the deposit for recovery (`None` to store
+/// nothing), `expected_index` is the leaf index the commitment must take
+/// (`None` for any), `max_deposit_fee` is the largest deposit fee the
+/// depositor agrees to pay (`None` for any), `treasury` is the instance's
+/// treasury (`None` if it charges no deposit fee) and `funding_account` funds
+/// a sponsored deposit (`None` for the payer).
+#[allow(clippy::too_many_arguments)]
 pub fn deposit(
     program_id: &Pubkey,
     payer: &Pubkey,
     tornado_instance: &Pubkey,
     merkle_tree: &Pubkey,
     commitment: [u8; 32],
+    encrypted_note: Option<Vec<u8>>,
+    expected_index: Option<u32>,
+    max_deposit_fee: Option<u64>,
+    treasury: Option<&Pubkey>,
+    funding_account: Option<&Pubkey>,
 ) -> Result<Instruction, ProgramError> {
-    let data = TornadoInstruction::Deposit { commitment }.try_to_vec()?;
+    let has_encrypted_note = encrypted_note.is_some();
+    let data = TornadoInstruction::Deposit {
+        commitment,
+        encrypted_note,
+        expected_index,
+        max_deposit_fee,
+    }
+    .try_to_vec()?;
 
-    let accounts = vec![
+    let (commitment_marker, _) = find_commitment_address(program_id, tornado_instance, &commitment);
+
+    let mut accounts = vec![
         AccountMeta::new(*payer, true),
         AccountMeta::new(*tornado_instance, false),
         AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new(commitment_marker, false),
         AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new(*funding_account.unwrap_or(payer), true),
     ];
+    if has_encrypted_note {
+        let (encrypted_note, _) = find_encrypted_note_address(program_id, tornado_instance, &commitment);
+        accounts.push(AccountMeta::new(encrypted_note, false));
+    }
+    if let Some(treasury) = treasury {
+        accounts.push(AccountMeta::new(*treasury, false));
+    }
 
     Ok(Instruction {
         program_id: *program_id,
@@ -115,6 +757,7 @@ pub fn deposit(
 }
 
 /// Create a Withdraw instruction
+#[allow(clippy::too_many_arguments)]
 pub fn withdraw(
     program_id: &Pubkey,
     payer: &Pubkey,
@@ -127,6 +770,8 @@ pub fn withdraw(
     nullifier_hash: [u8; 32],
     fee: u64,
     refund: u64,
+    relayer_fee_signature: Option<[u8; 64]>,
+    root_index: Option<u8>,
 ) -> Result<Instruction, ProgramError> {
     let data = TornadoInstruction::Withdraw {
         proof,
@@ -136,16 +781,25 @@ pub fn withdraw(
         relayer: *relayer,
         fee,
         refund,
+        relayer_fee_signature,
+        root_index,
     }
     .try_to_vec()?;
 
+    let (verifier, _) = find_verifier_address(program_id, tornado_instance);
+    let (nullifier_marker, _) = find_nullifier_address(program_id, tornado_instance, &nullifier_hash);
+
     let accounts = vec![
         AccountMeta::new(*payer, true),
         AccountMeta::new(*tornado_instance, false),
         AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new_readonly(verifier, false),
+        AccountMeta::new(nullifier_marker, false),
         AccountMeta::new(*recipient, false),
         AccountMeta::new(*relayer, false),
         AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::instructions::id(), false),
+        AccountMeta::new_readonly(find_compliance_list_address(program_id, tornado_instance).0, false),
     ];
 
     Ok(Instruction {
@@ -153,4 +807,834 @@ pub fn withdraw(
         accounts,
         data,
     })
-}
\ No newline at end of file
+}
+
+/// Create a WithdrawStealth instruction
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw_stealth(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    recipient: &Pubkey,
+    relayer: &Pubkey,
+    proof: Vec<u8>,
+    root: [u8; 32],
+    nullifier_hash: [u8; 32],
+    ephemeral_pubkey: [u8; 32],
+    fee: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::WithdrawStealth {
+        proof,
+        root,
+        nullifier_hash,
+        recipient: *recipient,
+        ephemeral_pubkey,
+        relayer: *relayer,
+        fee,
+    }
+    .try_to_vec()?;
+
+    let (verifier, _) = find_verifier_address(program_id, tornado_instance);
+    let (nullifier_marker, _) = find_nullifier_address(program_id, tornado_instance, &nullifier_hash);
+
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new_readonly(verifier, false),
+        AccountMeta::new(nullifier_marker, false),
+        AccountMeta::new(*recipient, false),
+        AccountMeta::new(*relayer, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::instructions::id(), false),
+        AccountMeta::new_readonly(find_compliance_list_address(program_id, tornado_instance).0, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Deposit into a Tornado instance from another program
+///
+/// `accounts` are the `Deposit` accounts in order: the payer, instance, Merkle
+/// tree, commitment marker, System Program and funding account, then the
+/// treasury if the instance charges a deposit fee. `signer_seeds` sign for a
+/// payer or funding account that is a PDA of the calling program, which must
+/// be owned by the System Program and hold no data to pay; pass `&[]` when
+/// both signed the transaction.
+pub fn invoke_deposit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    commitment: [u8; 32],
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let payer = next_account_info(account_info_iter)?;
+    let tornado_instance = next_account_info(account_info_iter)?;
+    let merkle_tree = next_account_info(account_info_iter)?;
+    let _commitment_marker = next_account_info(account_info_iter)?;
+    let _system_program = next_account_info(account_info_iter)?;
+    let funding_account = next_account_info(account_info_iter)?;
+    let treasury = account_info_iter.next().map(|treasury| treasury.key);
+
+    let instruction = deposit(
+        program_id,
+        payer.key,
+        tornado_instance.key,
+        merkle_tree.key,
+        commitment,
+        None,
+        None,
+        None,
+        treasury,
+        Some(funding_account.key),
+    )?;
+    invoke_signed(&instruction, accounts, signer_seeds)
+}
+
+/// Withdraw from a Tornado instance from another program
+///
+/// `accounts` are the ten `Withdraw` accounts in order; the recipient and
+/// relayer are taken from them. `signer_seeds` sign for a payer that is a PDA
+/// of the calling program, as in `invoke_deposit`. A fee can only be paid to
+/// a relayer that is the payer, since no relayer fee quote is passed, and
+/// instances with `strict_tx` set reject every withdrawal through CPI.
+#[allow(clippy::too_many_arguments)]
+pub fn invoke_withdraw(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    proof: Vec<u8>,
+    root: [u8; 32],
+    nullifier_hash: [u8; 32],
+    fee: u64,
+    refund: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let payer = next_account_info(account_info_iter)?;
+    let tornado_instance = next_account_info(account_info_iter)?;
+    let merkle_tree = next_account_info(account_info_iter)?;
+    let _verifier = next_account_info(account_info_iter)?;
+    let _nullifier_marker = next_account_info(account_info_iter)?;
+    let recipient = next_account_info(account_info_iter)?;
+    let relayer = next_account_info(account_info_iter)?;
+
+    let instruction = withdraw(
+        program_id,
+        payer.key,
+        tornado_instance.key,
+        merkle_tree.key,
+        recipient.key,
+        relayer.key,
+        proof,
+        root,
+        nullifier_hash,
+        fee,
+        refund,
+        None,
+        None,
+    )?;
+    invoke_signed(&instruction, accounts, signer_seeds)
+}
+
+/// Create a DepositVariable instruction
+///
+/// `treasury` is the instance's treasury (`None` if it charges no deposit fee)
+/// and `funding_account` funds a sponsored deposit (`None` for the payer).
+#[allow(clippy::too_many_arguments)]
+pub fn deposit_variable(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    commitment: [u8; 32],
+    amount: u64,
+    treasury: Option<&Pubkey>,
+    funding_account: Option<&Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::DepositVariable { commitment, amount }.try_to_vec()?;
+
+    let leaf = compute_amount_commitment(&commitment, amount)?;
+    let (commitment_marker, _) = find_commitment_address(program_id, tornado_instance, &leaf);
+
+    let mut accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new(commitment_marker, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new(*funding_account.unwrap_or(payer), true),
+    ];
+    if let Some(treasury) = treasury {
+        accounts.push(AccountMeta::new(*treasury, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a WithdrawVariable instruction
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw_variable(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    recipient: &Pubkey,
+    relayer: &Pubkey,
+    proof: Vec<u8>,
+    root: [u8; 32],
+    nullifier_hash: [u8; 32],
+    fee: u64,
+    refund: u64,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::WithdrawVariable {
+        proof,
+        root,
+        nullifier_hash,
+        recipient: *recipient,
+        relayer: *relayer,
+        fee,
+        refund,
+        amount,
+    }
+    .try_to_vec()?;
+
+    let (verifier, _) = find_verifier_address(program_id, tornado_instance);
+    let (nullifier_marker, _) = find_nullifier_address(program_id, tornado_instance, &nullifier_hash);
+
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new_readonly(verifier, false),
+        AccountMeta::new(nullifier_marker, false),
+        AccountMeta::new(*recipient, false),
+        AccountMeta::new(*relayer, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::instructions::id(), false),
+        AccountMeta::new_readonly(find_compliance_list_address(program_id, tornado_instance).0, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a DepositToken instruction
+pub fn deposit_token(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    payer_token_account: &Pubkey,
+    token_mint: &Pubkey,
+    commitment: [u8; 32],
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::DepositToken { commitment }.try_to_vec()?;
+
+    let (commitment_marker, _) = find_commitment_address(program_id, tornado_instance, &commitment);
+
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new(commitment_marker, false),
+        AccountMeta::new(*payer_token_account, false),
+        AccountMeta::new(get_vault_address(program_id, tornado_instance, token_mint), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a WithdrawToken instruction
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw_token(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    token_mint: &Pubkey,
+    recipient: &Pubkey,
+    relayer: &Pubkey,
+    relayer_token_account: &Pubkey,
+    proof: Vec<u8>,
+    root: [u8; 32],
+    nullifier_hash: [u8; 32],
+    fee: u64,
+    refund: u64,
+    fee_in_sol: bool,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::WithdrawToken {
+        proof,
+        root,
+        nullifier_hash,
+        recipient: *recipient,
+        relayer: *relayer,
+        fee,
+        refund,
+        fee_in_sol,
+    }
+    .try_to_vec()?;
+
+    let (verifier, _) = find_verifier_address(program_id, tornado_instance);
+    let (nullifier_marker, _) = find_nullifier_address(program_id, tornado_instance, &nullifier_hash);
+    let (vault_authority, _) = find_vault_authority_address(program_id, tornado_instance);
+
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new_readonly(verifier, false),
+        AccountMeta::new(nullifier_marker, false),
+        AccountMeta::new(*recipient, false),
+        AccountMeta::new(get_associated_token_address(recipient, token_mint), false),
+        AccountMeta::new(*relayer_token_account, false),
+        AccountMeta::new(get_vault_address(program_id, tornado_instance, token_mint), false),
+        AccountMeta::new_readonly(vault_authority, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*token_mint, false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a Pause instruction
+pub fn pause(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tornado_instance: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::Pause.try_to_vec()?;
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(*tornado_instance, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create an Unpause instruction
+pub fn unpause(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tornado_instance: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::Unpause.try_to_vec()?;
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(*tornado_instance, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a DepositBatch instruction
+///
+/// `max_deposit_fee` is the largest deposit fee per commitment the depositor
+/// agrees to pay (`None` for any) and `treasury` is the instance's treasury
+/// (`None` if it charges no deposit fee).
+pub fn deposit_batch(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    commitments: Vec<[u8; 32]>,
+    max_deposit_fee: Option<u64>,
+    treasury: Option<&Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    for commitment in &commitments {
+        let (commitment_marker, _) = find_commitment_address(program_id, tornado_instance, commitment);
+        accounts.push(AccountMeta::new(commitment_marker, false));
+    }
+    if let Some(treasury) = treasury {
+        accounts.push(AccountMeta::new(*treasury, false));
+    }
+
+    let data = TornadoInstruction::DepositBatch {
+        commitments,
+        max_deposit_fee,
+    }
+    .try_to_vec()?;
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a GetRoot instruction
+pub fn get_root(
+    program_id: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::GetRoot.try_to_vec()?;
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*tornado_instance, false),
+        AccountMeta::new_readonly(*merkle_tree, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create an IsSpent instruction
+pub fn is_spent(
+    program_id: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    nullifier_hash: [u8; 32],
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::IsSpent { nullifier_hash }.try_to_vec()?;
+
+    let (nullifier_marker, _) = find_nullifier_address(program_id, tornado_instance, &nullifier_hash);
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*tornado_instance, false),
+        AccountMeta::new_readonly(*merkle_tree, false),
+        AccountMeta::new_readonly(nullifier_marker, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create an InvalidateRoot instruction
+pub fn invalidate_root(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    root: [u8; 32],
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::InvalidateRoot { root }.try_to_vec()?;
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new_readonly(*tornado_instance, false),
+        AccountMeta::new(*merkle_tree, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a CloseInstance instruction
+///
+/// `token_mint` is the mint of a token instance (`None` for native SOL).
+pub fn close_instance(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tornado_instance: &Pubkey,
+    receiver: &Pubkey,
+    token_mint: Option<&Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::CloseInstance.try_to_vec()?;
+
+    let (merkle_tree, _) = find_merkle_tree_address(program_id, tornado_instance);
+    let (verifier, _) = find_verifier_address(program_id, tornado_instance);
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(merkle_tree, false),
+        AccountMeta::new(verifier, false),
+        AccountMeta::new(*receiver, false),
+        AccountMeta::new(find_registry_address(program_id).0, false),
+    ];
+    if let Some(token_mint) = token_mint {
+        let (vault_authority, _) = find_vault_authority_address(program_id, tornado_instance);
+        accounts.push(AccountMeta::new(get_vault_address(program_id, tornado_instance, token_mint), false));
+        accounts.push(AccountMeta::new_readonly(vault_authority, false));
+        accounts.push(AccountMeta::new_readonly(spl_token::id(), false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a Migrate instruction
+///
+/// `verifier` is the verifier account stored in the instance.
+pub fn migrate(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    verifier: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::Migrate.try_to_vec()?;
+
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*verifier, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a SimulateWithdraw instruction
+///
+/// `amount` is the proven amount of a variable-amount instance (`None` for a
+/// fixed denomination).
+#[allow(clippy::too_many_arguments)]
+pub fn simulate_withdraw(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    recipient: &Pubkey,
+    relayer: &Pubkey,
+    proof: Vec<u8>,
+    root: [u8; 32],
+    nullifier_hash: [u8; 32],
+    fee: u64,
+    refund: u64,
+    amount: Option<u64>,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::SimulateWithdraw {
+        proof,
+        root,
+        nullifier_hash,
+        recipient: *recipient,
+        relayer: *relayer,
+        fee,
+        refund,
+        amount,
+    }
+    .try_to_vec()?;
+
+    let (verifier, _) = find_verifier_address(program_id, tornado_instance);
+    let (nullifier_marker, _) = find_nullifier_address(program_id, tornado_instance, &nullifier_hash);
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*payer, true),
+        AccountMeta::new_readonly(*tornado_instance, false),
+        AccountMeta::new_readonly(*merkle_tree, false),
+        AccountMeta::new_readonly(verifier, false),
+        AccountMeta::new_readonly(nullifier_marker, false),
+        AccountMeta::new_readonly(find_compliance_list_address(program_id, tornado_instance).0, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a SetComplianceList instruction
+///
+/// `recipients` must be sorted in ascending order without duplicates (`None`
+/// disables the list).
+pub fn set_compliance_list(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tornado_instance: &Pubkey,
+    recipients: Option<Vec<Pubkey>>,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::SetComplianceList { recipients }.try_to_vec()?;
+
+    let (compliance_list, _) = find_compliance_list_address(program_id, tornado_instance);
+
+    let accounts = vec![
+        AccountMeta::new(*admin, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(compliance_list, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a SetPartialVerifier instruction
+pub fn set_partial_verifier(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tornado_instance: &Pubkey,
+    verifying_key: Vec<u8>,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::SetPartialVerifier { verifying_key }.try_to_vec()?;
+
+    let (merkle_tree, _) = find_merkle_tree_address(program_id, tornado_instance);
+    let (partial_verifier, _) = find_partial_verifier_address(program_id, tornado_instance);
+
+    let accounts = vec![
+        AccountMeta::new(*admin, true),
+        AccountMeta::new_readonly(*tornado_instance, false),
+        AccountMeta::new_readonly(merkle_tree, false),
+        AccountMeta::new(partial_verifier, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a WithdrawPartial instruction
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw_partial(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    recipient: &Pubkey,
+    relayer: &Pubkey,
+    proof: Vec<u8>,
+    root: [u8; 32],
+    nullifier_hash: [u8; 32],
+    fee: u64,
+    input_amount: u64,
+    output_amount: u64,
+    output_commitment: [u8; 32],
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::WithdrawPartial {
+        proof,
+        root,
+        nullifier_hash,
+        recipient: *recipient,
+        relayer: *relayer,
+        fee,
+        refund: 0,
+        input_amount,
+        output_amount,
+        output_commitment,
+    }
+    .try_to_vec()?;
+
+    let (partial_verifier, _) = find_partial_verifier_address(program_id, tornado_instance);
+    let (nullifier_marker, _) = find_nullifier_address(program_id, tornado_instance, &nullifier_hash);
+    let output_leaf = compute_amount_commitment(&output_commitment, output_amount)?;
+    let (commitment_marker, _) = find_commitment_address(program_id, tornado_instance, &output_leaf);
+
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new_readonly(partial_verifier, false),
+        AccountMeta::new(nullifier_marker, false),
+        AccountMeta::new(commitment_marker, false),
+        AccountMeta::new(*recipient, false),
+        AccountMeta::new(*relayer, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::instructions::id(), false),
+        AccountMeta::new_readonly(find_compliance_list_address(program_id, tornado_instance).0, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a SelfCheck instruction
+pub fn self_check(program_id: &Pubkey, tornado_instance: &Pubkey) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::SelfCheck.try_to_vec()?;
+
+    let (merkle_tree, _) = find_merkle_tree_address(program_id, tornado_instance);
+    let (verifier, _) = find_verifier_address(program_id, tornado_instance);
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*tornado_instance, false),
+        AccountMeta::new_readonly(merkle_tree, false),
+        AccountMeta::new_readonly(verifier, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a WithdrawBatch instruction
+pub fn withdraw_batch(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    tornado_instance: &Pubkey,
+    merkle_tree: &Pubkey,
+    withdrawals: Vec<WithdrawRequest>,
+) -> Result<Instruction, ProgramError> {
+    let (verifier, _) = find_verifier_address(program_id, tornado_instance);
+
+    let mut accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*tornado_instance, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new_readonly(verifier, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::instructions::id(), false),
+        AccountMeta::new_readonly(find_compliance_list_address(program_id, tornado_instance).0, false),
+    ];
+    for withdrawal in &withdrawals {
+        let (nullifier_marker, _) = find_nullifier_address(program_id, tornado_instance, &withdrawal.nullifier_hash);
+        accounts.push(AccountMeta::new(nullifier_marker, false));
+        accounts.push(AccountMeta::new(withdrawal.recipient, false));
+        accounts.push(AccountMeta::new(withdrawal.relayer, false));
+    }
+
+    let data = TornadoInstruction::WithdrawBatch { withdrawals }.try_to_vec()?;
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a SetDepositFee instruction
+pub fn set_deposit_fee(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tornado_instance: &Pubkey,
+    deposit_fee: u64,
+    treasury: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::SetDepositFee {
+        deposit_fee,
+        treasury: *treasury,
+    }
+    .try_to_vec()?;
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(*tornado_instance, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a SetMinAnonymitySet instruction
+pub fn set_min_anonymity_set(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tornado_instance: &Pubkey,
+    min_anonymity_set: u32,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::SetMinAnonymitySet { min_anonymity_set }.try_to_vec()?;
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(*tornado_instance, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a SetWithdrawDelay instruction
+pub fn set_withdraw_delay(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tornado_instance: &Pubkey,
+    withdraw_delay_secs: u32,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::SetWithdrawDelay { withdraw_delay_secs }.try_to_vec()?;
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(*tornado_instance, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Create a SetMaxSolFee instruction
+pub fn set_max_sol_fee(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    tornado_instance: &Pubkey,
+    max_sol_fee: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = TornadoInstruction::SetMaxSolFee { max_sol_fee }.try_to_vec()?;
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(*tornado_instance, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
@@ -9,10 +9,14 @@
 //! The program is organized into several modules:
 //!
 //! * `error`: Error types for the program
+//! * `events`: Structured events emitted for indexers
 //! * `instruction`: Instruction types and processing
 //! * `merkle_tree`: Merkle tree implementation
+//! * `note`: Portable deposit notes for clients
+//! * `off_chain`: Off-chain Merkle tree reconstruction for clients
 //! * `processor`: Main program logic
 //! * `state`: State types for the program
+//! * `stealth`: Stealth address derivation for clients
 //! * `utils`: Utility functions
 //! * `verifier`: zkSNARK proof verification
 //!
@@ -26,17 +30,25 @@
 //!
 //! See the [documentation](https://github.com/your-username/tornado-svm/docs) for more details.
 
-use solana_program::{
-    account_info::AccountInfo, entrypoint, entrypoint::ProgramResult, msg,
-    program_error::ProgramError, pubkey::Pubkey,
-};
+use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, msg, pubkey::Pubkey};
+
+// The mock verifier accepts a forged proof, so it must never reach a release or on-chain build
+#[cfg(all(feature = "mock-verifier", any(not(debug_assertions), target_os = "solana")))]
+compile_error!("the `mock-verifier` feature is for tests only and can't be enabled in release builds");
 
 // Module declarations
 pub mod error;
+pub mod events;
 pub mod instruction;
 pub mod merkle_tree;
+pub mod mimc_constants;
+pub mod note;
+#[cfg(not(target_os = "solana"))]
+pub mod off_chain;
 pub mod processor;
 pub mod state;
+#[cfg(not(target_os = "solana"))]
+pub mod stealth;
 pub mod utils;
 pub mod verifier;
 
@@ -47,8 +59,9 @@ pub use crate::state::{MerkleTree, TornadoInstance};
 
 use crate::processor::Processor;
 
-// Program entrypoint
-entrypoint!(process_instruction);
+// Program entrypoint, left out when another program links the crate (`cpi`)
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
 
 /// Process instruction
 ///
@@ -1,32 +1,401 @@
 //! Merkle tree implementation for the Tornado Cash Privacy Solution
 
-use crate::{error::TornadoError, state::ROOT_HISTORY_SIZE};
+use crate::{
+    error::TornadoError,
+    mimc_constants::MIMC_CONSTANTS,
+    state::MerkleTree,
+};
+use ark_bn254::Fr;
+use ark_ff::PrimeField;
 use solana_program::{
     msg,
+    poseidon::{self, Endianness, Parameters},
     program_error::ProgramError,
 };
-use sha3::{Digest, Keccak256};
 
-/// Field size for BN254 curve
+/// Hash the tree with MiMC, as Tornado's `MerkleTreeWithHistory.sol` does
+pub const HASH_ALGO_MIMC: u8 = 0;
+
+/// Hash the tree with Poseidon, for circuits built on circomlib's `Poseidon(2)`
+pub const HASH_ALGO_POSEIDON: u8 = 1;
+
+/// Field size for BN254 curve (the scalar field modulus, big-endian)
 pub const FIELD_SIZE: [u8; 32] = [
-    0x30, 0x64, 0x4e, 0x72, 0xe1, 0x31, 0xa0, 0x29, 0xb8, 0x5d, 0x12, 0x66, 0xb4, 0x1b, 0x4b, 0x30,
-    0x73, 0xbe, 0x54, 0x46, 0xc3, 0x36, 0xb1, 0x0b, 0x51, 0x10, 0x5a, 0xf4, 0x00, 0x00, 0x00, 0x01,
+    0x30, 0x64, 0x4e, 0x72, 0xe1, 0x31, 0xa0, 0x29, 0xb8, 0x50, 0x45, 0xb6, 0x81, 0x81, 0x58, 0x5d,
+    0x28, 0x33, 0xe8, 0x48, 0x79, 0xb9, 0x70, 0x91, 0x43, 0xe1, 0xf5, 0x93, 0xf0, 0x00, 0x00, 0x01,
 ];
 
+/// Number of levels in the zero value tables
+pub const MAX_TREE_HEIGHT: usize = 32;
+
+/// Tallest Merkle tree whose empty root is in the zero value tables
+///
+/// The empty root of a tree of height `h` is the zero value at level `h`, so
+/// the table's top level is only usable as a root.
+pub const MAX_SUPPORTED_HEIGHT: u8 = (MAX_TREE_HEIGHT - 1) as u8;
+
 /// Zero value for the Merkle tree (keccak256("tornado") % FIELD_SIZE)
 pub const ZERO_VALUE: [u8; 32] = [
     0x2f, 0xe5, 0x4c, 0x60, 0xd3, 0xac, 0xab, 0xf3, 0x34, 0x3a, 0x35, 0xb6, 0xeb, 0xa1, 0x5d, 0xb4,
     0x82, 0x1b, 0x34, 0x0f, 0x76, 0xe7, 0x41, 0xe2, 0x24, 0x96, 0x85, 0xed, 0x48, 0x99, 0xaf, 0x6c,
 ];
 
-/// Computes the hash of two leaves in the Merkle tree using MiMC
-pub fn hash_left_right(left: &[u8; 32], right: &[u8; 32]) -> Result<[u8; 32], ProgramError> {
-    // Ensure inputs are within the field
-    if !is_within_field(left) || !is_within_field(right) {
-        return Err(TornadoError::InvalidMerkleTreeState.into());
+/// Zero values for every level of the Merkle tree
+///
+/// `ZEROS[0]` is `ZERO_VALUE` and `ZEROS[i] = mimc_hash_left_right(ZEROS[i - 1], ZEROS[i - 1])`,
+/// matching `zeros(i)` in Tornado's `MerkleTreeWithHistory.sol`.
+pub const ZEROS: [[u8; 32]; MAX_TREE_HEIGHT] = [
+    // Level 0
+    [
+        0x2f, 0xe5, 0x4c, 0x60, 0xd3, 0xac, 0xab, 0xf3, 0x34, 0x3a, 0x35, 0xb6, 0xeb, 0xa1, 0x5d, 0xb4,
+        0x82, 0x1b, 0x34, 0x0f, 0x76, 0xe7, 0x41, 0xe2, 0x24, 0x96, 0x85, 0xed, 0x48, 0x99, 0xaf, 0x6c,
+    ],
+    // Level 1
+    [
+        0x25, 0x6a, 0x61, 0x35, 0x77, 0x7e, 0xee, 0x2f, 0xd2, 0x6f, 0x54, 0xb8, 0xb7, 0x03, 0x7a, 0x25,
+        0x43, 0x9d, 0x52, 0x35, 0xca, 0xee, 0x22, 0x41, 0x54, 0x18, 0x6d, 0x2b, 0x8a, 0x52, 0xe3, 0x1d,
+    ],
+    // Level 2
+    [
+        0x11, 0x51, 0x94, 0x98, 0x95, 0xe8, 0x2a, 0xb1, 0x99, 0x24, 0xde, 0x92, 0xc4, 0x0a, 0x3d, 0x6f,
+        0x7b, 0xcb, 0x60, 0xd9, 0x2b, 0x00, 0x50, 0x4b, 0x81, 0x99, 0x61, 0x36, 0x83, 0xf0, 0xc2, 0x00,
+    ],
+    // Level 3
+    [
+        0x20, 0x12, 0x1e, 0xe8, 0x11, 0x48, 0x9f, 0xf8, 0xd6, 0x1f, 0x09, 0xfb, 0x89, 0xe3, 0x13, 0xf1,
+        0x49, 0x59, 0xa0, 0xf2, 0x8b, 0xb4, 0x28, 0xa2, 0x0d, 0xba, 0x6b, 0x0b, 0x06, 0x8b, 0x3b, 0xdb,
+    ],
+    // Level 4
+    [
+        0x0a, 0x89, 0xca, 0x6f, 0xfa, 0x14, 0xcc, 0x46, 0x2c, 0xfe, 0xdb, 0x84, 0x2c, 0x30, 0xed, 0x22,
+        0x1a, 0x50, 0xa3, 0xd6, 0xbf, 0x02, 0x2a, 0x6a, 0x57, 0xdc, 0x82, 0xab, 0x24, 0xc1, 0x57, 0xc9,
+    ],
+    // Level 5
+    [
+        0x24, 0xca, 0x05, 0xc2, 0xb5, 0xcd, 0x42, 0xe8, 0x90, 0xd6, 0xbe, 0x94, 0xc6, 0x8d, 0x06, 0x89,
+        0xf4, 0xf2, 0x1c, 0x9c, 0xec, 0x9c, 0x0f, 0x13, 0xfe, 0x41, 0xd5, 0x66, 0xdf, 0xb5, 0x49, 0x59,
+    ],
+    // Level 6
+    [
+        0x1c, 0xcb, 0x97, 0xc9, 0x32, 0x56, 0x5a, 0x92, 0xc6, 0x01, 0x56, 0xbd, 0xba, 0x2d, 0x08, 0xf3,
+        0xbf, 0x13, 0x77, 0x46, 0x4e, 0x02, 0x5c, 0xee, 0x76, 0x56, 0x79, 0xe6, 0x04, 0xa7, 0x31, 0x5c,
+    ],
+    // Level 7
+    [
+        0x19, 0x15, 0x6f, 0xbd, 0x7d, 0x1a, 0x8b, 0xf5, 0xcb, 0xa8, 0x90, 0x93, 0x67, 0xde, 0x1b, 0x62,
+        0x45, 0x34, 0xeb, 0xab, 0x4f, 0x0f, 0x79, 0xe0, 0x03, 0xbc, 0xcd, 0xd1, 0xb1, 0x82, 0xbd, 0xb4,
+    ],
+    // Level 8
+    [
+        0x26, 0x1a, 0xf8, 0xc1, 0xf0, 0x91, 0x2e, 0x46, 0x57, 0x44, 0x64, 0x14, 0x09, 0xf6, 0x22, 0xd4,
+        0x66, 0xc3, 0x92, 0x0a, 0xc6, 0xe5, 0xff, 0x37, 0xe3, 0x66, 0x04, 0xcb, 0x11, 0xdf, 0xff, 0x80,
+    ],
+    // Level 9
+    [
+        0x00, 0x58, 0x45, 0x97, 0x24, 0xff, 0x6c, 0xa5, 0xa1, 0x65, 0x2f, 0xcb, 0xc3, 0xe8, 0x2b, 0x93,
+        0x89, 0x5c, 0xf0, 0x8e, 0x97, 0x5b, 0x19, 0xbe, 0xab, 0x3f, 0x54, 0xc2, 0x17, 0xd1, 0xc0, 0x07,
+    ],
+    // Level 10
+    [
+        0x1f, 0x04, 0xef, 0x20, 0xde, 0xe4, 0x8d, 0x39, 0x98, 0x4d, 0x8e, 0xab, 0xe7, 0x68, 0xa7, 0x0e,
+        0xaf, 0xa6, 0x31, 0x0a, 0xd2, 0x08, 0x49, 0xd4, 0x57, 0x3c, 0x3c, 0x40, 0xc2, 0xad, 0x1e, 0x30,
+    ],
+    // Level 11
+    [
+        0x1b, 0xea, 0x3d, 0xec, 0x5d, 0xab, 0x51, 0x56, 0x7c, 0xe7, 0xe2, 0x00, 0xa3, 0x0f, 0x7b, 0xa6,
+        0xd4, 0x27, 0x6a, 0xea, 0xa5, 0x3e, 0x26, 0x86, 0xf9, 0x62, 0xa4, 0x6c, 0x66, 0xd5, 0x11, 0xe5,
+    ],
+    // Level 12
+    [
+        0x0e, 0xe0, 0xf9, 0x41, 0xe2, 0xda, 0x4b, 0x9e, 0x31, 0xc3, 0xca, 0x97, 0xa4, 0x0d, 0x8f, 0xa9,
+        0xce, 0x68, 0xd9, 0x7c, 0x08, 0x41, 0x77, 0x07, 0x1b, 0x3c, 0xb4, 0x6c, 0xd3, 0x37, 0x2f, 0x0f,
+    ],
+    // Level 13
+    [
+        0x1c, 0xa9, 0x50, 0x3e, 0x89, 0x35, 0x88, 0x45, 0x01, 0xbb, 0xaf, 0x20, 0xbe, 0x14, 0xeb, 0x4c,
+        0x46, 0xb8, 0x97, 0x72, 0xc9, 0x7b, 0x96, 0xe3, 0xb2, 0xeb, 0xf3, 0xa3, 0x6a, 0x94, 0x8b, 0xbd,
+    ],
+    // Level 14
+    [
+        0x13, 0x3a, 0x80, 0xe3, 0x06, 0x97, 0xcd, 0x55, 0xd8, 0xf7, 0xd4, 0xb0, 0x96, 0x5b, 0x7b, 0xe2,
+        0x40, 0x57, 0xba, 0x5d, 0xc3, 0xda, 0x89, 0x8e, 0xe2, 0x18, 0x72, 0x32, 0x44, 0x6c, 0xb1, 0x08,
+    ],
+    // Level 15
+    [
+        0x13, 0xe6, 0xd8, 0xfc, 0x88, 0x83, 0x9e, 0xd7, 0x6e, 0x18, 0x2c, 0x2a, 0x77, 0x9a, 0xf5, 0xb2,
+        0xc0, 0xda, 0x9d, 0xd1, 0x8c, 0x90, 0x42, 0x7a, 0x64, 0x4f, 0x7e, 0x14, 0x8a, 0x62, 0x53, 0xb6,
+    ],
+    // Level 16
+    [
+        0x1e, 0xb1, 0x6b, 0x05, 0x7a, 0x47, 0x7f, 0x4b, 0xc8, 0xf5, 0x72, 0xea, 0x6b, 0xee, 0x39, 0x56,
+        0x10, 0x98, 0xf7, 0x8f, 0x15, 0xbf, 0xb3, 0x69, 0x9d, 0xcb, 0xb7, 0xbd, 0x8d, 0xb6, 0x18, 0x54,
+    ],
+    // Level 17
+    [
+        0x0d, 0xa2, 0xcb, 0x16, 0xa1, 0xce, 0xaa, 0xbf, 0x1c, 0x16, 0xb8, 0x38, 0xf7, 0xa9, 0xe3, 0xf2,
+        0xa3, 0xa3, 0x08, 0x8d, 0x9e, 0x0a, 0x6d, 0xeb, 0xaa, 0x74, 0x81, 0x14, 0x62, 0x06, 0x96, 0xea,
+    ],
+    // Level 18
+    [
+        0x24, 0xa3, 0xb3, 0xd8, 0x22, 0x42, 0x0b, 0x14, 0xb5, 0xd8, 0xcb, 0x6c, 0x28, 0xa5, 0x74, 0xf0,
+        0x1e, 0x98, 0xea, 0x9e, 0x94, 0x05, 0x51, 0xd2, 0xeb, 0xd7, 0x5c, 0xee, 0x12, 0x64, 0x9f, 0x9d,
+    ],
+    // Level 19
+    [
+        0x19, 0x86, 0x22, 0xac, 0xbd, 0x78, 0x3d, 0x1b, 0x0d, 0x90, 0x64, 0x10, 0x5b, 0x1f, 0xc8, 0xe4,
+        0xd8, 0x88, 0x9d, 0xe9, 0x5c, 0x4c, 0x51, 0x9b, 0x3f, 0x63, 0x58, 0x09, 0xfe, 0x6a, 0xfc, 0x05,
+    ],
+    // Level 20
+    [
+        0x29, 0xd7, 0xed, 0x39, 0x12, 0x56, 0xcc, 0xc3, 0xea, 0x59, 0x6c, 0x86, 0xe9, 0x33, 0xb8, 0x9f,
+        0xf3, 0x39, 0xd2, 0x5e, 0xa8, 0xdd, 0xce, 0xd9, 0x75, 0xae, 0x2f, 0xe3, 0x0b, 0x52, 0x96, 0xd4,
+    ],
+    // Level 21
+    [
+        0x19, 0xbe, 0x59, 0xf2, 0xf0, 0x41, 0x3c, 0xe7, 0x8c, 0x0c, 0x37, 0x03, 0xa3, 0xa5, 0x45, 0x1b,
+        0x1d, 0x7f, 0x39, 0x62, 0x9f, 0xa3, 0x3a, 0xbd, 0x11, 0x54, 0x8a, 0x76, 0x06, 0x5b, 0x29, 0x67,
+    ],
+    // Level 22
+    [
+        0x1f, 0xf3, 0xf6, 0x17, 0x97, 0xe5, 0x38, 0xb7, 0x0e, 0x61, 0x93, 0x10, 0xd3, 0x3f, 0x2a, 0x06,
+        0x3e, 0x7e, 0xb5, 0x91, 0x04, 0xe1, 0x12, 0xe9, 0x57, 0x38, 0xda, 0x12, 0x54, 0xdc, 0x34, 0x53,
+    ],
+    // Level 23
+    [
+        0x10, 0xc1, 0x6a, 0xe9, 0x95, 0x9c, 0xf8, 0x35, 0x89, 0x80, 0xd9, 0xdd, 0x96, 0x16, 0xe4, 0x82,
+        0x28, 0x73, 0x73, 0x10, 0xa1, 0x0e, 0x2b, 0x6b, 0x73, 0x1c, 0x1a, 0x54, 0x8f, 0x03, 0x6c, 0x48,
+    ],
+    // Level 24
+    [
+        0x0b, 0xa4, 0x33, 0xa6, 0x31, 0x74, 0xa9, 0x0a, 0xc2, 0x09, 0x92, 0xe7, 0x5e, 0x30, 0x95, 0x49,
+        0x68, 0x12, 0xb6, 0x52, 0x68, 0x5b, 0x5e, 0x1a, 0x2e, 0xae, 0x0b, 0x1b, 0xf4, 0xe8, 0xfc, 0xd1,
+    ],
+    // Level 25
+    [
+        0x01, 0x9d, 0xdb, 0x9d, 0xf2, 0xbc, 0x98, 0xd9, 0x87, 0xd0, 0xdf, 0xec, 0xa9, 0xd2, 0xb6, 0x43,
+        0xde, 0xaf, 0xab, 0x8f, 0x70, 0x36, 0x56, 0x2e, 0x62, 0x7c, 0x36, 0x67, 0x26, 0x6a, 0x04, 0x4c,
+    ],
+    // Level 26
+    [
+        0x2d, 0x3c, 0x88, 0xb2, 0x31, 0x75, 0xc5, 0xa5, 0x56, 0x5d, 0xb9, 0x28, 0x41, 0x4c, 0x66, 0xd1,
+        0x91, 0x2b, 0x11, 0xac, 0xf9, 0x74, 0xb2, 0xe6, 0x44, 0xca, 0xaa, 0xc0, 0x47, 0x39, 0xce, 0x99,
+    ],
+    // Level 27
+    [
+        0x2e, 0xab, 0x55, 0xf6, 0xae, 0x4e, 0x66, 0xe3, 0x2c, 0x51, 0x89, 0xee, 0xd5, 0xc4, 0x70, 0x84,
+        0x08, 0x63, 0x44, 0x57, 0x60, 0xf5, 0xed, 0x7e, 0x7b, 0x69, 0xb2, 0xa6, 0x26, 0x00, 0xf3, 0x54,
+    ],
+    // Level 28
+    [
+        0x00, 0x2d, 0xf3, 0x7a, 0x26, 0x42, 0x62, 0x18, 0x02, 0x38, 0x3c, 0xf9, 0x52, 0xbf, 0x4d, 0xd1,
+        0xf3, 0x2e, 0x05, 0x43, 0x3b, 0xee, 0xb1, 0xfd, 0x41, 0x03, 0x1f, 0xb7, 0xea, 0xce, 0x97, 0x9d,
+    ],
+    // Level 29
+    [
+        0x10, 0x4a, 0xeb, 0x41, 0x43, 0x5d, 0xb6, 0x6c, 0x3e, 0x62, 0xfe, 0xcc, 0xc1, 0xd6, 0xf5, 0xd9,
+        0x8d, 0x0a, 0x0e, 0xd7, 0x5d, 0x13, 0x74, 0xdb, 0x45, 0x7c, 0xf4, 0x62, 0xe3, 0xa1, 0xf4, 0x27,
+    ],
+    // Level 30
+    [
+        0x1f, 0x3c, 0x6f, 0xd8, 0x58, 0xe9, 0xa7, 0xd4, 0xb0, 0xd1, 0xf3, 0x8e, 0x25, 0x6a, 0x09, 0xd8,
+        0x1d, 0x5a, 0x5e, 0x3c, 0x96, 0x39, 0x87, 0xe2, 0xd4, 0xb8, 0x14, 0xcf, 0xab, 0x7c, 0x6e, 0xbb,
+    ],
+    // Level 31
+    [
+        0x2c, 0x7a, 0x07, 0xd2, 0x0d, 0xff, 0x79, 0xd0, 0x1f, 0xec, 0xed, 0xc1, 0x13, 0x42, 0x84, 0xa8,
+        0xd0, 0x84, 0x36, 0x60, 0x6c, 0x93, 0x69, 0x3b, 0x67, 0xe3, 0x33, 0xf6, 0x71, 0xbf, 0x69, 0xcc,
+    ],
+];
+
+/// Zero values for every level of a Merkle tree hashed with Poseidon
+///
+/// `POSEIDON_ZEROS[0]` is `ZERO_VALUE` and
+/// `POSEIDON_ZEROS[i] = poseidon_hash_left_right(POSEIDON_ZEROS[i - 1], POSEIDON_ZEROS[i - 1])`.
+pub const POSEIDON_ZEROS: [[u8; 32]; MAX_TREE_HEIGHT] = [
+    // Level 0
+    [
+        0x2f, 0xe5, 0x4c, 0x60, 0xd3, 0xac, 0xab, 0xf3, 0x34, 0x3a, 0x35, 0xb6, 0xeb, 0xa1, 0x5d, 0xb4,
+        0x82, 0x1b, 0x34, 0x0f, 0x76, 0xe7, 0x41, 0xe2, 0x24, 0x96, 0x85, 0xed, 0x48, 0x99, 0xaf, 0x6c,
+    ],
+    // Level 1
+    [
+        0x13, 0xe3, 0x7f, 0x2d, 0x6c, 0xb8, 0x6c, 0x78, 0xcc, 0xc1, 0x78, 0x86, 0x07, 0xc2, 0xb1, 0x99,
+        0x78, 0x8c, 0x6b, 0xb0, 0xa6, 0x15, 0xa2, 0x1f, 0x2e, 0x7a, 0x8e, 0x88, 0x38, 0x42, 0x22, 0xf8,
+    ],
+    // Level 2
+    [
+        0x21, 0x71, 0x26, 0xfa, 0x35, 0x2c, 0x32, 0x68, 0x96, 0xe8, 0xc2, 0x80, 0x3e, 0xec, 0x8f, 0xd6,
+        0x3a, 0xd5, 0x0c, 0xf6, 0x5e, 0xdf, 0xef, 0x27, 0xa4, 0x1a, 0x9e, 0x32, 0xdc, 0x62, 0x27, 0x65,
+    ],
+    // Level 3
+    [
+        0x0e, 0x28, 0xa6, 0x1a, 0x9b, 0x3e, 0x91, 0x00, 0x7d, 0x5a, 0x9e, 0x3a, 0xda, 0x18, 0xe1, 0xb2,
+        0x4d, 0x6d, 0x23, 0x0c, 0x61, 0x83, 0x88, 0xee, 0x5d, 0xf3, 0x4c, 0xac, 0xd7, 0x39, 0x7e, 0xee,
+    ],
+    // Level 4
+    [
+        0x27, 0x95, 0x34, 0x47, 0xa6, 0x97, 0x98, 0x39, 0x53, 0x6b, 0xad, 0xc5, 0x42, 0x5e, 0xd1, 0x5f,
+        0xad, 0xb0, 0xe2, 0x92, 0xe9, 0xbc, 0x36, 0xf9, 0x2f, 0x0a, 0xa5, 0xcf, 0xa5, 0x01, 0x35, 0x87,
+    ],
+    // Level 5
+    [
+        0x19, 0x41, 0x91, 0xed, 0xbf, 0xb9, 0x1d, 0x10, 0xf6, 0xa7, 0xaf, 0xd3, 0x15, 0xf3, 0x30, 0x95,
+        0x41, 0x0c, 0x78, 0x01, 0xc4, 0x71, 0x75, 0xc2, 0xdf, 0x6d, 0xc2, 0xcc, 0xe0, 0xe3, 0xaf, 0xfc,
+    ],
+    // Level 6
+    [
+        0x17, 0x33, 0xde, 0xce, 0x17, 0xd7, 0x11, 0x90, 0x51, 0x6d, 0xba, 0xf1, 0x92, 0x79, 0x36, 0xfa,
+        0x64, 0x3d, 0xc7, 0x07, 0x9f, 0xc0, 0xcc, 0x73, 0x1d, 0xe9, 0xd6, 0x84, 0x5a, 0x47, 0x74, 0x1f,
+    ],
+    // Level 7
+    [
+        0x26, 0x78, 0x55, 0xa7, 0xdc, 0x75, 0xdb, 0x39, 0xd8, 0x1d, 0x17, 0xf9, 0x5d, 0x0a, 0x7a, 0xa5,
+        0x72, 0xbf, 0x5a, 0xe1, 0x9f, 0x4d, 0xb0, 0xe8, 0x42, 0x21, 0xd2, 0xb2, 0xef, 0x99, 0x92, 0x19,
+    ],
+    // Level 8
+    [
+        0x11, 0x84, 0xe1, 0x18, 0x36, 0xb4, 0xc3, 0x6a, 0xd8, 0x23, 0x8a, 0x34, 0x0e, 0xcc, 0x09, 0x85,
+        0xee, 0xba, 0x66, 0x53, 0x27, 0xe3, 0x3e, 0x9b, 0x0e, 0x36, 0x41, 0x02, 0x7c, 0x27, 0x62, 0x0d,
+    ],
+    // Level 9
+    [
+        0x07, 0x02, 0xab, 0x83, 0xa1, 0x35, 0xd7, 0xf5, 0x53, 0x50, 0xab, 0x1b, 0xfa, 0xa9, 0x0b, 0xab,
+        0xd8, 0xfc, 0x1d, 0x2b, 0x3e, 0x6a, 0x72, 0x15, 0x38, 0x1a, 0x7b, 0x22, 0x13, 0xd6, 0xc5, 0xce,
+    ],
+    // Level 10
+    [
+        0x2e, 0xec, 0xc0, 0xde, 0x81, 0x4c, 0xfd, 0x8c, 0x57, 0xce, 0x88, 0x2b, 0xab, 0xb2, 0xe3, 0x0d,
+        0x1d, 0xa5, 0x66, 0x21, 0xae, 0xf7, 0xa4, 0x7f, 0x32, 0x91, 0xcf, 0xfe, 0xae, 0xc2, 0x6a, 0xd7,
+    ],
+    // Level 11
+    [
+        0x28, 0x0b, 0xc0, 0x21, 0x45, 0xc1, 0x55, 0xd5, 0x83, 0x35, 0x85, 0xb6, 0xc7, 0xb0, 0x85, 0x01,
+        0x05, 0x51, 0x57, 0xdd, 0x30, 0xce, 0x00, 0x53, 0x19, 0x62, 0x1d, 0xc4, 0x62, 0xd3, 0x3b, 0x47,
+    ],
+    // Level 12
+    [
+        0x04, 0x51, 0x32, 0x22, 0x1d, 0x1f, 0xa0, 0xa7, 0xf4, 0xae, 0xd8, 0xac, 0xd2, 0xcb, 0xec, 0x1e,
+        0x21, 0x89, 0xb7, 0x73, 0x2c, 0xcb, 0x2e, 0xc2, 0x72, 0xb9, 0xc6, 0x0f, 0x0d, 0x5a, 0xfc, 0x5b,
+    ],
+    // Level 13
+    [
+        0x27, 0xf4, 0x27, 0xcc, 0xbf, 0x58, 0xa4, 0x4b, 0x12, 0x70, 0xab, 0xbe, 0x4e, 0xda, 0x6b, 0xa5,
+        0x3b, 0xd6, 0xac, 0x4d, 0x88, 0xcf, 0x1e, 0x00, 0xa1, 0x3c, 0x43, 0x71, 0xce, 0x71, 0xd3, 0x66,
+    ],
+    // Level 14
+    [
+        0x16, 0x17, 0xea, 0xae, 0x50, 0x64, 0xf2, 0x6e, 0x8f, 0x8a, 0x64, 0x93, 0xae, 0x92, 0xbf, 0xde,
+        0xd7, 0xfd, 0xe7, 0x1b, 0x65, 0xdf, 0x1c, 0xa6, 0xd5, 0xdc, 0xec, 0x0d, 0xf7, 0x0b, 0x2c, 0xef,
+    ],
+    // Level 15
+    [
+        0x20, 0xc6, 0xb4, 0x00, 0xd0, 0xea, 0x1b, 0x15, 0x43, 0x57, 0x03, 0xc3, 0x1c, 0x31, 0xee, 0x63,
+        0xad, 0x7b, 0xa5, 0xc8, 0xda, 0x66, 0xce, 0xc2, 0x79, 0x6f, 0xea, 0xce, 0xa5, 0x75, 0xab, 0xca,
+    ],
+    // Level 16
+    [
+        0x09, 0x58, 0x9d, 0xdb, 0x43, 0x87, 0x23, 0xf5, 0x3a, 0x8e, 0x57, 0xbd, 0xad, 0xa7, 0xc5, 0xf8,
+        0xed, 0x67, 0xe8, 0xfe, 0xce, 0x38, 0x89, 0xa7, 0x36, 0x18, 0x73, 0x29, 0x65, 0x64, 0x5e, 0xec,
+    ],
+    // Level 17
+    [
+        0x00, 0x64, 0xb6, 0xa7, 0x38, 0xa5, 0xff, 0x53, 0x7d, 0xb7, 0xb2, 0x20, 0xf3, 0x39, 0x4f, 0x0e,
+        0xcb, 0xd3, 0x5b, 0xfd, 0x35, 0x5c, 0x54, 0x25, 0xdc, 0x11, 0x66, 0xbf, 0x32, 0x36, 0x07, 0x9b,
+    ],
+    // Level 18
+    [
+        0x09, 0x5d, 0xe5, 0x62, 0x81, 0xb1, 0xd5, 0x05, 0x5e, 0x89, 0x7c, 0x35, 0x74, 0xff, 0x79, 0x0d,
+        0x5e, 0xe8, 0x1d, 0xbc, 0x5d, 0xf7, 0x84, 0xad, 0x2d, 0x67, 0x79, 0x5e, 0x55, 0x7c, 0x9e, 0x9f,
+    ],
+    // Level 19
+    [
+        0x11, 0xcf, 0x2e, 0x28, 0x87, 0xaa, 0x21, 0x96, 0x3a, 0x6e, 0xc1, 0x42, 0x89, 0x18, 0x3e, 0xfe,
+        0x4d, 0x4c, 0x60, 0xf1, 0x4e, 0xcd, 0x3d, 0x6f, 0xe0, 0xbe, 0xeb, 0xdf, 0x85, 0x5a, 0x9b, 0x63,
+    ],
+    // Level 20
+    [
+        0x2b, 0x0f, 0x6f, 0xc0, 0x17, 0x9f, 0xa6, 0x5b, 0x6f, 0x73, 0x62, 0x7c, 0x0e, 0x1e, 0x84, 0xc7,
+        0x37, 0x4d, 0x2e, 0xae, 0xc4, 0x4c, 0x9a, 0x48, 0xf2, 0x57, 0x13, 0x93, 0xea, 0x77, 0xbc, 0xbb,
+    ],
+    // Level 21
+    [
+        0x16, 0xfd, 0xb6, 0x37, 0xc2, 0xab, 0xf9, 0xc0, 0xf9, 0x88, 0xdb, 0xf2, 0xfd, 0x64, 0x25, 0x8c,
+        0x46, 0xfb, 0x6a, 0x27, 0x3d, 0x53, 0x7b, 0x2c, 0xf1, 0x60, 0x3e, 0xa4, 0x60, 0xb1, 0x32, 0x79,
+    ],
+    // Level 22
+    [
+        0x21, 0xbb, 0xd7, 0xe9, 0x44, 0xf6, 0x12, 0x4d, 0xad, 0x4c, 0x37, 0x6d, 0xf9, 0xcc, 0x12, 0xe7,
+        0xca, 0x66, 0xe4, 0x7d, 0xff, 0x70, 0x3f, 0xf7, 0xce, 0xdb, 0x1a, 0x45, 0x4e, 0xdc, 0xf0, 0xff,
+    ],
+    // Level 23
+    [
+        0x27, 0x84, 0xf8, 0x22, 0x0b, 0x1c, 0x96, 0x3e, 0x46, 0x8f, 0x59, 0x0f, 0x13, 0x7b, 0xaa, 0xa1,
+        0x62, 0x5b, 0x3b, 0x92, 0xa2, 0x7a, 0xd9, 0xb6, 0xe8, 0x4e, 0xb0, 0xd3, 0x45, 0x4d, 0x99, 0x62,
+    ],
+    // Level 24
+    [
+        0x16, 0xac, 0xe1, 0xa6, 0x5b, 0x75, 0x34, 0x14, 0x2f, 0x8c, 0xc1, 0xaa, 0xd8, 0x10, 0xb3, 0xd6,
+        0xa7, 0xa7, 0x4c, 0xa9, 0x05, 0xd9, 0xc2, 0x75, 0xcb, 0x98, 0xba, 0x57, 0xe5, 0x09, 0xfc, 0x10,
+    ],
+    // Level 25
+    [
+        0x23, 0x28, 0x06, 0x8c, 0x6a, 0x8c, 0x24, 0x26, 0x51, 0x24, 0xde, 0xbd, 0x8f, 0xe1, 0x0d, 0x3f,
+        0x29, 0xf0, 0x66, 0x5e, 0xa7, 0x25, 0xa6, 0x5e, 0x36, 0x38, 0xf6, 0x19, 0x2a, 0x96, 0xa0, 0x13,
+    ],
+    // Level 26
+    [
+        0x2d, 0xdb, 0x99, 0x1b, 0xe1, 0xf0, 0x28, 0x02, 0x24, 0x11, 0xb4, 0xc4, 0xd2, 0xc2, 0x20, 0x43,
+        0xe5, 0xe7, 0x51, 0xc1, 0x20, 0x73, 0x6f, 0x00, 0xad, 0xf5, 0x4a, 0xca, 0xb1, 0xc9, 0xac, 0x14,
+    ],
+    // Level 27
+    [
+        0x01, 0x13, 0x79, 0x84, 0x10, 0xea, 0xeb, 0x95, 0x05, 0x6a, 0x46, 0x4f, 0x70, 0x52, 0x1e, 0xb5,
+        0x83, 0x77, 0xc0, 0x15, 0x5f, 0x2f, 0xe5, 0x18, 0xa5, 0x59, 0x4d, 0x38, 0xcc, 0x20, 0x9c, 0xc0,
+    ],
+    // Level 28
+    [
+        0x20, 0x2d, 0x1a, 0xe6, 0x15, 0x26, 0xf0, 0xd0, 0xd0, 0x1e, 0xf8, 0x0f, 0xb5, 0xd4, 0x05, 0x5a,
+        0x7a, 0xf4, 0x57, 0x21, 0x02, 0x4c, 0x2c, 0x24, 0xcf, 0xfd, 0x6a, 0x37, 0x98, 0xf5, 0x4d, 0x50,
+    ],
+    // Level 29
+    [
+        0x23, 0xab, 0x32, 0x34, 0x53, 0x74, 0x81, 0x29, 0xf2, 0x76, 0x5f, 0x79, 0x61, 0x50, 0x22, 0xf5,
+        0xbe, 0xbd, 0x6f, 0x40, 0x96, 0xa7, 0x96, 0x30, 0x0a, 0xab, 0x04, 0x9a, 0x60, 0xb0, 0xf1, 0x87,
+    ],
+    // Level 30
+    [
+        0x1f, 0x15, 0x58, 0x5f, 0x89, 0x47, 0xe3, 0x78, 0xbc, 0xf8, 0xbd, 0x91, 0x87, 0x16, 0x79, 0x9d,
+        0xa9, 0x09, 0xac, 0xdb, 0x94, 0x4c, 0x57, 0x15, 0x0b, 0x1e, 0xb4, 0x56, 0x5f, 0xda, 0x8a, 0xa0,
+    ],
+    // Level 31
+    [
+        0x1e, 0xb0, 0x64, 0xb2, 0x10, 0x55, 0xac, 0x6a, 0x35, 0x0c, 0xf4, 0x1e, 0xb3, 0x0e, 0x4c, 0xe2,
+        0xcb, 0x19, 0x68, 0x02, 0x17, 0xdf, 0x3a, 0x24, 0x36, 0x17, 0xc2, 0x83, 0x81, 0x85, 0xad, 0x06,
+    ],
+];
+
+/// Computes the hash of two nodes in the Merkle tree with the given algorithm
+///
+/// `hash_algo` is the `hash_algo` of the tree: `HASH_ALGO_MIMC` or
+/// `HASH_ALGO_POSEIDON`.
+pub fn hash_left_right(hash_algo: u8, left: &[u8; 32], right: &[u8; 32]) -> Result<[u8; 32], ProgramError> {
+    match hash_algo {
+        HASH_ALGO_MIMC => mimc_hash_left_right(left, right),
+        HASH_ALGO_POSEIDON => poseidon_hash_left_right(left, right),
+        _ => {
+            msg!("Unknown hash algorithm {}", hash_algo);
+            Err(TornadoError::UnsupportedHashAlgorithm.into())
+        }
     }
+}
 
-    // Convert bytes to field elements
+/// Computes the hash of two leaves in the Merkle tree using MiMC
+///
+/// Matches `hashLeftRight` in Tornado's `MerkleTreeWithHistory.sol`: both leaves are
+/// absorbed into a MiMC sponge (the `MiMCSponge(220)` permutation from circomlib).
+/// Leaves are big-endian encodings of field elements.
+pub fn mimc_hash_left_right(left: &[u8; 32], right: &[u8; 32]) -> Result<[u8; 32], ProgramError> {
+    // Convert bytes to field elements, rejecting non-canonical inputs
     let left_fe = bytes_to_field_element(left)?;
     let right_fe = bytes_to_field_element(right)?;
     
@@ -39,7 +408,25 @@ pub fn hash_left_right(left: &[u8; 32], right: &[u8; 32]) -> Result<[u8; 32], Pr
     Ok(result)
 }
 
-/// Convert bytes to a field element
+/// Computes the hash of two leaves in the Merkle tree using Poseidon
+///
+/// Uses the circomlib Poseidon parameters over BN254 (`Poseidon(2)`), the same
+/// as the note commitments. Leaves are big-endian encodings of field elements.
+pub fn poseidon_hash_left_right(left: &[u8; 32], right: &[u8; 32]) -> Result<[u8; 32], ProgramError> {
+    // Reject non-canonical inputs, like the MiMC hash
+    if !is_within_field(left) || !is_within_field(right) {
+        return Err(TornadoError::InvalidMerkleTreeState.into());
+    }
+
+    let hash = poseidon::hashv(Parameters::Bn254X5, Endianness::BigEndian, &[left, right])
+        .map_err(|_| TornadoError::InvalidMerkleTreeState)?;
+
+    Ok(hash.to_bytes())
+}
+
+/// Convert big-endian bytes to a field element
+///
+/// Fails unless the value is strictly less than `FIELD_SIZE`.
 fn bytes_to_field_element(bytes: &[u8; 32]) -> Result<[u64; 4], ProgramError> {
     if !is_within_field(bytes) {
         return Err(TornadoError::InvalidMerkleTreeState.into());
@@ -47,27 +434,25 @@ fn bytes_to_field_element(bytes: &[u8; 32]) -> Result<[u64; 4], ProgramError> {
     
     let mut result = [0u64; 4];
     
-    // Convert bytes to 4 u64 limbs
-    for i in 0..4 {
-        let mut limb = 0u64;
-        for j in 0..8 {
-            limb |= (bytes[i * 8 + j] as u64) << (j * 8);
-        }
-        result[i] = limb;
+    // Convert bytes to 4 u64 limbs, least significant limb first
+    for (i, limb) in result.iter_mut().enumerate() {
+        let start = 32 - (i + 1) * 8;
+        let mut limb_bytes = [0u8; 8];
+        limb_bytes.copy_from_slice(&bytes[start..start + 8]);
+        *limb = u64::from_be_bytes(limb_bytes);
     }
     
     Ok(result)
 }
 
-/// Convert a field element to bytes
+/// Convert a field element to big-endian bytes
 fn field_element_to_bytes(fe: [u64; 4]) -> [u8; 32] {
     let mut result = [0u8; 32];
     
-    // Convert 4 u64 limbs to bytes
-    for i in 0..4 {
-        for j in 0..8 {
-            result[i * 8 + j] = ((fe[i] >> (j * 8)) & 0xFF) as u8;
-        }
+    // Convert 4 u64 limbs to bytes, most significant limb first
+    for (i, limb) in fe.iter().enumerate() {
+        let start = 32 - (i + 1) * 8;
+        result[start..start + 8].copy_from_slice(&limb.to_be_bytes());
     }
     
     result
@@ -76,116 +461,79 @@ fn field_element_to_bytes(fe: [u64; 4]) -> [u8; 32] {
 /// MiMC hash function (Minimal Multiplicative Complexity)
 /// This is a zkSNARK-friendly hash function
 fn mimc_hash(left: [u64; 4], right: [u64; 4]) -> Result<[u64; 4], ProgramError> {
-    // MiMC constants (derived from the decimal digits of π)
-    const MIMC_ROUNDS: usize = 20;
-    const MIMC_CONSTANTS: [[u64; 4]; MIMC_ROUNDS] = [
-        [0x243f6a8885a308d3, 0x13198a2e03707344, 0xa4093822299f31d0, 0x082efa98ec4e6c89],
-        [0x452821e638d01377, 0xbe5466cf34e90c6c, 0xc0ac29b7c97c50dd, 0x3f84d5b5b5470917],
-        [0x9216d5d98979fb1b, 0xd1310ba698dfb5ac, 0x2ffd72dbd01adfb7, 0xb8e1afed6a267e96],
-        [0xba7c9045f12c7f99, 0x24a19947b3916cf7, 0x0801f2e2858efc16, 0x636920d871574e69],
-        [0xa458fea3f4933d7e, 0x0d95748f728eb658, 0x718bcd5882154aee, 0x7b54a41dc25a59b5],
-        [0x9c30d5392af26013, 0xc5d1b023286085f0, 0xca417918b8db38ef, 0x8e79dcb0603a180e],
-        [0x6c9e0e8bb01e8a3e, 0xd71577c1bd314b27, 0x78af2fda55605c60, 0xe65525f3aa55ab94],
-        [0xaa55ab94aaaa5555, 0x55aa55aa55aa55aa, 0xaa55ab94aaaa5555, 0x55aa55aa55aa55aa],
-        [0x5aa55aa55aa55aa5, 0xa55aa55aa55aa55a, 0x5aa55aa55aa55aa5, 0xa55aa55aa55aa55a],
-        [0xaaaaaaaaaaaaaaaa, 0xaaaaaaaaaaaaaaaa, 0xaaaaaaaaaaaaaaaa, 0xaaaaaaaaaaaaaaaa],
-        [0x5555555555555555, 0x5555555555555555, 0x5555555555555555, 0x5555555555555555],
-        [0xaaaaaaaaaaaaaaaa, 0x5555555555555555, 0xaaaaaaaaaaaaaaaa, 0x5555555555555555],
-        [0x5555555555555555, 0xaaaaaaaaaaaaaaaa, 0x5555555555555555, 0xaaaaaaaaaaaaaaaa],
-        [0x1111111111111111, 0x2222222222222222, 0x3333333333333333, 0x4444444444444444],
-        [0x5555555555555555, 0x6666666666666666, 0x7777777777777777, 0x8888888888888888],
-        [0x9999999999999999, 0xaaaaaaaaaaaaaaaa, 0xbbbbbbbbbbbbbbbb, 0xcccccccccccccccc],
-        [0xdddddddddddddddd, 0xeeeeeeeeeeeeeeee, 0xffffffffffffffff, 0x0000000000000000],
-        [0x1234567890abcdef, 0xfedcba0987654321, 0x1234567890abcdef, 0xfedcba0987654321],
-        [0x0123456789abcdef, 0xfedcba9876543210, 0x0123456789abcdef, 0xfedcba9876543210],
-        [0x0000000000000000, 0x0000000000000000, 0x0000000000000000, 0x0000000000000000],
-    ];
-    
-    // Initialize state with left input
-    let mut state = left;
-    
-    // Add right input to state
-    state = field_add(state, right);
-    
-    // Apply MiMC rounds
-    for i in 0..MIMC_ROUNDS {
-        // Add round constant
-        state = field_add(state, MIMC_CONSTANTS[i]);
-        
-        // Cube the state (x^3 is the MiMC S-box)
-        state = field_cube(state)?;
-    }
-    
-    // Add right input again (Feistel construction)
-    state = field_add(state, right);
-    
-    Ok(state)
+    // Absorb the left input
+    let (state_l, state_r) = mimc_sponge(left, [0u64; 4], &MIMC_CONSTANTS)?;
+    
+    // Absorb the right input
+    let state_l = field_add(state_l, right);
+    let (state_l, _) = mimc_sponge(state_l, state_r, &MIMC_CONSTANTS)?;
+    
+    Ok(state_l)
+}
+
+/// MiMC Feistel permutation with key zero
+///
+/// Runs one round per round constant; the last round doesn't swap the halves.
+fn mimc_sponge(
+    mut x_l: [u64; 4],
+    mut x_r: [u64; 4],
+    constants: &[[u64; 4]],
+) -> Result<([u64; 4], [u64; 4]), ProgramError> {
+    for (i, constant) in constants.iter().enumerate() {
+        // Add round constant and apply the x^5 S-box
+        let t = field_pow5(field_add(x_l, *constant))?;
+        let x_r_next = field_add(x_r, t);
+        
+        if i < constants.len() - 1 {
+            x_r = x_l;
+            x_l = x_r_next;
+        } else {
+            x_r = x_r_next;
+        }
+    }
+    
+    Ok((x_l, x_r))
 }
 
 /// Add two field elements
+///
+/// Both inputs must be canonical. Their sum is then below `2 * FIELD_SIZE < 2^255`,
+/// so it never overflows the limbs and is always reduced without branching on it.
 fn field_add(a: [u64; 4], b: [u64; 4]) -> [u64; 4] {
     let mut result = [0u64; 4];
-    let mut carry = 0u64;
+    let mut carry = false;
     
     for i in 0..4 {
         let (sum1, c1) = a[i].overflowing_add(b[i]);
-        let (sum2, c2) = sum1.overflowing_add(carry);
+        let (sum2, c2) = sum1.overflowing_add(carry as u64);
         
         result[i] = sum2;
-        carry = if c1 || c2 { 1 } else { 0 };
-    }
-    
-    // Reduce modulo field size if necessary
-    if carry > 0 || !is_within_field(&field_element_to_bytes(result)) {
-        result = field_mod(result);
+        carry = c1 | c2;
     }
+    debug_assert!(!carry);
     
-    result
+    field_mod(result)
 }
 
-/// Compute the cube of a field element (x^3)
-fn field_cube(a: [u64; 4]) -> Result<[u64; 4], ProgramError> {
-    // Compute a^2
+/// Compute the fifth power of a field element (x^5)
+fn field_pow5(a: [u64; 4]) -> Result<[u64; 4], ProgramError> {
+    // Compute a^2 and a^4
     let a_squared = field_mul(a, a)?;
+    let a_fourth = field_mul(a_squared, a_squared)?;
     
-    // Compute a^3 = a * a^2
-    field_mul(a, a_squared)
+    // Compute a^5 = a * a^4
+    field_mul(a, a_fourth)
 }
 
 /// Multiply two field elements
+///
+/// Delegates to the BN254 scalar field arithmetic from `ark_bn254`, which
+/// computes the full product and reduces it modulo `FIELD_SIZE`.
 fn field_mul(a: [u64; 4], b: [u64; 4]) -> Result<[u64; 4], ProgramError> {
-    // This is a simplified implementation of field multiplication
-    // In a real implementation, we would use a proper big integer library
-    
-    // Convert to bytes for simplicity
-    let a_bytes = field_element_to_bytes(a);
-    let b_bytes = field_element_to_bytes(b);
-    
-    // Use a simple schoolbook multiplication
-    let mut result = [0u8; 64]; // Temporary result (twice the size)
-    
-    for i in 0..32 {
-        let mut carry = 0u16;
-        for j in 0..32 {
-            let idx = i + j;
-            if idx < 64 {
-                let prod = (a_bytes[i] as u16) * (b_bytes[j] as u16) + (result[idx] as u16) + carry;
-                result[idx] = (prod & 0xFF) as u8;
-                carry = prod >> 8;
-            }
-        }
-    }
-    
-    // Reduce modulo field size
-    let mut reduced = [0u8; 32];
-    reduced.copy_from_slice(&result[0..32]); // Simplified reduction
-    
-    if !is_within_field(&reduced) {
-        reduced = mod_field_size(&reduced);
-    }
+    let a_fe = Fr::from_be_bytes_mod_order(&field_element_to_bytes(a));
+    let b_fe = Fr::from_be_bytes_mod_order(&field_element_to_bytes(b));
     
-    // Convert back to field element
-    bytes_to_field_element(&reduced)
+    Ok((a_fe * b_fe).into_bigint().0)
 }
 
 /// Reduce a field element modulo the field size
@@ -200,139 +548,262 @@ fn field_mod(a: [u64; 4]) -> [u64; 4] {
     bytes_to_field_element(&reduced).unwrap_or([0u64; 4])
 }
 
-/// Check if a value is within the BN254 field
-fn is_within_field(value: &[u8; 32]) -> bool {
+/// Subtract the field size from a big-endian value
+///
+/// # Returns
+///
+/// Returns the difference modulo 2^256 and whether the subtraction borrowed,
+/// i.e. whether the value was below the field size
+fn sub_field_size(value: &[u8; 32]) -> ([u8; 32], bool) {
+    let mut result = [0u8; 32];
+    let mut borrow = 0i16;
+    
+    // Propagate the borrow from the least significant byte
     for i in (0..32).rev() {
-        if value[i] < FIELD_SIZE[i] {
-            return true;
-        }
-        if value[i] > FIELD_SIZE[i] {
-            return false;
-        }
+        let diff = value[i] as i16 - FIELD_SIZE[i] as i16 - borrow;
+        borrow = (diff < 0) as i16;
+        result[i] = (diff + (borrow << 8)) as u8;
     }
-    true
+    
+    (result, borrow == 1)
 }
 
-/// Take a value modulo the field size
+/// Check if a big-endian value is a canonical element of the BN254 field,
+/// i.e. strictly less than `FIELD_SIZE`
+pub fn is_within_field(value: &[u8; 32]) -> bool {
+    sub_field_size(value).1
+}
+
+/// The most times `FIELD_SIZE` fits in a 256-bit value (`floor((2^256 - 1) / FIELD_SIZE)`)
+const MAX_FIELD_SIZE_MULTIPLE: usize = 5;
+
+/// Take any 256-bit big-endian value modulo the field size
+///
+/// The field size is subtracted `MAX_FIELD_SIZE_MULTIPLE` times, which fully
+/// reduces any value. Each pass subtracts unconditionally and selects the
+/// result with a mask, so the running time doesn't depend on the value.
 fn mod_field_size(value: &[u8; 32]) -> [u8; 32] {
-    // This is a simplified implementation
-    // In a real implementation, we would use a proper big integer library
-    let mut result = [0u8; 32];
-    let mut carry = 0u16;
+    let mut result = *value;
     
-    for i in (0..32).rev() {
-        let mut diff = value[i] as u16;
-        if carry > 0 {
-            diff += carry * 256;
-            carry = 0;
+    for _ in 0..MAX_FIELD_SIZE_MULTIPLE {
+        let (diff, below) = sub_field_size(&result);
+        let mask = 0u8.wrapping_sub(below as u8);
+        for i in 0..32 {
+            result[i] = (result[i] & mask) | (diff[i] & !mask);
         }
-        if diff >= FIELD_SIZE[i] as u16 {
-            diff -= FIELD_SIZE[i] as u16;
-            carry = 1;
-        }
-        result[i] = diff as u8;
     }
     
     result
 }
 
-/// Get the zero value at a specific level in the Merkle tree
-pub fn get_zero_value(level: usize) -> [u8; 32] {
-    if level == 0 {
-        return ZERO_VALUE;
-    }
-    
-    // Pre-computed zero values for levels 1-31
-    // These would be computed using hash_left_right(zeros(i-1), zeros(i-1))
-    // For simplicity, we're using hardcoded values from the original contract
-    match level {
-        1 => [0x25, 0x6a, 0x61, 0x35, 0x77, 0x7e, 0xee, 0x2f, 0xd2, 0x6f, 0x54, 0xb8, 0xb7, 0x03, 0x7a, 0x25, 0x43, 0x9d, 0x52, 0x35, 0xca, 0xee, 0x22, 0x41, 0x54, 0x18, 0x6d, 0x2b, 0x8a, 0x52, 0xe3, 0x1d],
-        2 => [0x11, 0x51, 0x94, 0x98, 0x95, 0xe8, 0x2a, 0xb1, 0x99, 0x24, 0xde, 0x92, 0xc4, 0x0a, 0x3d, 0x6f, 0x7b, 0xcb, 0x60, 0xd9, 0x2b, 0x00, 0x50, 0x4b, 0x81, 0x99, 0x61, 0x36, 0x83, 0xf0, 0xc2, 0x00],
-        3 => [0x20, 0x12, 0x1e, 0xe8, 0x11, 0x48, 0x9f, 0xf8, 0xd6, 0x1f, 0x09, 0xfb, 0x89, 0xe3, 0x13, 0xf1, 0x49, 0x59, 0xa0, 0xf2, 0x8b, 0xb4, 0x28, 0xa2, 0x0d, 0xba, 0x6b, 0x0b, 0x06, 0x8b, 0x3b, 0xdb],
-        // Add more levels as needed
+/// Get the zero value at a specific level in a Merkle tree hashed with `hash_algo`
+pub fn get_zero_value(hash_algo: u8, level: usize) -> Result<[u8; 32], ProgramError> {
+    let zeros = match hash_algo {
+        HASH_ALGO_MIMC => &ZEROS,
+        HASH_ALGO_POSEIDON => &POSEIDON_ZEROS,
         _ => {
-            msg!("Warning: Zero value for level {} not pre-computed, using level 0", level);
-            ZERO_VALUE
+            msg!("Unknown hash algorithm {}", hash_algo);
+            return Err(TornadoError::UnsupportedHashAlgorithm.into());
         }
-    }
+    };
+
+    zeros.get(level).copied().ok_or_else(|| {
+        msg!("No zero value for level {}", level);
+        TornadoError::InvalidMerkleTreeState.into()
+    })
 }
 
 /// Insert a leaf into the Merkle tree
+///
+/// The tree is hashed with `hash_algo`, as stored in its `MerkleTree`.
 pub fn insert_leaf(
     leaf: &[u8; 32],
-    current_index: u32,
     next_index: u32,
     height: u8,
+    hash_algo: u8,
     filled_subtrees: &mut [[u8; 32]],
-    roots: &mut [[u8; 32]; ROOT_HISTORY_SIZE],
+    roots: &mut [[u8; 32]],
     current_root_index: &mut u8,
 ) -> Result<u32, ProgramError> {
+    // Only heights the zero values cover can be hashed; a corrupted height
+    // is an error rather than an overflow
+    if height > MAX_SUPPORTED_HEIGHT {
+        msg!("Merkle tree height {} exceeds the maximum of {}", height, MAX_SUPPORTED_HEIGHT);
+        return Err(TornadoError::InvalidMerkleTreeState.into());
+    }
+
     // Check if the tree is full
-    if next_index >= 2u32.pow(height as u32) {
+    if u64::from(next_index) >= 1u64 << height {
         return Err(TornadoError::MerkleTreeFull.into());
     }
     
+    // The root history needs room for at least the new root
+    if roots.is_empty() {
+        return Err(TornadoError::InvalidMerkleTreeState.into());
+    }
+    
     let mut current_idx = next_index;
     let mut current_level_hash = *leaf;
     
     // Update the tree
-    for i in 0..height as usize {
+    for (i, filled_subtree) in filled_subtrees.iter_mut().enumerate().take(height as usize) {
         let left: [u8; 32];
         let right: [u8; 32];
         
-        if current_idx % 2 == 0 {
+        if current_idx & 1 == 0 {
             // If current_idx is even, the leaf is on the left
             left = current_level_hash;
-            right = get_zero_value(i);
-            filled_subtrees[i] = current_level_hash;
+            right = get_zero_value(hash_algo, i)?;
+            *filled_subtree = current_level_hash;
         } else {
             // If current_idx is odd, the leaf is on the right
-            left = filled_subtrees[i];
+            left = *filled_subtree;
             right = current_level_hash;
         }
         
         // Hash the left and right nodes
-        current_level_hash = hash_left_right(&left, &right)?;
+        current_level_hash = hash_left_right(hash_algo, &left, &right)?;
         current_idx /= 2;
     }
     
     // Update the root
-    let new_root_index = (*current_root_index as usize + 1) % ROOT_HISTORY_SIZE;
+    let new_root_index = (*current_root_index as usize + 1) % roots.len();
     *current_root_index = new_root_index as u8;
     roots[new_root_index] = current_level_hash;
     
     Ok(next_index)
 }
 
-/// Check if a root is in the root history
+/// Check if a root is in the root history of a tree with `next_index` leaves
 pub fn is_known_root(
     root: &[u8; 32],
-    roots: &[[u8; 32]; ROOT_HISTORY_SIZE],
+    roots: &[[u8; 32]],
     current_root_index: u8,
+    next_index: u32,
 ) -> bool {
+    root_age(root, roots, current_root_index, next_index).is_some()
+}
+
+/// Get the number of roots stored in a history of `history_size` slots after `next_index` insertions
+///
+/// The history starts with the root of the empty tree and gains one root per
+/// inserted leaf, until it wraps around and every slot holds a root.
+pub fn stored_root_count(next_index: u32, history_size: usize) -> usize {
+    (next_index as usize).saturating_add(1).min(history_size)
+}
+
+/// Get the age of a root in the root history, as the number of roots added since
+///
+/// The current root has age 0. Only the `stored_root_count` slots holding
+/// roots are searched, walking back from `current_root_index`. Returns `None`
+/// for a root that isn't in the history, the zero root and an out-of-range
+/// `current_root_index`.
+pub fn root_age(
+    root: &[u8; 32],
+    roots: &[[u8; 32]],
+    current_root_index: u8,
+    next_index: u32,
+) -> Option<u8> {
     // Check if the root is zero
     if root.iter().all(|&x| x == 0) {
-        return false;
+        return None;
     }
     
-    let mut i = current_root_index as usize;
-    loop {
-        if root == &roots[i] {
-            return true;
-        }
-        
-        if i == 0 {
-            i = ROOT_HISTORY_SIZE - 1;
+    // Check the index points into the history
+    if current_root_index as usize >= roots.len() {
+        return None;
+    }
+    
+    // Walk back from the current root over the stored roots; the history holds at most 255 roots
+    (0..stored_root_count(next_index, roots.len())).find_map(|age| {
+        let i = (current_root_index as usize + roots.len() - age) % roots.len();
+        (root == &roots[i]).then_some(age as u8)
+    })
+}
+
+/// Get the age of a root known to be at `root_index` of the root history
+///
+/// Like `root_age`, but only the given slot is checked rather than the whole
+/// history. Returns `None` when the slot doesn't hold `root`, holds no root
+/// yet, or is outside the history, and for the zero root.
+pub fn root_age_at(
+    root: &[u8; 32],
+    roots: &[[u8; 32]],
+    current_root_index: u8,
+    next_index: u32,
+    root_index: u8,
+) -> Option<u8> {
+    if root.iter().all(|&x| x == 0) || roots.get(root_index as usize) != Some(root) {
+        return None;
+    }
+    if current_root_index as usize >= roots.len() {
+        return None;
+    }
+
+    // Only the slots walked back from the current root hold roots
+    let age = (current_root_index as usize + roots.len() - root_index as usize) % roots.len();
+    (age < stored_root_count(next_index, roots.len())).then_some(age as u8)
+}
+
+/// Verify a Merkle path from a leaf up to a root
+///
+/// # Arguments
+///
+/// * `leaf` - The leaf to prove membership of
+/// * `path_elements` - The sibling of the path node at each level, from the leaves up
+/// * `path_indices` - Whether the path node is the right child at each level
+/// * `root` - The expected root
+/// * `height` - The height of the tree
+/// * `hash_algo` - The hash algorithm of the tree
+///
+/// # Returns
+///
+/// Returns whether the path hashes up to `root`
+pub fn verify_merkle_path(
+    leaf: &[u8; 32],
+    path_elements: &[[u8; 32]],
+    path_indices: &[bool],
+    root: &[u8; 32],
+    height: u8,
+    hash_algo: u8,
+) -> Result<bool, ProgramError> {
+    if height as usize > MAX_TREE_HEIGHT
+        || path_elements.len() != height as usize
+        || path_indices.len() != height as usize
+    {
+        msg!("Merkle path does not match the tree height {}", height);
+        return Err(TornadoError::InvalidMerkleTreeState.into());
+    }
+    
+    let mut current_level_hash = *leaf;
+    for (sibling, &is_right) in path_elements.iter().zip(path_indices) {
+        current_level_hash = if is_right {
+            hash_left_right(hash_algo, sibling, &current_level_hash)?
         } else {
-            i -= 1;
-        }
-        
-        if i == current_root_index as usize {
-            break;
-        }
+            hash_left_right(hash_algo, &current_level_hash, sibling)?
+        };
     }
     
-    false
+    Ok(current_level_hash == *root)
+}
+
+/// Zero every slot of the root history holding `root`, so `is_known_root` rejects it
+///
+/// # Returns
+///
+/// Returns whether the root was in the history
+pub fn invalidate_root(root: &[u8; 32], roots: &mut [[u8; 32]]) -> bool {
+    // The zero root is never known, so there is nothing to invalidate
+    if root.iter().all(|&x| x == 0) {
+        return false;
+    }
+
+    let mut found = false;
+    for known_root in roots.iter_mut().filter(|known_root| *known_root == root) {
+        *known_root = [0u8; 32];
+        found = true;
+    }
+    found
 }
 
 /// Get the last root
@@ -346,23 +817,78 @@ pub fn is_known_root(
 ///
 /// Returns the last root
 pub fn get_last_root(
-    roots: &[[u8; 32]; ROOT_HISTORY_SIZE],
+    roots: &[[u8; 32]],
     current_root_index: u8,
 ) -> [u8; 32] {
     roots[current_root_index as usize]
 }
 
+/// Get the most recent root of a Merkle tree and its index in the root history
+pub fn latest_root_with_index(merkle_tree: &MerkleTree) -> ([u8; 32], u8) {
+    (
+        get_last_root(&merkle_tree.roots, merkle_tree.current_root_index),
+        merkle_tree.current_root_index,
+    )
+}
+
+/// Get the number of leaves that can still be inserted into a Merkle tree
+pub fn remaining_capacity(merkle_tree: &MerkleTree) -> u64 {
+    (1u64 << merkle_tree.height).saturating_sub(merkle_tree.next_index as u64)
+}
+
+/// Build the Merkle path of a leaf from the on-chain state alone, as `(path_elements, path_indices)`
+///
+/// The tree account only keeps `filled_subtrees`, the last left node of each
+/// level, so this only works for the most recently inserted leaf: its left
+/// siblings are the filled subtrees and its right siblings are still empty.
+/// Any other leaf fails with `CannotReconstructPath`; its path needs the full
+/// tree, as rebuilt by `off_chain::TreeBuilder`. The path is for the latest root.
+pub fn try_build_path(merkle_tree: &MerkleTree, leaf_index: u32) -> Result<(Vec<[u8; 32]>, Vec<bool>), ProgramError> {
+    if merkle_tree.next_index == 0 || leaf_index != merkle_tree.next_index - 1 {
+        msg!(
+            "Only the path of the latest leaf can be built on-chain, leaf {} of {}",
+            leaf_index,
+            merkle_tree.next_index
+        );
+        return Err(TornadoError::CannotReconstructPath.into());
+    }
+
+    let mut path_elements = Vec::with_capacity(merkle_tree.height as usize);
+    let mut path_indices = Vec::with_capacity(merkle_tree.height as usize);
+    let mut current_idx = leaf_index;
+    for level in 0..merkle_tree.height as usize {
+        let is_right = current_idx & 1 == 1;
+        let sibling = if is_right {
+            *merkle_tree
+                .filled_subtrees
+                .get(level)
+                .ok_or(TornadoError::InvalidMerkleTreeState)?
+        } else {
+            get_zero_value(merkle_tree.hash_algo, level)?
+        };
+        path_elements.push(sibling);
+        path_indices.push(is_right);
+        current_idx /= 2;
+    }
+
+    Ok((path_elements, path_indices))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{off_chain::TreeBuilder, state::ROOT_HISTORY_SIZE};
+    use ark_ff::{BigInt, BigInteger, Field, UniformRand};
+    use sha3::{Digest, Keccak256};
     use solana_program::program_error::ProgramError;
+    use std::str::FromStr;
 
     #[test]
     fn test_hash_left_right() {
         // Test with valid inputs
         let left = [1u8; 32];
         let right = [2u8; 32];
-        let result = hash_left_right(&left, &right).unwrap();
+        let result = mimc_hash_left_right(&left, &right).unwrap();
         
         // Ensure result is not zero and is within field
         assert!(!result.iter().all(|&x| x == 0));
@@ -370,24 +896,82 @@ mod tests {
         
         // Test with inputs at field boundary
         let boundary = FIELD_SIZE;
-        let result = hash_left_right(&boundary, &right);
-        assert!(result.is_err());
+        assert!(mimc_hash_left_right(&boundary, &right).is_err());
         
         // Test with zero values
         let zero = [0u8; 32];
-        let result = hash_left_right(&zero, &zero).unwrap();
-        assert!(is_within_field(&result));
+        let zero_hash = mimc_hash_left_right(&zero, &zero).unwrap();
+        assert!(is_within_field(&zero_hash));
         
         // Test determinism
-        let result2 = hash_left_right(&left, &right).unwrap();
+        let result2 = mimc_hash_left_right(&left, &right).unwrap();
         assert_eq!(result, result2);
         
         // Test different inputs produce different outputs
         let left2 = [3u8; 32];
-        let result3 = hash_left_right(&left2, &right).unwrap();
+        let result3 = mimc_hash_left_right(&left2, &right).unwrap();
         assert!(result != result3);
     }
     
+    #[test]
+    fn test_field_mul() {
+        let mut rng = ark_std::test_rng();
+        
+        for _ in 0..100 {
+            let a = Fr::rand(&mut rng);
+            let b = Fr::rand(&mut rng);
+            let expected = (a * b).into_bigint().0;
+            
+            let result = field_mul(a.into_bigint().0, b.into_bigint().0).unwrap();
+            assert_eq!(result, expected);
+        }
+        
+        // Multiplying by one and zero
+        let a = Fr::rand(&mut rng).into_bigint().0;
+        assert_eq!(field_mul(a, [1, 0, 0, 0]).unwrap(), a);
+        assert_eq!(field_mul(a, [0, 0, 0, 0]).unwrap(), [0u64; 4]);
+        
+        // (p - 1) * (p - 1) = 1 mod p
+        let minus_one = (-Fr::from(1u64)).into_bigint().0;
+        assert_eq!(field_mul(minus_one, minus_one).unwrap(), [1, 0, 0, 0]);
+        
+        // Unreduced inputs are reduced before multiplying
+        let modulus = Fr::MODULUS.0;
+        assert_eq!(field_mul(modulus, a).unwrap(), [0u64; 4]);
+        
+        // The result is always canonical
+        let max = [u64::MAX; 4];
+        let result = field_mul(max, max).unwrap();
+        let expected = Fr::from_be_bytes_mod_order(&field_element_to_bytes(max)).square();
+        assert_eq!(result, expected.into_bigint().0);
+        assert_eq!(
+            field_element_to_bytes(result).to_vec(),
+            expected.into_bigint().to_bytes_be()
+        );
+    }
+    
+    #[test]
+    fn test_field_add() {
+        let to_limbs = |fe: Fr| fe.into_bigint().0;
+        let minus_one = -Fr::from(1u64);
+        let minus_two = -Fr::from(2u64);
+        
+        // Sums just under, at and just over the modulus
+        assert_eq!(field_add(to_limbs(minus_two), [1, 0, 0, 0]), to_limbs(minus_one));
+        assert_eq!(field_add(to_limbs(minus_one), [1, 0, 0, 0]), [0u64; 4]);
+        assert_eq!(field_add(to_limbs(minus_one), to_limbs(minus_one)), to_limbs(minus_one + minus_one));
+        
+        // The result is always canonical
+        let mut rng = ark_std::test_rng();
+        for _ in 0..100 {
+            let a = Fr::rand(&mut rng);
+            let b = Fr::rand(&mut rng);
+            let result = field_add(to_limbs(a), to_limbs(b));
+            assert_eq!(result, to_limbs(a + b));
+            assert!(is_within_field(&field_element_to_bytes(result)));
+        }
+    }
+    
     #[test]
     fn test_is_within_field() {
         // Test with value below field size
@@ -396,7 +980,7 @@ mod tests {
         
         // Test with value equal to field size
         let equal = FIELD_SIZE;
-        assert!(is_within_field(&equal));
+        assert!(!is_within_field(&equal));
         
         // Test with value above field size
         let mut above = FIELD_SIZE;
@@ -404,6 +988,45 @@ mod tests {
         assert!(!is_within_field(&above));
     }
     
+    #[test]
+    fn test_field_boundary() {
+        // p - 1 is the largest field element; p and p + 1 are not field elements
+        let mut below = FIELD_SIZE;
+        below[31] -= 1;
+        let mut above = FIELD_SIZE;
+        above[31] += 1;
+        
+        assert!(is_within_field(&below));
+        assert!(!is_within_field(&FIELD_SIZE));
+        assert!(!is_within_field(&above));
+        
+        assert_eq!(
+            bytes_to_field_element(&below).unwrap(),
+            (-Fr::from(1u64)).into_bigint().0
+        );
+        for value in [FIELD_SIZE, above] {
+            assert_eq!(
+                bytes_to_field_element(&value).unwrap_err(),
+                TornadoError::InvalidMerkleTreeState.into()
+            );
+        }
+        
+        // Both leaves of hash_left_right are checked
+        let leaf = [1u8; 32];
+        assert!(mimc_hash_left_right(&below, &leaf).is_ok());
+        assert!(mimc_hash_left_right(&leaf, &below).is_ok());
+        for value in [FIELD_SIZE, above] {
+            assert_eq!(
+                mimc_hash_left_right(&value, &leaf).unwrap_err(),
+                TornadoError::InvalidMerkleTreeState.into()
+            );
+            assert_eq!(
+                mimc_hash_left_right(&leaf, &value).unwrap_err(),
+                TornadoError::InvalidMerkleTreeState.into()
+            );
+        }
+    }
+    
     #[test]
     fn test_mod_field_size() {
         // Test with value below field size
@@ -417,25 +1040,222 @@ mod tests {
         let result = mod_field_size(&above);
         assert!(is_within_field(&result));
         assert!(result != above);
+        
+        // Multiples of the field size need more than one subtraction
+        // k * FIELD_SIZE + offset
+        let multiple = |k: u64, offset: i64| -> [u8; 32] {
+            let mut value = BigInt::<4>::from(0u64);
+            for _ in 0..k {
+                value.add_with_carry(&Fr::MODULUS);
+            }
+            let offset_value = BigInt::from(offset.unsigned_abs());
+            if offset < 0 {
+                value.sub_with_borrow(&offset_value);
+            } else {
+                value.add_with_carry(&offset_value);
+            }
+            value.to_bytes_be().try_into().unwrap()
+        };
+        let mut p_minus_one = FIELD_SIZE;
+        p_minus_one[31] -= 1;
+        for (value, expected) in [
+            (multiple(2, -1), p_minus_one),
+            (multiple(2, 0), [0u8; 32]),
+            (multiple(3, 0), [0u8; 32]),
+            (multiple(5, 7), multiple(0, 7)),
+        ] {
+            let result = mod_field_size(&value);
+            assert!(is_within_field(&result));
+            assert_eq!(result, expected);
+        }
+        
+        // The largest 256-bit value reduces like the field arithmetic does
+        let max = [0xffu8; 32];
+        let result = mod_field_size(&max);
+        assert!(is_within_field(&result));
+        assert_eq!(result, field_element_to_bytes(Fr::from_be_bytes_mod_order(&max).into_bigint().0));
     }
     
     #[test]
     fn test_get_zero_value() {
         // Test level 0
-        let level0 = get_zero_value(0);
+        let level0 = get_zero_value(HASH_ALGO_MIMC, 0).unwrap();
         assert_eq!(level0, ZERO_VALUE);
         
         // Test level 1
-        let level1 = get_zero_value(1);
+        let level1 = get_zero_value(HASH_ALGO_MIMC, 1).unwrap();
         assert!(level1 != ZERO_VALUE);
         
         // Test level 2
-        let level2 = get_zero_value(2);
+        let level2 = get_zero_value(HASH_ALGO_MIMC, 2).unwrap();
         assert!(level2 != level1);
         
-        // Test high level (should default to level 0)
-        let high_level = get_zero_value(100);
-        assert_eq!(high_level, ZERO_VALUE);
+        // Test the highest level
+        assert_eq!(get_zero_value(HASH_ALGO_MIMC, MAX_TREE_HEIGHT - 1).unwrap(), ZEROS[MAX_TREE_HEIGHT - 1]);
+        
+        // Test levels beyond the table
+        assert!(get_zero_value(HASH_ALGO_MIMC, MAX_TREE_HEIGHT).is_err());
+        assert!(get_zero_value(HASH_ALGO_MIMC, 100).is_err());
+    }
+    
+    #[test]
+    fn test_field_size() {
+        assert_eq!(FIELD_SIZE.to_vec(), Fr::MODULUS.to_bytes_be());
+    }
+    
+    #[test]
+    fn test_zero_values() {
+        // ZERO_VALUE is keccak256("tornado") % FIELD_SIZE
+        let hash: [u8; 32] = Keccak256::digest(b"tornado").into();
+        let expected = Fr::from_be_bytes_mod_order(&hash);
+        assert_eq!(ZERO_VALUE.to_vec(), expected.into_bigint().to_bytes_be());
+        assert_eq!(ZEROS[0], ZERO_VALUE);
+        
+        // Each level is the hash of two copies of the level below
+        let mut zero = ZERO_VALUE;
+        for (level, expected) in ZEROS.iter().enumerate() {
+            assert_eq!(&zero, expected, "zero value mismatch at level {}", level);
+            zero = mimc_hash_left_right(&zero, &zero).unwrap();
+        }
+    }
+    
+    #[test]
+    fn test_poseidon_zero_values() {
+        // Each level is the Poseidon hash of two copies of the level below
+        let mut zero = ZERO_VALUE;
+        for (level, expected) in POSEIDON_ZEROS.iter().enumerate() {
+            assert_eq!(&zero, expected, "zero value mismatch at level {}", level);
+            assert_eq!(get_zero_value(HASH_ALGO_POSEIDON, level).unwrap(), zero);
+            zero = poseidon_hash_left_right(&zero, &zero).unwrap();
+        }
+        
+        // poseidon([1, 2]) from circomlib
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let mut two = [0u8; 32];
+        two[31] = 2;
+        assert_eq!(
+            poseidon_hash_left_right(&one, &two).unwrap().to_vec(),
+            Fr::from_str("7853200120776062878684798364095072458815029376092732009249414926327459813530")
+                .unwrap()
+                .into_bigint()
+                .to_bytes_be()
+        );
+        assert!(poseidon_hash_left_right(&FIELD_SIZE, &two).is_err());
+    }
+    
+    #[test]
+    fn test_hash_algorithms() {
+        let height = 4;
+        let leaves: Vec<[u8; 32]> = (1..=5u8).map(|i| [i; 32]).collect();
+        
+        // Root of a tree built from `leaves` with the given algorithm
+        let root = |hash_algo: u8| {
+            let mut filled_subtrees: Vec<[u8; 32]> =
+                (0..height as usize).map(|i| get_zero_value(hash_algo, i).unwrap()).collect();
+            let mut roots = vec![[0u8; 32]; ROOT_HISTORY_SIZE];
+            roots[0] = get_zero_value(hash_algo, height as usize).unwrap();
+            let mut current_root_index = 0;
+            for (i, leaf) in leaves.iter().enumerate() {
+                insert_leaf(
+                    leaf,
+                    i as u32,
+                    height,
+                    hash_algo,
+                    &mut filled_subtrees,
+                    &mut roots,
+                    &mut current_root_index,
+                )
+                .unwrap();
+            }
+            roots[current_root_index as usize]
+        };
+        
+        // Each algorithm is deterministic, and they give different roots
+        let mimc_root = root(HASH_ALGO_MIMC);
+        let poseidon_root = root(HASH_ALGO_POSEIDON);
+        assert_eq!(root(HASH_ALGO_MIMC), mimc_root);
+        assert_eq!(root(HASH_ALGO_POSEIDON), poseidon_root);
+        assert_ne!(mimc_root, poseidon_root);
+        
+        // hash_left_right dispatches on the algorithm
+        let (left, right) = (leaves[0], leaves[1]);
+        assert_eq!(
+            hash_left_right(HASH_ALGO_MIMC, &left, &right).unwrap(),
+            mimc_hash_left_right(&left, &right).unwrap()
+        );
+        assert_eq!(
+            hash_left_right(HASH_ALGO_POSEIDON, &left, &right).unwrap(),
+            poseidon_hash_left_right(&left, &right).unwrap()
+        );
+        
+        // Unknown algorithms are rejected
+        assert_eq!(
+            hash_left_right(2, &left, &right),
+            Err(TornadoError::UnsupportedHashAlgorithm.into())
+        );
+        assert_eq!(get_zero_value(2, 0), Err(TornadoError::UnsupportedHashAlgorithm.into()));
+    }
+    
+    #[test]
+    fn test_new_merkle_tree() {
+        // The root of an empty height 20 tree, hashed up from the zero leaf
+        let mut empty_root = ZERO_VALUE;
+        for _ in 0..20 {
+            empty_root = mimc_hash_left_right(&empty_root, &empty_root).unwrap();
+        }
+        
+        let tree = MerkleTree::new(20, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        assert_eq!(tree.roots[0], empty_root);
+        assert_eq!(tree.roots.len(), ROOT_HISTORY_SIZE);
+        assert_eq!(tree.filled_subtrees, ZEROS[..20].to_vec());
+        assert_eq!((tree.next_index, tree.current_root_index), (0, 0));
+        assert!(tree.is_initialized);
+        
+        // Poseidon trees start from the Poseidon zero values
+        let tree = MerkleTree::new(20, ROOT_HISTORY_SIZE as u8, HASH_ALGO_POSEIDON).unwrap();
+        assert_eq!(tree.roots[0], POSEIDON_ZEROS[20]);
+        assert_eq!(tree.filled_subtrees, POSEIDON_ZEROS[..20].to_vec());
+        
+        // The history must hold at least the empty root
+        assert_eq!(
+            MerkleTree::new(20, 0, HASH_ALGO_MIMC),
+            Err(TornadoError::InvalidMerkleTreeState.into())
+        );
+        assert_eq!(
+            MerkleTree::new(20, 1, HASH_ALGO_POSEIDON + 1),
+            Err(TornadoError::UnsupportedHashAlgorithm.into())
+        );
+
+        // The tallest supported tree starts from the top of the zero table
+        for (hash_algo, zeros) in [(HASH_ALGO_MIMC, &ZEROS), (HASH_ALGO_POSEIDON, &POSEIDON_ZEROS)] {
+            let tree = MerkleTree::new(MAX_SUPPORTED_HEIGHT, 1, hash_algo).unwrap();
+            assert_eq!(tree.roots[0], zeros[MAX_TREE_HEIGHT - 1]);
+
+            // One level more has no zero value for its root
+            assert_eq!(
+                MerkleTree::new(MAX_SUPPORTED_HEIGHT + 1, 1, hash_algo),
+                Err(TornadoError::InvalidMerkleTreeState.into())
+            );
+        }
+    }
+    
+    #[test]
+    fn test_mimc_reference_vectors() {
+        // `zeros(i)` from Tornado's MerkleTreeWithHistory.sol, each the MiMC
+        // sponge hash of two copies of the one before
+        let reference = [
+            "2fe54c60d3acabf3343a35b6eba15db4821b340f76e741e2249685ed4899af6c",
+            "256a6135777eee2fd26f54b8b7037a25439d5235caee224154186d2b8a52e31d",
+            "1151949895e82ab19924de92c40a3d6f7bcb60d92b00504b8199613683f0c200",
+            "20121ee811489ff8d61f09fb89e313f14959a0f28bb428a20dba6b0b068b3bdb",
+        ]
+        .map(|hex| -> [u8; 32] {
+            core::array::from_fn(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+        });
+        for pair in reference.windows(2) {
+            assert_eq!(mimc_hash_left_right(&pair[0], &pair[0]).unwrap(), pair[1]);
+        }
     }
     
     #[test]
@@ -443,7 +1263,7 @@ mod tests {
         // Create a test Merkle tree
         let height = 3;
         let mut filled_subtrees = vec![[0u8; 32]; height as usize];
-        let mut roots = [[0u8; 32]; ROOT_HISTORY_SIZE];
+        let mut roots = vec![[0u8; 32]; ROOT_HISTORY_SIZE];
         let mut current_root_index = 0;
         
         // Insert first leaf
@@ -451,8 +1271,8 @@ mod tests {
         let result = insert_leaf(
             &leaf1,
             0,
-            0,
             height,
+            HASH_ALGO_MIMC,
             &mut filled_subtrees,
             &mut roots,
             &mut current_root_index,
@@ -465,9 +1285,9 @@ mod tests {
         let leaf2 = [2u8; 32];
         let result = insert_leaf(
             &leaf2,
-            0,
             1,
             height,
+            HASH_ALGO_MIMC,
             &mut filled_subtrees,
             &mut roots,
             &mut current_root_index,
@@ -479,24 +1299,114 @@ mod tests {
         // Try to insert when tree is full
         let result = insert_leaf(
             &[3u8; 32],
-            0,
             8, // 2^3 = 8, so tree is full
             height,
+            HASH_ALGO_MIMC,
             &mut filled_subtrees,
             &mut roots,
             &mut current_root_index,
         );
         assert!(result.is_err());
         assert_eq!(
-            result.unwrap_err().to_string(),
-            ProgramError::Custom(4).to_string() // MerkleTreeFull error
+            result.unwrap_err(),
+            ProgramError::from(TornadoError::MerkleTreeFull)
+        );
+        
+        // Heights past the zero values fail cleanly, including those whose
+        // capacity doesn't fit in a `u32`
+        for height in [32, 64, u8::MAX] {
+            let mut filled_subtrees = vec![[0u8; 32]; height as usize];
+            let result = insert_leaf(
+                &[3u8; 32],
+                0,
+                height,
+                HASH_ALGO_MIMC,
+                &mut filled_subtrees,
+                &mut roots,
+                &mut current_root_index,
+            );
+            assert_eq!(result, Err(TornadoError::InvalidMerkleTreeState.into()));
+        }
+    }
+    
+    #[test]
+    fn test_verify_merkle_path() {
+        // Build a tree of height 3 with five leaves
+        let height = 3u8;
+        let leaves: Vec<[u8; 32]> = (1..=5u8).map(|i| [i; 32]).collect();
+        let mut filled_subtrees: Vec<[u8; 32]> =
+            (0..height as usize).map(|i| get_zero_value(HASH_ALGO_MIMC, i).unwrap()).collect();
+        let mut roots = vec![[0u8; 32]; ROOT_HISTORY_SIZE];
+        let mut current_root_index = 0;
+        for (i, leaf) in leaves.iter().enumerate() {
+            insert_leaf(
+                leaf,
+                i as u32,
+                height,
+                HASH_ALGO_MIMC,
+                &mut filled_subtrees,
+                &mut roots,
+                &mut current_root_index,
+            )
+            .unwrap();
+        }
+        let root = roots[current_root_index as usize];
+        
+        // Compute every level of the tree, padding with the zero values
+        let mut levels = vec![leaves.clone()];
+        for level in 0..height as usize {
+            let mut nodes = levels[level].clone();
+            nodes.resize(1 << (height as usize - level), get_zero_value(HASH_ALGO_MIMC, level).unwrap());
+            let parents = nodes
+                .chunks(2)
+                .map(|pair| mimc_hash_left_right(&pair[0], &pair[1]).unwrap())
+                .collect();
+            levels.push(parents);
+        }
+        assert_eq!(levels[height as usize][0], root);
+        
+        // Extract the path of every leaf
+        let path = |index: usize| {
+            let mut path_elements = Vec::new();
+            let mut path_indices = Vec::new();
+            for (level, nodes) in levels.iter().enumerate().take(height as usize) {
+                let node = index >> level;
+                let sibling = nodes
+                    .get(node ^ 1)
+                    .copied()
+                    .unwrap_or_else(|| get_zero_value(HASH_ALGO_MIMC, level).unwrap());
+                path_elements.push(sibling);
+                path_indices.push(node & 1 == 1);
+            }
+            (path_elements, path_indices)
+        };
+        for (index, leaf) in leaves.iter().enumerate() {
+            let (path_elements, path_indices) = path(index);
+            assert!(verify_merkle_path(leaf, &path_elements, &path_indices, &root, height, HASH_ALGO_MIMC).unwrap());
+        }
+        
+        // A tampered path element, leaf, index or root fails
+        let (mut path_elements, mut path_indices) = path(4);
+        assert!(!verify_merkle_path(&leaves[3], &path_elements, &path_indices, &root, height, HASH_ALGO_MIMC).unwrap());
+        assert!(!verify_merkle_path(&leaves[4], &path_elements, &path_indices, &roots[1], height, HASH_ALGO_MIMC).unwrap());
+        path_indices[0] = true;
+        assert!(!verify_merkle_path(&leaves[4], &path_elements, &path_indices, &root, height, HASH_ALGO_MIMC).unwrap());
+        path_indices[0] = false;
+        path_elements[1][31] ^= 1;
+        assert!(!verify_merkle_path(&leaves[4], &path_elements, &path_indices, &root, height, HASH_ALGO_MIMC).unwrap());
+        
+        // The path must match the tree height
+        assert_eq!(
+            verify_merkle_path(&leaves[4], &path_elements[..2], &path_indices[..2], &root, height, HASH_ALGO_MIMC)
+                .unwrap_err(),
+            ProgramError::from(TornadoError::InvalidMerkleTreeState)
         );
     }
     
     #[test]
     fn test_is_known_root() {
         // Create a test root history
-        let mut roots = [[0u8; 32]; ROOT_HISTORY_SIZE];
+        let mut roots = vec![[0u8; 32]; ROOT_HISTORY_SIZE];
         let root1 = [1u8; 32];
         let root2 = [2u8; 32];
         
@@ -506,22 +1416,93 @@ mod tests {
         let current_root_index = 1;
         
         // Test with known root
-        assert!(is_known_root(&root1, &roots, current_root_index));
-        assert!(is_known_root(&root2, &roots, current_root_index));
+        assert!(is_known_root(&root1, &roots, current_root_index, 1));
+        assert!(is_known_root(&root2, &roots, current_root_index, 1));
         
         // Test with unknown root
         let unknown_root = [3u8; 32];
-        assert!(!is_known_root(&unknown_root, &roots, current_root_index));
+        assert!(!is_known_root(&unknown_root, &roots, current_root_index, 1));
         
         // Test with zero root
         let zero_root = [0u8; 32];
-        assert!(!is_known_root(&zero_root, &roots, current_root_index));
+        assert!(!is_known_root(&zero_root, &roots, current_root_index, 1));
+    }
+    
+    #[test]
+    fn test_root_age_small_tree() {
+        let height = 3;
+        let mut merkle_tree = MerkleTree::new(height, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        let empty_root = merkle_tree.roots[0];
+        assert_eq!(stored_root_count(merkle_tree.next_index, merkle_tree.roots.len()), 1);
+        assert_eq!(root_age(&empty_root, &merkle_tree.roots, 0, 0), Some(0));
+        
+        // Two insertions store two roots after the empty tree's
+        let mut tree_roots = vec![empty_root];
+        for i in 1..=2u8 {
+            insert_leaf(
+                &[i; 32],
+                merkle_tree.next_index,
+                height,
+                HASH_ALGO_MIMC,
+                &mut merkle_tree.filled_subtrees,
+                &mut merkle_tree.roots,
+                &mut merkle_tree.current_root_index,
+            )
+            .unwrap();
+            merkle_tree.next_index += 1;
+            tree_roots.push(get_last_root(&merkle_tree.roots, merkle_tree.current_root_index));
+        }
+        assert_eq!(stored_root_count(merkle_tree.next_index, merkle_tree.roots.len()), 3);
+        for (age, root) in tree_roots.iter().rev().enumerate() {
+            assert_eq!(
+                root_age(root, &merkle_tree.roots, merkle_tree.current_root_index, merkle_tree.next_index),
+                Some(age as u8)
+            );
+            
+            // Looked up at its index, a root has the same age, and isn't found at another
+            let (roots, current_root_index, next_index) =
+                (&merkle_tree.roots, merkle_tree.current_root_index, merkle_tree.next_index);
+            let root_index = current_root_index - age as u8;
+            assert_eq!(root_age_at(root, roots, current_root_index, next_index, root_index), Some(age as u8));
+            assert_eq!(root_age_at(root, roots, current_root_index, next_index, root_index + 1), None);
+        }
+        
+        // Slots past the stored roots are never searched, whatever they hold
+        let stale_root = [9u8; 32];
+        merkle_tree.roots[ROOT_HISTORY_SIZE - 1] = stale_root;
+        merkle_tree.roots[3] = stale_root;
+        assert!(!is_known_root(&stale_root, &merkle_tree.roots, merkle_tree.current_root_index, merkle_tree.next_index));
+        assert_eq!(
+            root_age_at(&stale_root, &merkle_tree.roots, merkle_tree.current_root_index, merkle_tree.next_index, 3),
+            None
+        );
+        
+        // A full history is searched in every slot
+        assert_eq!(stored_root_count(u32::MAX, ROOT_HISTORY_SIZE), ROOT_HISTORY_SIZE);
+        assert_eq!(
+            root_age(&stale_root, &merkle_tree.roots, merkle_tree.current_root_index, ROOT_HISTORY_SIZE as u32),
+            Some(3)
+        );
+        assert_eq!(
+            root_age_at(&stale_root, &merkle_tree.roots, merkle_tree.current_root_index, ROOT_HISTORY_SIZE as u32, 3),
+            Some(merkle_tree.current_root_index + ROOT_HISTORY_SIZE as u8 - 3)
+        );
+        
+        // Indices outside the history and the zero root are never found
+        for root_index in [ROOT_HISTORY_SIZE as u8, u8::MAX] {
+            assert_eq!(
+                root_age_at(&stale_root, &merkle_tree.roots, merkle_tree.current_root_index, u32::MAX, root_index),
+                None
+            );
+        }
+        merkle_tree.roots[4] = [0u8; 32];
+        assert_eq!(root_age_at(&[0u8; 32], &merkle_tree.roots, merkle_tree.current_root_index, u32::MAX, 4), None);
     }
     
     #[test]
     fn test_get_last_root() {
         // Create a test root history
-        let mut roots = [[0u8; 32]; ROOT_HISTORY_SIZE];
+        let mut roots = vec![[0u8; 32]; ROOT_HISTORY_SIZE];
         let root1 = [1u8; 32];
         let root2 = [2u8; 32];
         
@@ -534,4 +1515,163 @@ mod tests {
         // Test with current_root_index = 1
         assert_eq!(get_last_root(&roots, 1), root2);
     }
+    
+    #[test]
+    fn test_latest_root_with_index() {
+        let height = 3;
+        let mut merkle_tree = MerkleTree::new(height, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        assert_eq!(latest_root_with_index(&merkle_tree), (merkle_tree.roots[0], 0));
+        
+        // The latest root follows every insertion
+        for i in 0..5u8 {
+            insert_leaf(
+                &[i + 1; 32],
+                merkle_tree.next_index,
+                height,
+                HASH_ALGO_MIMC,
+                &mut merkle_tree.filled_subtrees,
+                &mut merkle_tree.roots,
+                &mut merkle_tree.current_root_index,
+            )
+            .unwrap();
+            merkle_tree.next_index += 1;
+            
+            let (root, index) = latest_root_with_index(&merkle_tree);
+            assert_eq!(index, i + 1);
+            assert_eq!(root, merkle_tree.roots[index as usize]);
+            assert!(is_known_root(&root, &merkle_tree.roots, merkle_tree.current_root_index, merkle_tree.next_index));
+        }
+    }
+    
+    #[test]
+    fn test_try_build_path() {
+        let height = 3;
+        for hash_algo in [HASH_ALGO_MIMC, HASH_ALGO_POSEIDON] {
+            let mut merkle_tree = MerkleTree::new(height, ROOT_HISTORY_SIZE as u8, hash_algo).unwrap();
+            let mut leaves = Vec::new();
+            
+            // Nothing to build before the first insertion
+            assert_eq!(
+                try_build_path(&merkle_tree, 0).unwrap_err(),
+                ProgramError::from(TornadoError::CannotReconstructPath)
+            );
+            
+            for i in 0..6u8 {
+                let leaf = [i + 1; 32];
+                insert_leaf(
+                    &leaf,
+                    merkle_tree.next_index,
+                    height,
+                    hash_algo,
+                    &mut merkle_tree.filled_subtrees,
+                    &mut merkle_tree.roots,
+                    &mut merkle_tree.current_root_index,
+                )
+                .unwrap();
+                merkle_tree.next_index += 1;
+                leaves.push(leaf);
+                
+                // The latest leaf's path proves it against the latest root
+                let index = merkle_tree.next_index - 1;
+                let (path_elements, path_indices) = try_build_path(&merkle_tree, index).unwrap();
+                let (root, _) = latest_root_with_index(&merkle_tree);
+                assert!(verify_merkle_path(&leaf, &path_elements, &path_indices, &root, height, hash_algo).unwrap());
+                let tree = TreeBuilder::from_leaves(height, hash_algo, &leaves).unwrap();
+                assert_eq!(tree.path(index).unwrap(), (path_elements, path_indices));
+                
+                // Earlier leaves and leaves not inserted yet need the full tree
+                for other in (0..index).chain([index + 1]) {
+                    assert_eq!(
+                        try_build_path(&merkle_tree, other).unwrap_err(),
+                        ProgramError::from(TornadoError::CannotReconstructPath)
+                    );
+                }
+            }
+        }
+    }
+    
+    #[test]
+    fn test_invalidate_root() {
+        let mut roots = vec![[0u8; 32]; 4];
+        roots[0] = [1u8; 32];
+        roots[1] = [2u8; 32];
+        roots[2] = [1u8; 32];
+
+        // Every slot holding the root is cleared, the others are kept
+        assert!(invalidate_root(&[1u8; 32], &mut roots));
+        assert_eq!(roots, vec![[0u8; 32], [2u8; 32], [0u8; 32], [0u8; 32]]);
+        assert!(!is_known_root(&[1u8; 32], &roots, 1, 3));
+        assert!(is_known_root(&[2u8; 32], &roots, 1, 3));
+
+        // Unknown and zero roots are not found
+        assert!(!invalidate_root(&[1u8; 32], &mut roots));
+        assert!(!invalidate_root(&[0u8; 32], &mut roots));
+    }
+    
+    #[test]
+    fn test_remaining_capacity() {
+        let mut merkle_tree = MerkleTree::new(3, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        assert_eq!(remaining_capacity(&merkle_tree), 8);
+        
+        merkle_tree.next_index = 5;
+        assert_eq!(remaining_capacity(&merkle_tree), 3);
+        
+        merkle_tree.next_index = 8;
+        assert_eq!(remaining_capacity(&merkle_tree), 0);
+        
+        // A tree of height 32 holds 2^32 leaves, more than fit in a u32
+        merkle_tree.height = 32;
+        merkle_tree.next_index = 0;
+        assert_eq!(remaining_capacity(&merkle_tree), 1u64 << 32);
+        merkle_tree.next_index = u32::MAX;
+        assert_eq!(remaining_capacity(&merkle_tree), 1);
+    }
+    
+    #[test]
+    fn test_root_history_wraparound() {
+        let height = 7u8;
+        
+        for root_history_size in [4usize, 100] {
+            let mut filled_subtrees: Vec<[u8; 32]> =
+                (0..height as usize).map(|i| get_zero_value(HASH_ALGO_MIMC, i).unwrap()).collect();
+            let mut roots = vec![[0u8; 32]; root_history_size];
+            roots[0] = get_zero_value(HASH_ALGO_MIMC, height as usize).unwrap();
+            let mut current_root_index = 0;
+            
+            // Insert enough leaves to wrap around the history
+            let mut all_roots = vec![roots[0]];
+            for i in 0..root_history_size as u32 + 5 {
+                insert_leaf(
+                    &[(i % 32) as u8 + 1; 32],
+                    i,
+                    height,
+                    HASH_ALGO_MIMC,
+                    &mut filled_subtrees,
+                    &mut roots,
+                    &mut current_root_index,
+                )
+                .unwrap();
+                all_roots.push(get_last_root(&roots, current_root_index));
+                assert_eq!(current_root_index as usize, (i as usize + 1) % root_history_size);
+            }
+            
+            // Only the latest `root_history_size` roots are still known
+            let next_index = root_history_size as u32 + 5;
+            let (evicted, kept) = all_roots.split_at(all_roots.len() - root_history_size);
+            for root in kept {
+                assert!(is_known_root(root, &roots, current_root_index, next_index));
+            }
+            for root in evicted {
+                assert!(!is_known_root(root, &roots, current_root_index, next_index));
+            }
+        }
+        
+        // An index outside the history or an empty history is rejected
+        let roots = [[1u8; 32]; 4];
+        assert!(!is_known_root(&[1u8; 32], &roots, 4, 4));
+        assert_eq!(
+            insert_leaf(&[1u8; 32], 0, height, HASH_ALGO_MIMC, &mut [[0u8; 32]; 7], &mut [], &mut 0).unwrap_err(),
+            ProgramError::from(TornadoError::InvalidMerkleTreeState)
+        );
+    }
 }
\ No newline at end of file
@@ -0,0 +1,169 @@
+//! Deposit notes for the Tornado Cash Privacy Solution
+//!
+//! A note holds the secret values of a deposit, so it can be withdrawn later.
+//! Notes are written as `tornado-svm-<denomination>-<payload>`, where the
+//! payload is the base58 encoding of `nullifier || secret || instance`.
+
+use std::{fmt, str::FromStr};
+
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+use crate::{
+    error::TornadoError,
+    utils::{compute_commitment, compute_nullifier_hash},
+};
+
+/// Prefix of every note string
+pub const NOTE_PREFIX: &str = "tornado-svm";
+
+/// Length of the decoded note payload: nullifier + secret + instance
+const NOTE_PAYLOAD_LEN: usize = 32 + 32 + 32;
+
+/// The secret values of a deposit
+#[derive(Clone, Debug, PartialEq)]
+pub struct Note {
+    /// The nullifier of the deposit
+    pub nullifier: [u8; 32],
+    /// The secret of the deposit
+    pub secret: [u8; 32],
+    /// The denomination of the instance
+    pub denomination: u64,
+    /// The Tornado instance the deposit was made to
+    pub instance: Pubkey,
+}
+
+impl Note {
+    /// The commitment deposited for this note
+    pub fn commitment(&self) -> Result<[u8; 32], ProgramError> {
+        compute_commitment(&self.nullifier, &self.secret, &self.instance)
+    }
+
+    /// The nullifier hash revealed when withdrawing this note
+    pub fn nullifier_hash(&self) -> Result<[u8; 32], ProgramError> {
+        compute_nullifier_hash(&self.nullifier)
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut payload = Vec::with_capacity(NOTE_PAYLOAD_LEN);
+        payload.extend_from_slice(&self.nullifier);
+        payload.extend_from_slice(&self.secret);
+        payload.extend_from_slice(self.instance.as_ref());
+
+        write!(
+            f,
+            "{}-{}-{}",
+            NOTE_PREFIX,
+            self.denomination,
+            bs58::encode(payload).into_string()
+        )
+    }
+}
+
+impl FromStr for Note {
+    type Err = TornadoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix(NOTE_PREFIX)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .ok_or(TornadoError::InvalidNote)?;
+        let (denomination, payload) = rest.split_once('-').ok_or(TornadoError::InvalidNote)?;
+
+        // Only plain decimal digits, so "+1" or "01" style variants are rejected
+        if denomination.is_empty()
+            || !denomination.bytes().all(|b| b.is_ascii_digit())
+            || (denomination.len() > 1 && denomination.starts_with('0'))
+        {
+            return Err(TornadoError::InvalidNote);
+        }
+        let denomination = denomination.parse().map_err(|_| TornadoError::InvalidNote)?;
+
+        let payload = bs58::decode(payload)
+            .into_vec()
+            .map_err(|_| TornadoError::InvalidNote)?;
+        if payload.len() != NOTE_PAYLOAD_LEN {
+            return Err(TornadoError::InvalidNote);
+        }
+
+        let mut nullifier = [0u8; 32];
+        let mut secret = [0u8; 32];
+        let mut instance = [0u8; 32];
+        nullifier.copy_from_slice(&payload[0..32]);
+        secret.copy_from_slice(&payload[32..64]);
+        instance.copy_from_slice(&payload[64..96]);
+
+        Ok(Note {
+            nullifier,
+            secret,
+            denomination,
+            instance: Pubkey::new_from_array(instance),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_note() -> Note {
+        Note {
+            nullifier: [1u8; 32],
+            secret: [2u8; 32],
+            denomination: 100_000_000,
+            instance: Pubkey::new_unique(),
+        }
+    }
+
+    #[test]
+    fn test_note_round_trip() {
+        let note = create_test_note();
+        let note_string = note.to_string();
+        assert!(note_string.starts_with("tornado-svm-100000000-"));
+        assert_eq!(note_string.parse::<Note>().unwrap(), note);
+
+        // Edge values survive the round trip too
+        let note = Note {
+            nullifier: [0u8; 32],
+            secret: [0xFF; 32],
+            denomination: u64::MAX,
+            instance: Pubkey::default(),
+        };
+        assert_eq!(Note::from_str(&note.to_string()).unwrap(), note);
+    }
+
+    #[test]
+    fn test_note_hashes() {
+        let note = create_test_note();
+        assert_eq!(note.commitment(), compute_commitment(&[1u8; 32], &[2u8; 32], &note.instance));
+        assert_eq!(note.nullifier_hash(), compute_nullifier_hash(&[1u8; 32]));
+    }
+
+    #[test]
+    fn test_note_malformed() {
+        let note_string = create_test_note().to_string();
+        let payload = note_string.rsplit('-').next().unwrap();
+        let short_payload = bs58::encode([1u8; NOTE_PAYLOAD_LEN - 1]).into_string();
+
+        for malformed in [
+            String::new(),
+            "tornado-svm".to_string(),
+            format!("tornado-eth-100000000-{}", payload),
+            format!("tornado-svm100000000-{}", payload),
+            format!("tornado-svm-{}", payload),
+            format!("tornado-svm--{}", payload),
+            format!("tornado-svm-+100000000-{}", payload),
+            format!("tornado-svm-0100000000-{}", payload),
+            format!("tornado-svm-18446744073709551616-{}", payload),
+            "tornado-svm-100000000-".to_string(),
+            // '0' and 'l' aren't in the base58 alphabet
+            format!("tornado-svm-100000000-0{}", payload),
+            format!("tornado-svm-100000000-{}l", payload),
+            format!("tornado-svm-100000000-{}", short_payload),
+            format!("{}-extra", note_string),
+        ] {
+            assert_eq!(Note::from_str(&malformed), Err(TornadoError::InvalidNote), "{}", malformed);
+        }
+    }
+}
@@ -0,0 +1,448 @@
+//! Off-chain Merkle tree reconstruction for clients and relayers
+//!
+//! A withdrawal proof needs the Merkle path of the deposited commitment, but the
+//! on-chain tree only keeps its filled subtrees and recent roots. `TreeBuilder`
+//! replays the commitments of the `DepositEvent`s of an instance, in leaf index
+//! order, and keeps every node of the tree. It hashes with `hash_left_right`
+//! and pads with the same zero values as `insert_leaf`, for the `hash_algo` of
+//! the on-chain tree, so its roots match the on-chain ones bit for bit.
+//!
+//! `MerkleTree::snapshot` and `MerkleTree::restore` capture the on-chain tree
+//! state in a versioned format of its own, for test fixtures and offline
+//! migration tooling. A snapshot doesn't depend on the account layout, so it
+//! still restores after `MerkleTree::VERSION` changes.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{msg, program_error::ProgramError};
+
+use crate::{
+    error::TornadoError,
+    merkle_tree::{get_zero_value, hash_left_right, HASH_ALGO_MIMC, HASH_ALGO_POSEIDON, MAX_SUPPORTED_HEIGHT},
+    state::MerkleTree,
+};
+
+/// Leading bytes of a `MerkleTree` snapshot
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"TMTS";
+
+/// Current version of the `MerkleTree` snapshot format
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+/// Full Merkle tree rebuilt from the inserted leaves
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeBuilder {
+    /// The height of the tree
+    height: u8,
+    /// The hash algorithm of the tree
+    hash_algo: u8,
+    /// The computed nodes of each level, with the leaves at level 0
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl TreeBuilder {
+    /// Create an empty tree of the given height and hash algorithm
+    pub fn new(height: u8, hash_algo: u8) -> Result<Self, ProgramError> {
+        if height == 0 || height > MAX_SUPPORTED_HEIGHT {
+            msg!("Merkle tree height must be between 1 and {}", MAX_SUPPORTED_HEIGHT);
+            return Err(TornadoError::InvalidMerkleTreeHeight.into());
+        }
+        if hash_algo != HASH_ALGO_MIMC && hash_algo != HASH_ALGO_POSEIDON {
+            msg!("Unknown hash algorithm {}", hash_algo);
+            return Err(TornadoError::UnsupportedHashAlgorithm.into());
+        }
+
+        Ok(Self {
+            height,
+            hash_algo,
+            levels: vec![Vec::new(); height as usize + 1],
+        })
+    }
+
+    /// Create a tree of the given height and hash algorithm from leaves in insertion order
+    pub fn from_leaves(height: u8, hash_algo: u8, leaves: &[[u8; 32]]) -> Result<Self, ProgramError> {
+        let mut tree = Self::new(height, hash_algo)?;
+        for leaf in leaves {
+            tree.insert(*leaf)?;
+        }
+        Ok(tree)
+    }
+
+    /// The height of the tree
+    pub fn height(&self) -> u8 {
+        self.height
+    }
+
+    /// The hash algorithm of the tree
+    pub fn hash_algo(&self) -> u8 {
+        self.hash_algo
+    }
+
+    /// The number of inserted leaves, i.e. the next leaf index
+    pub fn len(&self) -> u32 {
+        self.levels[0].len() as u32
+    }
+
+    /// Whether no leaf has been inserted yet
+    pub fn is_empty(&self) -> bool {
+        self.levels[0].is_empty()
+    }
+
+    /// Insert the next leaf, returning its index
+    pub fn insert(&mut self, leaf: [u8; 32]) -> Result<u32, ProgramError> {
+        let index = self.levels[0].len();
+        if index as u64 >= 1u64 << self.height {
+            return Err(TornadoError::MerkleTreeFull.into());
+        }
+        self.levels[0].push(leaf);
+
+        // Recompute the nodes on the path from the new leaf to the root
+        let mut current_idx = index;
+        for level in 0..self.height as usize {
+            let left = self.levels[level][current_idx & !1];
+            let right = match self.levels[level].get(current_idx | 1) {
+                Some(node) => *node,
+                None => get_zero_value(self.hash_algo, level)?,
+            };
+            let parent = hash_left_right(self.hash_algo, &left, &right)?;
+
+            current_idx /= 2;
+            let parents = &mut self.levels[level + 1];
+            if current_idx < parents.len() {
+                parents[current_idx] = parent;
+            } else {
+                parents.push(parent);
+            }
+        }
+
+        Ok(index as u32)
+    }
+
+    /// The current root of the tree
+    pub fn root(&self) -> Result<[u8; 32], ProgramError> {
+        match self.levels[self.height as usize].first() {
+            Some(root) => Ok(*root),
+            None => {
+                // Root of the empty tree
+                let zero = get_zero_value(self.hash_algo, self.height as usize - 1)?;
+                hash_left_right(self.hash_algo, &zero, &zero)
+            }
+        }
+    }
+
+    /// The Merkle path of a leaf, as `(path_elements, path_indices)`
+    ///
+    /// `path_indices[i]` is true when the node at level `i` is a right child,
+    /// as expected by `verify_merkle_path`.
+    pub fn path(&self, leaf_index: u32) -> Result<(Vec<[u8; 32]>, Vec<bool>), ProgramError> {
+        if leaf_index >= self.len() {
+            msg!("Leaf {} has not been inserted", leaf_index);
+            return Err(TornadoError::InvalidMerkleTreeState.into());
+        }
+
+        let mut path_elements = Vec::with_capacity(self.height as usize);
+        let mut path_indices = Vec::with_capacity(self.height as usize);
+        let mut current_idx = leaf_index as usize;
+        for level in 0..self.height as usize {
+            let sibling = match self.levels[level].get(current_idx ^ 1) {
+                Some(node) => *node,
+                None => get_zero_value(self.hash_algo, level)?,
+            };
+            path_elements.push(sibling);
+            path_indices.push(current_idx & 1 == 1);
+            current_idx /= 2;
+        }
+
+        Ok((path_elements, path_indices))
+    }
+}
+
+// Merkle tree state in a version 1 snapshot
+//
+// This layout is frozen: a change to it gets a new snapshot version, and
+// `MerkleTree::restore` keeps reading this one.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct MerkleTreeSnapshotV1 {
+    is_initialized: bool,
+    height: u8,
+    hash_algo: u8,
+    next_index: u32,
+    current_root_index: u8,
+    // Equal to `next_index`, so it isn't restored
+    commitment_count: u64,
+    nullifier_count: u64,
+    roots: Vec<[u8; 32]>,
+    root_timestamps: Vec<i64>,
+    filled_subtrees: Vec<[u8; 32]>,
+    nullifier_hashes: Vec<[u8; 32]>,
+}
+
+impl From<MerkleTreeSnapshotV1> for MerkleTree {
+    fn from(snapshot: MerkleTreeSnapshotV1) -> Self {
+        Self {
+            version: Self::VERSION,
+            is_initialized: snapshot.is_initialized,
+            height: snapshot.height,
+            hash_algo: snapshot.hash_algo,
+            next_index: snapshot.next_index,
+            current_root_index: snapshot.current_root_index,
+            nullifier_count: snapshot.nullifier_count,
+            roots: snapshot.roots,
+            root_timestamps: snapshot.root_timestamps,
+            filled_subtrees: snapshot.filled_subtrees,
+            nullifier_hashes: snapshot.nullifier_hashes,
+        }
+    }
+}
+
+impl MerkleTree {
+    /// Capture the tree state in a snapshot
+    ///
+    /// The snapshot is `SNAPSHOT_MAGIC` and `SNAPSHOT_VERSION` followed by the
+    /// state. It leaves out the account layout version: a tree is restored at
+    /// the layout of the program restoring it.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let state = MerkleTreeSnapshotV1 {
+            is_initialized: self.is_initialized,
+            height: self.height,
+            hash_algo: self.hash_algo,
+            next_index: self.next_index,
+            current_root_index: self.current_root_index,
+            commitment_count: self.next_index.into(),
+            nullifier_count: self.nullifier_count,
+            roots: self.roots.clone(),
+            root_timestamps: self.root_timestamps.clone(),
+            filled_subtrees: self.filled_subtrees.clone(),
+            nullifier_hashes: self.nullifier_hashes.clone(),
+        };
+
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        snapshot.push(SNAPSHOT_VERSION);
+        state.serialize(&mut snapshot).expect("serializing into a Vec doesn't fail");
+        snapshot
+    }
+
+    /// Restore a tree from a snapshot taken by `snapshot`
+    ///
+    /// Snapshots of every earlier format version are read too. Fails with
+    /// `InvalidAccountData` when the bytes aren't a snapshot,
+    /// `UnsupportedStateVersion` when the format is newer than this program's,
+    /// and `InvalidMerkleTreeState` when the restored state is inconsistent.
+    pub fn restore(snapshot: &[u8]) -> Result<Self, ProgramError> {
+        let Some((&version, state)) = snapshot.strip_prefix(&SNAPSHOT_MAGIC).and_then(|rest| rest.split_first()) else {
+            msg!("Not a Merkle tree snapshot");
+            return Err(TornadoError::InvalidAccountData.into());
+        };
+
+        let tree: Self = match version {
+            1 => MerkleTreeSnapshotV1::try_from_slice(state)
+                .map_err(|_| {
+                    msg!("Failed to deserialize the Merkle tree snapshot");
+                    TornadoError::InvalidAccountData
+                })?
+                .into(),
+            _ => {
+                msg!("Unsupported Merkle tree snapshot version {}", version);
+                return Err(TornadoError::UnsupportedStateVersion.into());
+            }
+        };
+
+        // The tree must be one `insert_leaf` can carry on from
+        if tree.height > MAX_SUPPORTED_HEIGHT
+            || tree.filled_subtrees.len() != tree.height as usize
+            || tree.roots.len() != tree.root_timestamps.len()
+            || tree.current_root_index as usize >= tree.roots.len()
+        {
+            msg!("Merkle tree snapshot is inconsistent");
+            return Err(TornadoError::InvalidMerkleTreeState.into());
+        }
+
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        merkle_tree::{insert_leaf, verify_merkle_path},
+        state::ROOT_HISTORY_SIZE,
+    };
+
+    #[test]
+    fn test_tree_builder_matches_insert_leaf() {
+        let height = 4u8;
+        let leaves: Vec<[u8; 32]> = (1..=11u8).map(|i| [i; 32]).collect();
+
+        for hash_algo in [HASH_ALGO_MIMC, HASH_ALGO_POSEIDON] {
+            // On-chain tree state
+            let mut filled_subtrees: Vec<[u8; 32]> =
+                (0..height as usize).map(|i| get_zero_value(hash_algo, i).unwrap()).collect();
+            let mut roots = vec![[0u8; 32]; ROOT_HISTORY_SIZE];
+            roots[0] = get_zero_value(hash_algo, height as usize).unwrap();
+            let mut current_root_index = 0;
+
+            let mut tree = TreeBuilder::new(height, hash_algo).unwrap();
+            assert!(tree.is_empty());
+            assert_eq!(tree.root().unwrap(), roots[0]);
+
+            for (i, leaf) in leaves.iter().enumerate() {
+                let leaf_index = insert_leaf(
+                    leaf,
+                    i as u32,
+                    height,
+                    hash_algo,
+                    &mut filled_subtrees,
+                    &mut roots,
+                    &mut current_root_index,
+                )
+                .unwrap();
+                assert_eq!(tree.insert(*leaf).unwrap(), leaf_index);
+                assert_eq!(tree.len(), leaf_index + 1);
+                assert_eq!(tree.root().unwrap(), roots[current_root_index as usize]);
+            }
+
+            // Rebuilding from the same leaves gives the same tree
+            assert_eq!(TreeBuilder::from_leaves(height, hash_algo, &leaves).unwrap(), tree);
+        }
+    }
+
+    #[test]
+    fn test_tree_builder_paths() {
+        let height = 3u8;
+        let leaves: Vec<[u8; 32]> = (1..=5u8).map(|i| [i; 32]).collect();
+
+        for hash_algo in [HASH_ALGO_MIMC, HASH_ALGO_POSEIDON] {
+            let tree = TreeBuilder::from_leaves(height, hash_algo, &leaves).unwrap();
+            let root = tree.root().unwrap();
+
+            for (i, leaf) in leaves.iter().enumerate() {
+                let (path_elements, path_indices) = tree.path(i as u32).unwrap();
+                assert_eq!(path_elements.len(), height as usize);
+                assert!(
+                    verify_merkle_path(leaf, &path_elements, &path_indices, &root, height, hash_algo).unwrap()
+                );
+            }
+
+            // Leaves that have not been inserted have no path
+            assert_eq!(
+                tree.path(leaves.len() as u32),
+                Err(TornadoError::InvalidMerkleTreeState.into())
+            );
+        }
+    }
+
+    // A tree after a few deposits and withdrawals
+    fn deposited_tree(hash_algo: u8) -> MerkleTree {
+        let mut tree = MerkleTree::new(4, ROOT_HISTORY_SIZE as u8, hash_algo).unwrap();
+        for i in 1..=11u8 {
+            insert_leaf(
+                &[i; 32],
+                tree.next_index,
+                tree.height,
+                tree.hash_algo,
+                &mut tree.filled_subtrees,
+                &mut tree.roots,
+                &mut tree.current_root_index,
+            )
+            .unwrap();
+            tree.next_index += 1;
+            tree.root_timestamps[tree.current_root_index as usize] = 1_700_000_000 + i as i64;
+        }
+        tree.nullifier_count = 3;
+        tree.nullifier_hashes = vec![[0xaa; 32]];
+        tree
+    }
+
+    #[test]
+    fn test_merkle_tree_snapshot_round_trip() {
+        for hash_algo in [HASH_ALGO_MIMC, HASH_ALGO_POSEIDON] {
+            let tree = deposited_tree(hash_algo);
+            let snapshot = tree.snapshot();
+            assert_eq!(snapshot[..4], SNAPSHOT_MAGIC);
+            assert_eq!(snapshot[4], SNAPSHOT_VERSION);
+            assert_eq!(MerkleTree::restore(&snapshot).unwrap(), tree);
+        }
+
+        // Bytes that aren't a whole snapshot are rejected
+        let snapshot = deposited_tree(HASH_ALGO_MIMC).snapshot();
+        let mut trailing = snapshot.clone();
+        trailing.push(0);
+        let mut bad_magic = snapshot.clone();
+        bad_magic[0] ^= 1;
+        for invalid in [&[][..], &snapshot[..4], &snapshot[..snapshot.len() - 1], &trailing, &bad_magic] {
+            assert_eq!(MerkleTree::restore(invalid), Err(TornadoError::InvalidAccountData.into()));
+        }
+
+        // So are states `insert_leaf` couldn't carry on from
+        let tree = deposited_tree(HASH_ALGO_MIMC);
+        let history = tree.roots.len();
+        for inconsistent in [
+            MerkleTree { height: tree.height + 1, ..deposited_tree(HASH_ALGO_MIMC) },
+            MerkleTree { root_timestamps: vec![0; history - 1], ..deposited_tree(HASH_ALGO_MIMC) },
+            MerkleTree { current_root_index: history as u8, ..deposited_tree(HASH_ALGO_MIMC) },
+        ] {
+            assert_eq!(
+                MerkleTree::restore(&inconsistent.snapshot()),
+                Err(TornadoError::InvalidMerkleTreeState.into())
+            );
+        }
+    }
+
+    #[test]
+    fn test_merkle_tree_snapshot_across_version_bump() {
+        // A tree read under an earlier account layout snapshots the same and
+        // restores at the current one
+        let tree = deposited_tree(HASH_ALGO_MIMC);
+        let previous_layout = MerkleTree {
+            version: MerkleTree::VERSION - 1,
+            ..deposited_tree(HASH_ALGO_MIMC)
+        };
+        assert_eq!(previous_layout.snapshot(), tree.snapshot());
+        assert_eq!(MerkleTree::restore(&previous_layout.snapshot()).unwrap(), tree);
+
+        // A version 1 snapshot, byte for byte, keeps restoring whatever the layout
+        let root = get_zero_value(HASH_ALGO_MIMC, 1).unwrap();
+        let zero = get_zero_value(HASH_ALGO_MIMC, 0).unwrap();
+        let snapshot = [
+            &SNAPSHOT_MAGIC[..],
+            &[1, 1, 1, HASH_ALGO_MIMC],
+            &0u32.to_le_bytes(),
+            &[0],
+            &0u64.to_le_bytes(),
+            &0u64.to_le_bytes(),
+            &1u32.to_le_bytes(),
+            &root,
+            &1u32.to_le_bytes(),
+            &0i64.to_le_bytes(),
+            &1u32.to_le_bytes(),
+            &zero,
+            &0u32.to_le_bytes(),
+        ]
+        .concat();
+        assert_eq!(MerkleTree::restore(&snapshot).unwrap(), MerkleTree::new(1, 1, HASH_ALGO_MIMC).unwrap());
+
+        // A snapshot from a newer format is refused rather than misread
+        let mut newer = tree.snapshot();
+        newer[4] = SNAPSHOT_VERSION + 1;
+        assert_eq!(MerkleTree::restore(&newer), Err(TornadoError::UnsupportedStateVersion.into()));
+    }
+
+    #[test]
+    fn test_tree_builder_limits() {
+        assert_eq!(
+            TreeBuilder::new(0, HASH_ALGO_MIMC),
+            Err(TornadoError::InvalidMerkleTreeHeight.into())
+        );
+        assert_eq!(
+            TreeBuilder::new(MAX_SUPPORTED_HEIGHT + 1, HASH_ALGO_MIMC),
+            Err(TornadoError::InvalidMerkleTreeHeight.into())
+        );
+        assert_eq!(
+            TreeBuilder::new(2, HASH_ALGO_POSEIDON + 1),
+            Err(TornadoError::UnsupportedHashAlgorithm.into())
+        );
+
+        // A tree of height 2 holds four leaves
+        let mut tree = TreeBuilder::from_leaves(2, HASH_ALGO_MIMC, &[[1u8; 32], [2u8; 32], [3u8; 32], [4u8; 32]]).unwrap();
+        assert_eq!(tree.insert([5u8; 32]), Err(TornadoError::MerkleTreeFull.into()));
+    }
+}
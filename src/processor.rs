@@ -2,28 +2,66 @@
 //!
 //! This module contains the main logic for processing instructions for the
 //! Tornado Cash Privacy Solution for Solana. It handles initialization,
-//! deposits, and withdrawals.
+//! deposits, and withdrawals of both native SOL and SPL tokens.
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    ed25519_program,
     entrypoint::ProgramResult,
     msg,
+    program::set_return_data,
     program_error::ProgramError,
-    program_pack::Pack,
+    program_pack::{IsInitialized, Pack},
+    pubkey,
     pubkey::Pubkey,
-    system_program,
+    sysvar::{
+        clock::Clock,
+        instructions::{load_current_index_checked, load_instruction_at_checked},
+        rent::Rent,
+        Sysvar,
+    },
 };
+#[cfg(feature = "log_cu")]
+use solana_program::log::sol_log_compute_units;
+use spl_associated_token_account::get_associated_token_address;
 
 use crate::{
-    error::TornadoError,
-    instruction::TornadoInstruction,
-    merkle_tree::{insert_leaf, is_known_root},
-    state::{MerkleTree, TornadoInstance, ROOT_HISTORY_SIZE},
-    utils::{add_commitment, add_nullifier_hash, commitment_exists, create_account, nullifier_hash_exists, transfer_sol},
-    verifier::verify_tornado_proof,
+    error::{explain, TornadoError},
+    events::{DepositEvent, NullifierStatusEvent, RootEvent, StealthAnnouncementEvent, TornadoEvent, WithdrawEvent},
+    instruction::{
+        DepositReturnData, TornadoInstruction, WithdrawRequest, MAX_DEPOSIT_BATCH_SIZE, MAX_ENCRYPTED_NOTE_SIZE,
+        MAX_WITHDRAW_BATCH_SIZE,
+    },
+    merkle_tree::{
+        insert_leaf, invalidate_root, is_within_field, latest_root_with_index, remaining_capacity, root_age, root_age_at, HASH_ALGO_MIMC, HASH_ALGO_POSEIDON,
+        MAX_SUPPORTED_HEIGHT, ZERO_VALUE,
+    },
+    state::{
+        CommitmentMarker, ComplianceList, EncryptedNote, MerkleTree, MerkleTreeV1, NullifierMarker, Registry, RegistryEntry,
+        TornadoInstance, TornadoInstanceV1, VerifierState,
+        deserialize_account_data, MAX_FEE_BPS,
+    },
+    utils::{
+        assert_rent_exempt, close_account, close_token_account, compute_amount_commitment, contains_hash, create_account, create_associated_token_account, find_commitment_address, find_compliance_list_address, find_encrypted_note_address,
+        find_merkle_tree_address, find_nullifier_address, find_partial_verifier_address, find_registry_address, find_vault_authority_address, find_verifier_address, get_vault_address, grow_account,
+        nullifier_hash_exists, pay_withdrawal_from_pda, relayer_fee_quote_message, stealth_recipient, transfer_sol, transfer_sol_from_pda, transfer_tokens,
+        validate_token_account,
+    },
+    verifier::{
+        check_verifying_key, hash_verifying_key, verify_tornado_proof, WithdrawPublicInputs, CURVE_BLS12_381, CURVE_BN254,
+        NUM_PARTIAL_WITHDRAW_PUBLIC_INPUTS, NUM_PUBLIC_INPUTS, NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS,
+    },
 };
 
+/// Size of an ed25519 program instruction's header and single signature offsets
+const ED25519_DATA_START: usize = 16;
+
+/// The compute budget program, whose instructions may accompany a strict transaction withdrawal
+///
+/// Not exported by `solana_program`.
+const COMPUTE_BUDGET_PROGRAM_ID: Pubkey = pubkey!("ComputeBudget111111111111111111111111111111");
+
 /// Program processor
 pub struct Processor;
 
@@ -53,13 +91,51 @@ impl Processor {
             TornadoInstruction::Initialize {
                 denomination,
                 merkle_tree_height,
+                root_history_size,
+                max_root_age,
+                token_mint,
+                variable_amount,
+                max_fee_bps,
+                hash_algo,
+                curve,
+                strict_tx,
+                proof_system,
+                verifying_key,
             } => {
                 msg!("Instruction: Initialize");
-                Self::process_initialize(program_id, accounts, denomination, merkle_tree_height)
+                Self::process_initialize(
+                    program_id,
+                    accounts,
+                    denomination,
+                    merkle_tree_height,
+                    root_history_size,
+                    max_root_age,
+                    token_mint,
+                    variable_amount,
+                    max_fee_bps,
+                    hash_algo,
+                    curve,
+                    strict_tx,
+                    proof_system,
+                    verifying_key,
+                )
             }
-            TornadoInstruction::Deposit { commitment } => {
+            TornadoInstruction::Deposit {
+                commitment,
+                encrypted_note,
+                expected_index,
+                max_deposit_fee,
+            } => {
                 msg!("Instruction: Deposit");
-                Self::process_deposit(program_id, accounts, &commitment)
+                Self::process_deposit(
+                    program_id,
+                    accounts,
+                    &commitment,
+                    None,
+                    encrypted_note.as_deref(),
+                    expected_index,
+                    max_deposit_fee,
+                )
             }
             TornadoInstruction::Withdraw {
                 proof,
@@ -69,6 +145,8 @@ impl Processor {
                 relayer,
                 fee,
                 refund,
+                relayer_fee_signature,
+                root_index,
             } => {
                 msg!("Instruction: Withdraw");
                 Self::process_withdraw(
@@ -81,8 +159,216 @@ impl Processor {
                     &relayer,
                     fee,
                     refund,
+                    None,
+                    relayer_fee_signature.as_ref(),
+                    None,
+                    root_index,
+                )
+            }
+            TornadoInstruction::DepositToken { commitment } => {
+                msg!("Instruction: DepositToken");
+                Self::process_deposit_token(program_id, accounts, &commitment)
+            }
+            TornadoInstruction::WithdrawToken {
+                proof,
+                root,
+                nullifier_hash,
+                recipient,
+                relayer,
+                fee,
+                refund,
+                fee_in_sol,
+            } => {
+                msg!("Instruction: WithdrawToken");
+                Self::process_withdraw_token(
+                    program_id,
+                    accounts,
+                    &proof,
+                    &root,
+                    &nullifier_hash,
+                    &recipient,
+                    &relayer,
+                    fee,
+                    refund,
+                    fee_in_sol,
+                )
+            }
+            TornadoInstruction::Pause => {
+                msg!("Instruction: Pause");
+                Self::process_set_paused(program_id, accounts, true)
+            }
+            TornadoInstruction::Unpause => {
+                msg!("Instruction: Unpause");
+                Self::process_set_paused(program_id, accounts, false)
+            }
+            TornadoInstruction::DepositVariable { commitment, amount } => {
+                msg!("Instruction: DepositVariable");
+                Self::process_deposit(program_id, accounts, &commitment, Some(amount), None, None, None)
+            }
+            TornadoInstruction::WithdrawVariable {
+                proof,
+                root,
+                nullifier_hash,
+                recipient,
+                relayer,
+                fee,
+                refund,
+                amount,
+            } => {
+                msg!("Instruction: WithdrawVariable");
+                Self::process_withdraw(
+                    program_id,
+                    accounts,
+                    &proof,
+                    &root,
+                    &nullifier_hash,
+                    &recipient,
+                    &relayer,
+                    fee,
+                    refund,
+                    Some(amount),
+                    None,
+                    None,
+                    None,
+                )
+            }
+            TornadoInstruction::DepositBatch {
+                commitments,
+                max_deposit_fee,
+            } => {
+                msg!("Instruction: DepositBatch");
+                Self::process_deposit_batch(program_id, accounts, &commitments, max_deposit_fee)
+            }
+            TornadoInstruction::GetRoot => {
+                msg!("Instruction: GetRoot");
+                Self::process_get_root(program_id, accounts)
+            }
+            TornadoInstruction::Migrate => {
+                msg!("Instruction: Migrate");
+                Self::process_migrate(program_id, accounts)
+            }
+            TornadoInstruction::CloseInstance => {
+                msg!("Instruction: CloseInstance");
+                Self::process_close_instance(program_id, accounts)
+            }
+            TornadoInstruction::IsSpent { nullifier_hash } => {
+                msg!("Instruction: IsSpent");
+                Self::process_is_spent(program_id, accounts, &nullifier_hash)
+            }
+            TornadoInstruction::InvalidateRoot { root } => {
+                msg!("Instruction: InvalidateRoot");
+                Self::process_invalidate_root(program_id, accounts, &root)
+            }
+            TornadoInstruction::SimulateWithdraw {
+                proof,
+                root,
+                nullifier_hash,
+                recipient,
+                relayer,
+                fee,
+                refund,
+                amount,
+            } => {
+                msg!("Instruction: SimulateWithdraw");
+                Self::process_simulate_withdraw(
+                    program_id,
+                    accounts,
+                    &proof,
+                    &root,
+                    &nullifier_hash,
+                    &recipient,
+                    &relayer,
+                    fee,
+                    refund,
+                    amount,
+                )
+            }
+            TornadoInstruction::SetComplianceList { recipients } => {
+                msg!("Instruction: SetComplianceList");
+                Self::process_set_compliance_list(program_id, accounts, recipients.as_deref())
+            }
+            TornadoInstruction::SetPartialVerifier { verifying_key } => {
+                msg!("Instruction: SetPartialVerifier");
+                Self::process_set_partial_verifier(program_id, accounts, verifying_key)
+            }
+            TornadoInstruction::WithdrawPartial {
+                proof,
+                root,
+                nullifier_hash,
+                recipient,
+                relayer,
+                fee,
+                refund,
+                input_amount,
+                output_amount,
+                output_commitment,
+            } => {
+                msg!("Instruction: WithdrawPartial");
+                Self::process_withdraw_partial(
+                    program_id,
+                    accounts,
+                    &proof,
+                    &root,
+                    &nullifier_hash,
+                    &recipient,
+                    &relayer,
+                    fee,
+                    refund,
+                    input_amount,
+                    output_amount,
+                    &output_commitment,
+                )
+            }
+            TornadoInstruction::SelfCheck => {
+                msg!("Instruction: SelfCheck");
+                Self::process_self_check(program_id, accounts)
+            }
+            TornadoInstruction::WithdrawBatch { withdrawals } => {
+                msg!("Instruction: WithdrawBatch");
+                Self::process_withdraw_batch(program_id, accounts, &withdrawals)
+            }
+            TornadoInstruction::SetDepositFee { deposit_fee, treasury } => {
+                msg!("Instruction: SetDepositFee");
+                Self::process_set_deposit_fee(program_id, accounts, deposit_fee, &treasury)
+            }
+            TornadoInstruction::SetMinAnonymitySet { min_anonymity_set } => {
+                msg!("Instruction: SetMinAnonymitySet");
+                Self::process_set_min_anonymity_set(program_id, accounts, min_anonymity_set)
+            }
+            TornadoInstruction::SetWithdrawDelay { withdraw_delay_secs } => {
+                msg!("Instruction: SetWithdrawDelay");
+                Self::process_set_withdraw_delay(program_id, accounts, withdraw_delay_secs)
+            }
+            TornadoInstruction::WithdrawStealth {
+                proof,
+                root,
+                nullifier_hash,
+                recipient,
+                ephemeral_pubkey,
+                relayer,
+                fee,
+            } => {
+                msg!("Instruction: WithdrawStealth");
+                Self::process_withdraw(
+                    program_id,
+                    accounts,
+                    &proof,
+                    &root,
+                    &nullifier_hash,
+                    &recipient,
+                    &relayer,
+                    fee,
+                    0,
+                    None,
+                    None,
+                    Some(&ephemeral_pubkey),
+                    None,
                 )
             }
+            TornadoInstruction::SetMaxSolFee { max_sol_fee } => {
+                msg!("Instruction: SetMaxSolFee");
+                Self::process_set_max_sol_fee(program_id, accounts, max_sol_fee)
+            }
         }
     }
 
@@ -94,56 +380,225 @@ impl Processor {
     /// * `accounts` - The accounts required for the instruction
     /// * `denomination` - The denomination amount for this instance
     /// * `merkle_tree_height` - The height of the Merkle tree
+    /// * `root_history_size` - The number of recent roots accepted by withdrawals
+    /// * `max_root_age` - The oldest root a withdrawal may use (0 for the whole history)
+    /// * `token_mint` - The SPL token mint to pool (`None` for native SOL)
+    /// * `variable_amount` - Whether the instance accepts arbitrary amounts up to `denomination`
+    /// * `max_fee_bps` - The largest relayer fee, in basis points of the amount withdrawn
+    /// * `hash_algo` - The hash algorithm of the Merkle tree
+    /// * `curve` - The curve of the withdrawal proofs
+    /// * `strict_tx` - Whether each withdrawal must be alone in its transaction
+    /// * `proof_system` - The proof system of the withdrawal proofs
+    /// * `verifying_key` - The compressed verifying key from the trusted setup
     ///
     /// # Returns
     ///
     /// Returns a `ProgramResult` indicating success or failure
+    #[allow(clippy::too_many_arguments)]
     fn process_initialize(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         denomination: u64,
         merkle_tree_height: u8,
+        root_history_size: u8,
+        max_root_age: u8,
+        token_mint: Option<Pubkey>,
+        variable_amount: bool,
+        max_fee_bps: u16,
+        hash_algo: u8,
+        curve: u8,
+        strict_tx: bool,
+        proof_system: u8,
+        verifying_key: Vec<u8>,
     ) -> ProgramResult {
         // Get the account information
         let account_info_iter = &mut accounts.iter();
         let payer = next_account_info(account_info_iter)?;
         let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let verifier_info = next_account_info(account_info_iter)?;
+        let registry_info = next_account_info(account_info_iter)?;
         let system_program_info = next_account_info(account_info_iter)?;
 
-        // Check if the tornado instance account is already initialized
-        if !tornado_instance_info.data.borrow().iter().all(|&x| x == 0) {
+        // Check if the tornado instance account is already initialized. A fresh
+        // account reads as version 0 with `is_initialized` unset, however many
+        // lamports it holds.
+        let existing_instance = TornadoInstance::unpack_unchecked(&tornado_instance_info.data.borrow())?;
+        if existing_instance.is_initialized() {
             return Err(TornadoError::AccountAlreadyInitialized.into());
         }
 
-        // Create a new Merkle tree account
-        let merkle_tree_seed = &[
-            b"merkle_tree",
-            tornado_instance_info.key.as_ref(),
-            &[0],
-        ];
-        let (merkle_tree_key, _) =
-            Pubkey::find_program_address(merkle_tree_seed, program_id);
+        // Check the Merkle tree account is the expected PDA
+        let (merkle_tree_key, merkle_tree_bump) =
+            find_merkle_tree_address(program_id, tornado_instance_info.key);
+        if *merkle_tree_info.key != merkle_tree_key {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
 
-        // Create a new verifier account
-        let verifier_seed = &[
-            b"verifier",
-            tornado_instance_info.key.as_ref(),
-            &[0],
-        ];
-        let (verifier_key, _) =
-            Pubkey::find_program_address(verifier_seed, program_id);
+        // Check the verifier account is the expected PDA
+        let (verifier_key, verifier_bump) =
+            find_verifier_address(program_id, tornado_instance_info.key);
+        if *verifier_info.key != verifier_key {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // Each deposit must transfer something
+        if denomination == 0 {
+            msg!("Denomination must be at least 1");
+            return Err(TornadoError::InvalidDenomination.into());
+        }
+
+        // Check the Merkle tree has at least one level and no more than the zero values cover,
+        // rather than starting from a root that isn't in the tables
+        if merkle_tree_height == 0 || merkle_tree_height > MAX_SUPPORTED_HEIGHT {
+            msg!("Merkle tree height must be between 1 and {}", MAX_SUPPORTED_HEIGHT);
+            return Err(TornadoError::InvalidMerkleTreeHeight.into());
+        }
+
+        // Check the root history holds at least the current root
+        if root_history_size == 0 {
+            msg!("Root history size must be at least 1");
+            return Err(TornadoError::InvalidInstructionData.into());
+        }
+
+        // A root age cap must leave out part of the history
+        if max_root_age >= root_history_size {
+            msg!("Maximum root age must be below the root history size {}", root_history_size);
+            return Err(TornadoError::InvalidInstructionData.into());
+        }
+
+        // Variable amounts are only supported for native SOL
+        if variable_amount && token_mint.is_some() {
+            msg!("Variable-amount instances cannot pool tokens");
+            return Err(TornadoError::InvalidInstructionData.into());
+        }
+
+        // Only SOL withdrawals check their transaction
+        if strict_tx && token_mint.is_some() {
+            msg!("Strict transaction instances cannot pool tokens");
+            return Err(TornadoError::InvalidInstructionData.into());
+        }
+
+        // A relayer can't take more than the whole withdrawal
+        if max_fee_bps > MAX_FEE_BPS {
+            msg!("Maximum fee must be at most {} basis points", MAX_FEE_BPS);
+            return Err(TornadoError::InvalidFee.into());
+        }
+
+        // Check the Merkle tree hash is one the program implements
+        if hash_algo != HASH_ALGO_MIMC && hash_algo != HASH_ALGO_POSEIDON {
+            msg!("Unknown hash algorithm {}", hash_algo);
+            return Err(TornadoError::UnsupportedHashAlgorithm.into());
+        }
+
+        // Check the proof curve is one the program implements
+        if curve != CURVE_BN254 && curve != CURVE_BLS12_381 {
+            msg!("Unknown curve {}", curve);
+            return Err(TornadoError::UnsupportedCurve.into());
+        }
+
+        // Check the verifying key before storing it
+        let num_public_inputs = if variable_amount {
+            NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS
+        } else {
+            NUM_PUBLIC_INPUTS
+        };
+        check_verifying_key(proof_system, curve, &verifying_key, num_public_inputs)?;
+
+        // The instance account is created by the client, so check it belongs to
+        // this program and can hold the instance for good
+        if tornado_instance_info.owner != program_id {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+        assert_rent_exempt(tornado_instance_info, TornadoInstance::LEN)?;
+
+        // Build the empty Merkle tree
+        let merkle_tree = MerkleTree::new(merkle_tree_height, root_history_size, hash_algo)?;
+
+        // Create the Merkle tree account
+        create_account(
+            payer,
+            merkle_tree_info,
+            system_program_info,
+            MerkleTree::get_account_size_bounded(merkle_tree_height, root_history_size),
+            program_id,
+            Some(&[
+                b"merkle_tree",
+                tornado_instance_info.key.as_ref(),
+                &[0],
+                &[merkle_tree_bump],
+            ]),
+        )?;
+        merkle_tree.to_account_data(&mut merkle_tree_info.data.borrow_mut())?;
+
+        // Create the verifier account and store the verifying key
+        let verifier_state = VerifierState {
+            is_initialized: true,
+            verifying_key,
+        };
+        create_account(
+            payer,
+            verifier_info,
+            system_program_info,
+            VerifierState::get_account_size(verifier_state.verifying_key.len()),
+            program_id,
+            Some(&[
+                b"verifier",
+                tornado_instance_info.key.as_ref(),
+                &[0],
+                &[verifier_bump],
+            ]),
+        )?;
+        verifier_state.serialize(&mut &mut verifier_info.data.borrow_mut()[..])?;
 
         // Initialize the tornado instance
         let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
             is_initialized: true,
             denomination,
             merkle_tree_height,
             merkle_tree: merkle_tree_key,
             verifier: verifier_key,
+            token_mint,
+            admin: *payer.key,
+            paused: false,
+            variable_amount,
+            max_fee_bps,
+            curve,
+            max_root_age,
+            strict_tx,
+            // Instances start permissionless; the admin can set a list later
+            compliance_list: None,
+            proof_system,
+            vault_authority_bump: find_vault_authority_address(program_id, tornado_instance_info.key).1,
+            vk_hash: hash_verifying_key(&verifier_state.verifying_key),
+            // Deposits are free until the admin sets a fee
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump,
+            // Token withdrawals pay no fees in SOL until the admin sets a cap
+            max_sol_fee: 0,
         };
 
+        // List the instance in the registry, with the admin and key clients vet it by
+        Self::register_instance(
+            program_id,
+            payer,
+            registry_info,
+            system_program_info,
+            RegistryEntry {
+                denomination,
+                instance: *tornado_instance_info.key,
+                token_mint,
+                admin: tornado_instance.admin,
+                vk_hash: tornado_instance.vk_hash,
+            },
+        )?;
+
         // Save the tornado instance
-        tornado_instance.serialize(&mut *tornado_instance_info.data.borrow_mut())?;
+        tornado_instance.serialize(&mut &mut tornado_instance_info.data.borrow_mut()[..])?;
 
         msg!("Tornado instance initialized with denomination {} and height {}", denomination, merkle_tree_height);
         Ok(())
@@ -151,11 +606,18 @@ impl Processor {
 
     /// Process a Deposit instruction
     ///
+    /// The commitment is checked and inserted before the deposit is
+    /// transferred, so a deposit that can't be recorded takes no funds.
+    ///
     /// # Arguments
     ///
     /// * `program_id` - The program ID
     /// * `accounts` - The accounts required for the instruction
     /// * `commitment` - The commitment to deposit
+    /// * `amount` - The amount of a `DepositVariable` (`None` for `Deposit`)
+    /// * `encrypted_note` - The encrypted note to store with the deposit, if any
+    /// * `expected_index` - The leaf index the commitment must take, if any
+    /// * `max_deposit_fee` - The largest deposit fee the depositor agreed to pay, if any
     ///
     /// # Returns
     ///
@@ -164,347 +626,6081 @@ impl Processor {
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         commitment: &[u8; 32],
+        amount: Option<u64>,
+        encrypted_note: Option<&[u8]>,
+        expected_index: Option<u32>,
+        max_deposit_fee: Option<u64>,
     ) -> ProgramResult {
         // Get the account information
         let account_info_iter = &mut accounts.iter();
         let payer = next_account_info(account_info_iter)?;
         let tornado_instance_info = next_account_info(account_info_iter)?;
         let merkle_tree_info = next_account_info(account_info_iter)?;
+        let commitment_marker_info = next_account_info(account_info_iter)?;
         let system_program_info = next_account_info(account_info_iter)?;
+        let funding_account = next_account_info(account_info_iter)?;
+        let encrypted_note = match encrypted_note {
+            Some(ciphertext) => Some((next_account_info(account_info_iter)?, ciphertext)),
+            None => None,
+        };
 
         // Check if the tornado instance is initialized
-        let tornado_instance = TornadoInstance::unpack(&tornado_instance_info.data.borrow())?;
-        if !tornado_instance.is_initialized {
-            return Err(TornadoError::AccountNotInitialized.into());
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Deposits and withdrawals are halted while the instance is paused
+        if tornado_instance.paused {
+            return Err(TornadoError::InstancePaused.into());
+        }
+
+        // Token instances only accept DepositToken
+        if tornado_instance.token_mint.is_some() {
+            return Err(TornadoError::InvalidTokenMint.into());
         }
 
         // Check if the merkle tree account is the correct one
-        if tornado_instance.merkle_tree != *merkle_tree_info.key {
-            return Err(TornadoError::InvalidAccountData.into());
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
+
+        // The treasury is only passed when the instance charges a deposit fee
+        Self::check_deposit_fee(&tornado_instance, max_deposit_fee)?;
+        let treasury_info = Self::next_treasury(account_info_iter, &tornado_instance)?;
+
+        // A sponsored deposit is funded by a separate account, both signing
+        if !payer.is_signer || !funding_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
         }
+        let mut written = vec![payer, funding_account, tornado_instance_info, merkle_tree_info, commitment_marker_info];
+        written.extend(encrypted_note.map(|(encrypted_note_info, _)| encrypted_note_info));
+        written.extend(treasury_info);
+        Self::check_writable(&written)?;
+
+        // A variable-amount deposit inserts the commitment bound to the amount received
+        let amount = Self::check_amount(&tornado_instance, amount)?;
+        let commitment = &if tornado_instance.variable_amount {
+            compute_amount_commitment(commitment, amount)?
+        } else {
+            *commitment
+        };
 
         // Check if the commitment already exists
-        let mut merkle_tree = MerkleTree::try_from_slice(&merkle_tree_info.data.borrow())?;
-        if commitment_exists(&merkle_tree.commitments, commitment) {
-            return Err(TornadoError::CommitmentAlreadyExists.into());
+        let marker_bump = Self::check_commitment_marker(
+            program_id,
+            tornado_instance_info.key,
+            commitment_marker_info,
+            commitment,
+        )?;
+        let encrypted_note = match encrypted_note {
+            Some((encrypted_note_info, ciphertext)) => {
+                let note_bump = Self::check_encrypted_note(
+                    program_id,
+                    tornado_instance_info.key,
+                    encrypted_note_info,
+                    commitment,
+                    ciphertext,
+                )?;
+                Some((encrypted_note_info, ciphertext, note_bump))
+            }
+            None => None,
+        };
+        let mut merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+
+        // A depositor that computed its path for a given leaf only deposits there
+        if let Some(expected_index) = expected_index {
+            if merkle_tree.next_index != expected_index {
+                msg!("Expected leaf index {}, next index is {}", expected_index, merkle_tree.next_index);
+                return Err(TornadoError::UnexpectedLeafIndex.into());
+            }
         }
 
-        // Transfer the denomination amount from the payer to the tornado instance
+        // Check that the tree has room and insert the commitment into a copy of it
+        Self::check_capacity(&merkle_tree, 1)?;
+        let timestamp = Clock::get()?.unix_timestamp;
+        let inserted_index = Self::insert_commitment(&mut merkle_tree, commitment, timestamp)?;
+
+        // Transfer the deposit amount from the funding account to the tornado
+        // instance. The funding account is system-owned, so this goes through the
+        // System Program; withdrawals move the lamports back out directly.
         transfer_sol(
-            payer,
+            funding_account,
             tornado_instance_info,
             system_program_info,
-            tornado_instance.denomination,
+            amount,
             None,
         )?;
+        if let Some(treasury_info) = treasury_info {
+            transfer_sol(
+                funding_account,
+                treasury_info,
+                system_program_info,
+                tornado_instance.deposit_fee,
+                None,
+            )?;
+        }
 
-        // Insert the commitment into the Merkle tree
-        let inserted_index = insert_leaf(
+        // Mark the commitment as deposited
+        Self::create_commitment_marker(
+            program_id,
+            payer,
+            tornado_instance_info.key,
+            commitment_marker_info,
+            system_program_info,
             commitment,
-            merkle_tree.current_index,
-            merkle_tree.next_index,
-            merkle_tree.height,
-            &mut merkle_tree.filled_subtrees,
-            &mut merkle_tree.roots,
-            &mut merkle_tree.current_root_index,
+            marker_bump,
+            inserted_index,
         )?;
 
-        // Update the Merkle tree state
-        merkle_tree.next_index += 1;
-
-        // Add the commitment to the commitments array
-        add_commitment(&mut merkle_tree.commitments, commitment)?;
+        // Store the encrypted note for recovery
+        if let Some((encrypted_note_info, ciphertext, note_bump)) = encrypted_note {
+            Self::create_encrypted_note(
+                program_id,
+                payer,
+                tornado_instance_info.key,
+                encrypted_note_info,
+                system_program_info,
+                commitment,
+                ciphertext,
+                note_bump,
+            )?;
+        }
 
         // Save the updated Merkle tree
-        merkle_tree.serialize(&mut *merkle_tree_info.data.borrow_mut())?;
+        merkle_tree.to_account_data(&mut merkle_tree_info.data.borrow_mut())?;
+
+        msg!(
+            "Deposit successful. Leaf index: {}, remaining capacity: {}, deposits: {}",
+            inserted_index,
+            remaining_capacity(&merkle_tree),
+            merkle_tree.next_index
+        );
+
+        TornadoEvent::Deposit(DepositEvent {
+            commitment: *commitment,
+            leaf_index: inserted_index,
+            timestamp,
+        })
+        .emit()?;
 
-        msg!("Deposit successful. Leaf index: {}", inserted_index);
+        // Hand the new leaf and root to a program depositing through CPI
+        let (root, _) = latest_root_with_index(&merkle_tree);
+        set_return_data(
+            &DepositReturnData {
+                leaf_index: inserted_index,
+                root,
+            }
+            .try_to_vec()?,
+        );
 
         Ok(())
     }
 
-    /// Process a Withdraw instruction
+    /// Process a DepositBatch instruction
+    ///
+    /// Every commitment is checked before anything is transferred or written,
+    /// so a single duplicate rejects the whole batch.
     ///
     /// # Arguments
     ///
     /// * `program_id` - The program ID
     /// * `accounts` - The accounts required for the instruction
-    /// * `proof` - The zkSNARK proof
-    /// * `root` - The Merkle root
-    /// * `nullifier_hash` - The nullifier hash
-    /// * `recipient_pubkey` - The recipient public key
-    /// * `relayer_pubkey` - The relayer public key
-    /// * `fee` - The fee to pay to the relayer
-    /// * `refund` - The refund amount (for token instances)
+    /// * `commitments` - The commitments to deposit
+    /// * `max_deposit_fee` - The largest deposit fee per commitment the depositor agreed to pay, if any
     ///
     /// # Returns
     ///
     /// Returns a `ProgramResult` indicating success or failure
-    fn process_withdraw(
+    fn process_deposit_batch(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        proof: &[u8],
-        root: &[u8; 32],
-        nullifier_hash: &[u8; 32],
-        recipient_pubkey: &Pubkey,
-        relayer_pubkey: &Pubkey,
-        fee: u64,
-        refund: u64,
+        commitments: &[[u8; 32]],
+        max_deposit_fee: Option<u64>,
     ) -> ProgramResult {
+        if commitments.is_empty() || commitments.len() > MAX_DEPOSIT_BATCH_SIZE {
+            return Err(TornadoError::InvalidInstructionData.into());
+        }
+
         // Get the account information
         let account_info_iter = &mut accounts.iter();
         let payer = next_account_info(account_info_iter)?;
         let tornado_instance_info = next_account_info(account_info_iter)?;
         let merkle_tree_info = next_account_info(account_info_iter)?;
-        let recipient_info = next_account_info(account_info_iter)?;
-        let relayer_info = next_account_info(account_info_iter)?;
         let system_program_info = next_account_info(account_info_iter)?;
+        let commitment_marker_infos = commitments
+            .iter()
+            .map(|_| next_account_info(account_info_iter))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        // Check if the tornado instance is initialized
-        let tornado_instance = TornadoInstance::unpack(&tornado_instance_info.data.borrow())?;
-        if !tornado_instance.is_initialized {
-            return Err(TornadoError::AccountNotInitialized.into());
+        // The payer funds the deposits and markers, and every account written to must be writable
+        if !payer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
         }
+        Self::check_writable(&[payer, tornado_instance_info, merkle_tree_info])?;
+        Self::check_writable(&commitment_marker_infos)?;
 
-        // Check if the merkle tree account is the correct one
-        if tornado_instance.merkle_tree != *merkle_tree_info.key {
-            return Err(TornadoError::InvalidAccountData.into());
-        }
+        // Check if the tornado instance is initialized
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
 
-        // Check if the recipient account is the correct one
-        if recipient_pubkey != recipient_info.key {
-            return Err(TornadoError::InvalidRecipient.into());
+        // Deposits and withdrawals are halted while the instance is paused
+        if tornado_instance.paused {
+            return Err(TornadoError::InstancePaused.into());
         }
 
-        // Check if the relayer account is the correct one
-        if relayer_pubkey != relayer_info.key {
-            return Err(TornadoError::InvalidRelayer.into());
+        // Token instances only accept DepositToken
+        if tornado_instance.token_mint.is_some() {
+            return Err(TornadoError::InvalidTokenMint.into());
         }
 
-        // Check if the fee is valid
-        if fee > tornado_instance.denomination {
-            return Err(TornadoError::InvalidFee.into());
-        }
+        // Check if the merkle tree account is the correct one
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
 
-        // Check if the refund is valid (should be 0 for SOL)
-        if refund != 0 {
-            return Err(TornadoError::InvalidAmount.into());
+        // The treasury follows the markers when the instance charges a deposit fee
+        Self::check_deposit_fee(&tornado_instance, max_deposit_fee)?;
+        let treasury_info = Self::next_treasury(account_info_iter, &tornado_instance)?;
+        if let Some(treasury_info) = treasury_info {
+            Self::check_writable(&[treasury_info])?;
         }
 
+        // Batches are fixed-denomination only
+        let denomination = Self::check_amount(&tornado_instance, None)?;
+        let total = denomination
+            .checked_mul(commitments.len() as u64)
+            .ok_or(TornadoError::InvalidAmount)?;
+        let total_fee = tornado_instance
+            .deposit_fee
+            .checked_mul(commitments.len() as u64)
+            .ok_or(TornadoError::InvalidAmount)?;
+
+        // Check every commitment, including repeats within the batch
+        let mut marker_bumps = Vec::with_capacity(commitments.len());
+        for (i, (commitment, commitment_marker_info)) in
+            commitments.iter().zip(&commitment_marker_infos).enumerate()
+        {
+            if contains_hash(&commitments[..i], commitment) {
+                return Err(TornadoError::CommitmentAlreadyExists.into());
+            }
+            marker_bumps.push(Self::check_commitment_marker(
+                program_id,
+                tornado_instance_info.key,
+                commitment_marker_info,
+                commitment,
+            )?);
+        }
+
+        // Insert the commitments into a copy of the Merkle tree
+        let mut merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+        Self::check_capacity(&merkle_tree, commitments.len())?;
+        let timestamp = Clock::get()?.unix_timestamp;
+        let inserted_indices = commitments
+            .iter()
+            .map(|commitment| Self::insert_commitment(&mut merkle_tree, commitment, timestamp))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Transfer the deposit amount and fee for the whole batch
+        transfer_sol(
+            payer,
+            tornado_instance_info,
+            system_program_info,
+            total,
+            None,
+        )?;
+        if let Some(treasury_info) = treasury_info {
+            transfer_sol(
+                payer,
+                treasury_info,
+                system_program_info,
+                total_fee,
+                None,
+            )?;
+        }
+
+        // Mark the commitments as deposited
+        for (((commitment, commitment_marker_info), marker_bump), inserted_index) in commitments
+            .iter()
+            .zip(&commitment_marker_infos)
+            .zip(&marker_bumps)
+            .zip(&inserted_indices)
+        {
+            Self::create_commitment_marker(
+                program_id,
+                payer,
+                tornado_instance_info.key,
+                commitment_marker_info,
+                system_program_info,
+                commitment,
+                *marker_bump,
+                *inserted_index,
+            )?;
+        }
+
+        // Save the updated Merkle tree
+        merkle_tree.to_account_data(&mut merkle_tree_info.data.borrow_mut())?;
+
+        msg!(
+            "Batch deposit successful. Leaf indices: {}..={}, remaining capacity: {}, deposits: {}",
+            inserted_indices[0],
+            inserted_indices[inserted_indices.len() - 1],
+            remaining_capacity(&merkle_tree),
+            merkle_tree.next_index
+        );
+
+        for (commitment, inserted_index) in commitments.iter().zip(inserted_indices) {
+            TornadoEvent::Deposit(DepositEvent {
+                commitment: *commitment,
+                leaf_index: inserted_index,
+                timestamp,
+            })
+            .emit()?;
+        }
+
+        Ok(())
+    }
+
+    /// Process a Withdraw instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `proof` - The zkSNARK proof
+    /// * `root` - The Merkle root
+    /// * `nullifier_hash` - The nullifier hash
+    /// * `recipient_pubkey` - The recipient public key
+    /// * `relayer_pubkey` - The relayer public key
+    /// * `fee` - The fee to pay to the relayer
+    /// * `refund` - The refund amount (for token instances)
+    /// * `amount` - The proven amount of a `WithdrawVariable` (`None` for `Withdraw`)
+    /// * `ephemeral_pubkey` - The ephemeral pubkey of a `WithdrawStealth` (`None` otherwise)
+    /// * `root_index` - The index of `root` in the root history, if the withdrawer knows it
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    #[allow(clippy::too_many_arguments)]
+    fn process_withdraw(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        proof: &[u8],
+        root: &[u8; 32],
+        nullifier_hash: &[u8; 32],
+        recipient_pubkey: &Pubkey,
+        relayer_pubkey: &Pubkey,
+        fee: u64,
+        refund: u64,
+        amount: Option<u64>,
+        relayer_fee_signature: Option<&[u8; 64]>,
+        ephemeral_pubkey: Option<&[u8; 32]>,
+        root_index: Option<u8>,
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let payer = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let verifier_info = next_account_info(account_info_iter)?;
+        let nullifier_marker_info = next_account_info(account_info_iter)?;
+        let recipient_info = next_account_info(account_info_iter)?;
+        let relayer_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        // Only needed for a fee quote or on a strict transaction instance
+        let instructions_sysvar_info = account_info_iter.next();
+        // Only needed on an instance with a compliance list
+        let compliance_list_info = account_info_iter.next();
+
+        // The payer funds the nullifier marker, and every account written to must be writable
+        if !payer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_writable(&[
+            payer,
+            tornado_instance_info,
+            merkle_tree_info,
+            nullifier_marker_info,
+            recipient_info,
+            relayer_info,
+        ])?;
+
+        // Check if the recipient account is the correct one
+        if recipient_pubkey != recipient_info.key {
+            return Err(TornadoError::InvalidRecipient.into());
+        }
+
+        // Check if the relayer account is the correct one
+        if relayer_pubkey != relayer_info.key {
+            return Err(TornadoError::InvalidRelayer.into());
+        }
+
+        // A quoted fee must match the quote the relayer signed
+        if let Some(signature) = relayer_fee_signature {
+            let instructions_sysvar_info = instructions_sysvar_info.ok_or(ProgramError::NotEnoughAccountKeys)?;
+            let message = relayer_fee_quote_message(nullifier_hash, fee, recipient_pubkey);
+            Self::check_relayer_fee_quote(instructions_sysvar_info, relayer_pubkey, &message, signature)?;
+        }
+
+        let (tornado_instance, mut merkle_tree, amount, marker_bump) = Self::check_withdraw(
+            program_id,
+            payer,
+            tornado_instance_info,
+            merkle_tree_info,
+            verifier_info,
+            nullifier_marker_info,
+            compliance_list_info,
+            proof,
+            root,
+            nullifier_hash,
+            recipient_pubkey,
+            relayer_pubkey,
+            fee,
+            refund,
+            amount,
+            None,
+            ephemeral_pubkey,
+            root_index,
+        )?;
+
+        // A strict transaction instance doesn't let anything else run around the withdrawal
+        if tornado_instance.strict_tx {
+            let instructions_sysvar_info = instructions_sysvar_info.ok_or(ProgramError::NotEnoughAccountKeys)?;
+            Self::check_sole_instruction(program_id, instructions_sysvar_info)?;
+        }
+
+        // Mark the nullifier hash as spent before any value is transferred, so a
+        // failed transfer aborts the whole withdrawal rather than leaving it half done
+        Self::create_nullifier_marker(
+            program_id,
+            payer,
+            tornado_instance_info.key,
+            nullifier_marker_info,
+            system_program_info,
+            nullifier_hash,
+            marker_bump,
+        )?;
+        Self::count_withdrawal(merkle_tree_info, &mut merkle_tree)?;
+
+        // The instance is owned by this program, so its lamports are moved
+        // directly, after the nullifier marker's cross-program invocation.
+        // The amount minus the fee goes to the recipient, and the fee to the
+        // relayer, both or neither.
+        pay_withdrawal_from_pda(
+            tornado_instance_info,
+            recipient_info,
+            Self::recipient_amount(amount, fee)?,
+            relayer_info,
+            fee,
+        )?;
+
+        msg!("Withdrawal successful. Withdrawals: {}", merkle_tree.nullifier_count);
+
+        TornadoEvent::Withdraw(WithdrawEvent {
+            nullifier_hash: *nullifier_hash,
+            recipient: *recipient_pubkey,
+            relayer: *relayer_pubkey,
+            fee,
+        })
+        .emit()?;
+
+        // The recipient scans the announcements for the addresses it can spend from
+        if let Some(ephemeral_pubkey) = ephemeral_pubkey {
+            TornadoEvent::StealthAnnouncement(StealthAnnouncementEvent {
+                recipient: *recipient_pubkey,
+                ephemeral_pubkey: *ephemeral_pubkey,
+            })
+            .emit()?;
+        }
+
+        Ok(())
+    }
+
+    /// Process a WithdrawBatch instruction
+    ///
+    /// Every withdrawal is checked before any nullifier is spent or anything is
+    /// transferred, so a single invalid withdrawal rejects the whole batch. Its
+    /// index is logged and set as the return data.
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `withdrawals` - The withdrawals to settle
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_withdraw_batch(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        withdrawals: &[WithdrawRequest],
+    ) -> ProgramResult {
+        if withdrawals.is_empty() || withdrawals.len() > MAX_WITHDRAW_BATCH_SIZE {
+            return Err(TornadoError::InvalidInstructionData.into());
+        }
+
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let payer = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let verifier_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let instructions_sysvar_info = next_account_info(account_info_iter)?;
+        let compliance_list_info = next_account_info(account_info_iter)?;
+        let withdrawal_infos = withdrawals
+            .iter()
+            .map(|_| {
+                Ok((
+                    next_account_info(account_info_iter)?,
+                    next_account_info(account_info_iter)?,
+                    next_account_info(account_info_iter)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, ProgramError>>()?;
+
+        // The payer funds the nullifier markers, and every account written to must be writable
+        if !payer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_writable(&[payer, tornado_instance_info, merkle_tree_info])?;
+        for (nullifier_marker_info, recipient_info, relayer_info) in &withdrawal_infos {
+            Self::check_writable(&[nullifier_marker_info, recipient_info, relayer_info])?;
+        }
+
+        // Check every withdrawal, including nullifier hashes repeated within the batch
+        let nullifier_hashes: Vec<[u8; 32]> = withdrawals.iter().map(|withdrawal| withdrawal.nullifier_hash).collect();
+        let mut checked = Vec::with_capacity(withdrawals.len());
+        for (index, (withdrawal, (nullifier_marker_info, recipient_info, relayer_info))) in
+            withdrawals.iter().zip(&withdrawal_infos).enumerate()
+        {
+            let check = || -> Result<(u64, u8), ProgramError> {
+                if withdrawal.recipient != *recipient_info.key {
+                    return Err(TornadoError::InvalidRecipient.into());
+                }
+                if withdrawal.relayer != *relayer_info.key {
+                    return Err(TornadoError::InvalidRelayer.into());
+                }
+                if contains_hash(&nullifier_hashes[..index], &withdrawal.nullifier_hash) {
+                    return Err(TornadoError::NullifierAlreadySpent.into());
+                }
+
+                let (_, _, amount, marker_bump) = Self::check_withdraw(
+                    program_id,
+                    payer,
+                    tornado_instance_info,
+                    merkle_tree_info,
+                    verifier_info,
+                    nullifier_marker_info,
+                    Some(compliance_list_info),
+                    &withdrawal.proof,
+                    &withdrawal.root,
+                    &withdrawal.nullifier_hash,
+                    &withdrawal.recipient,
+                    &withdrawal.relayer,
+                    withdrawal.fee,
+                    0,
+                    None,
+                    None,
+                    None,
+                    None,
+                )?;
+                Ok((amount, marker_bump))
+            };
+            match check() {
+                Ok(checked_withdrawal) => checked.push(checked_withdrawal),
+                Err(error) => {
+                    msg!("Withdrawal {} of the batch is invalid", index);
+                    set_return_data(&(index as u32).to_le_bytes());
+                    return Err(error);
+                }
+            }
+        }
+
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+        let mut merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+
+        // A strict transaction instance doesn't let anything else run around the withdrawals
+        if tornado_instance.strict_tx {
+            Self::check_sole_instruction(program_id, instructions_sysvar_info)?;
+        }
+
+        // Mark every nullifier hash as spent before any value is transferred, so
+        // the markers' cross-program invocations all come before the instance's
+        // lamports are moved directly
+        for ((withdrawal, (nullifier_marker_info, _, _)), (_, marker_bump)) in
+            withdrawals.iter().zip(&withdrawal_infos).zip(&checked)
+        {
+            Self::create_nullifier_marker(
+                program_id,
+                payer,
+                tornado_instance_info.key,
+                nullifier_marker_info,
+                system_program_info,
+                &withdrawal.nullifier_hash,
+                *marker_bump,
+            )?;
+            Self::count_withdrawal(merkle_tree_info, &mut merkle_tree)?;
+        }
+
+        // Pay each recipient and relayer as a `Withdraw` would
+        for ((withdrawal, (_, recipient_info, relayer_info)), (amount, _)) in
+            withdrawals.iter().zip(&withdrawal_infos).zip(checked)
+        {
+            pay_withdrawal_from_pda(
+                tornado_instance_info,
+                recipient_info,
+                Self::recipient_amount(amount, withdrawal.fee)?,
+                relayer_info,
+                withdrawal.fee,
+            )?;
+        }
+
+        msg!(
+            "Batch withdrawal successful. Withdrawn: {}, withdrawals: {}",
+            withdrawals.len(),
+            merkle_tree.nullifier_count
+        );
+
+        for withdrawal in withdrawals {
+            TornadoEvent::Withdraw(WithdrawEvent {
+                nullifier_hash: withdrawal.nullifier_hash,
+                recipient: withdrawal.recipient,
+                relayer: withdrawal.relayer,
+                fee: withdrawal.fee,
+            })
+            .emit()?;
+        }
+
+        Ok(())
+    }
+
+    /// Process a WithdrawPartial instruction
+    ///
+    /// Spends the input note and inserts the remainder's leaf into the Merkle
+    /// tree in one instruction. Every check runs before anything is written,
+    /// and any failure afterwards aborts the transaction, so the nullifier is
+    /// never spent without the remainder being re-deposited.
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `proof` - The zkSNARK proof
+    /// * `root` - The Merkle root
+    /// * `nullifier_hash` - The nullifier hash of the input note
+    /// * `recipient_pubkey` - The recipient public key
+    /// * `relayer_pubkey` - The relayer public key
+    /// * `fee` - The fee to pay to the relayer
+    /// * `refund` - The refund amount (must be 0)
+    /// * `input_amount` - The amount committed in the input note's deposit
+    /// * `output_amount` - The remainder re-deposited
+    /// * `output_commitment` - The commitment of the note holding the remainder
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    #[allow(clippy::too_many_arguments)]
+    fn process_withdraw_partial(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        proof: &[u8],
+        root: &[u8; 32],
+        nullifier_hash: &[u8; 32],
+        recipient_pubkey: &Pubkey,
+        relayer_pubkey: &Pubkey,
+        fee: u64,
+        refund: u64,
+        input_amount: u64,
+        output_amount: u64,
+        output_commitment: &[u8; 32],
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let payer = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let verifier_info = next_account_info(account_info_iter)?;
+        let nullifier_marker_info = next_account_info(account_info_iter)?;
+        let commitment_marker_info = next_account_info(account_info_iter)?;
+        let recipient_info = next_account_info(account_info_iter)?;
+        let relayer_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        // Only needed on a strict transaction instance
+        let instructions_sysvar_info = account_info_iter.next();
+        // Only needed on an instance with a compliance list
+        let compliance_list_info = account_info_iter.next();
+
+        // The payer funds both markers, and every account written to must be writable
+        if !payer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_writable(&[
+            payer,
+            tornado_instance_info,
+            merkle_tree_info,
+            nullifier_marker_info,
+            commitment_marker_info,
+            recipient_info,
+            relayer_info,
+        ])?;
+
+        // Check if the recipient account is the correct one
+        if recipient_pubkey != recipient_info.key {
+            return Err(TornadoError::InvalidRecipient.into());
+        }
+
+        // Check if the relayer account is the correct one
+        if relayer_pubkey != relayer_info.key {
+            return Err(TornadoError::InvalidRelayer.into());
+        }
+
+        let (tornado_instance, mut merkle_tree, amount, nullifier_bump) = Self::check_withdraw(
+            program_id,
+            payer,
+            tornado_instance_info,
+            merkle_tree_info,
+            verifier_info,
+            nullifier_marker_info,
+            compliance_list_info,
+            proof,
+            root,
+            nullifier_hash,
+            recipient_pubkey,
+            relayer_pubkey,
+            fee,
+            refund,
+            Some(input_amount),
+            Some((output_amount, output_commitment)),
+            None,
+            None,
+        )?;
+
+        // A strict transaction instance doesn't let anything else run around the withdrawal
+        if tornado_instance.strict_tx {
+            let instructions_sysvar_info = instructions_sysvar_info.ok_or(ProgramError::NotEnoughAccountKeys)?;
+            Self::check_sole_instruction(program_id, instructions_sysvar_info)?;
+        }
+
+        // The remainder is re-deposited as a variable-amount leaf, as by DepositVariable
+        let output_leaf = compute_amount_commitment(output_commitment, output_amount)?;
+        let commitment_bump = Self::check_commitment_marker(
+            program_id,
+            tornado_instance_info.key,
+            commitment_marker_info,
+            &output_leaf,
+        )?;
+        Self::check_capacity(&merkle_tree, 1)?;
+        let timestamp = Clock::get()?.unix_timestamp;
+        let inserted_index = Self::insert_commitment(&mut merkle_tree, &output_leaf, timestamp)?;
+
+        // Spend the input note and mark the remainder's leaf before any value is transferred
+        Self::create_nullifier_marker(
+            program_id,
+            payer,
+            tornado_instance_info.key,
+            nullifier_marker_info,
+            system_program_info,
+            nullifier_hash,
+            nullifier_bump,
+        )?;
+        Self::create_commitment_marker(
+            program_id,
+            payer,
+            tornado_instance_info.key,
+            commitment_marker_info,
+            system_program_info,
+            &output_leaf,
+            commitment_bump,
+            inserted_index,
+        )?;
+        // Saves the Merkle tree with the remainder's leaf
+        Self::count_withdrawal(merkle_tree_info, &mut merkle_tree)?;
+
+        // Only the withdrawn part leaves the instance; the remainder stays in the pool
+        pay_withdrawal_from_pda(
+            tornado_instance_info,
+            recipient_info,
+            Self::recipient_amount(amount, fee)?,
+            relayer_info,
+            fee,
+        )?;
+
+        msg!(
+            "Partial withdrawal successful. Withdrawn: {}, remainder leaf index: {}",
+            amount,
+            inserted_index
+        );
+
+        TornadoEvent::Withdraw(WithdrawEvent {
+            nullifier_hash: *nullifier_hash,
+            recipient: *recipient_pubkey,
+            relayer: *relayer_pubkey,
+            fee,
+        })
+        .emit()?;
+        TornadoEvent::Deposit(DepositEvent {
+            commitment: output_leaf,
+            leaf_index: inserted_index,
+            timestamp,
+        })
+        .emit()?;
+
+        // Hand the remainder's leaf and the new root to a program withdrawing through CPI
+        let (root, _) = latest_root_with_index(&merkle_tree);
+        set_return_data(
+            &DepositReturnData {
+                leaf_index: inserted_index,
+                root,
+            }
+            .try_to_vec()?,
+        );
+
+        Ok(())
+    }
+
+    /// Process a SimulateWithdraw instruction
+    ///
+    /// Runs the checks of `Withdraw` (or `WithdrawVariable` with `amount`)
+    /// without creating the nullifier marker or moving any lamports, and logs
+    /// whether the withdrawal would succeed.
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `proof` - The zkSNARK proof
+    /// * `root` - The Merkle root
+    /// * `nullifier_hash` - The nullifier hash
+    /// * `recipient_pubkey` - The recipient public key
+    /// * `relayer_pubkey` - The relayer public key
+    /// * `fee` - The fee to pay to the relayer
+    /// * `refund` - The refund amount (must be 0)
+    /// * `amount` - The proven amount of a variable-amount withdrawal (`None` for a fixed denomination)
+    ///
+    /// # Returns
+    ///
+    /// Returns the error the withdrawal would fail with, if any
+    #[allow(clippy::too_many_arguments)]
+    fn process_simulate_withdraw(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        proof: &[u8],
+        root: &[u8; 32],
+        nullifier_hash: &[u8; 32],
+        recipient_pubkey: &Pubkey,
+        relayer_pubkey: &Pubkey,
+        fee: u64,
+        refund: u64,
+        amount: Option<u64>,
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let payer = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let verifier_info = next_account_info(account_info_iter)?;
+        let nullifier_marker_info = next_account_info(account_info_iter)?;
+        // Only needed on an instance with a compliance list
+        let compliance_list_info = account_info_iter.next();
+
+        let result = Self::check_withdraw(
+            program_id,
+            payer,
+            tornado_instance_info,
+            merkle_tree_info,
+            verifier_info,
+            nullifier_marker_info,
+            compliance_list_info,
+            proof,
+            root,
+            nullifier_hash,
+            recipient_pubkey,
+            relayer_pubkey,
+            fee,
+            refund,
+            amount,
+            None,
+            None,
+            None,
+        );
+        match result {
+            Ok((_, _, amount, _)) => {
+                msg!("Withdrawal simulation passed. Amount: {}, fee: {}", amount, fee);
+                Ok(())
+            }
+            Err(ProgramError::Custom(code)) => {
+                msg!("Withdrawal simulation failed: {}", explain(code));
+                Err(ProgramError::Custom(code))
+            }
+            Err(error) => {
+                msg!("Withdrawal simulation failed: {}", error);
+                Err(error)
+            }
+        }
+    }
+
+    /// Process a DepositToken instruction
+    ///
+    /// The commitment is checked and inserted before the deposit is
+    /// transferred, so a deposit that can't be recorded takes no funds.
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `commitment` - The commitment to deposit
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_deposit_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        commitment: &[u8; 32],
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let payer = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let commitment_marker_info = next_account_info(account_info_iter)?;
+        let payer_token_info = next_account_info(account_info_iter)?;
+        let vault_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        // The payer signs the token transfer and funds the marker, and every account written to must be writable
+        if !payer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_writable(&[
+            payer,
+            tornado_instance_info,
+            merkle_tree_info,
+            commitment_marker_info,
+            payer_token_info,
+            vault_info,
+        ])?;
+
+        // Check if the tornado instance is initialized
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Deposits and withdrawals are halted while the instance is paused
+        if tornado_instance.paused {
+            return Err(TornadoError::InstancePaused.into());
+        }
+
+        // Only token instances accept DepositToken
+        let token_mint = tornado_instance
+            .token_mint
+            .ok_or(TornadoError::InvalidTokenMint)?;
+
+        // Check if the merkle tree account is the correct one
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
+
+        // Check the token program and token accounts
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        validate_token_account(payer_token_info, &token_mint, payer.key)?;
+        if *vault_info.key != get_vault_address(program_id, tornado_instance_info.key, &token_mint) {
+            return Err(TornadoError::InvalidTokenAccount.into());
+        }
+
+        // Check if the commitment already exists
+        let marker_bump = Self::check_commitment_marker(
+            program_id,
+            tornado_instance_info.key,
+            commitment_marker_info,
+            commitment,
+        )?;
+        let mut merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+
+        // Check that the tree has room and insert the commitment into a copy of it
+        Self::check_capacity(&merkle_tree, 1)?;
+        let timestamp = Clock::get()?.unix_timestamp;
+        let inserted_index = Self::insert_commitment(&mut merkle_tree, commitment, timestamp)?;
+
+        // Transfer the denomination amount from the payer to the vault
+        transfer_tokens(
+            payer_token_info,
+            vault_info,
+            payer,
+            token_program_info,
+            tornado_instance.denomination,
+            None,
+        )?;
+
+        // Mark the commitment as deposited
+        Self::create_commitment_marker(
+            program_id,
+            payer,
+            tornado_instance_info.key,
+            commitment_marker_info,
+            system_program_info,
+            commitment,
+            marker_bump,
+            inserted_index,
+        )?;
+
+        // Save the updated Merkle tree
+        merkle_tree.to_account_data(&mut merkle_tree_info.data.borrow_mut())?;
+
+        msg!(
+            "Token deposit successful. Leaf index: {}, remaining capacity: {}, deposits: {}",
+            inserted_index,
+            remaining_capacity(&merkle_tree),
+            merkle_tree.next_index
+        );
+
+        TornadoEvent::Deposit(DepositEvent {
+            commitment: *commitment,
+            leaf_index: inserted_index,
+            timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    /// Process a WithdrawToken instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `proof` - The zkSNARK proof
+    /// * `root` - The Merkle root
+    /// * `nullifier_hash` - The nullifier hash
+    /// * `recipient_pubkey` - The recipient public key
+    /// * `relayer_pubkey` - The relayer public key
+    /// * `fee` - The fee to pay to the relayer (in tokens)
+    /// * `refund` - The SOL refund sent from the payer to the recipient, minus
+    ///   the rent of the recipient's token account if it has to be created
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    #[allow(clippy::too_many_arguments)]
+    fn process_withdraw_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        proof: &[u8],
+        root: &[u8; 32],
+        nullifier_hash: &[u8; 32],
+        recipient_pubkey: &Pubkey,
+        relayer_pubkey: &Pubkey,
+        fee: u64,
+        refund: u64,
+        fee_in_sol: bool,
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let payer = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let verifier_info = next_account_info(account_info_iter)?;
+        let nullifier_marker_info = next_account_info(account_info_iter)?;
+        let recipient_info = next_account_info(account_info_iter)?;
+        let recipient_token_info = next_account_info(account_info_iter)?;
+        let relayer_token_info = next_account_info(account_info_iter)?;
+        let vault_info = next_account_info(account_info_iter)?;
+        let vault_authority_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let token_mint_info = next_account_info(account_info_iter)?;
+        let associated_token_program_info = next_account_info(account_info_iter)?;
+
+        // The payer funds the nullifier marker and the refund, and every account written to must be writable
+        if !payer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_writable(&[
+            payer,
+            tornado_instance_info,
+            merkle_tree_info,
+            nullifier_marker_info,
+            recipient_info,
+            recipient_token_info,
+            relayer_token_info,
+            vault_info,
+        ])?;
+
+        // Check if the tornado instance is initialized
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Deposits and withdrawals are halted while the instance is paused
+        if tornado_instance.paused {
+            return Err(TornadoError::InstancePaused.into());
+        }
+
+        // Only token instances accept WithdrawToken
+        let token_mint = tornado_instance
+            .token_mint
+            .ok_or(TornadoError::InvalidTokenMint)?;
+
+        // Check if the merkle tree account is the correct one
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
+
+        // Check if the verifier account is the correct one
+        if tornado_instance.verifier != *verifier_info.key || verifier_info.owner != program_id {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // Check if the recipient account is the correct one
+        if recipient_pubkey != recipient_info.key {
+            return Err(TornadoError::InvalidRecipient.into());
+        }
+
+        // Check the token programs, the mint, the vault and its authority
+        if *token_program_info.key != spl_token::id()
+            || *associated_token_program_info.key != spl_associated_token_account::id()
+        {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if *token_mint_info.key != token_mint {
+            return Err(TornadoError::InvalidTokenMint.into());
+        }
+        Self::check_vault_authority(program_id, tornado_instance_info.key, &tornado_instance, vault_authority_info)?;
+        if *vault_info.key != get_vault_address(program_id, tornado_instance_info.key, &token_mint) {
+            return Err(TornadoError::InvalidTokenAccount.into());
+        }
+
+        // The recipient is paid into their associated token account
+        if *recipient_token_info.key != get_associated_token_address(recipient_pubkey, &token_mint) {
+            return Err(TornadoError::InvalidTokenAccount.into());
+        }
+
+        // A missing associated token account is created with rent taken out of the refund
+        let create_recipient_token_account = recipient_token_info.data_is_empty();
+        let refund_to_recipient = if create_recipient_token_account {
+            let rent = Rent::get()?.minimum_balance(spl_token::state::Account::LEN);
+            refund.checked_sub(rent).ok_or(TornadoError::InsufficientFunds)?
+        } else {
+            validate_token_account(recipient_token_info, &token_mint, recipient_pubkey)?;
+            refund
+        };
+
+        // Check that the relayer's token account belongs to the relayer
+        validate_token_account(relayer_token_info, &token_mint, relayer_pubkey)
+            .map_err(|_| TornadoError::InvalidRelayer)?;
+
+        // Check the payer against the relayer committed to in the proof
+        Self::check_relayer(payer, recipient_pubkey, relayer_pubkey, fee)?;
+
+        // A fee in SOL is paid from the instance's lamports above its rent-exempt
+        // minimum rather than from the tokens. Those lamports pay every withdrawal's
+        // fee, so each is bounded by the admin's cap rather than by what is left.
+        let max_fee = if fee_in_sol {
+            let rent = Rent::get()?.minimum_balance(tornado_instance_info.data_len());
+            let spare_lamports = tornado_instance_info.lamports().saturating_sub(rent);
+            if fee > spare_lamports {
+                return Err(TornadoError::InsufficientFunds.into());
+            }
+            tornado_instance.max_sol_fee
+        } else {
+            // The instance's cap also keeps the fee within the denomination
+            tornado_instance.max_fee(tornado_instance.denomination)
+        };
+
+        // Check if the nullifier hash has already been spent
+        let marker_bump = Self::check_nullifier_marker(
+            program_id,
+            tornado_instance_info.key,
+            nullifier_marker_info,
+            nullifier_hash,
+        )?;
+
+        // Check the withdrawal against the Merkle tree and verify the proof
+        let mut merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+        Self::verify_withdrawal(
+            tornado_instance_info.key,
+            &tornado_instance,
+            &merkle_tree,
+            &verifier_info.data.borrow(),
+            proof,
+            root,
+            nullifier_hash,
+            recipient_pubkey,
+            relayer_pubkey,
+            fee,
+            fee_in_sol,
+            refund,
+            tornado_instance.denomination,
+            None,
+            max_fee,
+            None,
+        )?;
+
+        // Mark the nullifier hash as spent before any value is transferred, so a
+        // failed transfer aborts the whole withdrawal rather than leaving it half done
+        Self::create_nullifier_marker(
+            program_id,
+            payer,
+            tornado_instance_info.key,
+            nullifier_marker_info,
+            system_program_info,
+            nullifier_hash,
+            marker_bump,
+        )?;
+        Self::count_withdrawal(merkle_tree_info, &mut merkle_tree)?;
+
+        if create_recipient_token_account {
+            create_associated_token_account(
+                payer,
+                recipient_token_info,
+                recipient_info,
+                token_mint_info,
+                system_program_info,
+                token_program_info,
+                associated_token_program_info,
+            )?;
+        }
+
+        let vault_authority_seeds: &[&[u8]] = &[
+            b"vault",
+            tornado_instance_info.key.as_ref(),
+            &[tornado_instance.vault_authority_bump],
+        ];
+
+        // Transfer the denomination amount minus any token fee to the recipient
+        let token_fee = if fee_in_sol { 0 } else { fee };
+        transfer_tokens(
+            vault_info,
+            recipient_token_info,
+            vault_authority_info,
+            token_program_info,
+            Self::recipient_amount(tornado_instance.denomination, token_fee)?,
+            Some(vault_authority_seeds),
+        )?;
+
+        // If there's a token fee, transfer it to the relayer
+        if token_fee > 0 {
+            transfer_tokens(
+                vault_info,
+                relayer_token_info,
+                vault_authority_info,
+                token_program_info,
+                token_fee,
+                Some(vault_authority_seeds),
+            )?;
+        }
+
+        // If there's a refund left, the payer sends it to the recipient in SOL
+        if refund_to_recipient > 0 {
+            transfer_sol(payer, recipient_info, system_program_info, refund_to_recipient, None)?;
+        }
+
+        // A fee in SOL goes to the relayer, which is the payer whenever `fee > 0`.
+        // The instance is owned by this program, so its lamports are moved
+        // directly, after the last cross-program invocation.
+        if fee_in_sol && fee > 0 {
+            transfer_sol_from_pda(tornado_instance_info, payer, fee)?;
+        }
+
+        msg!("Token withdrawal successful. Withdrawals: {}", merkle_tree.nullifier_count);
+
+        TornadoEvent::Withdraw(WithdrawEvent {
+            nullifier_hash: *nullifier_hash,
+            recipient: *recipient_pubkey,
+            relayer: *relayer_pubkey,
+            fee,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    /// Process a Pause or Unpause instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `paused` - Whether deposits and withdrawals should be halted
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_set_paused(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        paused: bool,
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let mut tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Only the admin can pause or unpause the instance
+        if !admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *admin_info.key != tornado_instance.admin {
+            return Err(TornadoError::Unauthorized.into());
+        }
+
+        // Save the tornado instance
+        tornado_instance.paused = paused;
+        tornado_instance.serialize(&mut &mut tornado_instance_info.data.borrow_mut()[..])?;
+
+        msg!("Tornado instance paused: {}", paused);
+        Ok(())
+    }
+
+    /// Process an InvalidateRoot instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `root` - The root to remove from the root history
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_invalidate_root(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        root: &[u8; 32],
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Only the admin can invalidate roots
+        if !admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *admin_info.key != tornado_instance.admin {
+            return Err(TornadoError::Unauthorized.into());
+        }
+
+        // Check if the merkle tree account is the correct one
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
+
+        let mut merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+        if !invalidate_root(root, &mut merkle_tree.roots) {
+            return Err(TornadoError::InvalidMerkleRoot.into());
+        }
+
+        // Save the updated Merkle tree
+        merkle_tree.to_account_data(&mut merkle_tree_info.data.borrow_mut())?;
+
+        msg!("Merkle root invalidated");
+        Ok(())
+    }
+
+    /// Process a SetDepositFee instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `deposit_fee` - The lamports charged per deposit (0 for no fee)
+    /// * `treasury` - The account the fee is paid to
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_set_deposit_fee(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        deposit_fee: u64,
+        treasury: &Pubkey,
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let mut tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Only the admin can set the deposit fee
+        if !admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *admin_info.key != tornado_instance.admin {
+            return Err(TornadoError::Unauthorized.into());
+        }
+
+        // The fee is paid in lamports, so token deposits don't take it
+        if tornado_instance.token_mint.is_some() {
+            return Err(TornadoError::InvalidTokenMint.into());
+        }
+
+        // The fee is capped relative to the denomination
+        if deposit_fee > tornado_instance.max_deposit_fee() {
+            msg!("Deposit fee {} exceeds the cap of {}", deposit_fee, tornado_instance.max_deposit_fee());
+            return Err(TornadoError::InvalidFee.into());
+        }
+
+        // Save the tornado instance
+        tornado_instance.deposit_fee = deposit_fee;
+        tornado_instance.treasury = *treasury;
+        tornado_instance.serialize(&mut &mut tornado_instance_info.data.borrow_mut()[..])?;
+
+        msg!("Deposit fee set to {} lamports, paid to {}", deposit_fee, treasury);
+        Ok(())
+    }
+
+    /// Process a SetMinAnonymitySet instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `min_anonymity_set` - The number of deposits required before withdrawals (0 for none)
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_set_min_anonymity_set(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        min_anonymity_set: u32,
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let mut tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Only the admin can set the minimum
+        if !admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *admin_info.key != tornado_instance.admin {
+            return Err(TornadoError::Unauthorized.into());
+        }
+
+        // Save the tornado instance
+        tornado_instance.min_anonymity_set = min_anonymity_set;
+        tornado_instance.serialize(&mut &mut tornado_instance_info.data.borrow_mut()[..])?;
+
+        msg!("Minimum anonymity set: {} deposits", min_anonymity_set);
+        Ok(())
+    }
+
+    /// Process a SetWithdrawDelay instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `withdraw_delay_secs` - The seconds a root must have been in the tree for (0 for no time-lock)
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_set_withdraw_delay(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        withdraw_delay_secs: u32,
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let mut tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Only the admin can set the time-lock
+        if !admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *admin_info.key != tornado_instance.admin {
+            return Err(TornadoError::Unauthorized.into());
+        }
+
+        // Save the tornado instance
+        tornado_instance.withdraw_delay_secs = withdraw_delay_secs;
+        tornado_instance.serialize(&mut &mut tornado_instance_info.data.borrow_mut()[..])?;
+
+        msg!("Withdrawal time-lock: {} seconds", withdraw_delay_secs);
+        Ok(())
+    }
+
+    /// Process a SetMaxSolFee instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `max_sol_fee` - The largest relayer fee a token withdrawal may pay in lamports (0 for none)
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_set_max_sol_fee(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_sol_fee: u64,
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let mut tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Only the admin can set the cap
+        if !admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *admin_info.key != tornado_instance.admin {
+            return Err(TornadoError::Unauthorized.into());
+        }
+
+        // SOL instances always pay relayer fees in SOL, capped by `max_fee_bps`
+        if tornado_instance.token_mint.is_none() {
+            return Err(TornadoError::InvalidTokenMint.into());
+        }
+
+        // Save the tornado instance
+        tornado_instance.max_sol_fee = max_sol_fee;
+        tornado_instance.serialize(&mut &mut tornado_instance_info.data.borrow_mut()[..])?;
+
+        msg!("Maximum SOL fee: {} lamports", max_sol_fee);
+        Ok(())
+    }
+
+    /// Process a SetComplianceList instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `recipients` - The blocked recipients in ascending order (`None` to disable the list)
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_set_compliance_list(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        recipients: Option<&[Pubkey]>,
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let compliance_list_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let mut tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Only the admin can set the compliance list
+        if !admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *admin_info.key != tornado_instance.admin {
+            return Err(TornadoError::Unauthorized.into());
+        }
+
+        // Token withdrawals aren't checked against the list
+        if tornado_instance.token_mint.is_some() {
+            return Err(TornadoError::InvalidTokenMint.into());
+        }
+
+        let (compliance_list_key, compliance_list_bump) = find_compliance_list_address(program_id, tornado_instance_info.key);
+        if *compliance_list_info.key != compliance_list_key {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        tornado_instance.compliance_list = match recipients {
+            Some(recipients) => {
+                // Sorted without duplicates, so withdrawals can binary search it
+                if recipients.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    msg!("Compliance list recipients must be strictly ascending");
+                    return Err(ProgramError::InvalidInstructionData);
+                }
+
+                // Create the compliance list when it is first set
+                if compliance_list_info.data_is_empty() {
+                    create_account(
+                        admin_info,
+                        compliance_list_info,
+                        system_program_info,
+                        ComplianceList::get_account_size(0),
+                        program_id,
+                        Some(&[b"compliance_list", tornado_instance_info.key.as_ref(), &[compliance_list_bump]]),
+                    )?;
+                }
+                if compliance_list_info.owner != program_id {
+                    return Err(TornadoError::InvalidAccountData.into());
+                }
+
+                // Grow the account to fit the list; a shorter list leaves zeroed trailing bytes
+                let compliance_list = ComplianceList {
+                    is_initialized: true,
+                    recipients: recipients.to_vec(),
+                };
+                grow_account(
+                    admin_info,
+                    compliance_list_info,
+                    system_program_info,
+                    ComplianceList::get_account_size(recipients.len()),
+                )?;
+                let data = compliance_list.try_to_vec()?;
+                let mut account_data = compliance_list_info.data.borrow_mut();
+                account_data[..data.len()].copy_from_slice(&data);
+                account_data[data.len()..].fill(0);
+
+                Some(compliance_list_key)
+            }
+            None => None,
+        };
+
+        // Save the tornado instance
+        tornado_instance.serialize(&mut &mut tornado_instance_info.data.borrow_mut()[..])?;
+
+        msg!("Compliance list set: {}", tornado_instance.compliance_list.is_some());
+        Ok(())
+    }
+
+    /// Process a SetPartialVerifier instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `verifying_key` - The compressed verifying key of the partial withdrawal circuit
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_set_partial_verifier(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        verifying_key: Vec<u8>,
+    ) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let verifier_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Only the admin can set the partial withdrawal verifier
+        if !admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *admin_info.key != tornado_instance.admin {
+            return Err(TornadoError::Unauthorized.into());
+        }
+
+        // Partial withdrawals re-deposit amounts, so only variable-amount SOL instances have them
+        if tornado_instance.token_mint.is_some() {
+            return Err(TornadoError::InvalidTokenMint.into());
+        }
+        if !tornado_instance.variable_amount {
+            return Err(TornadoError::InvalidInstructionData.into());
+        }
+
+        // Partial proofs aren't checked against the instance's `vk_hash`, so the
+        // key can only be set before the first deposit, while depositors can
+        // still vet it; a key set later could drain the notes already deposited
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
+        let merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+        if merkle_tree.next_index > 0 {
+            msg!("The partial withdrawal verifier must be set before the first deposit");
+            return Err(TornadoError::InstanceNotEmpty.into());
+        }
+
+        // Check the verifier account is the expected PDA, and that it was never set
+        let (verifier_key, verifier_bump) = find_partial_verifier_address(program_id, tornado_instance_info.key);
+        if *verifier_info.key != verifier_key {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+        if !verifier_info.data_is_empty() {
+            return Err(TornadoError::AccountAlreadyInitialized.into());
+        }
+
+        // Check the verifying key before storing it
+        check_verifying_key(
+            tornado_instance.proof_system,
+            tornado_instance.curve,
+            &verifying_key,
+            NUM_PARTIAL_WITHDRAW_PUBLIC_INPUTS,
+        )?;
+
+        // Create the verifier account and store the verifying key
+        let verifier_state = VerifierState {
+            is_initialized: true,
+            verifying_key,
+        };
+        create_account(
+            admin_info,
+            verifier_info,
+            system_program_info,
+            VerifierState::get_account_size(verifier_state.verifying_key.len()),
+            program_id,
+            Some(&[
+                b"verifier",
+                tornado_instance_info.key.as_ref(),
+                &[1],
+                &[verifier_bump],
+            ]),
+        )?;
+        verifier_state.serialize(&mut &mut verifier_info.data.borrow_mut()[..])?;
+
+        msg!("Partial withdrawal verifier set");
+        Ok(())
+    }
+
+    /// Process a GetRoot instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_get_root(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Check if the merkle tree account is the correct one
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
+
+        let merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+        let (root, root_index) = latest_root_with_index(&merkle_tree);
+
+        msg!("Latest root index: {}", root_index);
+
+        TornadoEvent::Root(RootEvent { root, root_index }).emit()
+    }
+
+    /// Process a SelfCheck instruction
+    ///
+    /// Every check runs and is logged even after one fails, so a single
+    /// simulation reports all of an instance's inconsistencies. The balance is
+    /// only checked for fixed-amount SOL instances: a variable-amount instance
+    /// doesn't record its deposits' amounts, and a token instance pools its
+    /// vault. Lamports sent to the instance directly are allowed on top.
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_self_check(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let verifier_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        let mut healthy = true;
+        let mut check = |name: &str, passed: bool| {
+            msg!("Check {}: {}", name, if passed { "pass" } else { "fail" });
+            healthy &= passed;
+        };
+
+        // The tree and verifier are the instance's program-owned PDAs
+        let (merkle_tree_key, _) = find_merkle_tree_address(program_id, tornado_instance_info.key);
+        let merkle_tree_matches = tornado_instance.merkle_tree == merkle_tree_key
+            && *merkle_tree_info.key == merkle_tree_key
+            && merkle_tree_info.owner == program_id;
+        check("merkle_tree address", merkle_tree_matches);
+        let (verifier_key, _) = find_verifier_address(program_id, tornado_instance_info.key);
+        check(
+            "verifier address",
+            tornado_instance.verifier == verifier_key
+                && *verifier_info.key == verifier_key
+                && verifier_info.owner == program_id,
+        );
+
+        // The tree's own invariants, if it is the instance's tree
+        let merkle_tree = if merkle_tree_matches {
+            MerkleTree::from_account_data(&merkle_tree_info.data.borrow()).ok()
+        } else {
+            None
+        };
+        check("merkle_tree data", merkle_tree.is_some());
+        if let Some(merkle_tree) = merkle_tree {
+            let next_index = u64::from(merkle_tree.next_index);
+            check(
+                "next_index",
+                1u64.checked_shl(u32::from(merkle_tree.height))
+                    .is_none_or(|capacity| next_index <= capacity),
+            );
+            check(
+                "current_root_index",
+                usize::from(merkle_tree.current_root_index) < merkle_tree.roots.len(),
+            );
+            check(
+                "filled_subtrees",
+                merkle_tree.filled_subtrees.len() == usize::from(merkle_tree.height),
+            );
+            check("nullifier_count", merkle_tree.nullifier_count <= next_index);
+
+            // The instance holds its rent plus every unspent deposit
+            if tornado_instance.token_mint.is_none() && !tornado_instance.variable_amount {
+                let rent = Rent::get()?.minimum_balance(tornado_instance_info.data_len());
+                let expected = (next_index.saturating_sub(merkle_tree.nullifier_count))
+                    .checked_mul(tornado_instance.denomination)
+                    .and_then(|deposits| deposits.checked_add(rent));
+                check(
+                    "balance",
+                    expected.is_some_and(|expected| tornado_instance_info.lamports() >= expected),
+                );
+            }
+        }
+
+        if !healthy {
+            return Err(TornadoError::InconsistentState.into());
+        }
+        msg!("All checks passed");
+        Ok(())
+    }
+
+    /// Process an IsSpent instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    /// * `nullifier_hash` - The nullifier hash to look up
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_is_spent(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        nullifier_hash: &[u8; 32],
+    ) -> ProgramResult {
+        let spent = Self::is_nullifier_spent(program_id, accounts, nullifier_hash)?;
+
+        msg!("Nullifier spent: {}", spent);
+
+        TornadoEvent::NullifierStatus(NullifierStatusEvent {
+            nullifier_hash: *nullifier_hash,
+            spent,
+        })
+        .emit()
+    }
+
+    /// Look up whether a nullifier hash has been spent, for the accounts of an IsSpent instruction
+    fn is_nullifier_spent(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        nullifier_hash: &[u8; 32],
+    ) -> Result<bool, ProgramError> {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let nullifier_marker_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Check if the merkle tree account is the correct one
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
+
+        // Check if the nullifier marker account is the one of this nullifier hash
+        let (marker_key, _) = find_nullifier_address(program_id, tornado_instance_info.key, nullifier_hash);
+        if *nullifier_marker_info.key != marker_key {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // The marker only exists once the nullifier hash is spent
+        if !nullifier_marker_info.data_is_empty() {
+            return Ok(true);
+        }
+
+        // Nullifier hashes spent before nullifier markers existed
+        let merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+        Ok(nullifier_hash_exists(&merkle_tree.nullifier_hashes, nullifier_hash))
+    }
+
+    /// Process a Migrate instruction
+    ///
+    /// Rewrites an instance and its Merkle tree from older layouts to the
+    /// current ones, growing the accounts if they are too small.
+    /// Accounts already at the current version are left unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_migrate(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let payer = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        if tornado_instance_info.owner != program_id {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // Migrate the instance
+        let version = tornado_instance_info.data.borrow().first().copied();
+        let tornado_instance = match version {
+            Some(TornadoInstance::VERSION) => TornadoInstance::unpack(&tornado_instance_info.data.borrow())?,
+            Some(1) => {
+                let mut tornado_instance: TornadoInstance =
+                    deserialize_account_data::<TornadoInstanceV1>(&tornado_instance_info.data.borrow(), "Tornado instance")?
+                        .into();
+                tornado_instance.vault_authority_bump =
+                    find_vault_authority_address(program_id, tornado_instance_info.key).1;
+                tornado_instance.merkle_tree_bump = find_merkle_tree_address(program_id, tornado_instance_info.key).1;
+
+                // Record the hash of the verifying key the instance has been using
+                let verifier_info = next_account_info(account_info_iter)?;
+                if *verifier_info.key != tornado_instance.verifier || verifier_info.owner != program_id {
+                    return Err(TornadoError::InvalidAccountData.into());
+                }
+                let verifier_state: VerifierState = deserialize_account_data(&verifier_info.data.borrow(), "verifier")?;
+                tornado_instance.vk_hash = hash_verifying_key(&verifier_state.verifying_key);
+
+                grow_account(payer, tornado_instance_info, system_program_info, TornadoInstance::LEN)?;
+                tornado_instance.pack_into_slice(&mut tornado_instance_info.data.borrow_mut());
+                msg!("Migrated instance to version {}", TornadoInstance::VERSION);
+                tornado_instance
+            }
+            _ => return Err(TornadoError::UnsupportedStateVersion.into()),
+        };
+
+        // Check if the merkle tree account is the correct one
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
+
+        // Migrate the Merkle tree
+        let version = merkle_tree_info.data.borrow().first().copied();
+        match version {
+            Some(MerkleTree::VERSION) => {}
+            Some(1) => {
+                let mut merkle_tree: MerkleTree =
+                    deserialize_account_data::<MerkleTreeV1>(&merkle_tree_info.data.borrow(), "Merkle tree")?.into();
+                // The insertion times of legacy roots are unknown; count them from now
+                // so that a time-lock cannot be skipped with a root from before it
+                let now = Clock::get()?.unix_timestamp;
+                merkle_tree.root_timestamps.iter_mut().for_each(|inserted_at| *inserted_at = now);
+                let root_history_size =
+                    u8::try_from(merkle_tree.roots.len()).map_err(|_| TornadoError::InvalidMerkleTreeState)?;
+                let merkle_tree_size = MerkleTree::get_account_size_bounded(merkle_tree.height, root_history_size)
+                    .max(merkle_tree.try_to_vec()?.len());
+                grow_account(payer, merkle_tree_info, system_program_info, merkle_tree_size)?;
+                merkle_tree.to_account_data(&mut merkle_tree_info.data.borrow_mut())?;
+                msg!("Migrated Merkle tree to version {}", MerkleTree::VERSION);
+            }
+            _ => return Err(TornadoError::UnsupportedStateVersion.into()),
+        }
+
+        Ok(())
+    }
+
+    /// Process a CloseInstance instruction
+    ///
+    /// Once every deposit has been withdrawn, the admin can close the instance,
+    /// its Merkle tree and verifier, and the token vault of a token instance.
+    /// Their lamports go to the receiver.
+    ///
+    /// # Arguments
+    ///
+    /// * `program_id` - The program ID
+    /// * `accounts` - The accounts required for the instruction
+    ///
+    /// # Returns
+    ///
+    /// Returns a `ProgramResult` indicating success or failure
+    fn process_close_instance(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        // Get the account information
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let tornado_instance_info = next_account_info(account_info_iter)?;
+        let merkle_tree_info = next_account_info(account_info_iter)?;
+        let verifier_info = next_account_info(account_info_iter)?;
+        let receiver_info = next_account_info(account_info_iter)?;
+        let registry_info = next_account_info(account_info_iter)?;
+
+        // Check if the tornado instance is initialized
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Only the admin can close the instance
+        if !admin_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *admin_info.key != tornado_instance.admin {
+            return Err(TornadoError::Unauthorized.into());
+        }
+
+        // Check if the merkle tree and verifier accounts are the correct ones
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
+        if tornado_instance.verifier != *verifier_info.key || verifier_info.owner != program_id {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // The receiver can't be one of the closed accounts
+        if [tornado_instance_info.key, merkle_tree_info.key, verifier_info.key].contains(&receiver_info.key) {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // Every deposit must have been withdrawn. The tree's count includes the
+        // spends a migrated tree lists in `nullifier_hashes`.
+        let merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+        if u64::from(merkle_tree.next_index) != merkle_tree.nullifier_count {
+            msg!(
+                "{} of {} deposits withdrawn",
+                merkle_tree.nullifier_count,
+                merkle_tree.next_index
+            );
+            return Err(TornadoError::InstanceNotEmpty.into());
+        }
+
+        // With every note spent, lamports a SOL instance holds above its rent are
+        // dust or plain transfers that no note can claim: they are swept to the
+        // receiver with the rent, so a stray transfer can't block closing
+        if let Some(token_mint) = tornado_instance.token_mint {
+            let vault_info = next_account_info(account_info_iter)?;
+            let vault_authority_info = next_account_info(account_info_iter)?;
+            let token_program_info = next_account_info(account_info_iter)?;
+
+            // Check the token program, the vault and its authority
+            if *token_program_info.key != spl_token::id() {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            Self::check_vault_authority(program_id, tornado_instance_info.key, &tornado_instance, vault_authority_info)?;
+            if *vault_info.key != get_vault_address(program_id, tornado_instance_info.key, &token_mint) {
+                return Err(TornadoError::InvalidTokenAccount.into());
+            }
+
+            // A token instance holds the deposits in its vault. SOL it holds
+            // above its rent only funds relayer fees, so it is reclaimed too.
+            let vault = spl_token::state::Account::unpack(&vault_info.data.borrow())?;
+            if vault.amount != 0 {
+                return Err(TornadoError::InstanceNotEmpty.into());
+            }
+            close_token_account(
+                vault_info,
+                receiver_info,
+                vault_authority_info,
+                token_program_info,
+                &[b"vault", tornado_instance_info.key.as_ref(), &[tornado_instance.vault_authority_bump]],
+            )?;
+        }
+
+        Self::unregister_instance(program_id, registry_info, tornado_instance_info.key)?;
+
+        // Return the lamports of the program's accounts to the receiver
+        for account_info in [tornado_instance_info, merkle_tree_info, verifier_info] {
+            close_account(account_info, receiver_info)?;
+        }
+
+        msg!("Tornado instance closed");
+        Ok(())
+    }
+
+    /// Check that the Merkle tree has room for `count` more commitments
+    fn check_capacity(merkle_tree: &MerkleTree, count: usize) -> ProgramResult {
+        let capacity = remaining_capacity(merkle_tree);
+        if capacity < count as u64 {
+            msg!("Merkle tree has room for {} more deposits", capacity);
+            return Err(TornadoError::MerkleTreeFull.into());
+        }
+
+        Ok(())
+    }
+
+    /// Insert a commitment into the Merkle tree
+    ///
+    /// The new root is stamped with `timestamp`, the time the withdrawal
+    /// time-lock counts from.
+    ///
+    /// # Returns
+    ///
+    /// Returns the leaf index of the inserted commitment
+    fn insert_commitment(merkle_tree: &mut MerkleTree, commitment: &[u8; 32], timestamp: i64) -> Result<u32, ProgramError> {
+        if merkle_tree.root_timestamps.len() != merkle_tree.roots.len() {
+            return Err(TornadoError::InvalidMerkleTreeState.into());
+        }
+        let inserted_index = insert_leaf(
+            commitment,
+            merkle_tree.next_index,
+            merkle_tree.height,
+            merkle_tree.hash_algo,
+            &mut merkle_tree.filled_subtrees,
+            &mut merkle_tree.roots,
+            &mut merkle_tree.current_root_index,
+        )?;
+
+        // Update the Merkle tree state
+        merkle_tree.root_timestamps[merkle_tree.current_root_index as usize] = timestamp;
+        merkle_tree.next_index += 1;
+
+        Ok(inserted_index)
+    }
+
+    /// Add an instance to the registry, creating the registry on first use
+    ///
+    /// The registry account is grown by one entry, with the payer topping up
+    /// its rent. Fails if an instance with the same denomination and mint is
+    /// already registered.
+    fn register_instance<'a>(
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        registry_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        entry: RegistryEntry,
+    ) -> ProgramResult {
+        let (registry_key, registry_bump) = find_registry_address(program_id);
+        if *registry_info.key != registry_key {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // Create the registry when the first instance is initialized
+        if registry_info.data_is_empty() {
+            create_account(
+                payer,
+                registry_info,
+                system_program_info,
+                Registry::get_account_size(0),
+                program_id,
+                Some(&[b"registry", &[registry_bump]]),
+            )?;
+        }
+        if registry_info.owner != program_id {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // Anyone can initialize an instance, so several may pool the same
+        // denomination and mint; clients pick one by its admin and verifying key
+        let mut registry: Registry = deserialize_account_data(&registry_info.data.borrow(), "registry")?;
+        registry.is_initialized = true;
+        registry.instances.push(entry);
+
+        // Grow the registry to fit the new entry
+        grow_account(
+            payer,
+            registry_info,
+            system_program_info,
+            Registry::get_account_size(registry.instances.len()),
+        )?;
+        registry.serialize(&mut &mut registry_info.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    /// Remove a closed instance from the registry
+    ///
+    /// The registry account keeps its size, so the freed room is reused by the
+    /// next instance registered.
+    fn unregister_instance(
+        program_id: &Pubkey,
+        registry_info: &AccountInfo,
+        tornado_instance_key: &Pubkey,
+    ) -> ProgramResult {
+        let (registry_key, _) = find_registry_address(program_id);
+        if *registry_info.key != registry_key || registry_info.owner != program_id {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        let mut registry: Registry = deserialize_account_data(&registry_info.data.borrow(), "registry")?;
+        registry
+            .instances
+            .retain(|entry| entry.instance != *tornado_instance_key);
+
+        let registry_data = registry.try_to_vec()?;
+        let mut data = registry_info.try_borrow_mut_data()?;
+        data[..registry_data.len()].copy_from_slice(&registry_data);
+        data[registry_data.len()..].fill(0);
+
+        Ok(())
+    }
+
+    /// Check the amount of a deposit or withdrawal against the instance
+    ///
+    /// Fixed-denomination instances only accept `Deposit` and `Withdraw`, which
+    /// move the denomination. Variable-amount instances only accept
+    /// `DepositVariable` and `WithdrawVariable`, with an amount between 1 and
+    /// the denomination.
+    ///
+    /// # Returns
+    ///
+    /// Returns the amount to transfer
+    fn check_amount(tornado_instance: &TornadoInstance, amount: Option<u64>) -> Result<u64, ProgramError> {
+        match (tornado_instance.variable_amount, amount) {
+            (false, None) => Ok(tornado_instance.denomination),
+            (true, Some(amount)) if amount > 0 && amount <= tornado_instance.denomination => Ok(amount),
+            (true, Some(_)) => Err(TornadoError::InvalidAmount.into()),
+            _ => Err(TornadoError::InvalidInstructionData.into()),
+        }
+    }
+
+    /// The part of a withdrawn `amount` left to the recipient once the relayer's `fee` is taken
+    ///
+    /// The fee is checked against the instance's cap beforehand, so this only
+    /// fails if that check is bypassed.
+    fn recipient_amount(amount: u64, fee: u64) -> Result<u64, ProgramError> {
+        amount.checked_sub(fee).ok_or_else(|| TornadoError::InsufficientFunds.into())
+    }
+
+    /// Take the treasury account of an instance charging a deposit fee
+    ///
+    /// Returns `None` without taking an account when the instance charges no
+    /// fee, and fails with `InvalidAccountData` for any account but the
+    /// instance's treasury.
+    fn next_treasury<'a, 'b>(
+        account_info_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+        tornado_instance: &TornadoInstance,
+    ) -> Result<Option<&'a AccountInfo<'b>>, ProgramError> {
+        if tornado_instance.deposit_fee == 0 {
+            return Ok(None);
+        }
+
+        let treasury_info = next_account_info(account_info_iter)?;
+        if *treasury_info.key != tornado_instance.treasury {
+            msg!("Expected the treasury {}", tornado_instance.treasury);
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+        Ok(Some(treasury_info))
+    }
+
+    /// Check the instance's deposit fee against the most the depositor agreed to pay
+    ///
+    /// A depositor passing `max_deposit_fee` fails with `InvalidFee` rather
+    /// than paying a fee the admin raised after the transaction was signed.
+    fn check_deposit_fee(tornado_instance: &TornadoInstance, max_deposit_fee: Option<u64>) -> ProgramResult {
+        if let Some(max_deposit_fee) = max_deposit_fee {
+            if tornado_instance.deposit_fee > max_deposit_fee {
+                msg!("Deposit fee {} exceeds the maximum of {}", tornado_instance.deposit_fee, max_deposit_fee);
+                return Err(TornadoError::InvalidFee.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that every account an instruction writes to was passed as writable
+    ///
+    /// The runtime would reject the write anyway, but only after the
+    /// instruction ran; this fails first and names the account.
+    fn check_writable(accounts: &[&AccountInfo]) -> ProgramResult {
+        if let Some(account) = accounts.iter().find(|account| !account.is_writable) {
+            msg!("Account {} must be writable", account.key);
+            return Err(TornadoError::AccountNotWritable.into());
+        }
+
+        Ok(())
+    }
+
+    /// Check that the Merkle tree account is the instance's tree
+    ///
+    /// Besides matching the key stored in the instance, the account must be the
+    /// PDA of `[b"merkle_tree", instance, &[0]]` with the bump stored in the
+    /// instance, and owned by the program. Every deposit and withdrawal checks
+    /// the tree, so this saves searching for the bump each time.
+    fn check_merkle_tree(
+        program_id: &Pubkey,
+        tornado_instance_key: &Pubkey,
+        tornado_instance: &TornadoInstance,
+        merkle_tree_info: &AccountInfo,
+    ) -> ProgramResult {
+        let merkle_tree_key = Pubkey::create_program_address(
+            &[b"merkle_tree", tornado_instance_key.as_ref(), &[0], &[tornado_instance.merkle_tree_bump]],
+            program_id,
+        )
+        .map_err(|_| TornadoError::InvalidAccountData)?;
+        if tornado_instance.merkle_tree != *merkle_tree_info.key
+            || merkle_tree_key != *merkle_tree_info.key
+            || merkle_tree_info.owner != program_id
+        {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        Ok(())
+    }
+
+    /// Check that the vault authority account is the instance's vault authority
+    ///
+    /// The account must be the PDA of `[b"vault", instance]` with the bump
+    /// stored in the instance, which then signs for it without searching for
+    /// the bump again.
+    fn check_vault_authority(
+        program_id: &Pubkey,
+        tornado_instance_key: &Pubkey,
+        tornado_instance: &TornadoInstance,
+        vault_authority_info: &AccountInfo,
+    ) -> ProgramResult {
+        let vault_authority_key = Pubkey::create_program_address(
+            &[b"vault", tornado_instance_key.as_ref(), &[tornado_instance.vault_authority_bump]],
+            program_id,
+        )
+        .map_err(|_| TornadoError::InvalidAccountData)?;
+        if *vault_authority_info.key != vault_authority_key {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        Ok(())
+    }
+
+    /// Check that the commitment marker account is the expected PDA and not yet created
+    ///
+    /// The commitment must also be a field element, as the circuit takes it,
+    /// and neither zero nor `ZERO_VALUE`, the leaf of an empty slot.
+    ///
+    /// # Returns
+    ///
+    /// Returns the bump seed of the commitment marker PDA
+    fn check_commitment_marker(
+        program_id: &Pubkey,
+        tornado_instance: &Pubkey,
+        commitment_marker_info: &AccountInfo,
+        commitment: &[u8; 32],
+    ) -> Result<u8, ProgramError> {
+        if !is_within_field(commitment) {
+            return Err(TornadoError::InvalidCommitment.into());
+        }
+
+        // A degenerate leaf would be indistinguishable from the tree's empty slots
+        if *commitment == [0u8; 32] || *commitment == ZERO_VALUE {
+            msg!("Commitment is a zero value");
+            return Err(TornadoError::InvalidCommitment.into());
+        }
+
+        let (marker_key, marker_bump) =
+            find_commitment_address(program_id, tornado_instance, commitment);
+        if *commitment_marker_info.key != marker_key {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // The marker only exists if this commitment was deposited before
+        if !commitment_marker_info.data_is_empty() {
+            return Err(TornadoError::CommitmentAlreadyExists.into());
+        }
+
+        Ok(marker_bump)
+    }
+
+    /// Create the commitment marker account recording a deposit
+    #[allow(clippy::too_many_arguments)]
+    fn create_commitment_marker<'a>(
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        tornado_instance: &Pubkey,
+        commitment_marker_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        commitment: &[u8; 32],
+        marker_bump: u8,
+        leaf_index: u32,
+    ) -> ProgramResult {
+        create_account(
+            payer,
+            commitment_marker_info,
+            system_program_info,
+            CommitmentMarker::LEN,
+            program_id,
+            Some(&[
+                b"commitment",
+                tornado_instance.as_ref(),
+                commitment,
+                &[marker_bump],
+            ]),
+        )?;
+
+        let marker = CommitmentMarker {
+            is_initialized: true,
+            leaf_index,
+        };
+        CommitmentMarker::pack(marker, &mut commitment_marker_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Check an encrypted note and that its account is the expected PDA and not yet created
+    ///
+    /// # Returns
+    ///
+    /// Returns the bump seed of the encrypted note PDA
+    fn check_encrypted_note(
+        program_id: &Pubkey,
+        tornado_instance: &Pubkey,
+        encrypted_note_info: &AccountInfo,
+        commitment: &[u8; 32],
+        ciphertext: &[u8],
+    ) -> Result<u8, ProgramError> {
+        if ciphertext.is_empty() || ciphertext.len() > MAX_ENCRYPTED_NOTE_SIZE {
+            return Err(TornadoError::InvalidInstructionData.into());
+        }
+
+        let (note_key, note_bump) = find_encrypted_note_address(program_id, tornado_instance, commitment);
+        if *encrypted_note_info.key != note_key || !encrypted_note_info.data_is_empty() {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        Ok(note_bump)
+    }
+
+    /// Create the account storing the encrypted note of a deposit
+    #[allow(clippy::too_many_arguments)]
+    fn create_encrypted_note<'a>(
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        tornado_instance: &Pubkey,
+        encrypted_note_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        commitment: &[u8; 32],
+        ciphertext: &[u8],
+        note_bump: u8,
+    ) -> ProgramResult {
+        create_account(
+            payer,
+            encrypted_note_info,
+            system_program_info,
+            EncryptedNote::get_account_size(ciphertext.len()),
+            program_id,
+            Some(&[b"note", tornado_instance.as_ref(), commitment, &[note_bump]]),
+        )?;
+
+        let note = EncryptedNote {
+            is_initialized: true,
+            ciphertext: ciphertext.to_vec(),
+        };
+        note.serialize(&mut &mut encrypted_note_info.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    /// Check that the nullifier marker account is the expected PDA and not yet created
+    ///
+    /// The nullifier hash must also be a field element, as the circuit outputs it.
+    ///
+    /// # Returns
+    ///
+    /// Returns the bump seed of the nullifier marker PDA
+    fn check_nullifier_marker(
+        program_id: &Pubkey,
+        tornado_instance: &Pubkey,
+        nullifier_marker_info: &AccountInfo,
+        nullifier_hash: &[u8; 32],
+    ) -> Result<u8, ProgramError> {
+        if !is_within_field(nullifier_hash) {
+            return Err(TornadoError::InvalidNullifierHash.into());
+        }
+
+        let (marker_key, marker_bump) =
+            find_nullifier_address(program_id, tornado_instance, nullifier_hash);
+        if *nullifier_marker_info.key != marker_key {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // The marker only exists if this nullifier hash was spent before
+        if !nullifier_marker_info.data_is_empty() {
+            return Err(TornadoError::NullifierAlreadySpent.into());
+        }
+
+        Ok(marker_bump)
+    }
+
+    /// Create the nullifier marker account recording a spent note
+    fn create_nullifier_marker<'a>(
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        tornado_instance: &Pubkey,
+        nullifier_marker_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        nullifier_hash: &[u8; 32],
+        marker_bump: u8,
+    ) -> ProgramResult {
+        create_account(
+            payer,
+            nullifier_marker_info,
+            system_program_info,
+            NullifierMarker::LEN,
+            program_id,
+            Some(&[
+                b"nullifier",
+                tornado_instance.as_ref(),
+                nullifier_hash,
+                &[marker_bump],
+            ]),
+        )?;
+
+        let marker = NullifierMarker {
+            is_initialized: true,
+        };
+        NullifierMarker::pack(marker, &mut nullifier_marker_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Count a withdrawal in the Merkle tree, once its nullifier marker is created
+    fn count_withdrawal(merkle_tree_info: &AccountInfo, merkle_tree: &mut MerkleTree) -> ProgramResult {
+        merkle_tree.nullifier_count = merkle_tree
+            .nullifier_count
+            .checked_add(1)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        merkle_tree.to_account_data(&mut merkle_tree_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Check that the withdrawal is submitted by the relayer it pays
+    ///
+    /// A relayed withdrawal (`fee > 0`) must be signed by the relayer committed
+    /// to in the proof, so nobody else can submit it and collect the fee. A
+    /// self-withdrawal names the recipient as the relayer and must not pay a fee.
+    fn check_relayer(
+        payer: &AccountInfo,
+        recipient_pubkey: &Pubkey,
+        relayer_pubkey: &Pubkey,
+        fee: u64,
+    ) -> ProgramResult {
+        if fee > 0 && (!payer.is_signer || payer.key != relayer_pubkey) {
+            return Err(TornadoError::InvalidRelayer.into());
+        }
+
+        if relayer_pubkey == recipient_pubkey && fee != 0 {
+            return Err(TornadoError::InvalidRelayer.into());
+        }
+
+        Ok(())
+    }
+
+    /// Check the relayer's signed quote for the fee of a withdrawal
+    ///
+    /// Programs can't verify ed25519 signatures directly, so the instruction
+    /// immediately before the withdrawal must be an ed25519 program instruction
+    /// over the quote. The runtime rejects the transaction if that signature is
+    /// invalid; this checks that it is the relayer's signature over `message`.
+    fn check_relayer_fee_quote(
+        instructions_sysvar_info: &AccountInfo,
+        relayer_pubkey: &Pubkey,
+        message: &[u8],
+        signature: &[u8; 64],
+    ) -> ProgramResult {
+        let current_index = load_current_index_checked(instructions_sysvar_info)?;
+        let quote_index = current_index
+            .checked_sub(1)
+            .ok_or(TornadoError::InvalidRelayerFeeQuote)?;
+        let quote_instruction = load_instruction_at_checked(quote_index as usize, instructions_sysvar_info)?;
+        if quote_instruction.program_id != ed25519_program::id() {
+            return Err(TornadoError::InvalidRelayerFeeQuote.into());
+        }
+
+        // Exactly one signature, with its offsets table after the 2-byte header
+        let data = &quote_instruction.data;
+        if data.len() < ED25519_DATA_START || data[0] != 1 {
+            return Err(TornadoError::InvalidRelayerFeeQuote.into());
+        }
+        let offset = |i: usize| u16::from_le_bytes([data[2 + 2 * i], data[3 + 2 * i]]);
+        let (signature_offset, signature_index) = (offset(0) as usize, offset(1));
+        let (public_key_offset, public_key_index) = (offset(2) as usize, offset(3));
+        let (message_offset, message_size, message_index) = (offset(4) as usize, offset(5) as usize, offset(6));
+
+        // The signed values must be in the ed25519 instruction itself
+        if [signature_index, public_key_index, message_index].iter().any(|&index| index != u16::MAX) {
+            return Err(TornadoError::InvalidRelayerFeeQuote.into());
+        }
+
+        let field = |start: usize, len: usize| data.get(start..start.checked_add(len)?);
+        if field(public_key_offset, 32) != Some(relayer_pubkey.as_ref())
+            || field(signature_offset, 64) != Some(&signature[..])
+            || field(message_offset, message_size) != Some(message)
+        {
+            return Err(TornadoError::InvalidRelayerFeeQuote.into());
+        }
+
+        Ok(())
+    }
+
+    /// Check the current instruction is the only one of its transaction, besides compute budget instructions
+    ///
+    /// The current instruction must also be this program's own rather than
+    /// another program's that invokes it, so nothing can be bundled with a
+    /// withdrawal, such as a sweep of the recipient's funds. Ed25519 program
+    /// instructions only verify signatures, so they are allowed too, for a
+    /// relayer fee quote.
+    fn check_sole_instruction(program_id: &Pubkey, instructions_sysvar_info: &AccountInfo) -> ProgramResult {
+        let current_index = load_current_index_checked(instructions_sysvar_info)? as usize;
+        // The sysvar data starts with the number of instructions
+        let instruction_count = {
+            let data = instructions_sysvar_info.try_borrow_data()?;
+            u16::from_le_bytes([data[0], data[1]]) as usize
+        };
+
+        for index in 0..instruction_count {
+            let instruction = load_instruction_at_checked(index, instructions_sysvar_info)?;
+            let allowed = if index == current_index {
+                instruction.program_id == *program_id
+            } else {
+                instruction.program_id == COMPUTE_BUDGET_PROGRAM_ID || instruction.program_id == ed25519_program::id()
+            };
+            if !allowed {
+                msg!("Instruction {} of program {} is not allowed in the transaction", index, instruction.program_id);
+                return Err(TornadoError::UnexpectedInstructions.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check a recipient against the instance's compliance list, if it has one
+    fn check_recipient_allowed(
+        program_id: &Pubkey,
+        tornado_instance: &TornadoInstance,
+        compliance_list_info: Option<&AccountInfo>,
+        recipient_pubkey: &Pubkey,
+    ) -> ProgramResult {
+        let Some(compliance_list_key) = tornado_instance.compliance_list else {
+            return Ok(());
+        };
+        let compliance_list_info = compliance_list_info.ok_or(ProgramError::NotEnoughAccountKeys)?;
+        if *compliance_list_info.key != compliance_list_key || compliance_list_info.owner != program_id {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        let compliance_list: ComplianceList =
+            deserialize_account_data(&compliance_list_info.data.borrow(), "compliance list")?;
+        if compliance_list.is_blocked(recipient_pubkey) {
+            return Err(TornadoError::RecipientBlocked.into());
+        }
+
+        Ok(())
+    }
+
+    /// Run every check of a SOL withdrawal, for `Withdraw`, `WithdrawVariable`, `WithdrawBatch` and `SimulateWithdraw`
+    ///
+    /// `remainder` is the amount and commitment a partial withdrawal re-deposits,
+    /// and `ephemeral_pubkey` that of a stealth withdrawal, whose proof commits to
+    /// `stealth_recipient` rather than the recipient itself. A `root_index` is
+    /// passed on to `verify_withdrawal`. Nothing is modified. Returns the instance, its Merkle tree, the amount
+    /// withdrawn and the bump of the nullifier marker to create.
+    #[allow(clippy::too_many_arguments)]
+    fn check_withdraw(
+        program_id: &Pubkey,
+        payer: &AccountInfo,
+        tornado_instance_info: &AccountInfo,
+        merkle_tree_info: &AccountInfo,
+        verifier_info: &AccountInfo,
+        nullifier_marker_info: &AccountInfo,
+        compliance_list_info: Option<&AccountInfo>,
+        proof: &[u8],
+        root: &[u8; 32],
+        nullifier_hash: &[u8; 32],
+        recipient_pubkey: &Pubkey,
+        relayer_pubkey: &Pubkey,
+        fee: u64,
+        refund: u64,
+        amount: Option<u64>,
+        remainder: Option<(u64, &[u8; 32])>,
+        ephemeral_pubkey: Option<&[u8; 32]>,
+        root_index: Option<u8>,
+    ) -> Result<(TornadoInstance, MerkleTree, u64, u8), ProgramError> {
+        // Check if the tornado instance is initialized
+        let tornado_instance = TornadoInstance::load_checked(tornado_instance_info, program_id)?;
+
+        // Deposits and withdrawals are halted while the instance is paused
+        if tornado_instance.paused {
+            return Err(TornadoError::InstancePaused.into());
+        }
+
+        // Token instances only accept WithdrawToken
+        if tornado_instance.token_mint.is_some() {
+            return Err(TornadoError::InvalidTokenMint.into());
+        }
+
+        // Check if the merkle tree account is the correct one
+        Self::check_merkle_tree(program_id, tornado_instance_info.key, &tornado_instance, merkle_tree_info)?;
+
+        // Check if the verifier account is the correct one; partial withdrawals have their own circuit
+        let verifier_key = match remainder {
+            Some(_) => find_partial_verifier_address(program_id, tornado_instance_info.key).0,
+            None => tornado_instance.verifier,
+        };
+        if verifier_key != *verifier_info.key || verifier_info.owner != program_id {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        // Check the payer against the relayer committed to in the proof
+        Self::check_relayer(payer, recipient_pubkey, relayer_pubkey, fee)?;
+
+        // Listed recipients can't be paid
+        Self::check_recipient_allowed(program_id, &tornado_instance, compliance_list_info, recipient_pubkey)?;
+
+        // Only token withdrawals forward a refund (see `process_withdraw_token`);
+        // a SOL recipient is paid in SOL already
+        if refund != 0 {
+            msg!("SOL withdrawals take no refund");
+            return Err(TornadoError::InvalidAmount.into());
+        }
+
+        // The amount withdrawn is the denomination, or the proven amount of a
+        // variable-amount instance less any remainder a partial withdrawal re-deposits
+        let amount = Self::check_amount(&tornado_instance, amount)?;
+        let withdrawn = match remainder {
+            Some((output_amount, _)) if output_amount > 0 && output_amount < amount => amount - output_amount,
+            Some(_) => return Err(TornadoError::InvalidAmount.into()),
+            None => amount,
+        };
+
         // Check if the nullifier hash has already been spent
-        let mut merkle_tree = MerkleTree::try_from_slice(&merkle_tree_info.data.borrow())?;
+        let marker_bump = Self::check_nullifier_marker(
+            program_id,
+            tornado_instance_info.key,
+            nullifier_marker_info,
+            nullifier_hash,
+        )?;
+
+        // Check the withdrawal against the Merkle tree and verify the proof
+        let merkle_tree = MerkleTree::from_account_data(&merkle_tree_info.data.borrow())?;
+        let proof_recipient = match ephemeral_pubkey {
+            Some(ephemeral_pubkey) => stealth_recipient(recipient_pubkey, ephemeral_pubkey),
+            None => *recipient_pubkey,
+        };
+        Self::verify_withdrawal(
+            tornado_instance_info.key,
+            &tornado_instance,
+            &merkle_tree,
+            &verifier_info.data.borrow(),
+            proof,
+            root,
+            nullifier_hash,
+            &proof_recipient,
+            relayer_pubkey,
+            fee,
+            false,
+            refund,
+            amount,
+            remainder,
+            // The instance's cap also keeps the fee within the amount
+            tornado_instance.max_fee(withdrawn),
+            root_index,
+        )?;
+
+        Ok((tornado_instance, merkle_tree, withdrawn, marker_bump))
+    }
+
+    /// Check a withdrawal against the Merkle tree and verify its proof
+    ///
+    /// This performs the checks shared by SOL and token withdrawals: the fee
+    /// bound, nullifier reuse, root membership and age, the time-lock and the
+    /// zkSNARK proof. `max_fee` is the largest fee the withdrawal may pay, and
+    /// `fee_in_sol` whether the proof must commit to it being paid in lamports.
+    /// With a `root_index`, the root is only looked for at that index of the
+    /// root history.
+    #[allow(clippy::too_many_arguments)]
+    fn verify_withdrawal(
+        tornado_instance_key: &Pubkey,
+        tornado_instance: &TornadoInstance,
+        merkle_tree: &MerkleTree,
+        verifier_data: &[u8],
+        proof: &[u8],
+        root: &[u8; 32],
+        nullifier_hash: &[u8; 32],
+        recipient_pubkey: &Pubkey,
+        relayer_pubkey: &Pubkey,
+        fee: u64,
+        fee_in_sol: bool,
+        refund: u64,
+        amount: u64,
+        remainder: Option<(u64, &[u8; 32])>,
+        max_fee: u64,
+        root_index: Option<u8>,
+    ) -> ProgramResult {
+        // Check the fee against its bound
+        if fee > max_fee {
+            return Err(TornadoError::InvalidFee.into());
+        }
+
+        // Check the nullifier hashes spent before nullifier markers existed
         if nullifier_hash_exists(&merkle_tree.nullifier_hashes, nullifier_hash) {
             return Err(TornadoError::NullifierAlreadySpent.into());
         }
 
-        // Check if the root is known
-        if !is_known_root(root, &merkle_tree.roots, merkle_tree.current_root_index) {
-            return Err(TornadoError::InvalidMerkleRoot.into());
+        // Withdrawals wait until there are enough deposits to hide among
+        if merkle_tree.next_index < tornado_instance.min_anonymity_set {
+            msg!(
+                "The tree holds {} deposits, withdrawals need {}",
+                merkle_tree.next_index,
+                tornado_instance.min_anonymity_set
+            );
+            return Err(TornadoError::AnonymitySetTooSmall.into());
+        }
+
+        // Check if the root is known and recent enough
+        let age = match root_index {
+            Some(root_index) => root_age_at(
+                root,
+                &merkle_tree.roots,
+                merkle_tree.current_root_index,
+                merkle_tree.next_index,
+                root_index,
+            ),
+            None => root_age(root, &merkle_tree.roots, merkle_tree.current_root_index, merkle_tree.next_index),
+        }
+        .ok_or(TornadoError::InvalidMerkleRoot)?;
+        if tornado_instance.max_root_age != 0 && age > tornado_instance.max_root_age {
+            msg!("Root is {} roots old, the instance accepts at most {}", age, tornado_instance.max_root_age);
+            return Err(TornadoError::RootTooOld.into());
+        }
+
+        // Check the root has been in the tree for the time-lock
+        if tornado_instance.withdraw_delay_secs != 0 {
+            let roots_len = merkle_tree.roots.len();
+            let root_index = (merkle_tree.current_root_index as usize + roots_len - age as usize) % roots_len;
+            let inserted_at = *merkle_tree
+                .root_timestamps
+                .get(root_index)
+                .ok_or(TornadoError::InvalidMerkleTreeState)?;
+            let unlocks_at = inserted_at.saturating_add(tornado_instance.withdraw_delay_secs.into());
+            if Clock::get()?.unix_timestamp < unlocks_at {
+                msg!("Root is time-locked until {}", unlocks_at);
+                return Err(TornadoError::WithdrawTooEarly.into());
+            }
+        }
+
+        // Prepare the public inputs for the proof verification
+        let withdraw_inputs = WithdrawPublicInputs {
+            root: *root,
+            nullifier_hash: *nullifier_hash,
+            recipient: *recipient_pubkey,
+            relayer: *relayer_pubkey,
+            fee,
+            fee_in_sol,
+            refund,
+            instance: *tornado_instance_key,
+        };
+        let public_inputs = match remainder {
+            Some((output_amount, output_commitment)) => {
+                withdraw_inputs.to_partial_bytes(amount, output_amount, output_commitment)?.to_vec()
+            }
+            None if tornado_instance.variable_amount => withdraw_inputs.to_variable_amount_bytes(amount)?.to_vec(),
+            None => withdraw_inputs.to_bytes()?.to_vec(),
+        };
+
+        // Verify the proof
+        #[cfg(feature = "log_cu")]
+        {
+            msg!("Compute units before proof verification:");
+            sol_log_compute_units();
+        }
+        // The partial withdrawal verifier holds another circuit's key, found by its address alone
+        verify_tornado_proof(
+            tornado_instance.proof_system,
+            tornado_instance.curve,
+            remainder.is_none().then_some(&tornado_instance.vk_hash),
+            verifier_data,
+            proof,
+            &public_inputs,
+        )?;
+        #[cfg(feature = "log_cu")]
+        {
+            msg!("Compute units after proof verification:");
+            sol_log_compute_units();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{merkle_tree::{get_zero_value, FIELD_SIZE}, state::ROOT_HISTORY_SIZE, verifier::{PROOF_SYSTEM_GROTH16, PROOF_SYSTEM_PLONK}};
+    use ark_bn254::{Bn254, G1Affine, G2Affine};
+    use ark_ec::AffineRepr;
+    use ark_ff::{BigInteger, PrimeField};
+    use ark_groth16::VerifyingKey;
+    use ark_serialize::CanonicalSerialize;
+    use solana_program::{
+        entrypoint::SUCCESS,
+        program_stubs::{set_syscall_stubs, SyscallStubs},
+        rent::Rent,
+        system_program, sysvar,
+        sysvar::instructions::{construct_instructions_data, store_current_index, BorrowedInstruction},
+    };
+    use solana_sdk::{
+        ed25519_instruction,
+        feature_set::FeatureSet,
+        signature::{Keypair, Signer},
+    };
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    
+    // Syscall stubs that provide the rent sysvar to the processor
+    struct TestSyscallStubs;
+    
+    impl SyscallStubs for TestSyscallStubs {
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe {
+                *(var_addr as *mut Rent) = Rent::default();
+            }
+            SUCCESS
+        }
+
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe {
+                *(var_addr as *mut Clock) = Clock::default();
+            }
+            SUCCESS
+        }
+    }
+    
+    // Helper function to create a verifying key built from the curve generators
+    fn create_test_verifying_key() -> Vec<u8> {
+        let vk = VerifyingKey::<Bn254> {
+            alpha_g1: G1Affine::generator(),
+            beta_g2: G2Affine::generator(),
+            gamma_g2: G2Affine::generator(),
+            delta_g2: G2Affine::generator(),
+            gamma_abc_g1: vec![G1Affine::generator(); NUM_PUBLIC_INPUTS + 1],
+        };
+        
+        let mut vk_bytes = Vec::new();
+        vk.serialize_compressed(&mut vk_bytes).unwrap();
+        vk_bytes
+    }
+    
+    // A well-formed proof of generator points, which fails the pairing check
+    fn create_dummy_proof() -> Vec<u8> {
+        let g1 = G1Affine::generator();
+        let g2 = G2Affine::generator();
+        [g1.x, g1.y, g2.x.c0, g2.x.c1, g2.y.c0, g2.y.c1, g1.x, g1.y]
+            .iter()
+            .flat_map(|f| f.into_bigint().to_bytes_le())
+            .collect()
+    }
+    
+    // Helper function to create an account info
+    fn create_account_info<'a>(
+        key: &'a Pubkey,
+        is_signer: bool,
+        is_writable: bool,
+        lamports: &'a mut u64,
+        data: &'a mut [u8],
+        owner: &'a Pubkey,
+    ) -> AccountInfo<'a> {
+        AccountInfo {
+            key,
+            is_signer,
+            is_writable,
+            lamports: Rc::new(RefCell::new(lamports)),
+            data: Rc::new(RefCell::new(data)),
+            owner,
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+    
+    // Instruction data for the ed25519 program verifying one signature, laid out
+    // like `solana_sdk::ed25519_instruction::new_ed25519_instruction`
+    fn create_ed25519_instruction_data(pubkey: &Pubkey, signature: &[u8; 64], message: &[u8]) -> Vec<u8> {
+        let public_key_offset = ED25519_DATA_START as u16;
+        let signature_offset = public_key_offset + 32;
+        let message_offset = signature_offset + 64;
+        
+        let mut data = vec![1, 0];
+        for value in [
+            signature_offset,
+            u16::MAX,
+            public_key_offset,
+            u16::MAX,
+            message_offset,
+            message.len() as u16,
+            u16::MAX,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(pubkey.as_ref());
+        data.extend_from_slice(signature);
+        data.extend_from_slice(message);
+        data
+    }
+    
+    // Instructions sysvar data for a transaction executing the last of its instructions
+    fn create_instructions_sysvar_data(instructions: &[(Pubkey, Vec<u8>)]) -> Vec<u8> {
+        let borrowed_instructions: Vec<BorrowedInstruction> = instructions
+            .iter()
+            .map(|(program_id, data)| BorrowedInstruction {
+                program_id,
+                accounts: vec![],
+                data,
+            })
+            .collect();
+        let mut data = construct_instructions_data(&borrowed_instructions);
+        store_current_index(&mut data, (instructions.len() - 1) as u16);
+        data
+    }
+    
+    #[test]
+    fn test_process_initialize() {
+        set_syscall_stubs(Box::new(TestSyscallStubs));
+        
+        // Create program ID
+        let program_id = Pubkey::new_unique();
+        
+        // Create accounts
+        let payer_key = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let (verifier_key, _) = find_verifier_address(&program_id, &tornado_instance_key);
+        let (registry_key, _) = find_registry_address(&program_id);
+        let system_program_key = system_program::id();
+        
+        // Create account data
+        let verifying_key = create_test_verifying_key();
+        let mut payer_lamports = 1000000;
+        // The instance account starts out only partially funded
+        let instance_rent = Rent::default().minimum_balance(TornadoInstance::LEN);
+        let mut tornado_instance_lamports = instance_rent / 2;
+        let mut merkle_tree_lamports = 0;
+        let mut verifier_lamports = 0;
+        let mut registry_lamports = Rent::default().minimum_balance(Registry::get_account_size(1));
+        let mut system_program_lamports = 0;
+        
+        let mut payer_data = vec![0; 0];
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let merkle_tree_height = 10;
+        let root_history_size = 4;
+        let mut merkle_tree_data = vec![0; MerkleTree::get_account_size(merkle_tree_height, root_history_size)];
+        let mut verifier_data = vec![0; VerifierState::get_account_size(verifying_key.len())];
+        // The registry already has room for one entry, so it isn't grown
+        let mut registry_data = vec![0; Registry::get_account_size(1)];
+        let mut system_program_data = vec![0; 0];
+        
+        // Create account infos
+        let payer_account = create_account_info(
+            &payer_key,
+            true,
+            true,
+            &mut payer_lamports,
+            &mut payer_data,
+            &system_program_key,
+        );
+        
+        let tornado_instance_account = create_account_info(
+            &tornado_instance_key,
+            false,
+            true,
+            &mut tornado_instance_lamports,
+            &mut tornado_instance_data,
+            &program_id,
+        );
+        
+        let merkle_tree_account = create_account_info(
+            &merkle_tree_key,
+            false,
+            true,
+            &mut merkle_tree_lamports,
+            &mut merkle_tree_data,
+            &program_id,
+        );
+        
+        let verifier_account = create_account_info(
+            &verifier_key,
+            false,
+            true,
+            &mut verifier_lamports,
+            &mut verifier_data,
+            &program_id,
+        );
+        
+        let registry_account = create_account_info(
+            &registry_key,
+            false,
+            true,
+            &mut registry_lamports,
+            &mut registry_data,
+            &program_id,
+        );
+        
+        let system_program_account = create_account_info(
+            &system_program_key,
+            false,
+            false,
+            &mut system_program_lamports,
+            &mut system_program_data,
+            &system_program_key,
+        );
+        
+        // Create accounts array
+        let accounts = vec![
+            payer_account,
+            tornado_instance_account,
+            merkle_tree_account,
+            verifier_account,
+            registry_account,
+            system_program_account,
+        ];
+        
+        // Create instruction data
+        let denomination = 100000;
+        let instruction = TornadoInstruction::Initialize {
+            denomination,
+            merkle_tree_height,
+            root_history_size,
+            max_root_age: 3,
+            token_mint: None,
+            variable_amount: false,
+            max_fee_bps: 50,
+            hash_algo: HASH_ALGO_MIMC,
+            curve: CURVE_BN254,
+            strict_tx: true,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            verifying_key: verifying_key.clone(),
+        };
+        let instruction_data = instruction.try_to_vec().unwrap();
+        
+        // A partially funded account is uninitialized, but can't hold the instance until it is rent-exempt
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(ProgramError::AccountNotRentExempt));
+        **accounts[1].lamports.borrow_mut() = instance_rent;
+        
+        // Process the instruction
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        
+        // Check the result
+        assert!(result.is_ok());
+        
+        // Check the tornado instance data
+        let tornado_instance = TornadoInstance::unpack(&accounts[1].data.borrow()).unwrap();
+        assert!(tornado_instance.is_initialized);
+        assert_eq!(tornado_instance.denomination, denomination);
+        assert_eq!(tornado_instance.merkle_tree_height, merkle_tree_height);
+        assert_eq!(tornado_instance.merkle_tree, merkle_tree_key);
+        assert_eq!(tornado_instance.verifier, verifier_key);
+        assert_eq!(tornado_instance.admin, payer_key);
+        assert!(!tornado_instance.paused);
+        assert_eq!(tornado_instance.max_fee_bps, 50);
+        assert_eq!(tornado_instance.max_root_age, 3);
+        assert!(tornado_instance.strict_tx);
+        assert_eq!(tornado_instance.proof_system, PROOF_SYSTEM_GROTH16);
+        assert_eq!(
+            tornado_instance.vault_authority_bump,
+            find_vault_authority_address(&program_id, &tornado_instance_key).1
+        );
+
+        // Check the Merkle tree data
+        let merkle_tree = MerkleTree::deserialize(&mut &accounts[2].data.borrow()[..]).unwrap();
+        assert!(merkle_tree.is_initialized);
+        assert_eq!(merkle_tree.height, merkle_tree_height);
+        assert_eq!(merkle_tree.next_index, 0);
+        assert_eq!(merkle_tree.roots.len(), root_history_size as usize);
+        assert_eq!(merkle_tree.filled_subtrees.len(), merkle_tree_height as usize);
+        for (level, subtree) in merkle_tree.filled_subtrees.iter().enumerate() {
+            assert_eq!(*subtree, get_zero_value(HASH_ALGO_MIMC, level).unwrap());
+        }
+        assert_eq!(
+            merkle_tree.roots[0],
+            get_zero_value(HASH_ALGO_MIMC, merkle_tree_height as usize).unwrap()
+        );
+        
+        // Check the verifier data
+        let verifier_state = VerifierState::try_from_slice(&accounts[3].data.borrow()).unwrap();
+        assert!(verifier_state.is_initialized);
+        assert_eq!(verifier_state.verifying_key, verifying_key);
+        
+        // Check the instance was registered
+        let registry = Registry::deserialize(&mut &accounts[4].data.borrow()[..]).unwrap();
+        assert!(registry.is_initialized);
+        assert_eq!(
+            registry.instances,
+            vec![RegistryEntry {
+                denomination,
+                instance: tornado_instance_key,
+                token_mint: None,
+                admin: payer_key,
+                vk_hash: hash_verifying_key(&verifying_key),
+            }]
+        );
+        
+        // The instance can't be initialized again
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::AccountAlreadyInitialized.into()));
+    }
+    
+    #[test]
+    fn test_process_initialize_invalid_verifying_key() {
+        // Create program ID
+        let program_id = Pubkey::new_unique();
+        
+        // Create accounts
+        let payer_key = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let (verifier_key, _) = find_verifier_address(&program_id, &tornado_instance_key);
+        let (registry_key, _) = find_registry_address(&program_id);
+        let system_program_key = system_program::id();
+        
+        // Create account data
+        let mut payer_lamports = 1000000;
+        let mut tornado_instance_lamports = 0;
+        let mut merkle_tree_lamports = 0;
+        let mut verifier_lamports = 0;
+        let mut registry_lamports = 0;
+        let mut system_program_lamports = 0;
+        
+        let mut payer_data = vec![0; 0];
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let mut merkle_tree_data = vec![0; 0];
+        let mut verifier_data = vec![0; 0];
+        let mut registry_data = vec![0; 0];
+        let mut system_program_data = vec![0; 0];
+        
+        // Create accounts array
+        let accounts = vec![
+            create_account_info(
+                &payer_key,
+                true,
+                true,
+                &mut payer_lamports,
+                &mut payer_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &tornado_instance_key,
+                false,
+                true,
+                &mut tornado_instance_lamports,
+                &mut tornado_instance_data,
+                &program_id,
+            ),
+            create_account_info(
+                &merkle_tree_key,
+                false,
+                true,
+                &mut merkle_tree_lamports,
+                &mut merkle_tree_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &verifier_key,
+                false,
+                true,
+                &mut verifier_lamports,
+                &mut verifier_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &registry_key,
+                false,
+                true,
+                &mut registry_lamports,
+                &mut registry_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &system_program_key,
+                false,
+                false,
+                &mut system_program_lamports,
+                &mut system_program_data,
+                &system_program_key,
+            ),
+        ];
+        
+        // Create instruction data with a key that isn't a valid `VerifyingKey<Bn254>`
+        let instruction = TornadoInstruction::Initialize {
+            denomination: 100000,
+            merkle_tree_height: 10,
+            root_history_size: ROOT_HISTORY_SIZE as u8,
+            max_root_age: 0,
+            token_mint: None,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            hash_algo: HASH_ALGO_MIMC,
+            curve: CURVE_BN254,
+            strict_tx: false,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            verifying_key: vec![0xFF; 64],
+        };
+        let instruction_data = instruction.try_to_vec().unwrap();
+        
+        // Process the instruction
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidVerifyingKey.into()));
+    }
+    
+    #[test]
+    fn test_process_initialize_tree_too_large() {
+        // Create program ID
+        let program_id = Pubkey::new_unique();
+        
+        // Create accounts
+        let payer_key = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let (verifier_key, _) = find_verifier_address(&program_id, &tornado_instance_key);
+        let (registry_key, _) = find_registry_address(&program_id);
+        let system_program_key = system_program::id();
+        
+        // Create account data
+        let mut payer_lamports = 1000000;
+        let mut tornado_instance_lamports = 0;
+        let mut merkle_tree_lamports = 0;
+        let mut verifier_lamports = 0;
+        let mut registry_lamports = 0;
+        let mut system_program_lamports = 0;
+        
+        let mut payer_data = vec![0; 0];
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let mut merkle_tree_data = vec![0; 0];
+        let mut verifier_data = vec![0; 0];
+        let mut registry_data = vec![0; 0];
+        let mut system_program_data = vec![0; 0];
+        
+        // Create accounts array
+        let accounts = vec![
+            create_account_info(
+                &payer_key,
+                true,
+                true,
+                &mut payer_lamports,
+                &mut payer_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &tornado_instance_key,
+                false,
+                true,
+                &mut tornado_instance_lamports,
+                &mut tornado_instance_data,
+                &program_id,
+            ),
+            create_account_info(
+                &merkle_tree_key,
+                false,
+                true,
+                &mut merkle_tree_lamports,
+                &mut merkle_tree_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &verifier_key,
+                false,
+                true,
+                &mut verifier_lamports,
+                &mut verifier_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &registry_key,
+                false,
+                true,
+                &mut registry_lamports,
+                &mut registry_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &system_program_key,
+                false,
+                false,
+                &mut system_program_lamports,
+                &mut system_program_data,
+                &system_program_key,
+            ),
+        ];
+        
+        for (denomination, merkle_tree_height, root_history_size, max_fee_bps, error) in [
+            // The root history must hold at least the current root
+            (100000, 10, 0, MAX_FEE_BPS, TornadoError::InvalidInstructionData),
+            // Heights outside 1..=MAX_SUPPORTED_HEIGHT and a zero denomination are rejected
+            (100000, 0, ROOT_HISTORY_SIZE as u8, MAX_FEE_BPS, TornadoError::InvalidMerkleTreeHeight),
+            (100000, MAX_SUPPORTED_HEIGHT + 1, ROOT_HISTORY_SIZE as u8, MAX_FEE_BPS, TornadoError::InvalidMerkleTreeHeight),
+            (100000, u8::MAX, ROOT_HISTORY_SIZE as u8, MAX_FEE_BPS, TornadoError::InvalidMerkleTreeHeight),
+            (0, 10, ROOT_HISTORY_SIZE as u8, MAX_FEE_BPS, TornadoError::InvalidDenomination),
+            // A fee cap above 100% is rejected
+            (100000, 10, ROOT_HISTORY_SIZE as u8, MAX_FEE_BPS + 1, TornadoError::InvalidFee),
+        ] {
+            let instruction = TornadoInstruction::Initialize {
+                denomination,
+                merkle_tree_height,
+                root_history_size,
+                max_root_age: 0,
+                token_mint: None,
+                variable_amount: false,
+                max_fee_bps,
+                hash_algo: HASH_ALGO_MIMC,
+                curve: CURVE_BN254,
+                strict_tx: false,
+                proof_system: PROOF_SYSTEM_GROTH16,
+                verifying_key: create_test_verifying_key(),
+            };
+            let instruction_data = instruction.try_to_vec().unwrap();
+            
+            let result = Processor::process(&program_id, &accounts, &instruction_data);
+            assert_eq!(result, Err(error.into()));
+        }
+        
+        // Only MiMC and Poseidon trees are supported
+        let instruction = TornadoInstruction::Initialize {
+            denomination: 100000,
+            merkle_tree_height: 10,
+            root_history_size: ROOT_HISTORY_SIZE as u8,
+            max_root_age: 0,
+            token_mint: None,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            hash_algo: HASH_ALGO_POSEIDON + 1,
+            curve: CURVE_BN254,
+            strict_tx: false,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            verifying_key: create_test_verifying_key(),
+        };
+        let instruction_data = instruction.try_to_vec().unwrap();
+        assert_eq!(
+            Processor::process(&program_id, &accounts, &instruction_data),
+            Err(TornadoError::UnsupportedHashAlgorithm.into())
+        );
+
+        // A root age cap must be below the root history size
+        let instruction = TornadoInstruction::Initialize {
+            denomination: 100000,
+            merkle_tree_height: 10,
+            root_history_size: ROOT_HISTORY_SIZE as u8,
+            max_root_age: ROOT_HISTORY_SIZE as u8,
+            token_mint: None,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            hash_algo: HASH_ALGO_MIMC,
+            curve: CURVE_BN254,
+            strict_tx: false,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            verifying_key: create_test_verifying_key(),
+        };
+        let instruction_data = instruction.try_to_vec().unwrap();
+        assert_eq!(
+            Processor::process(&program_id, &accounts, &instruction_data),
+            Err(TornadoError::InvalidInstructionData.into())
+        );
+
+        // Only SOL instances can require withdrawals to be alone in their transaction
+        let instruction = TornadoInstruction::Initialize {
+            denomination: 100000,
+            merkle_tree_height: 10,
+            root_history_size: ROOT_HISTORY_SIZE as u8,
+            max_root_age: 0,
+            token_mint: Some(Pubkey::new_unique()),
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            hash_algo: HASH_ALGO_MIMC,
+            curve: CURVE_BN254,
+            strict_tx: true,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            verifying_key: create_test_verifying_key(),
+        };
+        let instruction_data = instruction.try_to_vec().unwrap();
+        assert_eq!(
+            Processor::process(&program_id, &accounts, &instruction_data),
+            Err(TornadoError::InvalidInstructionData.into())
+        );
+
+        // Only BN254 and BLS12-381 proofs are supported, and the key must be over the chosen curve
+        for (curve, error) in [
+            (CURVE_BLS12_381 + 1, TornadoError::UnsupportedCurve),
+            (CURVE_BLS12_381, TornadoError::InvalidVerifyingKey),
+        ] {
+            let instruction = TornadoInstruction::Initialize {
+                denomination: 100000,
+                merkle_tree_height: 10,
+                root_history_size: ROOT_HISTORY_SIZE as u8,
+                max_root_age: 0,
+                token_mint: None,
+                variable_amount: false,
+                max_fee_bps: MAX_FEE_BPS,
+                hash_algo: HASH_ALGO_MIMC,
+                curve,
+                strict_tx: false,
+                proof_system: PROOF_SYSTEM_GROTH16,
+                verifying_key: create_test_verifying_key(),
+            };
+            let instruction_data = instruction.try_to_vec().unwrap();
+            assert_eq!(
+                Processor::process(&program_id, &accounts, &instruction_data),
+                Err(error.into())
+            );
+        }
+
+        // Only Groth16 proofs are supported until PLONK is implemented
+        for proof_system in [PROOF_SYSTEM_PLONK, PROOF_SYSTEM_PLONK + 1] {
+            let instruction = TornadoInstruction::Initialize {
+                denomination: 100000,
+                merkle_tree_height: 10,
+                root_history_size: ROOT_HISTORY_SIZE as u8,
+                max_root_age: 0,
+                token_mint: None,
+                variable_amount: false,
+                max_fee_bps: MAX_FEE_BPS,
+                hash_algo: HASH_ALGO_MIMC,
+                curve: CURVE_BN254,
+                strict_tx: false,
+                proof_system,
+                verifying_key: create_test_verifying_key(),
+            };
+            let instruction_data = instruction.try_to_vec().unwrap();
+            assert_eq!(
+                Processor::process(&program_id, &accounts, &instruction_data),
+                Err(TornadoError::UnsupportedProofSystem.into())
+            );
+        }
+    }
+
+    #[test]
+    fn test_process_deposit() {
+        // Create program ID
+        let program_id = Pubkey::new_unique();
+        
+        // Create accounts
+        let payer_key = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let commitment = [1u8; 32];
+        let (commitment_marker_key, _) =
+            find_commitment_address(&program_id, &tornado_instance_key, &commitment);
+        let system_program_key = system_program::id();
+        
+        // Create account data
+        let mut payer_lamports = 1000000;
+        let mut tornado_instance_lamports = 0;
+        let mut merkle_tree_lamports = 0;
+        let mut commitment_marker_lamports = 0;
+        let mut system_program_lamports = 0;
+        
+        let mut payer_data = vec![0; 0];
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let mut commitment_marker_data = vec![0; CommitmentMarker::LEN];
+        let mut system_program_data = vec![0; 0];
+        
+        // Initialize tornado instance
+        let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 20,
+            merkle_tree: merkle_tree_key,
+            verifier: Pubkey::new_unique(),
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        };
+        tornado_instance.pack_into_slice(&mut tornado_instance_data);
+        
+        // Initialize merkle tree
+        let merkle_tree = MerkleTree::new(20, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        let mut merkle_tree_data = merkle_tree.try_to_vec().unwrap();
+        
+        // The commitment was already deposited, so its marker exists
+        CommitmentMarker::pack(
+            CommitmentMarker {
+                is_initialized: true,
+                leaf_index: 0,
+            },
+            &mut commitment_marker_data,
+        )
+        .unwrap();
+        
+        // Create account infos
+        let payer_account = create_account_info(
+            &payer_key,
+            true,
+            true,
+            &mut payer_lamports,
+            &mut payer_data,
+            &system_program_key,
+        );
+        
+        let tornado_instance_account = create_account_info(
+            &tornado_instance_key,
+            false,
+            true,
+            &mut tornado_instance_lamports,
+            &mut tornado_instance_data,
+            &program_id,
+        );
+        
+        let merkle_tree_account = create_account_info(
+            &merkle_tree_key,
+            false,
+            true,
+            &mut merkle_tree_lamports,
+            &mut merkle_tree_data,
+            &program_id,
+        );
+        
+        let commitment_marker_account = create_account_info(
+            &commitment_marker_key,
+            false,
+            true,
+            &mut commitment_marker_lamports,
+            &mut commitment_marker_data,
+            &program_id,
+        );
+        
+        let system_program_account = create_account_info(
+            &system_program_key,
+            false,
+            false,
+            &mut system_program_lamports,
+            &mut system_program_data,
+            &system_program_key,
+        );
+        
+        // Create accounts array
+        let accounts = vec![
+            payer_account.clone(),
+            tornado_instance_account,
+            merkle_tree_account,
+            commitment_marker_account,
+            system_program_account,
+            payer_account,
+        ];
+        
+        // Create instruction data
+        let instruction = TornadoInstruction::Deposit {
+            commitment,
+            encrypted_note: None,
+            expected_index: None,
+            max_deposit_fee: None,
+        };
+        let instruction_data = instruction.try_to_vec().unwrap();
+        
+        // A commitment with an existing marker is rejected
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::CommitmentAlreadyExists.into()));
+        
+        // The payer must sign
+        let mut unsigned_accounts = accounts.clone();
+        unsigned_accounts[0] = AccountInfo {
+            is_signer: false,
+            ..accounts[0].clone()
+        };
+        let result = Processor::process(&program_id, &unsigned_accounts, &instruction_data);
+        assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+        
+        // The funding account must be passed, even when it is the payer
+        let result = Processor::process(&program_id, &accounts[..5], &instruction_data);
+        assert_eq!(result, Err(ProgramError::NotEnoughAccountKeys));
+        
+        // Every account the deposit writes to must be writable
+        for index in [0, 1, 2, 3] {
+            let mut read_only_accounts = accounts.clone();
+            read_only_accounts[index] = AccountInfo {
+                is_writable: false,
+                ..accounts[index].clone()
+            };
+            let result = Processor::process(&program_id, &read_only_accounts, &instruction_data);
+            assert_eq!(result, Err(TornadoError::AccountNotWritable.into()));
+        }
+        
+        // A Merkle tree account not owned by the program is rejected
+        let foreign_owner = Pubkey::new_unique();
+        let mut foreign_tree_accounts = accounts.clone();
+        foreign_tree_accounts[2] = AccountInfo {
+            owner: &foreign_owner,
+            ..accounts[2].clone()
+        };
+        let result = Processor::process(&program_id, &foreign_tree_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+        
+        // So is a tree the instance points to that isn't its PDA
+        let fake_tree_key = Pubkey::new_unique();
+        let mut corrupted_instance_data = vec![0; TornadoInstance::LEN];
+        TornadoInstance {
+            merkle_tree: fake_tree_key,
+            ..tornado_instance
+        }
+        .pack_into_slice(&mut corrupted_instance_data);
+        let mut corrupted_accounts = accounts.clone();
+        corrupted_accounts[1] = AccountInfo {
+            data: Rc::new(RefCell::new(&mut corrupted_instance_data[..])),
+            ..accounts[1].clone()
+        };
+        corrupted_accounts[2] = AccountInfo {
+            key: &fake_tree_key,
+            ..accounts[2].clone()
+        };
+        let result = Processor::process(&program_id, &corrupted_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+
+        // A marker account that doesn't match the commitment is rejected
+        let instruction_data = TornadoInstruction::Deposit {
+            commitment: [2u8; 32],
+            encrypted_note: None,
+            expected_index: None,
+            max_deposit_fee: None,
+        }
+        .try_to_vec()
+        .unwrap();
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+
+        // A commitment outside the field is rejected, from the field size up
+        let mut above_field = FIELD_SIZE;
+        above_field[31] += 1;
+        for commitment in [FIELD_SIZE, above_field, [0xff; 32]] {
+            let instruction_data = TornadoInstruction::Deposit {
+                commitment,
+                encrypted_note: None,
+                expected_index: None,
+                max_deposit_fee: None,
+            }
+            .try_to_vec()
+            .unwrap();
+            let result = Processor::process(&program_id, &accounts, &instruction_data);
+            assert_eq!(result, Err(TornadoError::InvalidCommitment.into()));
+        }
+
+        // Zero and the empty-leaf value are rejected
+        for commitment in [[0u8; 32], ZERO_VALUE] {
+            let instruction_data = TornadoInstruction::Deposit {
+                commitment,
+                encrypted_note: None,
+                expected_index: None,
+                max_deposit_fee: None,
+            }
+            .try_to_vec()
+            .unwrap();
+            let result = Processor::process(&program_id, &accounts, &instruction_data);
+            assert_eq!(result, Err(TornadoError::InvalidCommitment.into()));
+        }
+        
+        // A fixed-denomination instance rejects variable-amount deposits
+        let deposit_variable = |amount| {
+            TornadoInstruction::DepositVariable { commitment, amount }
+                .try_to_vec()
+                .unwrap()
+        };
+        let result = Processor::process(&program_id, &accounts, &deposit_variable(100000));
+        assert_eq!(result, Err(TornadoError::InvalidInstructionData.into()));
+        
+        // A variable-amount instance only accepts amounts up to the denomination
+        let variable_instance = TornadoInstance {
+            variable_amount: true,
+            ..tornado_instance
+        };
+        variable_instance.pack_into_slice(&mut accounts[1].data.borrow_mut());
+        let result = Processor::process(&program_id, &accounts, &TornadoInstruction::Deposit { commitment, encrypted_note: None, expected_index: None, max_deposit_fee: None }.try_to_vec().unwrap());
+        assert_eq!(result, Err(TornadoError::InvalidInstructionData.into()));
+        for amount in [0, 100001] {
+            let result = Processor::process(&program_id, &accounts, &deposit_variable(amount));
+            assert_eq!(result, Err(TornadoError::InvalidAmount.into()));
+        }
+        
+        // The marker is derived from the leaf binding the amount, not the bare commitment
+        let result = Processor::process(&program_id, &accounts, &deposit_variable(500));
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+        
+        // The Merkle tree is left untouched
+        let stored_tree = MerkleTree::try_from_slice(&accounts[2].data.borrow()).unwrap();
+        assert_eq!(stored_tree, merkle_tree);
+    }
+
+    #[test]
+    fn test_process_deposit_batch() {
+        let program_id = Pubkey::new_unique();
+        
+        let payer_key = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let system_program_key = system_program::id();
+        let commitments = [[1u8; 32], [2u8; 32], [3u8; 32]];
+        let marker_keys: Vec<Pubkey> = commitments
+            .iter()
+            .map(|commitment| find_commitment_address(&program_id, &tornado_instance_key, commitment).0)
+            .collect();
+        
+        let mut payer_lamports = 1000000;
+        let mut tornado_instance_lamports = 0;
+        let mut merkle_tree_lamports = 0;
+        let mut system_program_lamports = 0;
+        let mut marker_lamports = [0u64; 3];
+        
+        let mut payer_data = vec![0; 0];
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let mut system_program_data = vec![0; 0];
+        let mut first_marker_data = vec![0; 0];
+        let mut second_marker_data = vec![0; 0];
+        let mut third_marker_data = vec![0; CommitmentMarker::LEN];
+        
+        let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 20,
+            merkle_tree: merkle_tree_key,
+            verifier: Pubkey::new_unique(),
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        };
+        tornado_instance.pack_into_slice(&mut tornado_instance_data);
+        
+        let merkle_tree = MerkleTree::new(20, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        let mut merkle_tree_data = merkle_tree.try_to_vec().unwrap();
+        
+        // The third commitment was already deposited
+        CommitmentMarker::pack(
+            CommitmentMarker {
+                is_initialized: true,
+                leaf_index: 0,
+            },
+            &mut third_marker_data,
+        )
+        .unwrap();
+        
+        let [first_marker_lamports, second_marker_lamports, third_marker_lamports] = &mut marker_lamports;
+        let accounts = vec![
+            create_account_info(&payer_key, true, true, &mut payer_lamports, &mut payer_data, &system_program_key),
+            create_account_info(
+                &tornado_instance_key,
+                false,
+                true,
+                &mut tornado_instance_lamports,
+                &mut tornado_instance_data,
+                &program_id,
+            ),
+            create_account_info(
+                &merkle_tree_key,
+                false,
+                true,
+                &mut merkle_tree_lamports,
+                &mut merkle_tree_data,
+                &program_id,
+            ),
+            create_account_info(
+                &system_program_key,
+                false,
+                false,
+                &mut system_program_lamports,
+                &mut system_program_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &marker_keys[0],
+                false,
+                true,
+                first_marker_lamports,
+                &mut first_marker_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &marker_keys[1],
+                false,
+                true,
+                second_marker_lamports,
+                &mut second_marker_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &marker_keys[2],
+                false,
+                true,
+                third_marker_lamports,
+                &mut third_marker_data,
+                &program_id,
+            ),
+        ];
+        let deposit_batch = |commitments: &[[u8; 32]]| {
+            TornadoInstruction::DepositBatch {
+                commitments: commitments.to_vec(),
+                max_deposit_fee: None,
+            }
+            .try_to_vec()
+            .unwrap()
+        };
+        
+        // A duplicate third commitment rejects the whole batch
+        let result = Processor::process(&program_id, &accounts, &deposit_batch(&commitments));
+        assert_eq!(result, Err(TornadoError::CommitmentAlreadyExists.into()));
+        
+        // So does a commitment repeated within the batch
+        let result = Processor::process(
+            &program_id,
+            &accounts,
+            &deposit_batch(&[commitments[0], commitments[1], commitments[0]]),
+        );
+        assert_eq!(result, Err(TornadoError::CommitmentAlreadyExists.into()));
+        
+        // Empty and oversized batches are rejected
+        let result = Processor::process(&program_id, &accounts, &deposit_batch(&[]));
+        assert_eq!(result, Err(TornadoError::InvalidInstructionData.into()));
+        let result = Processor::process(
+            &program_id,
+            &accounts,
+            &deposit_batch(&vec![[1u8; 32]; MAX_DEPOSIT_BATCH_SIZE + 1]),
+        );
+        assert_eq!(result, Err(TornadoError::InvalidInstructionData.into()));
+        
+        // Missing marker accounts are rejected
+        let result = Processor::process(&program_id, &accounts[..6], &deposit_batch(&commitments));
+        assert_eq!(result, Err(ProgramError::NotEnoughAccountKeys));
+        
+        // The payer must sign
+        let mut unsigned_accounts = accounts.clone();
+        unsigned_accounts[0] = AccountInfo {
+            is_signer: false,
+            ..accounts[0].clone()
+        };
+        let result = Processor::process(&program_id, &unsigned_accounts, &deposit_batch(&commitments[..2]));
+        assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+        
+        // Every account the batch writes to must be writable, including each marker
+        for index in [0, 1, 2, 4, 5] {
+            let mut read_only_accounts = accounts.clone();
+            read_only_accounts[index] = AccountInfo {
+                is_writable: false,
+                ..accounts[index].clone()
+            };
+            let result = Processor::process(&program_id, &read_only_accounts, &deposit_batch(&commitments[..2]));
+            assert_eq!(result, Err(TornadoError::AccountNotWritable.into()));
+        }
+        
+        // Nothing was transferred or written
+        assert_eq!(**accounts[0].lamports.borrow(), 1000000);
+        assert_eq!(**accounts[1].lamports.borrow(), 0);
+        let stored_tree = MerkleTree::try_from_slice(&accounts[2].data.borrow()).unwrap();
+        assert_eq!(stored_tree, merkle_tree);
+        assert!(accounts[4].data_is_empty());
+        assert!(accounts[5].data_is_empty());
+    }
+
+    #[test]
+    fn test_process_get_root() {
+        let program_id = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        
+        let mut tornado_instance_lamports = 0;
+        let mut merkle_tree_lamports = 0;
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        
+        let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 3,
+            merkle_tree: merkle_tree_key,
+            verifier: Pubkey::new_unique(),
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        };
+        tornado_instance.pack_into_slice(&mut tornado_instance_data);
+        
+        let mut merkle_tree = MerkleTree::new(3, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        for i in 0..3u8 {
+            Processor::insert_commitment(&mut merkle_tree, &[i + 1; 32], 0).unwrap();
+        }
+        let mut merkle_tree_data = merkle_tree.try_to_vec().unwrap();
+        
+        let accounts = vec![
+            create_account_info(
+                &tornado_instance_key,
+                false,
+                false,
+                &mut tornado_instance_lamports,
+                &mut tornado_instance_data,
+                &program_id,
+            ),
+            create_account_info(
+                &merkle_tree_key,
+                false,
+                false,
+                &mut merkle_tree_lamports,
+                &mut merkle_tree_data,
+                &program_id,
+            ),
+        ];
+        let instruction_data = TornadoInstruction::GetRoot.try_to_vec().unwrap();
+        
+        // The root after three deposits is read without modifying the tree
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Ok(()));
+        let stored_tree = MerkleTree::try_from_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(latest_root_with_index(&stored_tree), (merkle_tree.roots[3], 3));
+        
+        // The Merkle tree must be the instance's program-owned PDA
+        let foreign_owner = Pubkey::new_unique();
+        let foreign_tree_accounts = vec![
+            accounts[0].clone(),
+            AccountInfo {
+                owner: &foreign_owner,
+                ..accounts[1].clone()
+            },
+        ];
+        let result = Processor::process(&program_id, &foreign_tree_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+        
+        // The instance must be initialized
+        TornadoInstance::default().pack_into_slice(&mut accounts[0].data.borrow_mut());
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(ProgramError::UninitializedAccount));
+    }
+
+    #[test]
+    fn test_process_self_check() {
+        set_syscall_stubs(Box::new(TestSyscallStubs));
+        
+        let program_id = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let (verifier_key, _) = find_verifier_address(&program_id, &tornado_instance_key);
+        let denomination = 100000;
+        
+        let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination,
+            merkle_tree_height: 3,
+            merkle_tree: merkle_tree_key,
+            verifier: verifier_key,
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        };
+        
+        // Three deposits, one of them withdrawn
+        let mut merkle_tree = MerkleTree::new(3, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        for i in 0..3u8 {
+            Processor::insert_commitment(&mut merkle_tree, &[i + 1; 32], 0).unwrap();
+        }
+        merkle_tree.nullifier_count = 1;
+        let balance = Rent::default().minimum_balance(TornadoInstance::LEN) + 2 * denomination;
+        
+        let instruction_data = TornadoInstruction::SelfCheck.try_to_vec().unwrap();
+        let self_check = |tornado_instance: &TornadoInstance, merkle_tree: &MerkleTree, mut lamports: u64, owner: &Pubkey| {
+            let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+            tornado_instance.pack_into_slice(&mut tornado_instance_data);
+            let mut merkle_tree_data = merkle_tree.try_to_vec().unwrap();
+            let mut merkle_tree_lamports = 0;
+            let mut verifier_lamports = 0;
+            let mut verifier_data = vec![];
+            let accounts = vec![
+                create_account_info(
+                    &tornado_instance_key,
+                    false,
+                    false,
+                    &mut lamports,
+                    &mut tornado_instance_data,
+                    &program_id,
+                ),
+                create_account_info(&merkle_tree_key, false, false, &mut merkle_tree_lamports, &mut merkle_tree_data, owner),
+                create_account_info(&verifier_key, false, false, &mut verifier_lamports, &mut verifier_data, &program_id),
+            ];
+            Processor::process(&program_id, &accounts, &instruction_data)
+        };
+        
+        // A healthy instance passes, with or without lamports sent to it directly
+        assert_eq!(self_check(&tornado_instance, &merkle_tree, balance, &program_id), Ok(()));
+        assert_eq!(self_check(&tornado_instance, &merkle_tree, balance + 1, &program_id), Ok(()));
+        
+        // Each injected inconsistency is caught
+        let inconsistent: ProgramResult = Err(TornadoError::InconsistentState.into());
+        assert_eq!(self_check(&tornado_instance, &merkle_tree, balance - 1, &program_id), inconsistent);
+        let foreign_owner = Pubkey::new_unique();
+        assert_eq!(self_check(&tornado_instance, &merkle_tree, balance, &foreign_owner), inconsistent);
+        let wrong_verifier = TornadoInstance {
+            verifier: Pubkey::new_unique(),
+            ..tornado_instance
+        };
+        assert_eq!(self_check(&wrong_verifier, &merkle_tree, balance, &program_id), inconsistent);
+        for corrupt in [
+            |tree: &mut MerkleTree| {
+                tree.next_index = 9;
+                tree.nullifier_count = 7;
+            },
+            |tree: &mut MerkleTree| tree.current_root_index = ROOT_HISTORY_SIZE as u8,
+            |tree: &mut MerkleTree| {
+                tree.filled_subtrees.pop();
+            },
+            |tree: &mut MerkleTree| tree.nullifier_count = 4,
+            |tree: &mut MerkleTree| tree.version += 1,
+        ] {
+            let mut corrupt_tree = MerkleTree::try_from_slice(&merkle_tree.try_to_vec().unwrap()).unwrap();
+            corrupt(&mut corrupt_tree);
+            assert_eq!(self_check(&tornado_instance, &corrupt_tree, balance, &program_id), inconsistent);
+        }
+        
+        // The balance of a variable-amount instance isn't checked
+        let variable_instance = TornadoInstance {
+            variable_amount: true,
+            ..tornado_instance
+        };
+        assert_eq!(self_check(&variable_instance, &merkle_tree, 0, &program_id), Ok(()));
+    }
+    
+    #[test]
+    fn test_process_invalidate_root() {
+        let program_id = Pubkey::new_unique();
+        let admin_key = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let (verifier_key, _) = find_verifier_address(&program_id, &tornado_instance_key);
+        let system_program_key = system_program::id();
+        
+        let mut admin_lamports = 0;
+        let mut tornado_instance_lamports = 0;
+        let mut merkle_tree_lamports = 0;
+        let mut admin_data = vec![0; 0];
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        
+        let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 3,
+            merkle_tree: merkle_tree_key,
+            verifier: verifier_key,
+            token_mint: None,
+            admin: admin_key,
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        };
+        tornado_instance.pack_into_slice(&mut tornado_instance_data);
+        
+        let mut merkle_tree = MerkleTree::new(3, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        for i in 0..3u8 {
+            Processor::insert_commitment(&mut merkle_tree, &[i + 1; 32], 0).unwrap();
+        }
+        let mut merkle_tree_data = merkle_tree.try_to_vec().unwrap();
+        let (bad_root, good_root) = (merkle_tree.roots[2], merkle_tree.roots[3]);
+        
+        let accounts = vec![
+            create_account_info(
+                &admin_key,
+                true,
+                false,
+                &mut admin_lamports,
+                &mut admin_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &tornado_instance_key,
+                false,
+                false,
+                &mut tornado_instance_lamports,
+                &mut tornado_instance_data,
+                &program_id,
+            ),
+            create_account_info(
+                &merkle_tree_key,
+                false,
+                true,
+                &mut merkle_tree_lamports,
+                &mut merkle_tree_data,
+                &program_id,
+            ),
+        ];
+        let instruction_data = TornadoInstruction::InvalidateRoot { root: bad_root }.try_to_vec().unwrap();
+        
+        // Only the admin can invalidate a root
+        let other_key = Pubkey::new_unique();
+        let mut other_accounts = accounts.clone();
+        other_accounts[0] = AccountInfo {
+            key: &other_key,
+            ..accounts[0].clone()
+        };
+        let result = Processor::process(&program_id, &other_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::Unauthorized.into()));
+        let mut unsigned_accounts = accounts.clone();
+        unsigned_accounts[0].is_signer = false;
+        let result = Processor::process(&program_id, &unsigned_accounts, &instruction_data);
+        assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+        
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Ok(()));
+        let stored_tree = MerkleTree::try_from_slice(&accounts[2].data.borrow()).unwrap();
+        assert_eq!(stored_tree.roots[2], [0u8; 32]);
+        assert_eq!(stored_tree.next_index, merkle_tree.next_index);
+        
+        // A root that is no longer in the history can't be invalidated again
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidMerkleRoot.into()));
+        
+        // Withdrawals against the invalidated root fail, while other roots still reach proof verification
+        let verifier_data = VerifierState {
+            is_initialized: true,
+            verifying_key: create_test_verifying_key(),
+        }
+        .try_to_vec()
+        .unwrap();
+        let withdraw_from = |tornado_instance: &TornadoInstance, root: &[u8; 32], root_index: Option<u8>| {
+            Processor::verify_withdrawal(
+                &tornado_instance_key,
+                tornado_instance,
+                &stored_tree,
+                &verifier_data,
+                &create_dummy_proof(),
+                root,
+                &[3u8; 32],
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                0,
+                false,
+                0,
+                tornado_instance.denomination,
+                None,
+                tornado_instance.max_fee(tornado_instance.denomination),
+                root_index,
+            )
+        };
+        assert_eq!(withdraw_from(&tornado_instance, &bad_root, None), Err(TornadoError::InvalidMerkleRoot.into()));
+        assert_eq!(withdraw_from(&tornado_instance, &good_root, None), Err(TornadoError::InvalidProof.into()));
+
+        // A root index is checked directly: only the root at that index passes
+        for (root, root_index, expected) in [
+            (good_root, 3, Err(TornadoError::InvalidProof.into())),
+            (good_root, 1, Err(TornadoError::InvalidMerkleRoot.into())),
+            (bad_root, 2, Err(TornadoError::InvalidMerkleRoot.into())),
+            (good_root, ROOT_HISTORY_SIZE as u8, Err(TornadoError::InvalidMerkleRoot.into())),
+            (good_root, u8::MAX, Err(TornadoError::InvalidMerkleRoot.into())),
+        ] {
+            assert_eq!(withdraw_from(&tornado_instance, &root, Some(root_index)), expected);
+        }
+
+        // Withdrawals need the tree to hold at least the instance's minimum anonymity set
+        for (min_anonymity_set, expected) in [
+            (stored_tree.next_index + 1, Err(TornadoError::AnonymitySetTooSmall.into())),
+            (stored_tree.next_index, Err(TornadoError::InvalidProof.into())),
+        ] {
+            let instance = TornadoInstance {
+                min_anonymity_set,
+                ..tornado_instance
+            };
+            assert_eq!(withdraw_from(&instance, &good_root, None), expected);
+        }
+    }
+    
+    #[test]
+    fn test_process_set_partial_verifier() {
+        let program_id = Pubkey::new_unique();
+        let admin_key = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let (partial_verifier_key, _) = find_partial_verifier_address(&program_id, &tornado_instance_key);
+        let system_program_key = system_program::id();
+        
+        let mut admin_lamports = 0;
+        let mut tornado_instance_lamports = 0;
+        let mut merkle_tree_lamports = 0;
+        let mut verifier_lamports = 0;
+        let mut system_program_lamports = 0;
+        let mut admin_data = vec![0; 0];
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let mut verifier_data = vec![0; 0];
+        let mut system_program_data = vec![0; 0];
+        
+        let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 3,
+            merkle_tree: merkle_tree_key,
+            verifier: Pubkey::new_unique(),
+            token_mint: None,
+            admin: admin_key,
+            paused: false,
+            variable_amount: true,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        };
+        tornado_instance.pack_into_slice(&mut tornado_instance_data);
+        let mut merkle_tree = MerkleTree::new(3, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        let mut merkle_tree_data = merkle_tree.try_to_vec().unwrap();
+        
+        let accounts = vec![
+            create_account_info(&admin_key, true, true, &mut admin_lamports, &mut admin_data, &system_program_key),
+            create_account_info(
+                &tornado_instance_key,
+                false,
+                false,
+                &mut tornado_instance_lamports,
+                &mut tornado_instance_data,
+                &program_id,
+            ),
+            create_account_info(
+                &merkle_tree_key,
+                false,
+                false,
+                &mut merkle_tree_lamports,
+                &mut merkle_tree_data,
+                &program_id,
+            ),
+            create_account_info(
+                &partial_verifier_key,
+                false,
+                true,
+                &mut verifier_lamports,
+                &mut verifier_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &system_program_key,
+                false,
+                false,
+                &mut system_program_lamports,
+                &mut system_program_data,
+                &system_program_key,
+            ),
+        ];
+        let instruction_data = |verifying_key| TornadoInstruction::SetPartialVerifier { verifying_key }.try_to_vec().unwrap();
+        
+        // Only the admin can set the verifier
+        let other_key = Pubkey::new_unique();
+        let mut other_accounts = accounts.clone();
+        other_accounts[0] = AccountInfo {
+            key: &other_key,
+            ..accounts[0].clone()
+        };
+        let result = Processor::process(&program_id, &other_accounts, &instruction_data(create_test_verifying_key()));
+        assert_eq!(result, Err(TornadoError::Unauthorized.into()));
+        let mut unsigned_accounts = accounts.clone();
+        unsigned_accounts[0].is_signer = false;
+        let result = Processor::process(&program_id, &unsigned_accounts, &instruction_data(create_test_verifying_key()));
+        assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+        
+        // The key must be for the partial withdrawal circuit, at the instance's partial verifier PDA
+        let result = Processor::process(&program_id, &accounts, &instruction_data(create_test_verifying_key()));
+        assert_eq!(result, Err(TornadoError::InvalidVerifyingKey.into()));
+        let mut wrong_pda_accounts = accounts.clone();
+        let (verifier_key, _) = find_verifier_address(&program_id, &tornado_instance_key);
+        wrong_pda_accounts[3] = AccountInfo {
+            key: &verifier_key,
+            ..accounts[3].clone()
+        };
+        let result = Processor::process(&program_id, &wrong_pda_accounts, &instruction_data(create_test_verifying_key()));
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+        
+        // The Merkle tree must be the instance's
+        let other_tree_key = Pubkey::new_unique();
+        let mut wrong_tree_accounts = accounts.clone();
+        wrong_tree_accounts[2] = AccountInfo {
+            key: &other_tree_key,
+            ..accounts[2].clone()
+        };
+        let result = Processor::process(&program_id, &wrong_tree_accounts, &instruction_data(create_test_verifying_key()));
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+        
+        // Once a note is deposited, the key can no longer be set
+        merkle_tree.next_index = 1;
+        let tree_bytes = merkle_tree.try_to_vec().unwrap();
+        accounts[2].data.borrow_mut()[..tree_bytes.len()].copy_from_slice(&tree_bytes);
+        let result = Processor::process(&program_id, &accounts, &instruction_data(create_test_verifying_key()));
+        assert_eq!(result, Err(TornadoError::InstanceNotEmpty.into()));
+        
+        // Fixed-denomination and token instances have no partial withdrawals
+        for instance in [
+            TornadoInstance {
+                variable_amount: false,
+                ..tornado_instance
+            },
+            TornadoInstance {
+                token_mint: Some(Pubkey::new_unique()),
+                ..tornado_instance
+            },
+        ] {
+            let expected: ProgramError = if instance.token_mint.is_some() {
+                TornadoError::InvalidTokenMint.into()
+            } else {
+                TornadoError::InvalidInstructionData.into()
+            };
+            instance.pack_into_slice(&mut accounts[1].data.borrow_mut());
+            let result = Processor::process(&program_id, &accounts, &instruction_data(create_test_verifying_key()));
+            assert_eq!(result, Err(expected));
+        }
+    }
+    
+    #[test]
+    fn test_process_is_spent() {
+        let program_id = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let nullifier_hash = [3u8; 32];
+        let legacy_nullifier_hash = [4u8; 32];
+        let (nullifier_marker_key, _) =
+            find_nullifier_address(&program_id, &tornado_instance_key, &nullifier_hash);
+        let (legacy_marker_key, _) =
+            find_nullifier_address(&program_id, &tornado_instance_key, &legacy_nullifier_hash);
+        
+        let mut tornado_instance_lamports = 0;
+        let mut merkle_tree_lamports = 0;
+        let mut nullifier_marker_lamports = 0;
+        let mut legacy_marker_lamports = 0;
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let mut nullifier_marker_data = vec![0; 0];
+        let mut legacy_marker_data = vec![0; 0];
+        
+        let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 3,
+            merkle_tree: merkle_tree_key,
+            verifier: Pubkey::new_unique(),
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        };
+        tornado_instance.pack_into_slice(&mut tornado_instance_data);
+        
+        // One nullifier hash was spent before nullifier markers existed
+        let mut merkle_tree = MerkleTree::new(3, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        merkle_tree.nullifier_hashes.push(legacy_nullifier_hash);
+        let mut merkle_tree_data = merkle_tree.try_to_vec().unwrap();
+        
+        let instance_info = create_account_info(
+            &tornado_instance_key,
+            false,
+            false,
+            &mut tornado_instance_lamports,
+            &mut tornado_instance_data,
+            &program_id,
+        );
+        let merkle_tree_info = create_account_info(
+            &merkle_tree_key,
+            false,
+            false,
+            &mut merkle_tree_lamports,
+            &mut merkle_tree_data,
+            &program_id,
+        );
+        let nullifier_marker_info = create_account_info(
+            &nullifier_marker_key,
+            false,
+            false,
+            &mut nullifier_marker_lamports,
+            &mut nullifier_marker_data,
+            &program_id,
+        );
+        let legacy_marker_info = create_account_info(
+            &legacy_marker_key,
+            false,
+            false,
+            &mut legacy_marker_lamports,
+            &mut legacy_marker_data,
+            &program_id,
+        );
+        let accounts = vec![instance_info.clone(), merkle_tree_info.clone(), nullifier_marker_info.clone()];
+        let instruction_data = TornadoInstruction::IsSpent { nullifier_hash }.try_to_vec().unwrap();
+        
+        // Without a marker the nullifier hash is unspent
+        assert_eq!(Processor::is_nullifier_spent(&program_id, &accounts, &nullifier_hash), Ok(false));
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Ok(()));
+        
+        // The marker created by a withdrawal marks it spent
+        let mut spent_marker_data = vec![0; NullifierMarker::LEN];
+        NullifierMarker::pack(NullifierMarker { is_initialized: true }, &mut spent_marker_data).unwrap();
+        let spent_marker_info = AccountInfo {
+            data: Rc::new(RefCell::new(&mut spent_marker_data[..])),
+            ..nullifier_marker_info.clone()
+        };
+        let spent_accounts = vec![instance_info.clone(), merkle_tree_info.clone(), spent_marker_info];
+        assert_eq!(Processor::is_nullifier_spent(&program_id, &spent_accounts, &nullifier_hash), Ok(true));
+        
+        // Legacy nullifier hashes are found in the Merkle tree
+        let legacy_accounts = vec![instance_info.clone(), merkle_tree_info.clone(), legacy_marker_info];
+        assert_eq!(
+            Processor::is_nullifier_spent(&program_id, &legacy_accounts, &legacy_nullifier_hash),
+            Ok(true)
+        );
+        
+        // The marker must be the PDA of the queried nullifier hash
+        assert_eq!(
+            Processor::is_nullifier_spent(&program_id, &legacy_accounts, &nullifier_hash),
+            Err(TornadoError::InvalidAccountData.into())
+        );
+        
+        // The instance must be initialized
+        TornadoInstance::default().pack_into_slice(&mut accounts[0].data.borrow_mut());
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(ProgramError::UninitializedAccount));
+    }
+    
+    #[test]
+    fn test_process_migrate() {
+        set_syscall_stubs(Box::new(TestSyscallStubs));
+        
+        let program_id = Pubkey::new_unique();
+        let payer_key = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let system_program_id = system_program::id();
+        
+        let mut payer_lamports = 1_000_000_000;
+        let mut tornado_instance_lamports = 0;
+        let mut merkle_tree_lamports = 0;
+        let mut system_program_lamports = 0;
+        let mut verifier_lamports = 0;
+        let mut payer_data = vec![];
+        let mut system_program_data = vec![];
+        
+        // The verifier whose key hash legacy instances are given
+        let verifier_key = Pubkey::new_unique();
+        let vk_hash = hash_verifying_key(&create_test_verifying_key());
+        let mut verifier_data = VerifierState {
+            is_initialized: true,
+            verifying_key: create_test_verifying_key(),
+        }
+        .try_to_vec()
+        .unwrap();
+        
+        // Accounts in the unversioned layout are rejected until migrated
+        let legacy_instance = TornadoInstanceV1 {
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 3,
+            merkle_tree: merkle_tree_key,
+            verifier: verifier_key,
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+        };
+        let legacy_tree = MerkleTreeV1 {
+            is_initialized: true,
+            height: 3,
+            next_index: 0,
+            current_root_index: 0,
+            roots: vec![[0; 32]; ROOT_HISTORY_SIZE],
+            filled_subtrees: (0..3).map(|i| get_zero_value(HASH_ALGO_MIMC, i).unwrap()).collect(),
+            nullifier_hashes: Vec::new(),
+        };
+        assert_eq!(
+            TornadoInstance::unpack_unchecked(&legacy_instance.try_to_vec().unwrap()),
+            Err(TornadoError::UnsupportedStateVersion.into())
+        );
+        assert_eq!(
+            MerkleTree::from_account_data(&legacy_tree.try_to_vec().unwrap()),
+            Err(TornadoError::UnsupportedStateVersion.into())
+        );
+        let legacy_instance_data = legacy_instance.try_to_vec().unwrap();
+        
+        // Accounts already at the current version are left unchanged
+        let (_, vault_authority_bump) = find_vault_authority_address(&program_id, &tornado_instance_key);
+        let (_, merkle_tree_bump) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        TornadoInstance {
+            vault_authority_bump,
+            vk_hash,
+            merkle_tree_bump,
+            ..TornadoInstance::from(legacy_instance)
+        }
+        .pack_into_slice(&mut tornado_instance_data);
+        let mut merkle_tree_data = vec![0; MerkleTree::get_account_size(3, ROOT_HISTORY_SIZE as u8)];
+        MerkleTree::from(legacy_tree).serialize(&mut &mut merkle_tree_data[..]).unwrap();
+        let expected_instance_data = tornado_instance_data.clone();
+        let expected_merkle_tree_data = merkle_tree_data.clone();
+        
+        let accounts = vec![
+            create_account_info(&payer_key, true, true, &mut payer_lamports, &mut payer_data, &system_program_id),
+            create_account_info(
+                &tornado_instance_key,
+                false,
+                true,
+                &mut tornado_instance_lamports,
+                &mut tornado_instance_data,
+                &program_id,
+            ),
+            create_account_info(
+                &merkle_tree_key,
+                false,
+                true,
+                &mut merkle_tree_lamports,
+                &mut merkle_tree_data,
+                &program_id,
+            ),
+            create_account_info(
+                &system_program_id,
+                false,
+                false,
+                &mut system_program_lamports,
+                &mut system_program_data,
+                &system_program_id,
+            ),
+            create_account_info(
+                &verifier_key,
+                false,
+                false,
+                &mut verifier_lamports,
+                &mut verifier_data,
+                &program_id,
+            ),
+        ];
+        let instruction_data = TornadoInstruction::Migrate.try_to_vec().unwrap();
+        
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Ok(()));
+        assert_eq!(*accounts[1].data.borrow(), &expected_instance_data[..]);
+        assert_eq!(*accounts[2].data.borrow(), &expected_merkle_tree_data[..]);
+        
+        // An unversioned instance is rewritten with the defaults, its bumps and its verifying key's hash
+        accounts[1].data.borrow_mut().fill(0);
+        accounts[1].data.borrow_mut()[..legacy_instance_data.len()].copy_from_slice(&legacy_instance_data);
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Ok(()));
+        assert_eq!(*accounts[1].data.borrow(), &expected_instance_data[..]);
+        let migrated = TornadoInstance::unpack(&expected_instance_data).unwrap();
+        assert_eq!(migrated.max_fee_bps, MAX_FEE_BPS);
+        assert_eq!(migrated.vk_hash, vk_hash);
+        assert_eq!(
+            (migrated.vault_authority_bump, migrated.merkle_tree_bump),
+            (vault_authority_bump, merkle_tree_bump)
+        );
+
+        // It can't be migrated without its verifier, nor against another one
+        accounts[1].data.borrow_mut().fill(0);
+        accounts[1].data.borrow_mut()[..legacy_instance_data.len()].copy_from_slice(&legacy_instance_data);
+        let result = Processor::process(&program_id, &accounts[..4], &instruction_data);
+        assert_eq!(result, Err(ProgramError::NotEnoughAccountKeys));
+        let other_verifier_key = Pubkey::new_unique();
+        let mut wrong_accounts = accounts.clone();
+        wrong_accounts[4] = AccountInfo { key: &other_verifier_key, ..accounts[4].clone() };
+        let result = Processor::process(&program_id, &wrong_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+
+        // An unversioned tree is rewritten as a MiMC tree counting its deposits
+        // and the spends it lists, with a timestamp for every root
+        accounts[1].data.borrow_mut().copy_from_slice(&expected_instance_data);
+        let tree = MerkleTree::from_account_data(&expected_merkle_tree_data).unwrap();
+        let legacy_tree = MerkleTreeV1 {
+            is_initialized: true,
+            height: tree.height,
+            next_index: 3,
+            current_root_index: 3,
+            roots: tree.roots,
+            filled_subtrees: tree.filled_subtrees,
+            nullifier_hashes: vec![[1; 32]],
+        };
+        accounts[2].data.borrow_mut().fill(0);
+        let legacy_data = legacy_tree.try_to_vec().unwrap();
+        accounts[2].data.borrow_mut()[..legacy_data.len()].copy_from_slice(&legacy_data);
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Ok(()));
+        let migrated = MerkleTree::from_account_data(&accounts[2].data.borrow()).unwrap();
+        assert_eq!(migrated.hash_algo, HASH_ALGO_MIMC);
+        assert_eq!((migrated.next_index, migrated.nullifier_count), (3, 1));
+        assert_eq!(migrated.nullifier_hashes, vec![[1; 32]]);
+        assert_eq!(migrated.root_timestamps.len(), migrated.roots.len());
+        
+        // Unknown versions are rejected
+        accounts[2].data.borrow_mut()[0] = MerkleTree::VERSION + 1;
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::UnsupportedStateVersion.into()));
+        
+        accounts[1].data.borrow_mut()[0] = TornadoInstance::VERSION + 1;
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::UnsupportedStateVersion.into()));
+    }
+    
+    #[test]
+    fn test_recipient_amount() {
+        assert_eq!(Processor::recipient_amount(100000, 0), Ok(100000));
+        assert_eq!(Processor::recipient_amount(100000, 1000), Ok(99000));
+        
+        // A fee of the whole amount leaves nothing to the recipient
+        assert_eq!(Processor::recipient_amount(100000, 100000), Ok(0));
+        assert_eq!(Processor::recipient_amount(u64::MAX, u64::MAX), Ok(0));
+        
+        // A fee above the amount is rejected rather than underflowing
+        assert_eq!(
+            Processor::recipient_amount(100000, 100001),
+            Err(TornadoError::InsufficientFunds.into())
+        );
+        assert_eq!(
+            Processor::recipient_amount(0, u64::MAX),
+            Err(TornadoError::InsufficientFunds.into())
+        );
+    }
+    
+    #[test]
+    fn test_insert_commitment() {
+        let height = 3;
+        let mut merkle_tree = MerkleTree::new(height, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+
+        // Every deposit reports the leaf it was written to and advances the tree by one
+        for i in 0..2u32.pow(height as u32) {
+            let leaf_index = Processor::insert_commitment(&mut merkle_tree, &[i as u8 + 1; 32], 0).unwrap();
+            assert_eq!(leaf_index, i);
+            assert_eq!(merkle_tree.next_index, i + 1);
+            assert_eq!(merkle_tree.current_root_index as u32, i + 1);
+            assert_eq!(remaining_capacity(&merkle_tree), 2u64.pow(height as u32) - (i as u64 + 1));
+        }
+
+        // The full tree rejects further deposits without changing its state
+        assert_eq!(
+            Processor::check_capacity(&merkle_tree, 1),
+            Err(TornadoError::MerkleTreeFull.into())
+        );
+        assert_eq!(
+            Processor::insert_commitment(&mut merkle_tree, &[9u8; 32], 0).unwrap_err(),
+            ProgramError::from(TornadoError::MerkleTreeFull)
+        );
+        assert_eq!(merkle_tree.next_index, 2u32.pow(height as u32));
+    }
+
+    #[test]
+    fn test_merkle_tree_to_account_data() {
+        let mut merkle_tree = MerkleTree::new(3, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        Processor::insert_commitment(&mut merkle_tree, &[1u8; 32], 0).unwrap();
+        let tree_len = merkle_tree.try_to_vec().unwrap().len();
+
+        // A deposit's tree fits an account sized exactly for it
+        let mut merkle_tree_data = vec![0; tree_len];
+        assert_eq!(merkle_tree.to_account_data(&mut merkle_tree_data), Ok(()));
+        assert_eq!(MerkleTree::from_account_data(&merkle_tree_data).unwrap(), merkle_tree);
+
+        // An account one byte short is rejected rather than written in part
+        let mut merkle_tree_data = vec![0; tree_len - 1];
+        assert_eq!(
+            merkle_tree.to_account_data(&mut merkle_tree_data),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        assert!(merkle_tree_data.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_merkle_tree_account_size_bounded() {
+        // A height 20 tree takes a few KB, far from the 10 MB account limit
+        let size = MerkleTree::get_account_size_bounded(20, ROOT_HISTORY_SIZE as u8);
+        assert!(size < 4096);
+
+        // It is exactly the size of a full tree header, at any height and history size
+        for (height, root_history_size) in [(1, 1), (20, ROOT_HISTORY_SIZE as u8), (MAX_SUPPORTED_HEIGHT, u8::MAX)] {
+            let mut merkle_tree = MerkleTree::new(height, root_history_size, HASH_ALGO_POSEIDON).unwrap();
+            Processor::insert_commitment(&mut merkle_tree, &[1u8; 32], 1).unwrap();
+            let mut merkle_tree_data = vec![0; MerkleTree::get_account_size_bounded(height, root_history_size)];
+            assert_eq!(merkle_tree.try_to_vec().unwrap().len(), merkle_tree_data.len());
+            merkle_tree.to_account_data(&mut merkle_tree_data).unwrap();
+            assert_eq!(MerkleTree::from_account_data(&merkle_tree_data).unwrap(), merkle_tree);
+        }
+    }
+
+    #[test]
+    fn test_load_checked() {
+        let program_id = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let mut tornado_instance_lamports = 0;
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+
+        let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 20,
+            merkle_tree: Pubkey::new_unique(),
+            verifier: Pubkey::new_unique(),
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        };
+        tornado_instance.pack_into_slice(&mut tornado_instance_data);
+
+        // An instance owned by the program loads with its fields intact
+        let tornado_instance_account = create_account_info(
+            &tornado_instance_key,
+            false,
+            true,
+            &mut tornado_instance_lamports,
+            &mut tornado_instance_data,
+            &program_id,
+        );
+        let loaded = TornadoInstance::load_checked(&tornado_instance_account, &program_id).unwrap();
+        assert_eq!(loaded, tornado_instance);
+        assert_eq!(loaded.denomination(), 100000);
+        assert_eq!(loaded.merkle_tree_height(), 20);
+
+        // The same data under a foreign owner is rejected
+        let foreign_owner = Pubkey::new_unique();
+        let mut foreign_lamports = 0;
+        let mut foreign_data = tornado_instance_account.data.borrow().to_vec();
+        let foreign_account = create_account_info(
+            &tornado_instance_key,
+            false,
+            true,
+            &mut foreign_lamports,
+            &mut foreign_data,
+            &foreign_owner,
+        );
+        assert_eq!(
+            TornadoInstance::load_checked(&foreign_account, &program_id),
+            Err(TornadoError::InvalidAccountData.into())
+        );
+    }
+
+    #[test]
+    fn test_process_withdraw() {
+        // Create program ID
+        let program_id = Pubkey::new_unique();
+        
+        // Create accounts
+        let relayer_keypair = Keypair::new();
+        let payer_key = relayer_keypair.pubkey();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let (verifier_key, _) = find_verifier_address(&program_id, &tornado_instance_key);
+        let nullifier_hash = [3u8; 32];
+        let (nullifier_marker_key, _) =
+            find_nullifier_address(&program_id, &tornado_instance_key, &nullifier_hash);
+        let recipient_key = Pubkey::new_unique();
+        // The relayer submits the withdrawal and pays for it
+        let relayer_key = payer_key;
+        let system_program_key = system_program::id();
+        
+        // Create account data
+        let mut payer_lamports = 1000000;
+        let mut tornado_instance_lamports = 100000;
+        let mut merkle_tree_lamports = 0;
+        let mut verifier_lamports = 0;
+        let mut nullifier_marker_lamports = 0;
+        let mut recipient_lamports = 0;
+        let mut relayer_lamports = 0;
+        let mut system_program_lamports = 0;
+        
+        let mut payer_data = vec![0; 0];
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let mut verifier_data = VerifierState {
+            is_initialized: true,
+            verifying_key: create_test_verifying_key(),
+        }
+        .try_to_vec()
+        .unwrap();
+        let mut nullifier_marker_data = vec![0; NullifierMarker::LEN];
+        let mut recipient_data = vec![0; 0];
+        let mut relayer_data = vec![0; 0];
+        let mut system_program_data = vec![0; 0];
+        
+        // Initialize tornado instance
+        let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 20,
+            merkle_tree: merkle_tree_key,
+            verifier: verifier_key,
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        };
+        tornado_instance.pack_into_slice(&mut tornado_instance_data);
+        
+        // Initialize merkle tree with a known root
+        let root = [1u8; 32];
+        let mut roots = vec![[0; 32]; ROOT_HISTORY_SIZE];
+        roots[0] = root;
+        
+        let merkle_tree = MerkleTree {
+            version: MerkleTree::VERSION,
+            is_initialized: true,
+            height: 20,
+            hash_algo: HASH_ALGO_MIMC,
+            next_index: 1,
+            current_root_index: 0,
+            nullifier_count: 0,
+            roots,
+            root_timestamps: vec![0; ROOT_HISTORY_SIZE],
+            filled_subtrees: vec![[0; 32]; 20],
+            nullifier_hashes: Vec::new(),
+        };
+        let mut merkle_tree_data = merkle_tree.try_to_vec().unwrap();
+        
+        // The note was already spent, so its nullifier marker exists
+        NullifierMarker::pack(NullifierMarker { is_initialized: true }, &mut nullifier_marker_data)
+            .unwrap();
+        
+        // Create account infos
+        let payer_account = create_account_info(
+            &payer_key,
+            true,
+            true,
+            &mut payer_lamports,
+            &mut payer_data,
+            &system_program_key,
+        );
+        
+        let tornado_instance_account = create_account_info(
+            &tornado_instance_key,
+            false,
+            true,
+            &mut tornado_instance_lamports,
+            &mut tornado_instance_data,
+            &program_id,
+        );
+        
+        let merkle_tree_account = create_account_info(
+            &merkle_tree_key,
+            false,
+            true,
+            &mut merkle_tree_lamports,
+            &mut merkle_tree_data,
+            &program_id,
+        );
+        
+        let verifier_account = create_account_info(
+            &verifier_key,
+            false,
+            false,
+            &mut verifier_lamports,
+            &mut verifier_data,
+            &program_id,
+        );
+        
+        let nullifier_marker_account = create_account_info(
+            &nullifier_marker_key,
+            false,
+            true,
+            &mut nullifier_marker_lamports,
+            &mut nullifier_marker_data,
+            &program_id,
+        );
+        
+        let recipient_account = create_account_info(
+            &recipient_key,
+            false,
+            true,
+            &mut recipient_lamports,
+            &mut recipient_data,
+            &system_program_key,
+        );
+        
+        let relayer_account = create_account_info(
+            &relayer_key,
+            false,
+            true,
+            &mut relayer_lamports,
+            &mut relayer_data,
+            &system_program_key,
+        );
+        
+        let system_program_account = create_account_info(
+            &system_program_key,
+            false,
+            false,
+            &mut system_program_lamports,
+            &mut system_program_data,
+            &system_program_key,
+        );
+        
+        // Create accounts array
+        let accounts = vec![
+            payer_account,
+            tornado_instance_account,
+            merkle_tree_account,
+            verifier_account,
+            nullifier_marker_account,
+            recipient_account,
+            relayer_account,
+            system_program_account,
+        ];
+        
+        // Create instruction data
+        let proof = create_dummy_proof();
+        let fee = 1000;
+        let refund = 0;
+        
+        let instruction = TornadoInstruction::Withdraw {
+            proof: proof.clone(),
+            root,
+            nullifier_hash,
+            recipient: recipient_key,
+            relayer: relayer_key,
+            fee,
+            refund,
+            relayer_fee_signature: None,
+            root_index: None,
+        };
+        let instruction_data = instruction.try_to_vec().unwrap();
+        
+        // A spent nullifier hash is rejected
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::NullifierAlreadySpent.into()));
+        
+        // The payer must sign, even for a withdrawal without a fee
+        let mut unsigned_accounts = accounts.clone();
+        unsigned_accounts[0] = AccountInfo {
+            is_signer: false,
+            ..accounts[0].clone()
+        };
+        let result = Processor::process(&program_id, &unsigned_accounts, &instruction_data);
+        assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+        
+        // Every account the withdrawal writes to must be writable
+        for index in [0, 1, 2, 4, 5, 6] {
+            let mut read_only_accounts = accounts.clone();
+            read_only_accounts[index] = AccountInfo {
+                is_writable: false,
+                ..accounts[index].clone()
+            };
+            let result = Processor::process(&program_id, &read_only_accounts, &instruction_data);
+            assert_eq!(result, Err(TornadoError::AccountNotWritable.into()));
+        }
+        
+        // A nullifier hash spent before markers existed is still rejected
+        let legacy_tree = MerkleTree {
+            nullifier_hashes: vec![nullifier_hash],
+            ..merkle_tree
+        };
+        let mut legacy_tree_data = legacy_tree.try_to_vec().unwrap();
+        let mut legacy_tree_lamports = 0;
+        let mut empty_marker_data = vec![0; 0];
+        let mut empty_marker_lamports = 0;
+        let mut unspent_accounts = accounts.clone();
+        unspent_accounts[4] = create_account_info(
+            &nullifier_marker_key,
+            false,
+            true,
+            &mut empty_marker_lamports,
+            &mut empty_marker_data,
+            &system_program_key,
+        );
+        let mut legacy_accounts = unspent_accounts.clone();
+        legacy_accounts[2] = create_account_info(
+            &merkle_tree_key,
+            false,
+            true,
+            &mut legacy_tree_lamports,
+            &mut legacy_tree_data,
+            &program_id,
+        );
+        let result = Processor::process(&program_id, &legacy_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::NullifierAlreadySpent.into()));
+        
+        // An unspent nullifier hash gets as far as proof verification
+        let result = Processor::process(&program_id, &unspent_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
+
+        // A verifier account whose key no longer hashes to the instance's is rejected
+        let mut tampered_verifying_key = create_test_verifying_key();
+        tampered_verifying_key[0] ^= 1;
+        let mut tampered_verifier_data = VerifierState {
+            is_initialized: true,
+            verifying_key: tampered_verifying_key,
         }
+        .try_to_vec()
+        .unwrap();
+        let mut tampered_verifier_lamports = 0;
+        let mut tampered_accounts = unspent_accounts.clone();
+        tampered_accounts[3] = create_account_info(
+            &verifier_key,
+            false,
+            false,
+            &mut tampered_verifier_lamports,
+            &mut tampered_verifier_data,
+            &program_id,
+        );
+        let result = Processor::process(&program_id, &tampered_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::VerifierKeyMismatch.into()));
 
-        // Prepare the public inputs for the proof verification
-        let mut public_inputs = [0u8; 192]; // 6 public inputs * 32 bytes
-        public_inputs[0..32].copy_from_slice(root);
-        public_inputs[32..64].copy_from_slice(nullifier_hash);
-        public_inputs[64..96].copy_from_slice(&recipient_pubkey.to_bytes());
-        public_inputs[96..128].copy_from_slice(&relayer_pubkey.to_bytes());
-        public_inputs[128..160].copy_from_slice(&fee.to_le_bytes());
-        public_inputs[160..192].copy_from_slice(&refund.to_le_bytes());
+        // A Merkle tree account not owned by the program is rejected
+        let foreign_owner = Pubkey::new_unique();
+        let mut foreign_tree_accounts = unspent_accounts.clone();
+        foreign_tree_accounts[2] = AccountInfo {
+            owner: &foreign_owner,
+            ..unspent_accounts[2].clone()
+        };
+        let result = Processor::process(&program_id, &foreign_tree_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
 
-        // Verify the proof
-        if !verify_tornado_proof(proof, &public_inputs)? {
-            return Err(TornadoError::InvalidProof.into());
+        // A nullifier marker that doesn't match the nullifier hash is rejected
+        let instruction_data = TornadoInstruction::Withdraw {
+            proof: proof.clone(),
+            root,
+            nullifier_hash: [4u8; 32],
+            recipient: recipient_key,
+            relayer: relayer_key,
+            fee,
+            refund,
+            relayer_fee_signature: None,
+            root_index: None,
         }
-
-        // Add the nullifier hash to the nullifier_hashes array
-        add_nullifier_hash(&mut merkle_tree.nullifier_hashes, nullifier_hash)?;
-
-        // Transfer the denomination amount minus the fee to the recipient
-        transfer_sol(
-            tornado_instance_info,
-            recipient_info,
-            system_program_info,
-            tornado_instance.denomination - fee,
-            None,
-        )?;
-
-        // If there's a fee, transfer it to the relayer
-        if fee > 0 {
-            transfer_sol(
-                tornado_instance_info,
-                relayer_info,
-                system_program_info,
+        .try_to_vec()
+        .unwrap();
+        let result = Processor::process(&program_id, &unspent_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+        
+        // So is a nullifier hash outside the field, before its marker is looked at
+        let instruction_data = TornadoInstruction::Withdraw {
+            proof: proof.clone(),
+            root,
+            nullifier_hash: FIELD_SIZE,
+            recipient: recipient_key,
+            relayer: relayer_key,
+            fee,
+            refund,
+            relayer_fee_signature: None,
+            root_index: None,
+        }
+        .try_to_vec()
+        .unwrap();
+        let result = Processor::process(&program_id, &unspent_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidNullifierHash.into()));
+        
+        // Only token withdrawals forward a refund
+        let instruction_data = TornadoInstruction::Withdraw {
+            proof: proof.clone(),
+            root,
+            nullifier_hash,
+            recipient: recipient_key,
+            relayer: relayer_key,
+            fee,
+            refund: 1,
+            relayer_fee_signature: None,
+            root_index: None,
+        }
+        .try_to_vec()
+        .unwrap();
+        let result = Processor::process(&program_id, &unspent_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidAmount.into()));
+        
+        // A relayed withdrawal submitted by anyone but its relayer is rejected
+        let other_relayer_key = Pubkey::new_unique();
+        let mut other_relayer_lamports = 0;
+        let mut other_relayer_data = vec![0; 0];
+        let mut other_relayer_accounts = unspent_accounts.clone();
+        other_relayer_accounts[6] = create_account_info(
+            &other_relayer_key,
+            false,
+            true,
+            &mut other_relayer_lamports,
+            &mut other_relayer_data,
+            &system_program_key,
+        );
+        let instruction_data = TornadoInstruction::Withdraw {
+            proof: proof.clone(),
+            root,
+            nullifier_hash,
+            recipient: recipient_key,
+            relayer: other_relayer_key,
+            fee,
+            refund,
+            relayer_fee_signature: None,
+            root_index: None,
+        }
+        .try_to_vec()
+        .unwrap();
+        let result = Processor::process(&program_id, &other_relayer_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidRelayer.into()));
+        
+        // A self-withdrawal names the recipient as the relayer and pays no fee
+        let mut self_withdraw_accounts = unspent_accounts.clone();
+        self_withdraw_accounts[6] = self_withdraw_accounts[5].clone();
+        let self_withdraw = |fee| {
+            TornadoInstruction::Withdraw {
+                proof: proof.clone(),
+                root,
+                nullifier_hash,
+                recipient: recipient_key,
+                relayer: recipient_key,
                 fee,
-                None,
-            )?;
+                refund,
+                relayer_fee_signature: None,
+                root_index: None,
+            }
+            .try_to_vec()
+            .unwrap()
+        };
+        let result = Processor::process(&program_id, &self_withdraw_accounts, &self_withdraw(0));
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
+        let result = Processor::process(&program_id, &self_withdraw_accounts, &self_withdraw(fee));
+        assert_eq!(result, Err(TornadoError::InvalidRelayer.into()));
+        
+        // A quoted fee must match the relayer's signature in the preceding ed25519 instruction
+        let quote = relayer_fee_quote_message(&nullifier_hash, fee, &recipient_key);
+        let signature: [u8; 64] = relayer_keypair.sign_message(&quote).into();
+        let ed25519_data = create_ed25519_instruction_data(&relayer_key, &signature, &quote);
+        ed25519_instruction::verify(&ed25519_data, &[&ed25519_data], &FeatureSet::all_enabled()).unwrap();
+        let quoted_withdraw = |fee, signature| {
+            TornadoInstruction::Withdraw {
+                proof: proof.clone(),
+                root,
+                nullifier_hash,
+                recipient: recipient_key,
+                relayer: relayer_key,
+                fee,
+                refund,
+                relayer_fee_signature: Some(signature),
+                root_index: None,
+            }
+            .try_to_vec()
+            .unwrap()
+        };
+        let instructions_key = sysvar::instructions::id();
+        let sysvar_owner = sysvar::id();
+        let mut instructions_lamports = 0;
+        let mut instructions_data = create_instructions_sysvar_data(&[
+            (ed25519_program::id(), ed25519_data),
+            (program_id, quoted_withdraw(fee, signature)),
+        ]);
+        let mut quote_accounts = unspent_accounts.clone();
+        quote_accounts.push(create_account_info(
+            &instructions_key,
+            false,
+            false,
+            &mut instructions_lamports,
+            &mut instructions_data,
+            &sysvar_owner,
+        ));
+        let result = Processor::process(&program_id, &quote_accounts, &quoted_withdraw(fee, signature));
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
+        
+        // The fee and signature submitted must be the quoted ones
+        let result = Processor::process(&program_id, &quote_accounts, &quoted_withdraw(fee + 1, signature));
+        assert_eq!(result, Err(TornadoError::InvalidRelayerFeeQuote.into()));
+        let mut tampered_signature = signature;
+        tampered_signature[0] ^= 1;
+        let result = Processor::process(&program_id, &quote_accounts, &quoted_withdraw(fee, tampered_signature));
+        assert_eq!(result, Err(TornadoError::InvalidRelayerFeeQuote.into()));
+        
+        // The quote must be signed by the relayer
+        let other_keypair = Keypair::new();
+        let other_signature: [u8; 64] = other_keypair.sign_message(&quote).into();
+        let mut other_signer_lamports = 0;
+        let mut other_signer_data = create_instructions_sysvar_data(&[
+            (
+                ed25519_program::id(),
+                create_ed25519_instruction_data(&other_keypair.pubkey(), &other_signature, &quote),
+            ),
+            (program_id, quoted_withdraw(fee, other_signature)),
+        ]);
+        quote_accounts[8] = create_account_info(
+            &instructions_key,
+            false,
+            false,
+            &mut other_signer_lamports,
+            &mut other_signer_data,
+            &sysvar_owner,
+        );
+        let result = Processor::process(&program_id, &quote_accounts, &quoted_withdraw(fee, other_signature));
+        assert_eq!(result, Err(TornadoError::InvalidRelayerFeeQuote.into()));
+        
+        // The quote must be verified by the instruction right before the withdrawal
+        let mut unverified_lamports = 0;
+        let mut unverified_data = create_instructions_sysvar_data(&[(program_id, quoted_withdraw(fee, signature))]);
+        quote_accounts[8] = create_account_info(
+            &instructions_key,
+            false,
+            false,
+            &mut unverified_lamports,
+            &mut unverified_data,
+            &sysvar_owner,
+        );
+        let result = Processor::process(&program_id, &quote_accounts, &quoted_withdraw(fee, signature));
+        assert_eq!(result, Err(TornadoError::InvalidRelayerFeeQuote.into()));
+        let result = Processor::process(&program_id, &unspent_accounts, &quoted_withdraw(fee, signature));
+        assert_eq!(result, Err(ProgramError::NotEnoughAccountKeys));
+        
+        // A SOL instance pays its fees in SOL through Withdraw and rejects WithdrawToken in either fee unit
+        let mut token_withdraw_accounts = unspent_accounts.clone();
+        token_withdraw_accounts.extend(unspent_accounts[..6].iter().cloned());
+        for account in &mut token_withdraw_accounts {
+            account.is_writable = true;
         }
-
-        // Save the updated Merkle tree
-        merkle_tree.serialize(&mut *merkle_tree_info.data.borrow_mut())?;
-
-        msg!("Withdrawal successful");
-
-        Ok(())
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use solana_program::{
-        account_info::AccountInfo,
-        entrypoint::ProgramResult,
-        program_error::ProgramError,
-        pubkey::Pubkey,
-        rent::Rent,
-        system_program,
-    };
-    use solana_program_test::*;
-    use std::cell::RefCell;
-    use std::rc::Rc;
-    
-    // Helper function to create an account info
-    fn create_account_info<'a>(
-        key: &'a Pubkey,
-        is_signer: bool,
-        is_writable: bool,
-        lamports: &'a mut u64,
-        data: &'a mut [u8],
-        owner: &'a Pubkey,
-    ) -> AccountInfo<'a> {
-        AccountInfo {
-            key,
-            is_signer,
-            is_writable,
-            lamports: Rc::new(RefCell::new(lamports)),
-            data: Rc::new(RefCell::new(data)),
-            owner,
-            executable: false,
-            rent_epoch: 0,
+        for fee_in_sol in [false, true] {
+            let instruction_data = TornadoInstruction::WithdrawToken {
+                proof: proof.clone(),
+                root,
+                nullifier_hash,
+                recipient: recipient_key,
+                relayer: relayer_key,
+                fee,
+                refund,
+                fee_in_sol,
+            }
+            .try_to_vec()
+            .unwrap();
+            let result = Processor::process(&program_id, &token_withdraw_accounts, &instruction_data);
+            assert_eq!(result, Err(TornadoError::InvalidTokenMint.into()));
+        }
+        
+        // A 50 bps cap on the 100000 denomination allows relayer fees up to 500
+        let capped_instance = TornadoInstance {
+            max_fee_bps: 50,
+            ..tornado_instance
+        };
+        capped_instance.pack_into_slice(&mut unspent_accounts[1].data.borrow_mut());
+        let relayed_withdraw = |fee| {
+            TornadoInstruction::Withdraw {
+                proof: proof.clone(),
+                root,
+                nullifier_hash,
+                recipient: recipient_key,
+                relayer: relayer_key,
+                fee,
+                refund,
+                relayer_fee_signature: None,
+                root_index: None,
+            }
+            .try_to_vec()
+            .unwrap()
+        };
+        let result = Processor::process(&program_id, &unspent_accounts, &relayed_withdraw(500));
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
+        for fee in [501, fee] {
+            let result = Processor::process(&program_id, &unspent_accounts, &relayed_withdraw(fee));
+            assert_eq!(result, Err(TornadoError::InvalidFee.into()));
+        }
+        
+        // With a root age cap of 2, the latest root is accepted but not one 3 roots back
+        let max_root_age = 2;
+        TornadoInstance {
+            max_root_age,
+            ..tornado_instance
+        }
+        .pack_into_slice(&mut unspent_accounts[1].data.borrow_mut());
+        let result = Processor::process(&program_id, &unspent_accounts, &relayed_withdraw(fee));
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
+        let tree_data = unspent_accounts[2].data.borrow().to_vec();
+        let aged_tree = MerkleTree {
+            current_root_index: max_root_age + 1,
+            next_index: max_root_age as u32 + 1,
+            ..MerkleTree::from_account_data(&tree_data).unwrap()
+        };
+        aged_tree.serialize(&mut &mut unspent_accounts[2].data.borrow_mut()[..]).unwrap();
+        let result = Processor::process(&program_id, &unspent_accounts, &relayed_withdraw(fee));
+        assert_eq!(result, Err(TornadoError::RootTooOld.into()));
+        unspent_accounts[2].data.borrow_mut().copy_from_slice(&tree_data);
+        tornado_instance.pack_into_slice(&mut unspent_accounts[1].data.borrow_mut());
+        
+        // With a compliance list, listed recipients are blocked and others pass through to the proof
+        let (compliance_list_key, _) = find_compliance_list_address(&program_id, &tornado_instance_key);
+        TornadoInstance {
+            compliance_list: Some(compliance_list_key),
+            ..tornado_instance
+        }
+        .pack_into_slice(&mut unspent_accounts[1].data.borrow_mut());
+        let mut listed = vec![recipient_key, Pubkey::new_unique(), Pubkey::new_unique()];
+        listed.sort();
+        let mut compliance_list_lamports = 0;
+        let mut compliance_list_data = ComplianceList {
+            is_initialized: true,
+            recipients: listed,
+        }
+        .try_to_vec()
+        .unwrap();
+        compliance_list_data.resize(ComplianceList::get_account_size(5), 0);
+        let mut compliance_accounts = quote_accounts.clone();
+        compliance_accounts.push(create_account_info(
+            &compliance_list_key,
+            false,
+            false,
+            &mut compliance_list_lamports,
+            &mut compliance_list_data,
+            &program_id,
+        ));
+        let result = Processor::process(&program_id, &compliance_accounts, &relayed_withdraw(fee));
+        assert_eq!(result, Err(TornadoError::RecipientBlocked.into()));
+        let mut allowed_accounts = compliance_accounts.clone();
+        allowed_accounts[5] = other_relayer_accounts[6].clone();
+        let allowed_withdraw = TornadoInstruction::Withdraw {
+            proof: proof.clone(),
+            root,
+            nullifier_hash,
+            recipient: other_relayer_key,
+            relayer: relayer_key,
+            fee,
+            refund,
+            relayer_fee_signature: None,
+            root_index: None,
+        }
+        .try_to_vec()
+        .unwrap();
+        let result = Processor::process(&program_id, &allowed_accounts, &allowed_withdraw);
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
+        
+        // The list must be passed, and be the instance's
+        let result = Processor::process(&program_id, &quote_accounts, &relayed_withdraw(fee));
+        assert_eq!(result, Err(ProgramError::NotEnoughAccountKeys));
+        let mut wrong_list_accounts = compliance_accounts.clone();
+        wrong_list_accounts[9] = AccountInfo {
+            owner: &foreign_owner,
+            ..compliance_accounts[9].clone()
+        };
+        let result = Processor::process(&program_id, &wrong_list_accounts, &relayed_withdraw(fee));
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+        
+        // Without a compliance list, the same recipient is allowed and the account isn't read
+        tornado_instance.pack_into_slice(&mut unspent_accounts[1].data.borrow_mut());
+        let result = Processor::process(&program_id, &wrong_list_accounts, &relayed_withdraw(fee));
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
+        
+        // A fixed-denomination instance rejects variable-amount withdrawals
+        let withdraw_variable = |fee, amount| {
+            TornadoInstruction::WithdrawVariable {
+                proof: proof.clone(),
+                root,
+                nullifier_hash,
+                recipient: recipient_key,
+                relayer: relayer_key,
+                fee,
+                refund,
+                amount,
+            }
+            .try_to_vec()
+            .unwrap()
+        };
+        let result = Processor::process(&program_id, &unspent_accounts, &withdraw_variable(fee, 50000));
+        assert_eq!(result, Err(TornadoError::InvalidInstructionData.into()));
+        
+        // A variable-amount instance only accepts WithdrawVariable with an amount up to the denomination
+        let variable_instance = TornadoInstance {
+            variable_amount: true,
+            ..tornado_instance
+        };
+        variable_instance.pack_into_slice(&mut unspent_accounts[1].data.borrow_mut());
+        let result = Processor::process(&program_id, &self_withdraw_accounts, &self_withdraw(0));
+        assert_eq!(result, Err(TornadoError::InvalidInstructionData.into()));
+        for amount in [0, 100001] {
+            let result = Processor::process(&program_id, &unspent_accounts, &withdraw_variable(0, amount));
+            assert_eq!(result, Err(TornadoError::InvalidAmount.into()));
         }
+        
+        // The fee comes out of the proven amount
+        let result = Processor::process(&program_id, &unspent_accounts, &withdraw_variable(fee, fee - 1));
+        assert_eq!(result, Err(TornadoError::InvalidFee.into()));
+        
+        // The proof is checked with the amount as an extra public input, which this key doesn't have
+        let result = Processor::process(&program_id, &unspent_accounts, &withdraw_variable(fee, 50000));
+        assert_eq!(result, Err(TornadoError::InvalidVerifyingKey.into()));
     }
     
     #[test]
-    fn test_process_initialize() {
-        // Create program ID
+    fn test_process_withdraw_batch() {
         let program_id = Pubkey::new_unique();
         
-        // Create accounts
         let payer_key = Pubkey::new_unique();
         let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let (verifier_key, _) = find_verifier_address(&program_id, &tornado_instance_key);
         let system_program_key = system_program::id();
+        let instructions_key = sysvar::instructions::id();
+        let (compliance_list_key, _) = find_compliance_list_address(&program_id, &tornado_instance_key);
+        let nullifier_hash = [3u8; 32];
+        let (nullifier_marker_key, _) = find_nullifier_address(&program_id, &tornado_instance_key, &nullifier_hash);
+        let recipient_key = Pubkey::new_unique();
         
-        // Create account data
         let mut payer_lamports = 1000000;
-        let mut tornado_instance_lamports = 0;
+        let mut tornado_instance_lamports = 100000;
+        let mut merkle_tree_lamports = 0;
+        let mut verifier_lamports = 0;
         let mut system_program_lamports = 0;
+        let mut instructions_lamports = 0;
+        let mut compliance_list_lamports = 0;
+        let mut nullifier_marker_lamports = 0;
+        let mut recipient_lamports = 0;
         
         let mut payer_data = vec![0; 0];
         let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let mut verifier_data = VerifierState {
+            is_initialized: true,
+            verifying_key: create_test_verifying_key(),
+        }
+        .try_to_vec()
+        .unwrap();
         let mut system_program_data = vec![0; 0];
+        let mut instructions_data = vec![0; 0];
+        let mut compliance_list_data = vec![0; 0];
+        let mut nullifier_marker_data = vec![0; 0];
+        let mut recipient_data = vec![0; 0];
         
-        // Create account infos
-        let payer_account = create_account_info(
-            &payer_key,
-            true,
-            true,
-            &mut payer_lamports,
-            &mut payer_data,
-            &system_program_key,
-        );
+        TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 20,
+            merkle_tree: merkle_tree_key,
+            verifier: verifier_key,
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        }
+        .pack_into_slice(&mut tornado_instance_data);
         
-        let tornado_instance_account = create_account_info(
-            &tornado_instance_key,
-            false,
-            true,
-            &mut tornado_instance_lamports,
-            &mut tornado_instance_data,
-            &program_id,
+        let root = [1u8; 32];
+        let mut roots = vec![[0; 32]; ROOT_HISTORY_SIZE];
+        roots[0] = root;
+        let mut merkle_tree_data = MerkleTree {
+            version: MerkleTree::VERSION,
+            is_initialized: true,
+            height: 20,
+            hash_algo: HASH_ALGO_MIMC,
+            next_index: 1,
+            current_root_index: 0,
+            nullifier_count: 0,
+            roots,
+            root_timestamps: vec![0; ROOT_HISTORY_SIZE],
+            filled_subtrees: vec![[0; 32]; 20],
+            nullifier_hashes: Vec::new(),
+        }
+        .try_to_vec()
+        .unwrap();
+        
+        // The payer relays the withdrawal
+        let mut accounts = vec![
+            create_account_info(&payer_key, true, true, &mut payer_lamports, &mut payer_data, &system_program_key),
+            create_account_info(
+                &tornado_instance_key,
+                false,
+                true,
+                &mut tornado_instance_lamports,
+                &mut tornado_instance_data,
+                &program_id,
+            ),
+            create_account_info(
+                &merkle_tree_key,
+                false,
+                true,
+                &mut merkle_tree_lamports,
+                &mut merkle_tree_data,
+                &program_id,
+            ),
+            create_account_info(&verifier_key, false, false, &mut verifier_lamports, &mut verifier_data, &program_id),
+            create_account_info(
+                &system_program_key,
+                false,
+                false,
+                &mut system_program_lamports,
+                &mut system_program_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &instructions_key,
+                false,
+                false,
+                &mut instructions_lamports,
+                &mut instructions_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &compliance_list_key,
+                false,
+                false,
+                &mut compliance_list_lamports,
+                &mut compliance_list_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &nullifier_marker_key,
+                false,
+                true,
+                &mut nullifier_marker_lamports,
+                &mut nullifier_marker_data,
+                &system_program_key,
+            ),
+            create_account_info(
+                &recipient_key,
+                false,
+                true,
+                &mut recipient_lamports,
+                &mut recipient_data,
+                &system_program_key,
+            ),
+        ];
+        accounts.push(accounts[0].clone());
+        
+        let request = WithdrawRequest {
+            proof: create_dummy_proof(),
+            root,
+            nullifier_hash,
+            recipient: recipient_key,
+            relayer: payer_key,
+            fee: 1000,
+        };
+        let withdraw_batch = |withdrawals: Vec<WithdrawRequest>| {
+            TornadoInstruction::WithdrawBatch { withdrawals }.try_to_vec().unwrap()
+        };
+        
+        // A batch holds at least one withdrawal and at most `MAX_WITHDRAW_BATCH_SIZE`
+        let result = Processor::process(&program_id, &accounts, &withdraw_batch(Vec::new()));
+        assert_eq!(result, Err(TornadoError::InvalidInstructionData.into()));
+        let oversized = vec![request.clone(); MAX_WITHDRAW_BATCH_SIZE + 1];
+        let result = Processor::process(&program_id, &accounts, &withdraw_batch(oversized));
+        assert_eq!(result, Err(TornadoError::InvalidInstructionData.into()));
+        
+        // The payer must sign and each withdrawal's accounts must be writable
+        let mut unsigned_accounts = accounts.clone();
+        unsigned_accounts[0] = AccountInfo {
+            is_signer: false,
+            ..accounts[0].clone()
+        };
+        let result = Processor::process(&program_id, &unsigned_accounts, &withdraw_batch(vec![request.clone()]));
+        assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+        let mut read_only_accounts = accounts.clone();
+        read_only_accounts[8] = AccountInfo {
+            is_writable: false,
+            ..accounts[8].clone()
+        };
+        let result = Processor::process(&program_id, &read_only_accounts, &withdraw_batch(vec![request.clone()]));
+        assert_eq!(result, Err(TornadoError::AccountNotWritable.into()));
+        
+        // Each withdrawal needs its three accounts
+        let result = Processor::process(&program_id, &accounts, &withdraw_batch(vec![request.clone(); 2]));
+        assert_eq!(result, Err(ProgramError::NotEnoughAccountKeys));
+        
+        // Each withdrawal's accounts must match its recipient and relayer
+        let other_recipient = WithdrawRequest {
+            recipient: Pubkey::new_unique(),
+            ..request.clone()
+        };
+        let result = Processor::process(&program_id, &accounts, &withdraw_batch(vec![other_recipient]));
+        assert_eq!(result, Err(TornadoError::InvalidRecipient.into()));
+        let other_relayer = WithdrawRequest {
+            relayer: Pubkey::new_unique(),
+            ..request.clone()
+        };
+        let result = Processor::process(&program_id, &accounts, &withdraw_batch(vec![other_relayer]));
+        assert_eq!(result, Err(TornadoError::InvalidRelayer.into()));
+        
+        // A withdrawal is checked as a `Withdraw`, so a valid-looking one gets as far as proof verification
+        let result = Processor::process(&program_id, &accounts, &withdraw_batch(vec![request]));
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
+        
+        // Nothing was written
+        assert!(accounts[7].data_is_empty());
+        assert_eq!(MerkleTree::from_account_data(&accounts[2].data.borrow()).unwrap().nullifier_count, 0);
+    }
+    
+    #[test]
+    fn test_check_sole_instruction() {
+        let program_id = Pubkey::new_unique();
+        let instructions_key = sysvar::instructions::id();
+        let sysvar_owner = sysvar::id();
+        let check = |instructions: &[(Pubkey, Vec<u8>)]| {
+            let mut lamports = 0;
+            let mut data = create_instructions_sysvar_data(instructions);
+            let instructions_sysvar_info =
+                create_account_info(&instructions_key, false, false, &mut lamports, &mut data, &sysvar_owner);
+            Processor::check_sole_instruction(&program_id, &instructions_sysvar_info)
+        };
+        
+        // The withdrawal may be alone or follow compute budget and fee quote instructions
+        assert_eq!(check(&[(program_id, vec![1])]), Ok(()));
+        assert_eq!(
+            check(&[(COMPUTE_BUDGET_PROGRAM_ID, vec![2]), (COMPUTE_BUDGET_PROGRAM_ID, vec![3]), (program_id, vec![1])]),
+            Ok(())
         );
+        assert_eq!(check(&[(ed25519_program::id(), vec![2]), (program_id, vec![1])]), Ok(()));
         
-        let system_program_account = create_account_info(
-            &system_program_key,
-            false,
-            false,
-            &mut system_program_lamports,
-            &mut system_program_data,
-            &system_program_key,
+        // Any other instruction is rejected, as is a withdrawal invoked by another program
+        let other_program = Pubkey::new_unique();
+        for instructions in [
+            vec![(system_program::id(), vec![2]), (program_id, vec![1])],
+            vec![(program_id, vec![1]), (program_id, vec![1])],
+            vec![(other_program, vec![1])],
+        ] {
+            assert_eq!(check(&instructions), Err(TornadoError::UnexpectedInstructions.into()));
+        }
+        
+        // The instructions sysvar must be the real one
+        let mut lamports = 0;
+        let mut data = create_instructions_sysvar_data(&[(program_id, vec![1])]);
+        let fake_key = Pubkey::new_unique();
+        let fake_sysvar_info = create_account_info(&fake_key, false, false, &mut lamports, &mut data, &sysvar_owner);
+        assert_eq!(
+            Processor::check_sole_instruction(&program_id, &fake_sysvar_info),
+            Err(ProgramError::UnsupportedSysvar)
         );
+    }
+    
+    #[test]
+    fn test_process_simulate_withdraw() {
+        let program_id = Pubkey::new_unique();
+        let payer_key = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let (verifier_key, _) = find_verifier_address(&program_id, &tornado_instance_key);
+        let nullifier_hash = [3u8; 32];
+        let (nullifier_marker_key, _) =
+            find_nullifier_address(&program_id, &tornado_instance_key, &nullifier_hash);
+        let recipient_key = Pubkey::new_unique();
+        let system_program_key = system_program::id();
         
-        // Create accounts array
+        let mut payer_lamports = 1000000;
+        let mut tornado_instance_lamports = 100000;
+        let mut merkle_tree_lamports = 0;
+        let mut verifier_lamports = 0;
+        let mut nullifier_marker_lamports = 0;
+        let mut payer_data = vec![0; 0];
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let mut verifier_data = VerifierState {
+            is_initialized: true,
+            verifying_key: create_test_verifying_key(),
+        }
+        .try_to_vec()
+        .unwrap();
+        let mut nullifier_marker_data = vec![0; 0];
+        
+        TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 3,
+            merkle_tree: merkle_tree_key,
+            verifier: verifier_key,
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: 100,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        }
+        .pack_into_slice(&mut tornado_instance_data);
+        let root = [1u8; 32];
+        let mut merkle_tree = MerkleTree::new(3, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        merkle_tree.roots[0] = root;
+        let mut merkle_tree_data = vec![0; MerkleTree::get_account_size(3, ROOT_HISTORY_SIZE as u8)];
+        merkle_tree.serialize(&mut &mut merkle_tree_data[..]).unwrap();
+        
+        // Only read-only accounts are passed; the relayer signs
         let accounts = vec![
-            payer_account,
-            tornado_instance_account,
-            system_program_account,
+            create_account_info(&payer_key, true, false, &mut payer_lamports, &mut payer_data, &system_program_key),
+            create_account_info(
+                &tornado_instance_key,
+                false,
+                false,
+                &mut tornado_instance_lamports,
+                &mut tornado_instance_data,
+                &program_id,
+            ),
+            create_account_info(
+                &merkle_tree_key,
+                false,
+                false,
+                &mut merkle_tree_lamports,
+                &mut merkle_tree_data,
+                &program_id,
+            ),
+            create_account_info(
+                &verifier_key,
+                false,
+                false,
+                &mut verifier_lamports,
+                &mut verifier_data,
+                &program_id,
+            ),
+            create_account_info(
+                &nullifier_marker_key,
+                false,
+                false,
+                &mut nullifier_marker_lamports,
+                &mut nullifier_marker_data,
+                &system_program_key,
+            ),
         ];
-        
-        // Create instruction data
-        let denomination = 100000;
-        let merkle_tree_height = 20;
-        let instruction = TornadoInstruction::Initialize {
-            denomination,
-            merkle_tree_height,
+        let simulate = |root, relayer, fee, refund| {
+            TornadoInstruction::SimulateWithdraw {
+                proof: create_dummy_proof(),
+                root,
+                nullifier_hash,
+                recipient: recipient_key,
+                relayer,
+                fee,
+                refund,
+                amount: None,
+            }
+            .try_to_vec()
+            .unwrap()
+        };
+        let simulate_withdraw = |instruction_data: &[u8]| {
+            let instance_before = accounts[1].data.borrow().to_vec();
+            let tree_before = accounts[2].data.borrow().to_vec();
+            let result = Processor::process(&program_id, &accounts, instruction_data);
+            
+            // Nothing is modified, whatever the outcome
+            assert_eq!(*accounts[1].data.borrow(), &instance_before[..]);
+            assert_eq!(*accounts[2].data.borrow(), &tree_before[..]);
+            assert_eq!(accounts[1].lamports(), 100000);
+            assert_eq!(accounts[4].lamports(), 0);
+            result
         };
-        let instruction_data = instruction.try_to_vec().unwrap();
         
-        // Process the instruction
-        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        // Each check fails with its own error
+        let result = simulate_withdraw(&simulate(root, payer_key, 1001, 0));
+        assert_eq!(result, Err(TornadoError::InvalidFee.into()));
+        let result = simulate_withdraw(&simulate(root, Pubkey::new_unique(), 1000, 0));
+        assert_eq!(result, Err(TornadoError::InvalidRelayer.into()));
+        let result = simulate_withdraw(&simulate(root, payer_key, 1000, 1));
+        assert_eq!(result, Err(TornadoError::InvalidAmount.into()));
+        let result = simulate_withdraw(&simulate([2u8; 32], payer_key, 1000, 0));
+        assert_eq!(result, Err(TornadoError::InvalidMerkleRoot.into()));
+        let result = simulate_withdraw(&simulate(root, payer_key, 1000, 0));
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
         
-        // Check the result
-        assert!(result.is_ok());
+        // A spent nullifier hash is reported before the proof is verified
+        let spent_tree = MerkleTree {
+            nullifier_hashes: vec![nullifier_hash],
+            ..merkle_tree
+        };
+        let spent_tree_data = spent_tree.try_to_vec().unwrap();
+        accounts[2].data.borrow_mut()[..spent_tree_data.len()].copy_from_slice(&spent_tree_data);
+        let result = simulate_withdraw(&simulate(root, payer_key, 1000, 0));
+        assert_eq!(result, Err(TornadoError::NullifierAlreadySpent.into()));
         
-        // Check the tornado instance data
-        let tornado_instance = TornadoInstance::unpack(&tornado_instance_account.data.borrow()).unwrap();
-        assert!(tornado_instance.is_initialized);
-        assert_eq!(tornado_instance.denomination, denomination);
-        assert_eq!(tornado_instance.merkle_tree_height, merkle_tree_height);
+        // A paused instance rejects the withdrawal too
+        let mut instance = TornadoInstance::unpack(&accounts[1].data.borrow()).unwrap();
+        instance.paused = true;
+        instance.pack_into_slice(&mut accounts[1].data.borrow_mut());
+        let result = simulate_withdraw(&simulate(root, payer_key, 1000, 0));
+        assert_eq!(result, Err(TornadoError::InstancePaused.into()));
     }
     
     #[test]
-    fn test_process_deposit() {
+    fn test_process_deposit_token() {
         // Create program ID
         let program_id = Pubkey::new_unique();
         
         // Create accounts
         let payer_key = Pubkey::new_unique();
         let tornado_instance_key = Pubkey::new_unique();
-        let merkle_tree_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let token_mint = Pubkey::new_unique();
+        let vault_key = get_vault_address(&program_id, &tornado_instance_key, &token_mint);
+        let payer_token_key = Pubkey::new_unique();
+        let commitment = [1u8; 32];
+        let (commitment_marker_key, _) =
+            find_commitment_address(&program_id, &tornado_instance_key, &commitment);
         let system_program_key = system_program::id();
+        let token_program_key = spl_token::id();
         
         // Create account data
         let mut payer_lamports = 1000000;
         let mut tornado_instance_lamports = 0;
         let mut merkle_tree_lamports = 0;
+        let mut commitment_marker_lamports = 0;
+        let mut payer_token_lamports = 0;
+        let mut vault_lamports = 0;
+        let mut token_program_lamports = 0;
         let mut system_program_lamports = 0;
         
         let mut payer_data = vec![0; 0];
         let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
         let mut merkle_tree_data = vec![0; 1000]; // Simplified for testing
+        let mut commitment_marker_data = vec![0; 0];
+        let mut payer_token_data = vec![0; spl_token::state::Account::LEN];
+        let mut vault_data = vec![0; spl_token::state::Account::LEN];
+        let mut token_program_data = vec![0; 0];
         let mut system_program_data = vec![0; 0];
         
-        // Initialize tornado instance
+        // Initialize tornado instance as a token pool
         let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
             is_initialized: true,
             denomination: 100000,
             merkle_tree_height: 20,
             merkle_tree: merkle_tree_key,
             verifier: Pubkey::new_unique(),
+            token_mint: Some(token_mint),
+            admin: Pubkey::new_unique(),
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
         };
         tornado_instance.pack_into_slice(&mut tornado_instance_data);
         
-        // Initialize merkle tree
-        let mut merkle_tree = MerkleTree {
-            is_initialized: true,
-            height: 20,
-            current_index: 0,
-            next_index: 0,
-            current_root_index: 0,
-            roots: [[0; 32]; ROOT_HISTORY_SIZE],
-            filled_subtrees: vec![[0; 32]; 20],
-            nullifier_hashes: Vec::new(),
-            commitments: Vec::new(),
+        // The payer's token account holds a different mint
+        let payer_token = spl_token::state::Account {
+            mint: Pubkey::new_unique(),
+            owner: payer_key,
+            amount: 100000,
+            state: spl_token::state::AccountState::Initialized,
+            ..Default::default()
         };
-        merkle_tree.serialize(&mut merkle_tree_data).unwrap();
+        spl_token::state::Account::pack(payer_token, &mut payer_token_data).unwrap();
         
         // Create account infos
         let payer_account = create_account_info(
@@ -534,6 +6730,42 @@ mod tests {
             &program_id,
         );
         
+        let commitment_marker_account = create_account_info(
+            &commitment_marker_key,
+            false,
+            true,
+            &mut commitment_marker_lamports,
+            &mut commitment_marker_data,
+            &system_program_key,
+        );
+        
+        let payer_token_account = create_account_info(
+            &payer_token_key,
+            false,
+            true,
+            &mut payer_token_lamports,
+            &mut payer_token_data,
+            &token_program_key,
+        );
+        
+        let vault_account = create_account_info(
+            &vault_key,
+            false,
+            true,
+            &mut vault_lamports,
+            &mut vault_data,
+            &token_program_key,
+        );
+        
+        let token_program_account = create_account_info(
+            &token_program_key,
+            false,
+            false,
+            &mut token_program_lamports,
+            &mut token_program_data,
+            &token_program_key,
+        );
+        
         let system_program_account = create_account_info(
             &system_program_key,
             false,
@@ -548,89 +6780,239 @@ mod tests {
             payer_account,
             tornado_instance_account,
             merkle_tree_account,
+            commitment_marker_account,
+            payer_token_account,
+            vault_account,
+            token_program_account,
             system_program_account,
         ];
         
         // Create instruction data
-        let commitment = [1u8; 32];
-        let instruction = TornadoInstruction::Deposit { commitment };
-        let instruction_data = instruction.try_to_vec().unwrap();
+        let instruction_data = TornadoInstruction::DepositToken { commitment }.try_to_vec().unwrap();
         
-        // Process the instruction
+        // A token account for the wrong mint is rejected
         let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidTokenAccount.into()));
+        
+        // The payer must sign
+        let mut unsigned_accounts = accounts.clone();
+        unsigned_accounts[0] = AccountInfo {
+            is_signer: false,
+            ..accounts[0].clone()
+        };
+        let result = Processor::process(&program_id, &unsigned_accounts, &instruction_data);
+        assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+        
+        // Every account the deposit writes to must be writable
+        for index in [0, 1, 2, 3, 4, 5] {
+            let mut read_only_accounts = accounts.clone();
+            read_only_accounts[index] = AccountInfo {
+                is_writable: false,
+                ..accounts[index].clone()
+            };
+            let result = Processor::process(&program_id, &read_only_accounts, &instruction_data);
+            assert_eq!(result, Err(TornadoError::AccountNotWritable.into()));
+        }
         
-        // Check the result (this will fail in a test environment due to CPI calls)
-        assert!(result.is_err());
+        // A native SOL deposit into a token instance is rejected
+        let instruction_data = TornadoInstruction::Deposit { commitment, encrypted_note: None, expected_index: None, max_deposit_fee: None }.try_to_vec().unwrap();
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidTokenMint.into()));
         
-        // In a real environment, we would check:
-        // 1. The commitment was added to the merkle tree
-        // 2. The funds were transferred
-        // 3. The merkle tree state was updated
+        // A token deposit into a SOL instance is rejected
+        let sol_instance = TornadoInstance {
+            token_mint: None,
+            ..tornado_instance
+        };
+        sol_instance.pack_into_slice(&mut accounts[1].data.borrow_mut());
+        let instruction_data = TornadoInstruction::DepositToken { commitment }.try_to_vec().unwrap();
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidTokenMint.into()));
     }
     
     #[test]
-    fn test_process_withdraw() {
-        // Create program ID
+    fn test_check_vault_authority() {
+        let program_id = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (vault_authority_key, vault_authority_bump) =
+            find_vault_authority_address(&program_id, &tornado_instance_key);
+        let tornado_instance = TornadoInstance {
+            vault_authority_bump,
+            ..TornadoInstance::default()
+        };
+        let system_program_key = system_program::id();
+        let mut vault_authority_lamports = 0;
+        let mut vault_authority_data = vec![];
+        let vault_authority_info = create_account_info(
+            &vault_authority_key,
+            false,
+            false,
+            &mut vault_authority_lamports,
+            &mut vault_authority_data,
+            &system_program_key,
+        );
+        
+        // The stored bump derives the vault authority, which signs token transfers with it
+        assert_eq!(
+            Processor::check_vault_authority(&program_id, &tornado_instance_key, &tornado_instance, &vault_authority_info),
+            Ok(())
+        );
+        assert_eq!(
+            Pubkey::create_program_address(
+                &[b"vault", tornado_instance_key.as_ref(), &[tornado_instance.vault_authority_bump]],
+                &program_id
+            ),
+            Ok(vault_authority_key)
+        );
+        
+        // Another instance's vault authority is rejected
+        let other_instance_key = Pubkey::new_unique();
+        assert_eq!(
+            Processor::check_vault_authority(&program_id, &other_instance_key, &tornado_instance, &vault_authority_info),
+            Err(TornadoError::InvalidAccountData.into())
+        );
+        
+        // So is a bump that doesn't derive the vault authority
+        let wrong_bump_instance = TornadoInstance {
+            vault_authority_bump: vault_authority_bump.wrapping_sub(1),
+            ..TornadoInstance::default()
+        };
+        assert_eq!(
+            Processor::check_vault_authority(&program_id, &tornado_instance_key, &wrong_bump_instance, &vault_authority_info),
+            Err(TornadoError::InvalidAccountData.into())
+        );
+    }
+
+    #[test]
+    fn test_check_merkle_tree() {
+        let program_id = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, merkle_tree_bump) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let tornado_instance = TornadoInstance {
+            merkle_tree: merkle_tree_key,
+            merkle_tree_bump,
+            ..TornadoInstance::default()
+        };
+        let mut merkle_tree_lamports = 0;
+        let mut merkle_tree_data = vec![];
+        let merkle_tree_info = create_account_info(
+            &merkle_tree_key,
+            false,
+            false,
+            &mut merkle_tree_lamports,
+            &mut merkle_tree_data,
+            &program_id,
+        );
+
+        // The stored bump derives the instance's tree
+        assert_eq!(
+            Processor::check_merkle_tree(&program_id, &tornado_instance_key, &tornado_instance, &merkle_tree_info),
+            Ok(())
+        );
+
+        // Another instance's tree is rejected
+        let other_instance_key = Pubkey::new_unique();
+        assert_eq!(
+            Processor::check_merkle_tree(&program_id, &other_instance_key, &tornado_instance, &merkle_tree_info),
+            Err(TornadoError::InvalidAccountData.into())
+        );
+
+        // So is a bump that doesn't derive the tree, as left by an unmigrated instance
+        let wrong_bump_instance = TornadoInstance {
+            merkle_tree_bump: merkle_tree_bump.wrapping_sub(1),
+            ..tornado_instance
+        };
+        assert_eq!(
+            Processor::check_merkle_tree(&program_id, &tornado_instance_key, &wrong_bump_instance, &merkle_tree_info),
+            Err(TornadoError::InvalidAccountData.into())
+        );
+
+        // And a tree the program doesn't own
+        let other_owner = Pubkey::new_unique();
+        let foreign_info = AccountInfo { owner: &other_owner, ..merkle_tree_info.clone() };
+        assert_eq!(
+            Processor::check_merkle_tree(&program_id, &tornado_instance_key, &tornado_instance, &foreign_info),
+            Err(TornadoError::InvalidAccountData.into())
+        );
+    }
+
+    #[test]
+    fn test_process_pause() {
         let program_id = Pubkey::new_unique();
         
         // Create accounts
-        let payer_key = Pubkey::new_unique();
+        let admin_key = Pubkey::new_unique();
+        let other_key = Pubkey::new_unique();
         let tornado_instance_key = Pubkey::new_unique();
-        let merkle_tree_key = Pubkey::new_unique();
-        let recipient_key = Pubkey::new_unique();
-        let relayer_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let commitment = [1u8; 32];
+        let (commitment_marker_key, _) =
+            find_commitment_address(&program_id, &tornado_instance_key, &commitment);
         let system_program_key = system_program::id();
         
         // Create account data
-        let mut payer_lamports = 1000000;
-        let mut tornado_instance_lamports = 100000;
+        let mut admin_lamports = 1000000;
+        let mut other_lamports = 1000000;
+        let mut tornado_instance_lamports = 0;
         let mut merkle_tree_lamports = 0;
-        let mut recipient_lamports = 0;
-        let mut relayer_lamports = 0;
+        let mut commitment_marker_lamports = 0;
         let mut system_program_lamports = 0;
         
-        let mut payer_data = vec![0; 0];
+        let mut admin_data = vec![0; 0];
+        let mut other_data = vec![0; 0];
         let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
-        let mut merkle_tree_data = vec![0; 1000]; // Simplified for testing
-        let mut recipient_data = vec![0; 0];
-        let mut relayer_data = vec![0; 0];
+        let mut commitment_marker_data = vec![0; 0];
         let mut system_program_data = vec![0; 0];
         
         // Initialize tornado instance
         let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
             is_initialized: true,
             denomination: 100000,
             merkle_tree_height: 20,
             merkle_tree: merkle_tree_key,
             verifier: Pubkey::new_unique(),
+            token_mint: None,
+            admin: admin_key,
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
         };
         tornado_instance.pack_into_slice(&mut tornado_instance_data);
         
-        // Initialize merkle tree with a known root
-        let root = [1u8; 32];
-        let mut roots = [[0; 32]; ROOT_HISTORY_SIZE];
-        roots[0] = root;
-        
-        let mut merkle_tree = MerkleTree {
-            is_initialized: true,
-            height: 20,
-            current_index: 0,
-            next_index: 1,
-            current_root_index: 0,
-            roots,
-            filled_subtrees: vec![[0; 32]; 20],
-            nullifier_hashes: Vec::new(),
-            commitments: vec![[2u8; 32]],
-        };
-        merkle_tree.serialize(&mut merkle_tree_data).unwrap();
+        // Initialize merkle tree
+        let merkle_tree = MerkleTree::new(20, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+        let mut merkle_tree_data = merkle_tree.try_to_vec().unwrap();
         
         // Create account infos
-        let payer_account = create_account_info(
-            &payer_key,
+        let admin_account = create_account_info(
+            &admin_key,
             true,
+            false,
+            &mut admin_lamports,
+            &mut admin_data,
+            &system_program_key,
+        );
+        
+        let other_account = create_account_info(
+            &other_key,
             true,
-            &mut payer_lamports,
-            &mut payer_data,
+            false,
+            &mut other_lamports,
+            &mut other_data,
             &system_program_key,
         );
         
@@ -652,21 +7034,12 @@ mod tests {
             &program_id,
         );
         
-        let recipient_account = create_account_info(
-            &recipient_key,
-            false,
-            true,
-            &mut recipient_lamports,
-            &mut recipient_data,
-            &system_program_key,
-        );
-        
-        let relayer_account = create_account_info(
-            &relayer_key,
+        let commitment_marker_account = create_account_info(
+            &commitment_marker_key,
             false,
             true,
-            &mut relayer_lamports,
-            &mut relayer_data,
+            &mut commitment_marker_lamports,
+            &mut commitment_marker_data,
             &system_program_key,
         );
         
@@ -679,42 +7052,234 @@ mod tests {
             &system_program_key,
         );
         
-        // Create accounts array
-        let accounts = vec![
-            payer_account,
-            tornado_instance_account,
+        let pause_data = TornadoInstruction::Pause.try_to_vec().unwrap();
+        let unpause_data = TornadoInstruction::Unpause.try_to_vec().unwrap();
+        
+        // Only the admin can pause the instance
+        let accounts = vec![other_account.clone(), tornado_instance_account.clone()];
+        let result = Processor::process(&program_id, &accounts, &pause_data);
+        assert_eq!(result, Err(TornadoError::Unauthorized.into()));
+        
+        // The admin must sign
+        let mut unsigned_admin_account = admin_account.clone();
+        unsigned_admin_account.is_signer = false;
+        let accounts = vec![unsigned_admin_account, tornado_instance_account.clone()];
+        let result = Processor::process(&program_id, &accounts, &pause_data);
+        assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+        assert!(!TornadoInstance::unpack(&tornado_instance_account.data.borrow()).unwrap().paused);
+        
+        // The admin pauses the instance
+        let pause_accounts = vec![admin_account.clone(), tornado_instance_account.clone()];
+        let result = Processor::process(&program_id, &pause_accounts, &pause_data);
+        assert!(result.is_ok());
+        assert!(TornadoInstance::unpack(&tornado_instance_account.data.borrow()).unwrap().paused);
+        
+        // Deposits fail while paused
+        let deposit_accounts = vec![
+            admin_account.clone(),
+            tornado_instance_account.clone(),
             merkle_tree_account,
-            recipient_account,
-            relayer_account,
+            commitment_marker_account,
             system_program_account,
+            admin_account,
         ];
+        let deposit_data = TornadoInstruction::Deposit { commitment, encrypted_note: None, expected_index: None, max_deposit_fee: None }.try_to_vec().unwrap();
+        let result = Processor::process(&program_id, &deposit_accounts, &deposit_data);
+        assert_eq!(result, Err(TornadoError::InstancePaused.into()));
         
-        // Create instruction data
-        let proof = vec![0u8; 256]; // Dummy proof
-        let nullifier_hash = [3u8; 32];
-        let fee = 1000;
-        let refund = 0;
+        // A non-admin cannot unpause it either
+        let accounts = vec![other_account, tornado_instance_account.clone()];
+        let result = Processor::process(&program_id, &accounts, &unpause_data);
+        assert_eq!(result, Err(TornadoError::Unauthorized.into()));
         
-        let instruction = TornadoInstruction::Withdraw {
-            proof,
-            root,
-            nullifier_hash,
-            recipient: recipient_key,
-            relayer: relayer_key,
-            fee,
-            refund,
+        // The admin unpauses the instance
+        let result = Processor::process(&program_id, &pause_accounts, &unpause_data);
+        assert!(result.is_ok());
+        let stored_instance = TornadoInstance::unpack(&tornado_instance_account.data.borrow()).unwrap();
+        assert_eq!(stored_instance, tornado_instance);
+    }
+    
+    #[test]
+    fn test_process_close_instance() {
+        set_syscall_stubs(Box::new(TestSyscallStubs));
+        
+        let program_id = Pubkey::new_unique();
+        
+        // Create accounts
+        let admin_key = Pubkey::new_unique();
+        let other_key = Pubkey::new_unique();
+        let tornado_instance_key = Pubkey::new_unique();
+        let (merkle_tree_key, _) = find_merkle_tree_address(&program_id, &tornado_instance_key);
+        let (verifier_key, _) = find_verifier_address(&program_id, &tornado_instance_key);
+        let receiver_key = Pubkey::new_unique();
+        let (registry_key, _) = find_registry_address(&program_id);
+        let system_program_key = system_program::id();
+        let rent = Rent::default();
+        
+        // Create account data
+        let mut admin_lamports = 1000000;
+        let mut other_lamports = 1000000;
+        let mut tornado_instance_lamports = rent.minimum_balance(TornadoInstance::LEN) + 100000;
+        let mut merkle_tree_lamports = 2000000;
+        let mut verifier_lamports = 3000000;
+        let mut receiver_lamports = 0;
+        let mut registry_lamports = rent.minimum_balance(Registry::get_account_size(2));
+        
+        let mut admin_data = vec![0; 0];
+        let mut other_data = vec![0; 0];
+        let mut tornado_instance_data = vec![0; TornadoInstance::LEN];
+        let mut verifier_data = VerifierState {
+            is_initialized: true,
+            verifying_key: create_test_verifying_key(),
+        }
+        .try_to_vec()
+        .unwrap();
+        let mut receiver_data = vec![0; 0];
+        let other_entry = RegistryEntry {
+            denomination: 200000,
+            instance: Pubkey::new_unique(),
+            token_mint: None,
+            admin: Pubkey::new_unique(),
+            vk_hash: [1u8; 32],
         };
-        let instruction_data = instruction.try_to_vec().unwrap();
+        let mut registry_data = vec![0; Registry::get_account_size(2)];
+        Registry {
+            is_initialized: true,
+            instances: vec![
+                RegistryEntry {
+                    denomination: 100000,
+                    instance: tornado_instance_key,
+                    token_mint: None,
+                    admin: admin_key,
+                    vk_hash: hash_verifying_key(&create_test_verifying_key()),
+                },
+                other_entry.clone(),
+            ],
+        }
+        .serialize(&mut &mut registry_data[..])
+        .unwrap();
         
-        // Process the instruction
+        // An instance with two deposits, one of them withdrawn
+        let tornado_instance = TornadoInstance {
+            version: TornadoInstance::VERSION,
+            is_initialized: true,
+            denomination: 100000,
+            merkle_tree_height: 20,
+            merkle_tree: merkle_tree_key,
+            verifier: verifier_key,
+            token_mint: None,
+            admin: admin_key,
+            paused: false,
+            variable_amount: false,
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance_key).1,
+            vk_hash: hash_verifying_key(&create_test_verifying_key()),
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            merkle_tree_bump: find_merkle_tree_address(&program_id, &tornado_instance_key).1,
+            max_sol_fee: 0,
+        };
+        tornado_instance.pack_into_slice(&mut tornado_instance_data);
+        let merkle_tree = MerkleTree {
+            version: MerkleTree::VERSION,
+            is_initialized: true,
+            height: 20,
+            hash_algo: HASH_ALGO_MIMC,
+            next_index: 2,
+            current_root_index: 2,
+            nullifier_count: 1,
+            roots: vec![[0; 32]; ROOT_HISTORY_SIZE],
+            root_timestamps: vec![0; ROOT_HISTORY_SIZE],
+            filled_subtrees: vec![[0; 32]; 20],
+            nullifier_hashes: Vec::new(),
+        };
+        let mut merkle_tree_data = merkle_tree.try_to_vec().unwrap();
+        
+        let accounts = vec![
+            create_account_info(&admin_key, true, false, &mut admin_lamports, &mut admin_data, &system_program_key),
+            create_account_info(
+                &tornado_instance_key,
+                false,
+                true,
+                &mut tornado_instance_lamports,
+                &mut tornado_instance_data,
+                &program_id,
+            ),
+            create_account_info(&merkle_tree_key, false, true, &mut merkle_tree_lamports, &mut merkle_tree_data, &program_id),
+            create_account_info(&verifier_key, false, true, &mut verifier_lamports, &mut verifier_data, &program_id),
+            create_account_info(
+                &receiver_key,
+                false,
+                true,
+                &mut receiver_lamports,
+                &mut receiver_data,
+                &system_program_key,
+            ),
+            create_account_info(&registry_key, false, true, &mut registry_lamports, &mut registry_data, &program_id),
+        ];
+        let other_account = create_account_info(
+            &other_key,
+            true,
+            false,
+            &mut other_lamports,
+            &mut other_data,
+            &system_program_key,
+        );
+        let instruction_data = TornadoInstruction::CloseInstance.try_to_vec().unwrap();
+        
+        // Only the admin can close the instance
+        let mut other_accounts = accounts.clone();
+        other_accounts[0] = other_account;
+        let result = Processor::process(&program_id, &other_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::Unauthorized.into()));
+        let mut unsigned_accounts = accounts.clone();
+        unsigned_accounts[0].is_signer = false;
+        let result = Processor::process(&program_id, &unsigned_accounts, &instruction_data);
+        assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+        
+        // The instance can't be closed while a deposit is unspent
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InstanceNotEmpty.into()));
+        
+        // Once both are withdrawn, lamports above the rent don't keep it open
+        MerkleTree {
+            nullifier_count: 2,
+            ..merkle_tree
+        }
+        .to_account_data(&mut accounts[2].data.borrow_mut())
+        .unwrap();
+        
+        // The lamports can't be sent to one of the closed accounts
+        let mut self_receiver_accounts = accounts.clone();
+        self_receiver_accounts[4] = accounts[2].clone();
+        let result = Processor::process(&program_id, &self_receiver_accounts, &instruction_data);
+        assert_eq!(result, Err(TornadoError::InvalidAccountData.into()));
+        
+        // The emptied instance is closed, and its rent and dust go to the receiver
         let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            accounts[4].lamports(),
+            rent.minimum_balance(TornadoInstance::LEN) + 100000 + 2000000 + 3000000
+        );
+        for account in &accounts[1..4] {
+            assert_eq!(account.lamports(), 0);
+            assert!(account.data.borrow().iter().all(|&byte| byte == 0));
+        }
         
-        // Check the result (this will fail in a test environment due to proof verification)
-        assert!(result.is_err());
+        // Only the other instance is left in the registry
+        let registry = Registry::deserialize(&mut &accounts[5].data.borrow()[..]).unwrap();
+        assert_eq!(registry.instances, vec![other_entry]);
         
-        // In a real environment, we would check:
-        // 1. The nullifier hash was added to the merkle tree
-        // 2. The funds were transferred to the recipient and relayer
-        // 3. The merkle tree state was updated
+        // A closed instance can't be closed again
+        let result = Processor::process(&program_id, &accounts, &instruction_data);
+        assert_eq!(result, Err(ProgramError::UninitializedAccount));
     }
-}
\ No newline at end of file
+}
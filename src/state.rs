@@ -2,17 +2,48 @@
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    msg,
     program_error::ProgramError,
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
 };
 
-/// Maximum number of roots to store in history
+use crate::{
+    error::TornadoError,
+    merkle_tree::{get_zero_value, HASH_ALGO_MIMC},
+    verifier::{CURVE_BN254, PROOF_SYSTEM_GROTH16},
+};
+
+/// Default number of roots to store in history
 pub const ROOT_HISTORY_SIZE: usize = 30;
 
+/// Maximum number of roots an instance can store in history (`current_root_index` is a `u8`)
+pub const MAX_ROOT_HISTORY_SIZE: usize = u8::MAX as usize;
+
+/// Largest fee cap an instance can set, in basis points of the amount withdrawn (100%)
+pub const MAX_FEE_BPS: u16 = 10_000;
+
+/// Largest deposit fee an instance can charge, in basis points of its denomination (5%)
+pub const MAX_DEPOSIT_FEE_BPS: u16 = 500;
+
+/// Deserialize the state stored in an account
+///
+/// `account` names the account in the log when its data doesn't deserialize,
+/// which then fails with `InvalidAccountData` rather than a Borsh I/O error.
+pub fn deserialize_account_data<T: BorshDeserialize>(data: &[u8], account: &str) -> Result<T, ProgramError> {
+    T::deserialize(&mut &data[..]).map_err(|_| {
+        msg!("Failed to deserialize the {} account", account);
+        TornadoError::InvalidAccountData.into()
+    })
+}
+
 /// Tornado instance state
 #[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
 pub struct TornadoInstance {
+    /// The layout version of the account (`TornadoInstance::VERSION`)
+    pub version: u8,
     /// Is the instance initialized
     pub is_initialized: bool,
     /// The denomination amount for this instance
@@ -23,6 +54,85 @@ pub struct TornadoInstance {
     pub merkle_tree: Pubkey,
     /// The verifier account
     pub verifier: Pubkey,
+    /// The SPL token mint pooled by this instance (`None` for native SOL)
+    pub token_mint: Option<Pubkey>,
+    /// The authority that can pause and unpause the instance
+    pub admin: Pubkey,
+    /// Are deposits and withdrawals halted
+    pub paused: bool,
+    /// Does the instance accept arbitrary amounts (`DepositVariable` / `WithdrawVariable`)
+    ///
+    /// The denomination is then the maximum amount of a single deposit.
+    pub variable_amount: bool,
+    /// The largest relayer fee, in basis points of the amount withdrawn
+    pub max_fee_bps: u16,
+    /// The curve of the withdrawal proofs (`CURVE_BN254` or `CURVE_BLS12_381`)
+    pub curve: u8,
+    /// The oldest root a withdrawal may use, as the number of roots added since (0 accepts the whole history)
+    pub max_root_age: u8,
+    /// Must each SOL withdrawal be the only instruction of its transaction, besides compute budget and ed25519 instructions
+    pub strict_tx: bool,
+    /// The `ComplianceList` of recipients withdrawals may not pay (`None` for a permissionless instance)
+    pub compliance_list: Option<Pubkey>,
+    /// The proof system of the withdrawal proofs (`PROOF_SYSTEM_GROTH16`)
+    pub proof_system: u8,
+    /// The bump of the vault authority PDA (`[b"vault", instance]`), which signs token transfers out of the vault
+    pub vault_authority_bump: u8,
+    /// The keccak hash of the verifying key stored in the verifier account (see `hash_verifying_key`)
+    pub vk_hash: [u8; 32],
+    /// The lamports a SOL deposit pays `treasury` on top of its amount (0 for no fee)
+    pub deposit_fee: u64,
+    /// The account paid the deposit fee (unused while `deposit_fee` is 0)
+    pub treasury: Pubkey,
+    /// The number of deposits the Merkle tree must hold before withdrawals are allowed (0 for none)
+    pub min_anonymity_set: u32,
+    /// The seconds a withdrawal's root must have been in the tree for (0 for no time-lock)
+    pub withdraw_delay_secs: u32,
+    /// The bump of the Merkle tree PDA (`[b"merkle_tree", instance, &[0]]`), which checks the tree account without searching for the bump
+    pub merkle_tree_bump: u8,
+    /// The largest relayer fee a token withdrawal may pay in lamports with `fee_in_sol` (0 allows none)
+    pub max_sol_fee: u64,
+}
+
+impl TornadoInstance {
+    /// Current layout version
+    ///
+    /// Version 1 is the unversioned layout of `TornadoInstanceV1`, whose first
+    /// byte is `is_initialized` (always 1 for an initialized instance).
+    pub const VERSION: u8 = 2;
+
+    /// The largest fee a withdrawal of `amount` may pay its relayer
+    pub fn max_fee(&self, amount: u64) -> u64 {
+        // At most `amount`, since `max_fee_bps` is at most `MAX_FEE_BPS`
+        (amount as u128 * self.max_fee_bps as u128 / MAX_FEE_BPS as u128) as u64
+    }
+
+    /// The largest deposit fee the admin may set, `MAX_DEPOSIT_FEE_BPS` of the denomination
+    pub fn max_deposit_fee(&self) -> u64 {
+        (self.denomination as u128 * MAX_DEPOSIT_FEE_BPS as u128 / MAX_FEE_BPS as u128) as u64
+    }
+
+    /// The denomination amount of the instance (the cap of a variable-amount deposit)
+    pub fn denomination(&self) -> u64 {
+        self.denomination
+    }
+
+    /// The height of the instance's Merkle tree
+    pub fn merkle_tree_height(&self) -> u8 {
+        self.merkle_tree_height
+    }
+
+    /// Load an initialized instance from an account owned by the program
+    ///
+    /// Fails with `InvalidAccountData` for an account owned by another program,
+    /// and like `unpack` for an uninitialized or unmigrated one.
+    pub fn load_checked(account: &AccountInfo, program_id: &Pubkey) -> Result<Self, ProgramError> {
+        if account.owner != program_id {
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+
+        Self::unpack(&account.data.borrow())
+    }
 }
 
 impl Sealed for TornadoInstance {}
@@ -34,11 +144,285 @@ impl IsInitialized for TornadoInstance {
 }
 
 impl Pack for TornadoInstance {
-    const LEN: usize = 1 + 8 + 1 + 32 + 32; // is_initialized + denomination + merkle_tree_height + merkle_tree + verifier
+    const LEN: usize = 1 + 1 + 8 + 1 + 32 + 32 + 33 + 32 + 1 + 1 + 2 + 1 + 1 + 1 + 33 + 1 + 1 + 32 + 8 + 32 + 4 + 4 + 1 + 8; // version + is_initialized + denomination + merkle_tree_height + merkle_tree + verifier + token_mint + admin + paused + variable_amount + max_fee_bps + curve + max_root_age + strict_tx + compliance_list + proof_system + vault_authority_bump + vk_hash + deposit_fee + treasury + min_anonymity_set + withdraw_delay_secs + merkle_tree_bump + max_sol_fee
+
+    fn unpack_unchecked(input: &[u8]) -> Result<Self, ProgramError> {
+        // Older layouts have a different length, so check the version first.
+        // They must be migrated; zeroed data is an uninitialized account.
+        let version = input.first().copied().unwrap_or_default();
+        if version != Self::VERSION && version != 0 {
+            return Err(TornadoError::UnsupportedStateVersion.into());
+        }
+        if input.len() != Self::LEN {
+            msg!("Tornado instance account has {} bytes, expected {}", input.len(), Self::LEN);
+            return Err(TornadoError::InvalidAccountData.into());
+        }
+        Self::unpack_from_slice(input)
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        // `token_mint` is an `Option`, so the serialized data may be shorter than `LEN`
+        deserialize_account_data(src, "Tornado instance")
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let data = self.try_to_vec().unwrap();
+        dst[..data.len()].copy_from_slice(&data);
+    }
+}
+
+/// Tornado instance state before accounts were versioned (version 1)
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct TornadoInstanceV1 {
+    /// Is the instance initialized
+    pub is_initialized: bool,
+    /// The denomination amount for this instance
+    pub denomination: u64,
+    /// The height of the Merkle tree
+    pub merkle_tree_height: u8,
+    /// The Merkle tree account
+    pub merkle_tree: Pubkey,
+    /// The verifier account
+    pub verifier: Pubkey,
+    /// The SPL token mint pooled by this instance (`None` for native SOL)
+    pub token_mint: Option<Pubkey>,
+    /// The authority that can pause and unpause the instance
+    pub admin: Pubkey,
+    /// Are deposits and withdrawals halted
+    pub paused: bool,
+    /// Does the instance accept arbitrary amounts
+    pub variable_amount: bool,
+}
+
+impl From<TornadoInstanceV1> for TornadoInstance {
+    fn from(instance: TornadoInstanceV1) -> Self {
+        Self {
+            version: Self::VERSION,
+            is_initialized: instance.is_initialized,
+            denomination: instance.denomination,
+            merkle_tree_height: instance.merkle_tree_height,
+            merkle_tree: instance.merkle_tree,
+            verifier: instance.verifier,
+            token_mint: instance.token_mint,
+            admin: instance.admin,
+            paused: instance.paused,
+            variable_amount: instance.variable_amount,
+            // Unversioned instances accept fees up to the whole amount and verify
+            // BN254 Groth16 proofs; every setting added since starts off
+            max_fee_bps: MAX_FEE_BPS,
+            curve: CURVE_BN254,
+            max_root_age: 0,
+            strict_tx: false,
+            compliance_list: None,
+            proof_system: PROOF_SYSTEM_GROTH16,
+            deposit_fee: 0,
+            treasury: Pubkey::default(),
+            min_anonymity_set: 0,
+            withdraw_delay_secs: 0,
+            max_sol_fee: 0,
+            // Derived by `Migrate`, which knows the instance's address and verifier
+            vault_authority_bump: 0,
+            merkle_tree_bump: 0,
+            vk_hash: [0; 32],
+        }
+    }
+}
+
+/// An instance listed in the registry
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, Default, PartialEq)]
+pub struct RegistryEntry {
+    /// The denomination amount of the instance
+    pub denomination: u64,
+    /// The Tornado instance account
+    pub instance: Pubkey,
+    /// The SPL token mint pooled by the instance (`None` for native SOL)
+    pub token_mint: Option<Pubkey>,
+    /// The admin of the instance
+    pub admin: Pubkey,
+    /// The hash of the instance's verifying key (`hash_verifying_key`)
+    pub vk_hash: [u8; 32],
+}
+
+impl RegistryEntry {
+    /// Maximum serialized size of an entry
+    pub const LEN: usize = 8 + 32 + 33 + 32 + 32; // denomination + instance + token_mint + admin + vk_hash
+}
+
+/// Instance registry state
+///
+/// The registry account (PDA of `[b"registry"]`) lists every initialized
+/// instance, so clients can discover pools by denomination and mint. It is
+/// grown by one entry each time an instance is initialized. Several
+/// instances can pool the same denomination and mint; clients filter them by
+/// admin and verifying key hash.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct Registry {
+    /// Is the registry initialized
+    pub is_initialized: bool,
+    /// The registered instances, in initialization order
+    pub instances: Vec<RegistryEntry>,
+}
+
+impl Sealed for Registry {}
+
+impl IsInitialized for Registry {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Registry {
+    /// Calculate the size of the registry account holding the given number of entries
+    pub fn get_account_size(num_instances: usize) -> usize {
+        // is_initialized + instances length prefix + instances
+        1 + 4 + num_instances * RegistryEntry::LEN
+    }
+
+    /// The instances pooling the given denomination and mint, in initialization order
+    ///
+    /// Anyone can initialize an instance, with their own admin and verifying
+    /// key, so an entry is only as trustworthy as those two.
+    pub fn find_instances(
+        &self,
+        denomination: u64,
+        token_mint: Option<Pubkey>,
+    ) -> impl Iterator<Item = &RegistryEntry> {
+        self.instances
+            .iter()
+            .filter(move |entry| entry.denomination == denomination && entry.token_mint == token_mint)
+    }
+
+    /// Find the first instance pooling the given denomination and mint with a trusted verifying key
+    pub fn find_instance(
+        &self,
+        denomination: u64,
+        token_mint: Option<Pubkey>,
+        vk_hash: &[u8; 32],
+    ) -> Option<&RegistryEntry> {
+        self.find_instances(denomination, token_mint)
+            .find(|entry| entry.vk_hash == *vk_hash)
+    }
+}
+
+/// Compliance list state
+///
+/// The compliance list account of an instance (PDA of `[b"compliance_list", instance]`)
+/// is a denylist of recipients, set by the instance admin. Withdrawals to a
+/// listed recipient are rejected while the instance's `compliance_list` points
+/// to it. The recipients are kept sorted, so a lookup is a binary search.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct ComplianceList {
+    /// Is the compliance list initialized
+    pub is_initialized: bool,
+    /// The blocked recipients, in ascending order without duplicates
+    pub recipients: Vec<Pubkey>,
+}
+
+impl Sealed for ComplianceList {}
+
+impl IsInitialized for ComplianceList {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl ComplianceList {
+    /// Calculate the size of the compliance list account holding the given number of recipients
+    pub fn get_account_size(num_recipients: usize) -> usize {
+        // is_initialized + recipients length prefix + recipients
+        1 + 4 + num_recipients * 32
+    }
+
+    /// Whether the recipient is on the list
+    pub fn is_blocked(&self, recipient: &Pubkey) -> bool {
+        self.recipients.binary_search(recipient).is_ok()
+    }
+}
+
+/// Verifier state
+///
+/// Stores the Groth16 verifying key produced by the trusted setup
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct VerifierState {
+    /// Is the verifier initialized
+    pub is_initialized: bool,
+    /// The `VerifyingKey<Bn254>` serialized with `CanonicalSerialize` (compressed):
+    /// alpha_g1, beta_g2, gamma_g2, delta_g2 and the `gamma_abc_g1` vector
+    pub verifying_key: Vec<u8>,
+}
+
+impl Sealed for VerifierState {}
+
+impl IsInitialized for VerifierState {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl VerifierState {
+    /// Calculate the size of the verifier account for a serialized verifying key
+    pub fn get_account_size(verifying_key_len: usize) -> usize {
+        // is_initialized + verifying_key length prefix + verifying_key
+        1 + 4 + verifying_key_len
+    }
+}
+
+/// Commitment marker state
+///
+/// One marker account (PDA of `[b"commitment", instance, commitment]`) is created
+/// per deposit, so duplicate commitments are rejected without scanning the tree.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct CommitmentMarker {
+    /// Is the marker initialized
+    pub is_initialized: bool,
+    /// The leaf index of the commitment in the Merkle tree
+    pub leaf_index: u32,
+}
+
+impl Sealed for CommitmentMarker {}
+
+impl IsInitialized for CommitmentMarker {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for CommitmentMarker {
+    const LEN: usize = 1 + 4; // is_initialized + leaf_index
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        deserialize_account_data(src, "commitment marker")
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let data = self.try_to_vec().unwrap();
+        dst[..data.len()].copy_from_slice(&data);
+    }
+}
+
+/// Nullifier marker state
+///
+/// One marker account (PDA of `[b"nullifier", instance, nullifier_hash]`) is created
+/// per withdrawal, so a note is unspent exactly when its marker doesn't exist.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct NullifierMarker {
+    /// Is the marker initialized
+    pub is_initialized: bool,
+}
+
+impl Sealed for NullifierMarker {}
+
+impl IsInitialized for NullifierMarker {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for NullifierMarker {
+    const LEN: usize = 1; // is_initialized
 
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        let instance = Self::try_from_slice(src)?;
-        Ok(instance)
+        deserialize_account_data(src, "nullifier marker")
     }
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
@@ -47,27 +431,66 @@ impl Pack for TornadoInstance {
     }
 }
 
+/// Encrypted note state
+///
+/// Stored at the PDA of `[b"note", instance, commitment]` when a deposit includes
+/// an encrypted copy of its note. The program never interprets the ciphertext;
+/// it lets the depositor recover the note from any device.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct EncryptedNote {
+    /// Is the note initialized
+    pub is_initialized: bool,
+    /// The encrypted note, as provided by the depositor
+    pub ciphertext: Vec<u8>,
+}
+
+impl Sealed for EncryptedNote {}
+
+impl IsInitialized for EncryptedNote {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl EncryptedNote {
+    /// Calculate the size of the encrypted note account for a ciphertext
+    pub fn get_account_size(ciphertext_len: usize) -> usize {
+        // is_initialized + ciphertext length prefix + ciphertext
+        1 + 4 + ciphertext_len
+    }
+}
+
 /// Merkle tree state
 #[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
 pub struct MerkleTree {
+    /// The layout version of the account (`MerkleTree::VERSION`)
+    pub version: u8,
     /// Is the tree initialized
     pub is_initialized: bool,
     /// The height of the tree
     pub height: u8,
-    /// The current index in the tree
-    pub current_index: u32,
+    /// The hash algorithm of the tree (`HASH_ALGO_MIMC` or `HASH_ALGO_POSEIDON`)
+    pub hash_algo: u8,
     /// The next index to insert
     pub next_index: u32,
     /// The current root index
     pub current_root_index: u8,
-    /// The roots history
-    pub roots: [[u8; 32]; ROOT_HISTORY_SIZE],
+    /// The number of notes withdrawn, including spends in `nullifier_hashes`
+    ///
+    /// Once it reaches `next_index`, the number of deposits, every deposit has
+    /// been withdrawn and the instance can be closed.
+    pub nullifier_count: u64,
+    /// The roots history, sized at initialization
+    pub roots: Vec<[u8; 32]>,
+    /// The unix timestamp each root in `roots` was inserted at, by index
+    pub root_timestamps: Vec<i64>,
     /// The filled subtrees
     pub filled_subtrees: Vec<[u8; 32]>,
-    /// The nullifier hashes that have been used
+    /// The nullifier hashes spent before nullifier markers were introduced
+    ///
+    /// New withdrawals create a `NullifierMarker` instead; this list is only
+    /// checked so that older spends stay rejected.
     pub nullifier_hashes: Vec<[u8; 32]>,
-    /// The commitments that have been used
-    pub commitments: Vec<[u8; 32]>,
 }
 
 impl Sealed for MerkleTree {}
@@ -79,11 +502,132 @@ impl IsInitialized for MerkleTree {
 }
 
 impl MerkleTree {
-    /// Calculate the size of the Merkle tree account based on the height
-    pub fn get_account_size(height: u8) -> usize {
-        // Base size + filled_subtrees + nullifier_hashes + commitments
-        // We allocate space for 2^height nullifiers and commitments
-        let max_leaves = 2u32.pow(height as u32);
-        1 + 1 + 4 + 4 + 1 + (ROOT_HISTORY_SIZE * 32) + (height as usize * 32) + (max_leaves as usize * 32) + (max_leaves as usize * 32)
-    }
-}
\ No newline at end of file
+    /// Current layout version
+    ///
+    /// Version 1 is the unversioned layout of `MerkleTreeV1`, whose first
+    /// byte is `is_initialized` (always 1 for an initialized tree).
+    pub const VERSION: u8 = 2;
+
+    /// Create an empty tree
+    ///
+    /// Every filled subtree starts as the zero value of its level and the first
+    /// root is the root of the empty tree, as `insert_leaf` expects. The
+    /// history must hold at least that root.
+    pub fn new(height: u8, root_history_size: u8, hash_algo: u8) -> Result<Self, ProgramError> {
+        if root_history_size == 0 {
+            return Err(TornadoError::InvalidMerkleTreeState.into());
+        }
+
+        let filled_subtrees = (0..height as usize)
+            .map(|level| get_zero_value(hash_algo, level))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut roots = vec![[0u8; 32]; root_history_size as usize];
+        roots[0] = get_zero_value(hash_algo, height as usize)?;
+
+        Ok(Self {
+            version: Self::VERSION,
+            is_initialized: true,
+            height,
+            hash_algo,
+            next_index: 0,
+            current_root_index: 0,
+            nullifier_count: 0,
+            root_timestamps: vec![0; root_history_size as usize],
+            roots,
+            filled_subtrees,
+            nullifier_hashes: Vec::new(),
+        })
+    }
+
+    /// Calculate the size of the Merkle tree account based on the height and root history size
+    ///
+    /// This leaves room for 2^height nullifier hashes, as accounts did before
+    /// nullifier markers. New trees keep none, so they are allocated
+    /// `get_account_size_bounded` instead.
+    pub fn get_account_size(height: u8, root_history_size: u8) -> usize {
+        // Base size + roots + root_timestamps + filled_subtrees + nullifier_hashes
+        // We allocate space for 2^height nullifiers
+        let max_leaves = 2usize.pow(height as u32);
+        1 + 1 + 1 + 1 + 4 + 1 + 8 + 4 + (root_history_size as usize * 32) + 4 + (root_history_size as usize * 8) + (height as usize * 32) + (max_leaves * 32)
+    }
+
+    /// Calculate the size of a Merkle tree account without legacy nullifier hashes
+    ///
+    /// Commitments and nullifiers are recorded in marker PDAs, so the tree
+    /// only holds its header, root history and filled subtrees: a few KB at
+    /// any height.
+    pub fn get_account_size_bounded(height: u8, root_history_size: u8) -> usize {
+        // version + is_initialized + height + hash_algo + next_index + current_root_index
+        // + nullifier_count, then each vector with its length prefix
+        1 + 1 + 1 + 1 + 4 + 1 + 8
+            + (4 + root_history_size as usize * 32)
+            + (4 + root_history_size as usize * 8)
+            + (4 + height as usize * 32)
+            + 4
+    }
+
+    /// Deserialize the Merkle tree stored in an account
+    ///
+    /// The account is sized for a full tree, so it can be longer than the
+    /// serialized state. Older layouts must be migrated first.
+    pub fn from_account_data(data: &[u8]) -> Result<Self, ProgramError> {
+        if data.first() != Some(&Self::VERSION) {
+            return Err(TornadoError::UnsupportedStateVersion.into());
+        }
+
+        deserialize_account_data(data, "Merkle tree")
+    }
+
+    /// Serialize the Merkle tree into its account
+    ///
+    /// Fails with `AccountDataTooSmall`, leaving the account unchanged, when
+    /// the serialized state doesn't fit rather than writing part of it.
+    pub fn to_account_data(&self, data: &mut [u8]) -> ProgramResult {
+        let tree_data = self.try_to_vec()?;
+        let Some(tree_account_data) = data.get_mut(..tree_data.len()) else {
+            msg!("Merkle tree needs {} bytes, its account has {}", tree_data.len(), data.len());
+            return Err(ProgramError::AccountDataTooSmall);
+        };
+        tree_account_data.copy_from_slice(&tree_data);
+        Ok(())
+    }
+}
+
+/// Merkle tree state before accounts were versioned (version 1)
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct MerkleTreeV1 {
+    /// Is the tree initialized
+    pub is_initialized: bool,
+    /// The height of the tree
+    pub height: u8,
+    /// The next index to insert
+    pub next_index: u32,
+    /// The current root index
+    pub current_root_index: u8,
+    /// The roots history
+    pub roots: Vec<[u8; 32]>,
+    /// The filled subtrees
+    pub filled_subtrees: Vec<[u8; 32]>,
+    /// The nullifier hashes spent before nullifier markers were introduced
+    pub nullifier_hashes: Vec<[u8; 32]>,
+}
+
+impl From<MerkleTreeV1> for MerkleTree {
+    fn from(tree: MerkleTreeV1) -> Self {
+        Self {
+            version: Self::VERSION,
+            is_initialized: tree.is_initialized,
+            height: tree.height,
+            // Unversioned trees were all hashed with MiMC
+            hash_algo: HASH_ALGO_MIMC,
+            next_index: tree.next_index,
+            current_root_index: tree.current_root_index,
+            nullifier_count: tree.nullifier_hashes.len() as u64,
+            // Stamped with the migration time by `Migrate`
+            root_timestamps: vec![0; tree.roots.len()],
+            roots: tree.roots,
+            filled_subtrees: tree.filled_subtrees,
+            nullifier_hashes: tree.nullifier_hashes,
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Stealth addresses for `WithdrawStealth`
+//!
+//! A recipient publishes a meta-address of two ed25519 points, a spend key
+//! `S = s·B` and a view key `V = v·B`. The withdrawer picks an ephemeral
+//! secret `e` and pays the one-time address `P = S + h·B`, where
+//! `h = H(e·V)` is a scalar only the withdrawer and the recipient can compute.
+//! The ephemeral pubkey `E = e·B` is announced on-chain, and the recipient
+//! finds its payments by checking `S + H(v·E)·B` against each announced
+//! address. Only the recipient can spend from `P`: its key is `s + h`, which
+//! needs the spend secret.
+//!
+//! `P` is an ordinary ed25519 pubkey, so it holds lamports like any wallet
+//! address. Its key is a scalar rather than a seed, so spending from it needs
+//! a wallet that signs with an expanded ed25519 key.
+//!
+//! Deriving `P` needs the recipient's spend key, which would link the payment
+//! to the recipient if it were passed on-chain. The program instead checks the
+//! proof against `stealth_recipient(P, E)`, which binds the address the
+//! withdrawer derived to the ephemeral pubkey it announces.
+
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_TABLE,
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    scalar::Scalar,
+};
+use sha3::{Digest, Sha3_512};
+use solana_program::{msg, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::{error::TornadoError, utils::STEALTH_DOMAIN};
+
+/// The public keys a recipient publishes to be paid at stealth addresses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StealthMetaAddress {
+    /// The spend pubkey `S`
+    pub spend_pubkey: [u8; 32],
+    /// The view pubkey `V`
+    pub view_pubkey: [u8; 32],
+}
+
+/// A recipient's stealth secrets
+#[derive(Clone)]
+pub struct StealthKeys {
+    /// The spend scalar `s`
+    spend_key: Scalar,
+    /// The view scalar `v`
+    view_key: Scalar,
+}
+
+impl StealthKeys {
+    /// Create stealth keys from two secrets, each reduced to a scalar
+    pub fn new(spend_secret: &[u8; 32], view_secret: &[u8; 32]) -> Self {
+        Self {
+            spend_key: Scalar::from_bytes_mod_order(*spend_secret),
+            view_key: Scalar::from_bytes_mod_order(*view_secret),
+        }
+    }
+
+    /// The meta-address to publish
+    pub fn meta_address(&self) -> StealthMetaAddress {
+        StealthMetaAddress {
+            spend_pubkey: (&self.spend_key * &ED25519_BASEPOINT_TABLE).compress().to_bytes(),
+            view_pubkey: (&self.view_key * &ED25519_BASEPOINT_TABLE).compress().to_bytes(),
+        }
+    }
+
+    /// Check whether an announced stealth address was derived for these keys
+    ///
+    /// Returns the scalar `s + h` that signs for `recipient`, or `None` when
+    /// the address belongs to someone else.
+    pub fn scan(&self, recipient: &Pubkey, ephemeral_pubkey: &[u8; 32]) -> Option<[u8; 32]> {
+        let ephemeral_point = decompress(ephemeral_pubkey).ok()?;
+        let shared_secret = shared_secret_scalar(&(self.view_key * ephemeral_point));
+        let spend_key = self.spend_key + shared_secret;
+        let address = (&spend_key * &ED25519_BASEPOINT_TABLE).compress().to_bytes();
+        (address == recipient.to_bytes()).then(|| spend_key.to_bytes())
+    }
+}
+
+/// Derive a one-time stealth address for a meta-address
+///
+/// `ephemeral_secret` must be fresh for each withdrawal. Returns the stealth
+/// address and the ephemeral pubkey to withdraw with.
+pub fn derive_stealth_address(
+    meta_address: &StealthMetaAddress,
+    ephemeral_secret: &[u8; 32],
+) -> Result<(Pubkey, [u8; 32]), ProgramError> {
+    let spend_point = decompress(&meta_address.spend_pubkey)?;
+    let view_point = decompress(&meta_address.view_pubkey)?;
+
+    let ephemeral_key = Scalar::from_bytes_mod_order(*ephemeral_secret);
+    let ephemeral_pubkey = (&ephemeral_key * &ED25519_BASEPOINT_TABLE).compress().to_bytes();
+    let shared_secret = shared_secret_scalar(&(ephemeral_key * view_point));
+    let address = spend_point + &shared_secret * &ED25519_BASEPOINT_TABLE;
+
+    Ok((Pubkey::new_from_array(address.compress().to_bytes()), ephemeral_pubkey))
+}
+
+// Hash the Diffie-Hellman point shared by the withdrawer and the recipient to a scalar
+fn shared_secret_scalar(shared_point: &EdwardsPoint) -> Scalar {
+    let digest: [u8; 64] = Sha3_512::new()
+        .chain_update(STEALTH_DOMAIN)
+        .chain_update(shared_point.compress().as_bytes())
+        .finalize()
+        .into();
+    Scalar::from_bytes_mod_order_wide(&digest)
+}
+
+// Decompress a public key of a meta-address or announcement
+fn decompress(pubkey: &[u8; 32]) -> Result<EdwardsPoint, ProgramError> {
+    CompressedEdwardsY(*pubkey).decompress().ok_or_else(|| {
+        msg!("Stealth key is not an ed25519 point");
+        TornadoError::InvalidRecipient.into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stealth_address_round_trip() {
+        let keys = StealthKeys::new(&[1u8; 32], &[2u8; 32]);
+        let meta_address = keys.meta_address();
+
+        let (address, ephemeral_pubkey) = derive_stealth_address(&meta_address, &[3u8; 32]).unwrap();
+        assert!(address.is_on_curve());
+        assert_ne!(address.to_bytes(), meta_address.spend_pubkey);
+
+        // The recipient finds the address, and its key signs for it
+        let spend_key = keys.scan(&address, &ephemeral_pubkey).unwrap();
+        let signer = &Scalar::from_canonical_bytes(spend_key).unwrap() * &ED25519_BASEPOINT_TABLE;
+        assert_eq!(signer.compress().to_bytes(), address.to_bytes());
+
+        // Each ephemeral secret gives an unrelated address
+        let (other_address, other_ephemeral_pubkey) = derive_stealth_address(&meta_address, &[4u8; 32]).unwrap();
+        assert_ne!(other_address, address);
+        assert!(keys.scan(&other_address, &other_ephemeral_pubkey).is_some());
+
+        // An address isn't found with another announcement
+        assert!(keys.scan(&address, &other_ephemeral_pubkey).is_none());
+    }
+
+    #[test]
+    fn test_stealth_address_of_another_recipient() {
+        let keys = StealthKeys::new(&[1u8; 32], &[2u8; 32]);
+        let other_keys = StealthKeys::new(&[5u8; 32], &[6u8; 32]);
+
+        let (address, ephemeral_pubkey) = derive_stealth_address(&keys.meta_address(), &[3u8; 32]).unwrap();
+        assert!(other_keys.scan(&address, &ephemeral_pubkey).is_none());
+
+        // A view key alone with another spend key doesn't find it either
+        let mixed_keys = StealthKeys::new(&[5u8; 32], &[2u8; 32]);
+        assert!(mixed_keys.scan(&address, &ephemeral_pubkey).is_none());
+    }
+
+    #[test]
+    fn test_invalid_meta_address() {
+        let meta_address = StealthKeys::new(&[1u8; 32], &[2u8; 32]).meta_address();
+
+        // Not every 32 bytes decompress to an ed25519 point
+        let invalid = (0..=u8::MAX)
+            .map(|byte| [byte; 32])
+            .find(|bytes| CompressedEdwardsY(*bytes).decompress().is_none())
+            .unwrap();
+        for invalid_meta_address in [
+            StealthMetaAddress { spend_pubkey: invalid, ..meta_address },
+            StealthMetaAddress { view_pubkey: invalid, ..meta_address },
+        ] {
+            assert_eq!(
+                derive_stealth_address(&invalid_meta_address, &[3u8; 32]),
+                Err(TornadoError::InvalidRecipient.into())
+            );
+        }
+    }
+}
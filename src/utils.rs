@@ -1,17 +1,33 @@
 //! Utility functions for the Tornado Cash Privacy Solution
 
+use ark_ff::{BigInteger, PrimeField};
 use solana_program::{
     account_info::AccountInfo,
     entrypoint::ProgramResult,
+    keccak, msg,
+    poseidon::{self, Endianness, Parameters},
     program::{invoke, invoke_signed},
     program_error::ProgramError,
+    program_pack::Pack,
     pubkey::Pubkey,
     system_instruction,
+    sysvar::{rent::Rent, Sysvar},
 };
+use spl_token::state::Account as TokenAccount;
+use subtle::{Choice, ConstantTimeEq};
 
-use crate::error::TornadoError;
+use crate::{
+    error::TornadoError,
+    state::{MerkleTree, TornadoInstance},
+    verifier::reduce_pubkey_to_field,
+};
 
 /// Create a new account with the given size and owner
+///
+/// Anyone can send lamports to an address before its account is created, and
+/// the system program refuses to create an account that already holds
+/// lamports. A prefunded account is therefore topped up to the rent-exempt
+/// minimum, allocated and assigned instead.
 pub fn create_account<'a>(
     payer: &AccountInfo<'a>,
     new_account: &AccountInfo<'a>,
@@ -20,12 +36,31 @@ pub fn create_account<'a>(
     owner: &Pubkey,
     seeds: Option<&[&[u8]]>,
 ) -> ProgramResult {
-    let rent = solana_program::sysvar::rent::Rent::get()?;
+    let rent = Rent::get()?;
     let lamports = rent.minimum_balance(space);
 
-    if seeds.is_some() {
+    if new_account.lamports() > 0 {
+        let top_up = lamports.saturating_sub(new_account.lamports());
+        if top_up > 0 {
+            transfer_sol(payer, new_account, system_program, top_up, None)?;
+        }
+        let account_infos = [new_account.clone(), system_program.clone()];
+        let signer_seeds = seeds.as_slice();
+        invoke_signed(
+            &system_instruction::allocate(new_account.key, space as u64),
+            &account_infos,
+            signer_seeds,
+        )?;
+        invoke_signed(
+            &system_instruction::assign(new_account.key, owner),
+            &account_infos,
+            signer_seeds,
+        )?;
+        return Ok(());
+    }
+
+    if let Some(seeds_slice) = seeds {
         // Create account with PDA
-        let seeds_slice = seeds.unwrap();
         invoke_signed(
             &system_instruction::create_account(
                 payer.key,
@@ -54,6 +89,42 @@ pub fn create_account<'a>(
     Ok(())
 }
 
+/// Check that an account holds `space` bytes and enough lamports to be rent-exempt
+pub fn assert_rent_exempt(account: &AccountInfo, space: usize) -> ProgramResult {
+    if account.data_len() != space {
+        return Err(TornadoError::InvalidAccountData.into());
+    }
+
+    if !Rent::get()?.is_exempt(account.lamports(), space) {
+        return Err(ProgramError::AccountNotRentExempt);
+    }
+
+    Ok(())
+}
+
+/// Grow a program-owned account to the given size, with the payer topping up its rent
+///
+/// Accounts that are already large enough are left unchanged. The payer pays
+/// the rent of the added bytes in full: lamports the account holds above its
+/// rent, such as an instance's pooled deposits, don't count towards it.
+pub fn grow_account<'a>(
+    payer: &AccountInfo<'a>,
+    account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    size: usize,
+) -> ProgramResult {
+    if account.data_len() >= size {
+        return Ok(());
+    }
+
+    let rent = Rent::get()?;
+    let lamports = rent.minimum_balance(size).saturating_sub(rent.minimum_balance(account.data_len()));
+    if lamports > 0 {
+        transfer_sol(payer, account, system_program, lamports, None)?;
+    }
+    account.realloc(size, false)
+}
+
 /// Transfer SOL from one account to another
 pub fn transfer_sol<'a>(
     from: &AccountInfo<'a>,
@@ -62,9 +133,8 @@ pub fn transfer_sol<'a>(
     amount: u64,
     seeds: Option<&[&[u8]]>,
 ) -> ProgramResult {
-    if seeds.is_some() {
+    if let Some(seeds_slice) = seeds {
         // Transfer with PDA
-        let seeds_slice = seeds.unwrap();
         invoke_signed(
             &system_instruction::transfer(from.key, to.key, amount),
             &[from.clone(), to.clone(), system_program.clone()],
@@ -81,88 +151,507 @@ pub fn transfer_sol<'a>(
     Ok(())
 }
 
-/// Check if a commitment exists in the commitments array
-pub fn commitment_exists(commitments: &[[u8; 32]], commitment: &[u8; 32]) -> bool {
-    commitments.iter().any(|c| c == commitment)
-}
+/// Transfer SOL out of a program-owned account, such as the instance PDA
+///
+/// The program owns `from`, so the System Program can't debit it and the
+/// lamports are moved directly. This must come after any cross-program
+/// invocation in the instruction.
+pub fn transfer_sol_from_pda(from: &AccountInfo, to: &AccountInfo, amount: u64) -> ProgramResult {
+    let from_lamports = from
+        .lamports()
+        .checked_sub(amount)
+        .ok_or(TornadoError::InsufficientFunds)?;
+    let to_lamports = to.lamports().checked_add(amount).ok_or(TornadoError::InvalidAmount)?;
+    **from.try_borrow_mut_lamports()? = from_lamports;
+    **to.try_borrow_mut_lamports()? = to_lamports;
 
-/// Check if a nullifier hash exists in the nullifier_hashes array
-pub fn nullifier_hash_exists(nullifier_hashes: &[[u8; 32]], nullifier_hash: &[u8; 32]) -> bool {
-    nullifier_hashes.iter().any(|n| n == nullifier_hash)
+    Ok(())
 }
 
-/// Add a commitment to the commitments array
-pub fn add_commitment(commitments: &mut Vec<[u8; 32]>, commitment: &[u8; 32]) -> ProgramResult {
-    if commitment_exists(commitments, commitment) {
-        return Err(TornadoError::CommitmentAlreadyExists.into());
+/// Pay a withdrawal out of a program-owned account, to the recipient and a fee to the relayer
+///
+/// Every balance is checked before any is written, so the recipient and the
+/// relayer are both paid or neither is. A failed instruction rolls the whole
+/// transaction back anyway, but this keeps a failing relayer payment from
+/// leaving the recipient paid in between. Like `transfer_sol_from_pda`, this
+/// must come after any cross-program invocation in the instruction.
+pub fn pay_withdrawal_from_pda(
+    from: &AccountInfo,
+    recipient: &AccountInfo,
+    recipient_amount: u64,
+    relayer: &AccountInfo,
+    fee: u64,
+) -> ProgramResult {
+    // A relayer that is the recipient, or takes nothing, is paid as part of one transfer
+    if fee == 0 || relayer.key == recipient.key {
+        let amount = recipient_amount.checked_add(fee).ok_or(TornadoError::InvalidAmount)?;
+        return transfer_sol_from_pda(from, recipient, amount);
     }
-    commitments.push(*commitment);
+
+    let mut from_lamports = from.try_borrow_mut_lamports()?;
+    let mut recipient_lamports = recipient.try_borrow_mut_lamports()?;
+    let mut relayer_lamports = relayer.try_borrow_mut_lamports()?;
+    let remaining = recipient_amount
+        .checked_add(fee)
+        .and_then(|amount| from_lamports.checked_sub(amount))
+        .ok_or(TornadoError::InsufficientFunds)?;
+    let recipient_balance = recipient_lamports.checked_add(recipient_amount).ok_or(TornadoError::InvalidAmount)?;
+    let relayer_balance = relayer_lamports.checked_add(fee).ok_or(TornadoError::InvalidAmount)?;
+    **from_lamports = remaining;
+    **recipient_lamports = recipient_balance;
+    **relayer_lamports = relayer_balance;
+
     Ok(())
 }
 
-/// Add a nullifier hash to the nullifier_hashes array
-pub fn add_nullifier_hash(nullifier_hashes: &mut Vec<[u8; 32]>, nullifier_hash: &[u8; 32]) -> ProgramResult {
-    if nullifier_hash_exists(nullifier_hashes, nullifier_hash) {
-        return Err(TornadoError::NullifierAlreadySpent.into());
+/// Check that a fixed-amount SOL instance holds exactly its unspent deposits
+///
+/// `lamports` and `rent` are the instance account's balance and rent-exempt
+/// minimum. Returns the pooled balance, `lamports - rent`, when it equals
+/// `(next_index - nullifier_count) * denomination`, and fails with
+/// `InconsistentState` otherwise. Lamports sent to the instance directly show
+/// up as a surplus, so auditors should account for them; `SelfCheck` only
+/// checks the balance as a lower bound. Token and variable-amount instances
+/// don't pool their deposits as fixed lamport amounts and fail with
+/// `InvalidAccountData`.
+pub fn pool_balance_invariant(
+    tornado_instance: &TornadoInstance,
+    merkle_tree: &MerkleTree,
+    lamports: u64,
+    rent: u64,
+) -> Result<u64, ProgramError> {
+    if tornado_instance.token_mint.is_some() || tornado_instance.variable_amount {
+        msg!("Only fixed-amount SOL instances pool their deposits in lamports");
+        return Err(TornadoError::InvalidAccountData.into());
+    }
+
+    let unspent = u64::from(merkle_tree.next_index)
+        .checked_sub(merkle_tree.nullifier_count)
+        .ok_or(TornadoError::InconsistentState)?;
+    let expected = unspent
+        .checked_mul(tornado_instance.denomination)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    let pooled = lamports.checked_sub(rent).ok_or(TornadoError::InconsistentState)?;
+    if pooled != expected {
+        msg!("Instance pools {} lamports, expected {} for {} unspent deposits", pooled, expected, unspent);
+        return Err(TornadoError::InconsistentState.into());
     }
-    nullifier_hashes.push(*nullifier_hash);
+
+    Ok(pooled)
+}
+
+/// Transfer SPL tokens from one token account to another
+pub fn transfer_tokens<'a>(
+    source: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    amount: u64,
+    seeds: Option<&[&[u8]]>,
+) -> ProgramResult {
+    let ix = spl_token::instruction::transfer(
+        token_program.key,
+        source.key,
+        destination.key,
+        authority.key,
+        &[],
+        amount,
+    )?;
+
+    if let Some(seeds_slice) = seeds {
+        // Transfer with PDA authority
+        invoke_signed(
+            &ix,
+            &[source.clone(), destination.clone(), authority.clone(), token_program.clone()],
+            &[seeds_slice],
+        )?;
+    } else {
+        // Transfer with signer authority
+        invoke(
+            &ix,
+            &[source.clone(), destination.clone(), authority.clone(), token_program.clone()],
+        )?;
+    }
+
     Ok(())
 }
 
-/// Compute the Pedersen hash of a nullifier and secret
-/// This is a simplified implementation using Keccak256
-pub fn compute_commitment(nullifier: &[u8; 32], secret: &[u8; 32]) -> [u8; 32] {
-    use sha3::{Digest, Keccak256};
-    
-    let mut hasher = Keccak256::new();
-    hasher.update(nullifier);
-    hasher.update(secret);
-    let result = hasher.finalize();
-    
-    let mut commitment = [0u8; 32];
-    commitment.copy_from_slice(&result[..32]);
-    
-    commitment
+/// Close an SPL token account, sending its rent lamports to the destination
+pub fn close_token_account<'a>(
+    account: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let ix = spl_token::instruction::close_account(
+        token_program.key,
+        account.key,
+        destination.key,
+        authority.key,
+        &[],
+    )?;
+
+    invoke_signed(
+        &ix,
+        &[account.clone(), destination.clone(), authority.clone(), token_program.clone()],
+        &[seeds],
+    )
 }
 
-/// Compute the hash of a nullifier
-/// This is a simplified implementation using Keccak256
-pub fn compute_nullifier_hash(nullifier: &[u8; 32]) -> [u8; 32] {
-    use sha3::{Digest, Keccak256};
-    
-    let mut hasher = Keccak256::new();
-    hasher.update(nullifier);
-    let result = hasher.finalize();
-    
-    let mut nullifier_hash = [0u8; 32];
-    nullifier_hash.copy_from_slice(&result[..32]);
-    
-    nullifier_hash
+/// Close a program-owned account, moving all its lamports to the receiver
+///
+/// The data is zeroed so the account reads as uninitialized for the rest of
+/// the transaction; the runtime deletes it once it holds no lamports. The
+/// lamports are moved directly, so this must come after any cross-program
+/// invocation in the instruction.
+pub fn close_account(account: &AccountInfo, receiver: &AccountInfo) -> ProgramResult {
+    let receiver_lamports = receiver
+        .lamports()
+        .checked_add(account.lamports())
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    **receiver.try_borrow_mut_lamports()? = receiver_lamports;
+    **account.try_borrow_mut_lamports()? = 0;
+    account.try_borrow_mut_data()?.fill(0);
+
+    Ok(())
+}
+
+/// Create the associated token account of a wallet, funded by the payer
+pub fn create_associated_token_account<'a>(
+    payer: &AccountInfo<'a>,
+    associated_token_account: &AccountInfo<'a>,
+    wallet: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    associated_token_program: &AccountInfo<'a>,
+) -> ProgramResult {
+    invoke(
+        &spl_associated_token_account::instruction::create_associated_token_account(
+            payer.key,
+            wallet.key,
+            mint.key,
+            token_program.key,
+        ),
+        &[
+            payer.clone(),
+            associated_token_account.clone(),
+            wallet.clone(),
+            mint.clone(),
+            system_program.clone(),
+            token_program.clone(),
+            associated_token_program.clone(),
+        ],
+    )
+}
+
+/// Find the Merkle tree account address of an instance
+pub fn find_merkle_tree_address(program_id: &Pubkey, tornado_instance: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"merkle_tree", tornado_instance.as_ref(), &[0]], program_id)
+}
+
+/// Find the verifier account address of an instance
+pub fn find_verifier_address(program_id: &Pubkey, tornado_instance: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"verifier", tornado_instance.as_ref(), &[0]], program_id)
+}
+
+/// Find the verifier account address of an instance's partial withdrawal circuit
+pub fn find_partial_verifier_address(program_id: &Pubkey, tornado_instance: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"verifier", tornado_instance.as_ref(), &[1]], program_id)
+}
+
+/// Find the address of the instance registry
+pub fn find_registry_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"registry"], program_id)
+}
+
+/// Find the compliance list address of a Tornado instance
+pub fn find_compliance_list_address(program_id: &Pubkey, tornado_instance: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"compliance_list", tornado_instance.as_ref()], program_id)
+}
+
+/// Find the commitment marker address of a deposit
+pub fn find_commitment_address(
+    program_id: &Pubkey,
+    tornado_instance: &Pubkey,
+    commitment: &[u8; 32],
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"commitment", tornado_instance.as_ref(), commitment], program_id)
+}
+
+/// Find the encrypted note address of a deposit
+pub fn find_encrypted_note_address(
+    program_id: &Pubkey,
+    tornado_instance: &Pubkey,
+    commitment: &[u8; 32],
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"note", tornado_instance.as_ref(), commitment], program_id)
+}
+
+/// Find the nullifier marker address of a withdrawal
+pub fn find_nullifier_address(
+    program_id: &Pubkey,
+    tornado_instance: &Pubkey,
+    nullifier_hash: &[u8; 32],
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"nullifier", tornado_instance.as_ref(), nullifier_hash], program_id)
+}
+
+/// Find the PDA that owns the token vault of an instance
+///
+/// The instance account itself is not a PDA, so it cannot sign for token
+/// transfers. The vault is the associated token account of this authority.
+pub fn find_vault_authority_address(program_id: &Pubkey, tornado_instance: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"vault", tornado_instance.as_ref()], program_id)
+}
+
+/// Get the token vault address of an instance for the given mint
+pub fn get_vault_address(program_id: &Pubkey, tornado_instance: &Pubkey, token_mint: &Pubkey) -> Pubkey {
+    let (vault_authority, _) = find_vault_authority_address(program_id, tornado_instance);
+    spl_associated_token_account::get_associated_token_address(&vault_authority, token_mint)
+}
+
+/// Check that a token account belongs to the expected mint and owner
+pub fn validate_token_account(
+    token_account_info: &AccountInfo,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Result<TokenAccount, ProgramError> {
+    if *token_account_info.owner != spl_token::id() {
+        return Err(TornadoError::InvalidTokenAccount.into());
+    }
+
+    let token_account = TokenAccount::unpack(&token_account_info.data.borrow())
+        .map_err(|_| TornadoError::InvalidTokenAccount)?;
+    if token_account.mint != *mint || token_account.owner != *owner {
+        return Err(TornadoError::InvalidTokenAccount.into());
+    }
+
+    Ok(token_account)
+}
+
+/// Check in constant time if a hash is in a list of hashes
+///
+/// Every entry is compared in full with `ConstantTimeEq`, and the scan doesn't
+/// stop at a match, so the time taken depends only on the length of the list:
+/// not on where a match is, nor on how many leading bytes an entry shares
+/// with the hash.
+pub fn contains_hash(hashes: &[[u8; 32]], hash: &[u8; 32]) -> bool {
+    hashes
+        .iter()
+        .fold(Choice::from(0), |found, entry| found | entry.ct_eq(hash))
+        .into()
+}
+
+/// Check if a nullifier hash exists in the nullifier_hashes array, in constant time (see `contains_hash`)
+pub fn nullifier_hash_exists(nullifier_hashes: &[[u8; 32]], nullifier_hash: &[u8; 32]) -> bool {
+    contains_hash(nullifier_hashes, nullifier_hash)
+}
+
+/// Compute the commitment of a deposit as `Poseidon(nullifier, secret, instance)`
+///
+/// Uses the circomlib Poseidon parameters over BN254 with big-endian field
+/// elements, so the result matches the commitment computed inside the
+/// withdrawal circuit. The nullifier and secret must be canonical field
+/// elements. The instance is reduced with `reduce_pubkey_to_field`; binding
+/// it into the commitment keeps a note from being proven in another pool.
+pub fn compute_commitment(
+    nullifier: &[u8; 32],
+    secret: &[u8; 32],
+    instance: &Pubkey,
+) -> Result<[u8; 32], ProgramError> {
+    let instance_bytes = reduce_pubkey_to_field(instance).into_bigint().to_bytes_be();
+    let hash = poseidon::hashv(
+        Parameters::Bn254X5,
+        Endianness::BigEndian,
+        &[nullifier, secret, &instance_bytes],
+    )
+    .map_err(|_| TornadoError::InvalidCommitment)?;
+
+    Ok(hash.to_bytes())
+}
+
+/// Compute the nullifier hash of a deposit as `Poseidon(nullifier)`
+///
+/// The nullifier must be a canonical field element.
+pub fn compute_nullifier_hash(nullifier: &[u8; 32]) -> Result<[u8; 32], ProgramError> {
+    let hash = poseidon::hashv(Parameters::Bn254X5, Endianness::BigEndian, &[nullifier])
+        .map_err(|_| TornadoError::InvalidNullifierHash)?;
+
+    Ok(hash.to_bytes())
+}
+
+/// Derive the public values of a note: its commitment and its nullifier hash
+///
+/// Deposits reveal the commitment and withdrawals the nullifier hash, so both
+/// must come from the same hash the circuit uses. Returns `(commitment, nullifier_hash)`.
+pub fn derive_note_fields(
+    nullifier: &[u8; 32],
+    secret: &[u8; 32],
+    instance: &Pubkey,
+) -> Result<([u8; 32], [u8; 32]), ProgramError> {
+    Ok((compute_commitment(nullifier, secret, instance)?, compute_nullifier_hash(nullifier)?))
+}
+
+/// Compute the leaf inserted for a variable-amount deposit as `Poseidon(commitment, amount)`
+///
+/// The program hashes in the amount it actually received, so a proof can only
+/// withdraw the amount that was deposited.
+pub fn compute_amount_commitment(commitment: &[u8; 32], amount: u64) -> Result<[u8; 32], ProgramError> {
+    let mut amount_bytes = [0u8; 32];
+    amount_bytes[24..].copy_from_slice(&amount.to_be_bytes());
+
+    let hash = poseidon::hashv(Parameters::Bn254X5, Endianness::BigEndian, &[commitment, &amount_bytes])
+        .map_err(|_| TornadoError::InvalidCommitment)?;
+
+    Ok(hash.to_bytes())
+}
+
+/// Domain separator prefixed to relayer fee quotes
+pub const FEE_QUOTE_DOMAIN: &[u8] = b"tornado-svm-fee-quote";
+
+/// Build the message a relayer signs to quote its fee for a withdrawal
+///
+/// The quote binds the fee to one note and one recipient, so it can't be
+/// reused for another withdrawal or submitted with a higher fee. The layout is
+/// `FEE_QUOTE_DOMAIN || nullifier_hash || fee (u64 LE) || recipient`.
+pub fn relayer_fee_quote_message(nullifier_hash: &[u8; 32], fee: u64, recipient: &Pubkey) -> Vec<u8> {
+    let mut message = Vec::with_capacity(FEE_QUOTE_DOMAIN.len() + 32 + 8 + 32);
+    message.extend_from_slice(FEE_QUOTE_DOMAIN);
+    message.extend_from_slice(nullifier_hash);
+    message.extend_from_slice(&fee.to_le_bytes());
+    message.extend_from_slice(recipient.as_ref());
+    message
+}
+
+/// Domain separator of the recipient committed to by a stealth withdrawal
+pub const STEALTH_DOMAIN: &[u8] = b"tornado-svm-stealth";
+
+/// Compute the recipient a `WithdrawStealth` proof commits to
+///
+/// The proof's `recipient` signal is this keccak hash of the one-time address
+/// and the ephemeral pubkey it was derived with, in place of the address alone,
+/// so a relayer can swap neither. The layout is
+/// `STEALTH_DOMAIN || recipient || ephemeral_pubkey`.
+pub fn stealth_recipient(recipient: &Pubkey, ephemeral_pubkey: &[u8; 32]) -> Pubkey {
+    Pubkey::new_from_array(keccak::hashv(&[STEALTH_DOMAIN, recipient.as_ref(), ephemeral_pubkey]).to_bytes())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use solana_program::{
-        account_info::AccountInfo,
-        program_error::ProgramError,
-        pubkey::Pubkey,
-    };
     
     #[test]
-    fn test_commitment_exists() {
-        // Create test commitments
-        let mut commitments = Vec::new();
-        let commitment1 = [1u8; 32];
-        let commitment2 = [2u8; 32];
+    fn test_find_commitment_address() {
+        let program_id = Pubkey::new_unique();
+        let tornado_instance = Pubkey::new_unique();
+        let commitment = [1u8; 32];
+        
+        // Derivation is deterministic
+        let (marker, bump) = find_commitment_address(&program_id, &tornado_instance, &commitment);
+        assert_eq!(
+            find_commitment_address(&program_id, &tornado_instance, &commitment),
+            (marker, bump)
+        );
+        assert_eq!(
+            Pubkey::create_program_address(
+                &[b"commitment", tornado_instance.as_ref(), &commitment, &[bump]],
+                &program_id
+            )
+            .unwrap(),
+            marker
+        );
+        
+        // Different commitments and instances get different markers
+        let (other, _) = find_commitment_address(&program_id, &tornado_instance, &[2u8; 32]);
+        assert_ne!(marker, other);
+        let (other, _) = find_commitment_address(&program_id, &Pubkey::new_unique(), &commitment);
+        assert_ne!(marker, other);
+    }
+    
+    #[test]
+    fn test_find_instance_addresses() {
+        let program_id = Pubkey::new_unique();
+        let tornado_instance = Pubkey::new_unique();
+        
+        // The Merkle tree and verifier are the PDAs the processor creates in Initialize
+        let (merkle_tree, merkle_tree_bump) = find_merkle_tree_address(&program_id, &tornado_instance);
+        assert_eq!(
+            Pubkey::create_program_address(
+                &[b"merkle_tree", tornado_instance.as_ref(), &[0], &[merkle_tree_bump]],
+                &program_id
+            )
+            .unwrap(),
+            merkle_tree
+        );
+        let (verifier, verifier_bump) = find_verifier_address(&program_id, &tornado_instance);
+        assert_eq!(
+            Pubkey::create_program_address(
+                &[b"verifier", tornado_instance.as_ref(), &[0], &[verifier_bump]],
+                &program_id
+            )
+            .unwrap(),
+            verifier
+        );
         
-        commitments.push(commitment1);
+        // The partial withdrawal verifier is a separate account
+        let (partial_verifier, _) = find_partial_verifier_address(&program_id, &tornado_instance);
+        assert_ne!(partial_verifier, verifier);
         
-        // Test with existing commitment
-        assert!(commitment_exists(&commitments, &commitment1));
+        // The Initialize builder passes the same accounts
+        let initialize_ix = crate::instruction::initialize(
+            &program_id,
+            &Pubkey::new_unique(),
+            &tornado_instance,
+            1,
+            1,
+            1,
+            0,
+            None,
+            false,
+            0,
+            crate::merkle_tree::HASH_ALGO_MIMC,
+            crate::verifier::CURVE_BN254,
+            false,
+            crate::verifier::PROOF_SYSTEM_GROTH16,
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(initialize_ix.accounts[2].pubkey, merkle_tree);
+        assert_eq!(initialize_ix.accounts[3].pubkey, verifier);
         
-        // Test with non-existing commitment
-        assert!(!commitment_exists(&commitments, &commitment2));
+        // Each instance gets its own accounts
+        let other_instance = Pubkey::new_unique();
+        assert_ne!(find_merkle_tree_address(&program_id, &other_instance).0, merkle_tree);
+        assert_ne!(find_verifier_address(&program_id, &other_instance).0, verifier);
+    }
+    
+    #[test]
+    fn test_find_nullifier_address() {
+        let program_id = Pubkey::new_unique();
+        let tornado_instance = Pubkey::new_unique();
+        let nullifier_hash = [1u8; 32];
+        
+        let (marker, bump) = find_nullifier_address(&program_id, &tornado_instance, &nullifier_hash);
+        assert_eq!(
+            Pubkey::create_program_address(
+                &[b"nullifier", tornado_instance.as_ref(), &nullifier_hash, &[bump]],
+                &program_id
+            )
+            .unwrap(),
+            marker
+        );
+        
+        // Nullifier and commitment markers never collide
+        let (commitment_marker, _) =
+            find_commitment_address(&program_id, &tornado_instance, &nullifier_hash);
+        assert_ne!(marker, commitment_marker);
+
+        // Neither do encrypted notes and commitment markers
+        let (encrypted_note, _) =
+            find_encrypted_note_address(&program_id, &tornado_instance, &nullifier_hash);
+        assert_ne!(encrypted_note, commitment_marker);
     }
     
     #[test]
@@ -182,57 +671,40 @@ mod tests {
     }
     
     #[test]
-    fn test_add_commitment() {
-        // Create test commitments
-        let mut commitments = Vec::new();
-        let commitment1 = [1u8; 32];
-        let commitment2 = [2u8; 32];
-        
-        // Add first commitment
-        let result = add_commitment(&mut commitments, &commitment1);
-        assert!(result.is_ok());
-        assert_eq!(commitments.len(), 1);
-        
-        // Add second commitment
-        let result = add_commitment(&mut commitments, &commitment2);
-        assert!(result.is_ok());
-        assert_eq!(commitments.len(), 2);
-        
-        // Try to add duplicate commitment
-        let result = add_commitment(&mut commitments, &commitment1);
-        assert!(result.is_err());
-        assert_eq!(commitments.len(), 2);
-    }
-    
-    #[test]
-    fn test_add_nullifier_hash() {
-        // Create test nullifier hashes
-        let mut nullifier_hashes = Vec::new();
-        let nullifier_hash1 = [1u8; 32];
-        let nullifier_hash2 = [2u8; 32];
+    fn test_contains_hash() {
+        // Hashes sharing every prefix length with `hash`, and `hash` itself
+        let hash = [7u8; 32];
+        let mut hashes: Vec<[u8; 32]> = (0..32)
+            .map(|prefix_len| {
+                let mut entry = hash;
+                entry[prefix_len] ^= 1;
+                entry
+            })
+            .collect();
+        hashes.push(hash);
         
-        // Add first nullifier hash
-        let result = add_nullifier_hash(&mut nullifier_hashes, &nullifier_hash1);
-        assert!(result.is_ok());
-        assert_eq!(nullifier_hashes.len(), 1);
-        
-        // Add second nullifier hash
-        let result = add_nullifier_hash(&mut nullifier_hashes, &nullifier_hash2);
-        assert!(result.is_ok());
-        assert_eq!(nullifier_hashes.len(), 2);
-        
-        // Try to add duplicate nullifier hash
-        let result = add_nullifier_hash(&mut nullifier_hashes, &nullifier_hash1);
-        assert!(result.is_err());
-        assert_eq!(nullifier_hashes.len(), 2);
+        // Matches the early-exit comparison for every sublist and every probe
+        for start in 0..hashes.len() {
+            for end in start..=hashes.len() {
+                let list = &hashes[start..end];
+                for probe in hashes.iter().chain([[0u8; 32], [0xFF; 32]].iter()) {
+                    assert_eq!(contains_hash(list, probe), list.iter().any(|entry| entry == probe));
+                }
+            }
+        }
+        assert!(!contains_hash(&[], &hash));
+        assert!(contains_hash(&hashes, &hash));
+        assert!(!contains_hash(&hashes[..32], &hash));
     }
     
     #[test]
     fn test_compute_commitment() {
+        let instance = Pubkey::new_unique();
+        
         // Test with different inputs
         let nullifier1 = [1u8; 32];
         let secret1 = [2u8; 32];
-        let commitment1 = compute_commitment(&nullifier1, &secret1);
+        let commitment1 = compute_commitment(&nullifier1, &secret1, &instance).unwrap();
         
         // Ensure commitment is not zero
         assert!(!commitment1.iter().all(|&x| x == 0));
@@ -240,38 +712,299 @@ mod tests {
         // Test with different inputs
         let nullifier2 = [3u8; 32];
         let secret2 = [4u8; 32];
-        let commitment2 = compute_commitment(&nullifier2, &secret2);
+        let commitment2 = compute_commitment(&nullifier2, &secret2, &instance).unwrap();
         
         // Ensure different inputs produce different commitments
         assert!(commitment1 != commitment2);
         
         // Test with same inputs
-        let commitment1_duplicate = compute_commitment(&nullifier1, &secret1);
+        let commitment1_duplicate = compute_commitment(&nullifier1, &secret1, &instance).unwrap();
         
         // Ensure same inputs produce same commitment
         assert_eq!(commitment1, commitment1_duplicate);
+        
+        // The same note commits differently in another instance
+        let other_instance = Pubkey::new_unique();
+        assert_ne!(compute_commitment(&nullifier1, &secret1, &other_instance).unwrap(), commitment1);
     }
     
     #[test]
     fn test_compute_nullifier_hash() {
         // Test with different inputs
         let nullifier1 = [1u8; 32];
-        let nullifier_hash1 = compute_nullifier_hash(&nullifier1);
+        let nullifier_hash1 = compute_nullifier_hash(&nullifier1).unwrap();
         
         // Ensure nullifier hash is not zero
         assert!(!nullifier_hash1.iter().all(|&x| x == 0));
         
         // Test with different input
         let nullifier2 = [2u8; 32];
-        let nullifier_hash2 = compute_nullifier_hash(&nullifier2);
+        let nullifier_hash2 = compute_nullifier_hash(&nullifier2).unwrap();
         
         // Ensure different inputs produce different nullifier hashes
         assert!(nullifier_hash1 != nullifier_hash2);
         
         // Test with same input
-        let nullifier_hash1_duplicate = compute_nullifier_hash(&nullifier1);
+        let nullifier_hash1_duplicate = compute_nullifier_hash(&nullifier1).unwrap();
         
         // Ensure same input produces same nullifier hash
         assert_eq!(nullifier_hash1, nullifier_hash1_duplicate);
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_derive_note_fields() {
+        let instance = Pubkey::new_unique();
+        for (nullifier, secret) in [([1u8; 32], [2u8; 32]), ([3u8; 32], [3u8; 32])] {
+            assert_eq!(
+                derive_note_fields(&nullifier, &secret, &instance).unwrap(),
+                (
+                    compute_commitment(&nullifier, &secret, &instance).unwrap(),
+                    compute_nullifier_hash(&nullifier).unwrap()
+                )
+            );
+        }
+        
+        // Values outside the field are rejected as by the underlying hashes
+        assert_eq!(
+            derive_note_fields(&[1u8; 32], &[0xffu8; 32], &instance),
+            Err(TornadoError::InvalidCommitment.into())
+        );
+    }
+    
+    // Known-answer vectors from circomlib's `poseidon.js`
+    #[test]
+    fn test_poseidon_known_answers() {
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let mut two = [0u8; 32];
+        two[31] = 2;
+        
+        // poseidon([1, 2])
+        assert_eq!(
+            poseidon::hashv(Parameters::Bn254X5, Endianness::BigEndian, &[&one, &two])
+                .unwrap()
+                .to_bytes(),
+            hex_to_bytes("115cc0f5e7d690413df64c6b9662e9cf2a3617f2743245519e19607a4417189a")
+        );
+        
+        // The commitment is poseidon([nullifier, secret, instance])
+        let mut three = [0u8; 32];
+        three[31] = 3;
+        assert_eq!(
+            compute_commitment(&one, &two, &Pubkey::new_from_array(three)).unwrap(),
+            poseidon::hashv(Parameters::Bn254X5, Endianness::BigEndian, &[&one, &two, &three])
+                .unwrap()
+                .to_bytes()
+        );
+        
+        // poseidon([1])
+        assert_eq!(
+            compute_nullifier_hash(&one).unwrap(),
+            hex_to_bytes("29176100eaa962bdc1fe6c654d6a3c130e96a4d1168b33848b897dc502820133")
+        );
+    }
+    
+    #[test]
+    fn test_compute_amount_commitment() {
+        let commitment = compute_commitment(&[1u8; 32], &[2u8; 32], &Pubkey::new_unique()).unwrap();
+        
+        // poseidon([commitment, amount])
+        let mut amount = [0u8; 32];
+        amount[24..].copy_from_slice(&1_000_000u64.to_be_bytes());
+        assert_eq!(
+            compute_amount_commitment(&commitment, 1_000_000).unwrap(),
+            poseidon::hashv(Parameters::Bn254X5, Endianness::BigEndian, &[&commitment, &amount])
+                .unwrap()
+                .to_bytes()
+        );
+        
+        // The leaf depends on the amount
+        assert_ne!(
+            compute_amount_commitment(&commitment, 1_000_000).unwrap(),
+            compute_amount_commitment(&commitment, 1_000_001).unwrap()
+        );
+        
+        assert_eq!(
+            compute_amount_commitment(&[0xffu8; 32], 1),
+            Err(TornadoError::InvalidCommitment.into())
+        );
+    }
+    
+    #[test]
+    fn test_relayer_fee_quote_message() {
+        let nullifier_hash = [5u8; 32];
+        let recipient = Pubkey::new_unique();
+        
+        let message = relayer_fee_quote_message(&nullifier_hash, 1000, &recipient);
+        assert_eq!(message.len(), FEE_QUOTE_DOMAIN.len() + 72);
+        assert_eq!(&message[..FEE_QUOTE_DOMAIN.len()], FEE_QUOTE_DOMAIN);
+        let fields = &message[FEE_QUOTE_DOMAIN.len()..];
+        assert_eq!(&fields[..32], &nullifier_hash);
+        assert_eq!(&fields[32..40], &1000u64.to_le_bytes());
+        assert_eq!(&fields[40..], recipient.as_ref());
+        
+        // Each field changes the quote
+        assert_ne!(message, relayer_fee_quote_message(&[6u8; 32], 1000, &recipient));
+        assert_ne!(message, relayer_fee_quote_message(&nullifier_hash, 1001, &recipient));
+        assert_ne!(message, relayer_fee_quote_message(&nullifier_hash, 1000, &Pubkey::new_unique()));
+    }
+    
+    #[test]
+    fn test_stealth_recipient() {
+        let recipient = Pubkey::new_unique();
+        let ephemeral_pubkey = [7u8; 32];
+        
+        let committed = stealth_recipient(&recipient, &ephemeral_pubkey);
+        let expected = keccak::hashv(&[STEALTH_DOMAIN, recipient.as_ref(), &ephemeral_pubkey]);
+        assert_eq!(committed.to_bytes(), expected.to_bytes());
+        assert_ne!(committed, recipient);
+        
+        // Both the address and the ephemeral pubkey change the commitment
+        assert_ne!(committed, stealth_recipient(&Pubkey::new_unique(), &ephemeral_pubkey));
+        assert_ne!(committed, stealth_recipient(&recipient, &[8u8; 32]));
+    }
+    
+    #[test]
+    fn test_hash_inputs_outside_field() {
+        let valid = [1u8; 32];
+        let too_large = [0xffu8; 32];
+        let instance = Pubkey::new_unique();
+        
+        assert_eq!(
+            compute_commitment(&too_large, &valid, &instance),
+            Err(TornadoError::InvalidCommitment.into())
+        );
+        assert_eq!(
+            compute_commitment(&valid, &too_large, &instance),
+            Err(TornadoError::InvalidCommitment.into())
+        );
+        
+        // Any instance address is reduced into the field
+        let instance = Pubkey::new_from_array(too_large);
+        assert!(compute_commitment(&valid, &valid, &instance).is_ok());
+        assert_eq!(
+            compute_nullifier_hash(&too_large),
+            Err(TornadoError::InvalidNullifierHash.into())
+        );
+    }
+    
+    #[test]
+    fn test_transfer_sol_from_pda() {
+        let program_id = Pubkey::new_unique();
+        let (from_key, to_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let system_program = solana_program::system_program::id();
+        let (mut from_lamports, mut to_lamports) = (1_000u64, 50u64);
+        let (mut from_data, mut to_data) = (vec![0u8; 8], vec![]);
+        let from = AccountInfo::new(&from_key, false, true, &mut from_lamports, &mut from_data, &program_id, false, 0);
+        let to = AccountInfo::new(&to_key, false, true, &mut to_lamports, &mut to_data, &system_program, false, 0);
+        
+        // Lamports leave the program-owned account without the system program
+        transfer_sol_from_pda(&from, &to, 400).unwrap();
+        assert_eq!(from.lamports(), 600);
+        assert_eq!(to.lamports(), 450);
+        
+        // Overdrawing fails and leaves both balances untouched
+        assert_eq!(
+            transfer_sol_from_pda(&from, &to, 601),
+            Err(TornadoError::InsufficientFunds.into())
+        );
+        assert_eq!(from.lamports(), 600);
+        assert_eq!(to.lamports(), 450);
+    }
+    
+    #[test]
+    fn test_pay_withdrawal_from_pda() {
+        let program_id = Pubkey::new_unique();
+        let system_program = solana_program::system_program::id();
+        let (from_key, recipient_key, relayer_key) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (mut from_lamports, mut recipient_lamports, mut relayer_lamports) = (1_000u64, 0u64, 10u64);
+        let (mut from_data, mut recipient_data, mut relayer_data) = (vec![0u8; 8], vec![], vec![]);
+        let from = AccountInfo::new(&from_key, false, true, &mut from_lamports, &mut from_data, &program_id, false, 0);
+        let recipient = AccountInfo::new(
+            &recipient_key,
+            false,
+            true,
+            &mut recipient_lamports,
+            &mut recipient_data,
+            &system_program,
+            false,
+            0,
+        );
+        let relayer = AccountInfo::new(&relayer_key, true, true, &mut relayer_lamports, &mut relayer_data, &system_program, false, 0);
+        
+        // The recipient and relayer are paid together
+        pay_withdrawal_from_pda(&from, &recipient, 300, &relayer, 20).unwrap();
+        assert_eq!((from.lamports(), recipient.lamports(), relayer.lamports()), (680, 300, 30));
+        
+        // A relayer that can't be credited fails the payment before the recipient is paid
+        **relayer.try_borrow_mut_lamports().unwrap() = u64::MAX;
+        assert_eq!(
+            pay_withdrawal_from_pda(&from, &recipient, 300, &relayer, 20),
+            Err(TornadoError::InvalidAmount.into())
+        );
+        assert_eq!((from.lamports(), recipient.lamports(), relayer.lamports()), (680, 300, u64::MAX));
+        
+        // So does a pool that can't cover the fee on top of the recipient's share
+        **relayer.try_borrow_mut_lamports().unwrap() = 30;
+        assert_eq!(
+            pay_withdrawal_from_pda(&from, &recipient, 670, &relayer, 20),
+            Err(TornadoError::InsufficientFunds.into())
+        );
+        assert_eq!((from.lamports(), recipient.lamports(), relayer.lamports()), (680, 300, 30));
+        
+        // A recipient relaying its own withdrawal gets the fee too
+        pay_withdrawal_from_pda(&from, &recipient, 100, &recipient, 20).unwrap();
+        assert_eq!((from.lamports(), recipient.lamports()), (560, 420));
+    }
+    
+    #[test]
+    fn test_pool_balance_invariant() {
+        let tornado_instance = TornadoInstance {
+            denomination: 100,
+            ..TornadoInstance::default()
+        };
+        let merkle_tree = MerkleTree {
+            next_index: 5,
+            nullifier_count: 2,
+            ..MerkleTree::default()
+        };
+        let rent = 1_000;
+        
+        // The instance holds its rent plus its three unspent deposits
+        assert_eq!(pool_balance_invariant(&tornado_instance, &merkle_tree, rent + 300, rent), Ok(300));
+        
+        // A leak or a surplus is detected
+        for lamports in [rent + 299, rent + 301, rent - 1] {
+            assert_eq!(
+                pool_balance_invariant(&tornado_instance, &merkle_tree, lamports, rent),
+                Err(TornadoError::InconsistentState.into())
+            );
+        }
+        
+        // More withdrawals than deposits can't balance
+        let overspent_tree = MerkleTree {
+            next_index: 5,
+            nullifier_count: 6,
+            ..MerkleTree::default()
+        };
+        assert_eq!(
+            pool_balance_invariant(&tornado_instance, &overspent_tree, rent, rent),
+            Err(TornadoError::InconsistentState.into())
+        );
+        
+        // Token and variable-amount instances don't pool fixed lamport amounts
+        for other_instance in [
+            TornadoInstance { token_mint: Some(Pubkey::new_unique()), ..TornadoInstance::default() },
+            TornadoInstance { variable_amount: true, ..TornadoInstance::default() },
+        ] {
+            assert_eq!(
+                pool_balance_invariant(&other_instance, &merkle_tree, rent + 300, rent),
+                Err(TornadoError::InvalidAccountData.into())
+            );
+        }
+    }
+    
+    fn hex_to_bytes(hex: &str) -> [u8; 32] {
+        core::array::from_fn(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+    }
+}
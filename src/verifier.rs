@@ -1,46 +1,275 @@
 //! Verifier implementation for the Tornado Cash Privacy Solution
 
-use ark_bn254::{Bn254, Fr, G1Affine, G2Affine};
-use ark_ec::pairing::Pairing;
+use ark_bls12_381::{self as bls12_381, Bls12_381};
+use ark_bn254::{Bn254, Fq, Fq2, Fr, G1Affine, G2Affine};
+use ark_ec::{
+    pairing::Pairing,
+    short_weierstrass::{Affine, SWCurveConfig},
+};
 use ark_ff::{BigInteger, PrimeField};
-use ark_groth16::{prepare_verifying_key, verify_proof, Proof, VerifyingKey};
-use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_groth16::{prepare_verifying_key, Groth16, Proof, VerifyingKey};
+use ark_serialize::CanonicalDeserialize;
+use borsh::BorshDeserialize;
 use solana_program::{
-    msg,
+    alt_bn128::prelude::{
+        alt_bn128_addition, alt_bn128_multiplication, alt_bn128_pairing, ALT_BN128_PAIRING_ELEMENT_LEN,
+    },
+    keccak, msg,
     program_error::ProgramError,
+    pubkey::Pubkey,
 };
 
-use crate::error::TornadoError;
+use crate::{error::TornadoError, state::VerifierState};
+
+/// Number of public inputs of the fixed-denomination withdrawal circuit
+pub const NUM_PUBLIC_INPUTS: usize = 7;
+
+/// Number of public inputs of the variable-amount withdrawal circuit
+pub const NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS: usize = 8;
+
+/// Number of public inputs of the partial withdrawal circuit
+pub const NUM_PARTIAL_WITHDRAW_PUBLIC_INPUTS: usize = 10;
+
+/// Curve of an instance whose proofs are over BN254, checked with the alt_bn128 syscalls on-chain
+pub const CURVE_BN254: u8 = 0;
+
+/// Curve of an instance whose proofs are over BLS12-381
+///
+/// No syscalls cover BLS12-381, so its proofs are checked with ark-groth16
+/// on-chain too, at a far higher compute cost.
+pub const CURVE_BLS12_381: u8 = 1;
+
+/// Proof system of an instance whose withdrawal proofs are Groth16 proofs
+pub const PROOF_SYSTEM_GROTH16: u8 = 0;
+
+/// Proof system reserved for PLONK withdrawal proofs
+///
+/// No implementation exists yet, so instances can't select it.
+pub const PROOF_SYSTEM_PLONK: u8 = 1;
+
+/// Sentinel proof accepted without verification when built with `mock-verifier`
+///
+/// Lets integration tests run a full deposit and withdrawal without a real
+/// proof. The feature is rejected at compile time outside debug builds.
+#[cfg(feature = "mock-verifier")]
+pub const MOCK_PROOF: [u8; PROOF_SIZE] = [0xFF; PROOF_SIZE];
+
+/// Hash of a verifying key, as recorded in the instance's `vk_hash`
+///
+/// The keccak hash of the key bytes exactly as stored in the verifier account.
+pub fn hash_verifying_key(verifying_key: &[u8]) -> [u8; 32] {
+    keccak::hash(verifying_key).to_bytes()
+}
+
+/// Check the verifying key stored in a verifier account against its recorded hash
+fn check_vk_hash(vk_hash: &[u8; 32], verifier_data: &[u8]) -> Result<(), ProgramError> {
+    let verifier_state = VerifierState::deserialize(&mut &verifier_data[..])
+        .map_err(|_| TornadoError::InvalidVerifyingKey)?;
+    if hash_verifying_key(&verifier_state.verifying_key) != *vk_hash {
+        msg!("Verifying key doesn't match the instance's vk_hash");
+        return Err(TornadoError::VerifierKeyMismatch.into());
+    }
+
+    Ok(())
+}
 
-/// Verifies a zkSNARK proof
+/// Verifies a zkSNARK proof against the verifying key stored in a verifier account
+///
+/// The proof and key are for `proof_system` over `curve` (see
+/// `select_proof_system`). With `vk_hash`, the stored key must first hash to
+/// it, or verification fails with `VerifierKeyMismatch`. `public_inputs` holds
+/// 32 bytes per public input, as encoded by `WithdrawPublicInputs`. Returns
+/// `Ok(())` only for a valid proof; a proof that doesn't verify fails with
+/// `InvalidProof`.
 pub fn verify_tornado_proof(
+    proof_system: u8,
+    curve: u8,
+    vk_hash: Option<&[u8; 32]>,
+    verifier_data: &[u8],
     proof_data: &[u8],
-    public_inputs: &[u8; 192], // 6 public inputs * 32 bytes
-) -> Result<bool, ProgramError> {
+    public_inputs: &[u8],
+) -> Result<(), ProgramError> {
+    if let Some(vk_hash) = vk_hash {
+        check_vk_hash(vk_hash, verifier_data)?;
+    }
+
+    #[cfg(feature = "mock-verifier")]
+    if proof_data == MOCK_PROOF {
+        msg!("Accepting mock proof");
+        return Ok(());
+    }
+
+    select_proof_system(proof_system, curve)?.verify(proof_data, public_inputs, verifier_data)
+}
+
+/// Check a verifying key for `proof_system` over `curve` before it is stored in a verifier account
+pub fn check_verifying_key(
+    proof_system: u8,
+    curve: u8,
+    vk_bytes: &[u8],
+    num_public_inputs: usize,
+) -> Result<(), ProgramError> {
+    select_proof_system(proof_system, curve)?.check_verifying_key(vk_bytes, num_public_inputs)
+}
+
+/// A zkSNARK proof system withdrawal proofs can be verified with
+///
+/// Each implementation covers one proof system over one curve. The instance's
+/// `proof_system` and `curve` pick one with `select_proof_system`.
+pub trait ProofSystem {
+    /// Check a verifying key before it is stored in a verifier account
+    fn check_verifying_key(&self, vk_bytes: &[u8], num_public_inputs: usize) -> Result<(), ProgramError>;
+
+    /// Verify a proof against public inputs encoded by `WithdrawPublicInputs`
+    /// and the verifying key stored in a verifier account
+    fn verify(&self, proof_data: &[u8], public_inputs: &[u8], verifier_data: &[u8]) -> Result<(), ProgramError>;
+}
+
+/// Groth16 over BN254, checked with the alt_bn128 syscalls on-chain
+pub struct Groth16Bn254;
+
+/// Groth16 over BLS12-381, checked with ark-groth16 on-chain
+pub struct Groth16Bls12_381;
+
+impl ProofSystem for Groth16Bn254 {
+    /// See `parse_verifying_key`
+    fn check_verifying_key(&self, vk_bytes: &[u8], num_public_inputs: usize) -> Result<(), ProgramError> {
+        parse_verifying_key::<Bn254>(vk_bytes, num_public_inputs).map(|_| ())
+    }
+
+    fn verify(&self, proof_data: &[u8], public_inputs: &[u8], verifier_data: &[u8]) -> Result<(), ProgramError> {
+        verify_proof::<Bn254>(verifier_data, proof_data, public_inputs)
+    }
+}
+
+impl ProofSystem for Groth16Bls12_381 {
+    /// See `parse_verifying_key`
+    fn check_verifying_key(&self, vk_bytes: &[u8], num_public_inputs: usize) -> Result<(), ProgramError> {
+        parse_verifying_key::<Bls12_381>(vk_bytes, num_public_inputs).map(|_| ())
+    }
+
+    fn verify(&self, proof_data: &[u8], public_inputs: &[u8], verifier_data: &[u8]) -> Result<(), ProgramError> {
+        verify_proof::<Bls12_381>(verifier_data, proof_data, public_inputs)
+    }
+}
+
+/// Select the implementation of `proof_system` over `curve`
+///
+/// Fails with `UnsupportedProofSystem` for `PROOF_SYSTEM_PLONK` or an unknown
+/// proof system, and with `UnsupportedCurve` for an unknown curve.
+pub fn select_proof_system(proof_system: u8, curve: u8) -> Result<&'static dyn ProofSystem, ProgramError> {
+    match (proof_system, curve) {
+        (PROOF_SYSTEM_GROTH16, CURVE_BN254) => Ok(&Groth16Bn254),
+        (PROOF_SYSTEM_GROTH16, CURVE_BLS12_381) => Ok(&Groth16Bls12_381),
+        (PROOF_SYSTEM_GROTH16, _) => Err(unsupported_curve(curve)),
+        _ => {
+            msg!("Unsupported proof system {}", proof_system);
+            Err(TornadoError::UnsupportedProofSystem.into())
+        }
+    }
+}
+
+fn unsupported_curve(curve: u8) -> ProgramError {
+    msg!("Unknown curve {}", curve);
+    TornadoError::UnsupportedCurve.into()
+}
+
+/// A pairing curve withdrawal proofs can be verified over
+pub trait ProofCurve: Pairing {
+    /// Size of a proof with uncompressed affine coordinates
+    const PROOF_SIZE: usize;
+
+    /// Size of a proof with compressed points
+    const COMPRESSED_PROOF_SIZE: usize;
+
+    /// Deserialize a proof from `PROOF_SIZE` bytes of little-endian coordinates
+    /// (a.x, a.y, b.x.c0, b.x.c1, b.y.c0, b.y.c1, c.x, c.y)
+    fn deserialize_uncompressed_proof(proof_data: &[u8]) -> Result<Proof<Self>, ProgramError>;
+
+    /// Run the Groth16 check
+    fn verify_groth16(
+        vk: &VerifyingKey<Self>,
+        proof: &Proof<Self>,
+        inputs: &[Self::ScalarField],
+    ) -> Result<bool, ProgramError>;
+}
+
+impl ProofCurve for Bn254 {
+    const PROOF_SIZE: usize = PROOF_SIZE;
+    const COMPRESSED_PROOF_SIZE: usize = COMPRESSED_PROOF_SIZE;
+
+    fn deserialize_uncompressed_proof(proof_data: &[u8]) -> Result<Proof<Self>, ProgramError> {
+        let [a_x, a_y, b_x_1, b_x_2, b_y_1, b_y_2, c_x, c_y] = extract_coordinates::<Fq>(proof_data)?;
+
+        // Create the G1 and G2 points, which must be valid group elements
+        let a = check_proof_point(G1Affine::new_unchecked(a_x, a_y))?;
+        let b = check_proof_point(G2Affine::new_unchecked(Fq2::new(b_x_1, b_x_2), Fq2::new(b_y_1, b_y_2)))?;
+        let c = check_proof_point(G1Affine::new_unchecked(c_x, c_y))?;
+
+        Ok(Proof { a, b, c })
+    }
+
+    /// Uses the alt_bn128 syscalls on-chain and ark-groth16 off-chain
+    fn verify_groth16(vk: &VerifyingKey<Self>, proof: &Proof<Self>, inputs: &[Fr]) -> Result<bool, ProgramError> {
+        #[cfg(target_os = "solana")]
+        return verify_groth16_syscalls(vk, proof, inputs);
+
+        #[cfg(not(target_os = "solana"))]
+        verify_groth16_ark(vk, proof, inputs)
+    }
+}
+
+impl ProofCurve for Bls12_381 {
+    const PROOF_SIZE: usize = BLS12_381_PROOF_SIZE;
+    const COMPRESSED_PROOF_SIZE: usize = BLS12_381_COMPRESSED_PROOF_SIZE;
+
+    fn deserialize_uncompressed_proof(proof_data: &[u8]) -> Result<Proof<Self>, ProgramError> {
+        use bls12_381::{Fq, Fq2, G1Affine, G2Affine};
+
+        let [a_x, a_y, b_x_1, b_x_2, b_y_1, b_y_2, c_x, c_y] = extract_coordinates::<Fq>(proof_data)?;
+
+        // Create the G1 and G2 points, which must be valid group elements
+        let a = check_proof_point(G1Affine::new_unchecked(a_x, a_y))?;
+        let b = check_proof_point(G2Affine::new_unchecked(Fq2::new(b_x_1, b_x_2), Fq2::new(b_y_1, b_y_2)))?;
+        let c = check_proof_point(G1Affine::new_unchecked(c_x, c_y))?;
+
+        Ok(Proof { a, b, c })
+    }
+
+    /// Uses ark-groth16 both on-chain and off-chain
+    fn verify_groth16(
+        vk: &VerifyingKey<Self>,
+        proof: &Proof<Self>,
+        inputs: &[bls12_381::Fr],
+    ) -> Result<bool, ProgramError> {
+        verify_groth16_ark(vk, proof, inputs)
+    }
+}
+
+/// Verify a proof over the curve `E`
+fn verify_proof<E: ProofCurve>(
+    verifier_data: &[u8],
+    proof_data: &[u8],
+    public_inputs: &[u8],
+) -> Result<(), ProgramError> {
     // Deserialize the proof
-    let proof = deserialize_proof(proof_data)?;
+    let proof = deserialize_proof::<E>(proof_data)?;
     
     // Deserialize the public inputs
-    let inputs = deserialize_public_inputs(public_inputs)?;
-    
-    // Get the hardcoded verifying key
-    let vk = get_verifying_key()?;
-    
-    // Prepare the verifying key
-    let pvk = prepare_verifying_key(&vk);
+    let inputs = deserialize_public_inputs::<E::ScalarField>(public_inputs)?;
     
+    // Load the verifying key from the verifier account
+    let vk = load_verifying_key::<E>(verifier_data, inputs.len())?;
+
     // Verify the proof
-    let result = verify_proof(&pvk, &proof, &inputs);
-    
-    match result {
-        Ok(valid) => {
-            if valid {
-                msg!("Proof verification successful");
-                Ok(true)
-            } else {
-                msg!("Proof verification failed");
-                Err(TornadoError::InvalidProof.into())
-            }
+    match E::verify_groth16(&vk, &proof, &inputs) {
+        Ok(true) => {
+            msg!("Proof verification successful");
+            Ok(())
+        }
+        Ok(false) => {
+            msg!("Proof verification failed");
+            Err(TornadoError::InvalidProof.into())
         }
         Err(e) => {
             msg!("Error verifying proof: {:?}", e);
@@ -49,139 +278,546 @@ pub fn verify_tornado_proof(
     }
 }
 
-/// Deserialize a proof from bytes
-fn deserialize_proof(proof_data: &[u8]) -> Result<Proof<Bn254>, ProgramError> {
-    // Ensure the proof data is the correct length
-    if proof_data.len() != 256 {
-        msg!("Invalid proof data length: {}", proof_data.len());
-        return Err(TornadoError::InvalidProof.into());
+/// Run the Groth16 check with ark-groth16
+#[cfg_attr(target_os = "solana", allow(dead_code))]
+fn verify_groth16_ark<E: Pairing>(
+    vk: &VerifyingKey<E>,
+    proof: &Proof<E>,
+    inputs: &[E::ScalarField],
+) -> Result<bool, ProgramError> {
+    let pvk = prepare_verifying_key(vk);
+    Groth16::<E>::verify_proof(&pvk, proof, inputs).map_err(|e| {
+        msg!("Error verifying proof: {:?}", e);
+        TornadoError::InvalidProof.into()
+    })
+}
+
+/// Groth16 verification using the alt_bn128 syscalls
+///
+/// Checks `e(-A, B) * e(alpha, beta) * e(vk_x, gamma) * e(C, delta) == 1`, where
+/// `vk_x = IC[0] + sum(input[i] * IC[i + 1])`.
+#[cfg_attr(not(target_os = "solana"), allow(dead_code))]
+fn verify_groth16_syscalls(
+    vk: &VerifyingKey<Bn254>,
+    proof: &Proof<Bn254>,
+    inputs: &[Fr],
+) -> Result<bool, ProgramError> {
+    if vk.gamma_abc_g1.len() != inputs.len() + 1 {
+        msg!("Verifying key does not match the number of public inputs");
+        return Err(TornadoError::InvalidVerifyingKey.into());
+    }
+    
+    // Prepare the public inputs
+    let mut vk_x = g1_to_be_bytes(&vk.gamma_abc_g1[0]);
+    for (input, ic) in inputs.iter().zip(&vk.gamma_abc_g1[1..]) {
+        let mut mul_input = [0u8; 96];
+        mul_input[..64].copy_from_slice(&g1_to_be_bytes(ic));
+        mul_input[64..].copy_from_slice(&input.into_bigint().to_bytes_be());
+        let product = alt_bn128_multiplication(&mul_input).map_err(|e| {
+            msg!("alt_bn128 multiplication failed: {:?}", e);
+            TornadoError::InvalidProof
+        })?;
+        
+        let mut add_input = [0u8; 128];
+        add_input[..64].copy_from_slice(&vk_x);
+        add_input[64..].copy_from_slice(&product);
+        let sum = alt_bn128_addition(&add_input).map_err(|e| {
+            msg!("alt_bn128 addition failed: {:?}", e);
+            TornadoError::InvalidProof
+        })?;
+        vk_x.copy_from_slice(&sum);
     }
     
-    // Extract the proof components
-    let a_x = extract_field_element(&proof_data[0..32])?;
-    let a_y = extract_field_element(&proof_data[32..64])?;
-    let b_x_1 = extract_field_element(&proof_data[64..96])?;
-    let b_x_2 = extract_field_element(&proof_data[96..128])?;
-    let b_y_1 = extract_field_element(&proof_data[128..160])?;
-    let b_y_2 = extract_field_element(&proof_data[160..192])?;
-    let c_x = extract_field_element(&proof_data[192..224])?;
-    let c_y = extract_field_element(&proof_data[224..256])?;
+    // Build the pairing input: (-A, B), (alpha, beta), (vk_x, gamma), (C, delta)
+    let mut pairing_input = [0u8; 4 * ALT_BN128_PAIRING_ELEMENT_LEN];
+    let pairs: [([u8; 64], [u8; 128]); 4] = [
+        (g1_to_be_bytes(&-proof.a), g2_to_be_bytes(&proof.b)),
+        (g1_to_be_bytes(&vk.alpha_g1), g2_to_be_bytes(&vk.beta_g2)),
+        (vk_x, g2_to_be_bytes(&vk.gamma_g2)),
+        (g1_to_be_bytes(&proof.c), g2_to_be_bytes(&vk.delta_g2)),
+    ];
+    for (chunk, (g1, g2)) in pairing_input
+        .chunks_exact_mut(ALT_BN128_PAIRING_ELEMENT_LEN)
+        .zip(pairs.iter())
+    {
+        chunk[..64].copy_from_slice(g1);
+        chunk[64..].copy_from_slice(g2);
+    }
     
-    // Create the G1 and G2 points
-    let a = G1Affine::new(a_x, a_y);
-    let b = G2Affine::new([b_x_1, b_x_2], [b_y_1, b_y_2]);
-    let c = G1Affine::new(c_x, c_y);
+    let result = alt_bn128_pairing(&pairing_input).map_err(|e| {
+        msg!("alt_bn128 pairing failed: {:?}", e);
+        TornadoError::InvalidProof
+    })?;
     
-    // Create the proof
-    Ok(Proof { a, b, c })
+    // The syscall returns 1 as a big-endian 32-byte integer when the product is one
+    let mut one = [0u8; 32];
+    one[31] = 1;
+    Ok(result == one)
+}
+
+/// Encode a G1 point in the syscall format (x || y, big-endian)
+fn g1_to_be_bytes(point: &G1Affine) -> [u8; 64] {
+    let mut bytes = [0u8; 64];
+    // The point at infinity is encoded as all zeros
+    if !point.infinity {
+        bytes[..32].copy_from_slice(&point.x.into_bigint().to_bytes_be());
+        bytes[32..].copy_from_slice(&point.y.into_bigint().to_bytes_be());
+    }
+    bytes
 }
 
-/// Extract a field element from bytes
-fn extract_field_element(data: &[u8]) -> Result<Fr, ProgramError> {
-    if data.len() != 32 {
+/// Encode a G2 point in the syscall format (x.c1 || x.c0 || y.c1 || y.c0, big-endian)
+fn g2_to_be_bytes(point: &G2Affine) -> [u8; 128] {
+    let mut bytes = [0u8; 128];
+    if !point.infinity {
+        bytes[..32].copy_from_slice(&point.x.c1.into_bigint().to_bytes_be());
+        bytes[32..64].copy_from_slice(&point.x.c0.into_bigint().to_bytes_be());
+        bytes[64..96].copy_from_slice(&point.y.c1.into_bigint().to_bytes_be());
+        bytes[96..].copy_from_slice(&point.y.c0.into_bigint().to_bytes_be());
+    }
+    bytes
+}
+
+/// Size of a BN254 proof with uncompressed affine coordinates
+pub const PROOF_SIZE: usize = 256;
+
+/// Size of a BN254 proof with compressed points (32-byte G1, 64-byte G2)
+pub const COMPRESSED_PROOF_SIZE: usize = 128;
+
+/// Size of a BLS12-381 proof with uncompressed affine coordinates (48 bytes each)
+pub const BLS12_381_PROOF_SIZE: usize = 384;
+
+/// Size of a BLS12-381 proof with compressed points (48-byte G1, 96-byte G2)
+pub const BLS12_381_COMPRESSED_PROOF_SIZE: usize = 192;
+
+/// Deserialize a proof over the curve `E` from bytes
+///
+/// The encoding is selected by length: `E::PROOF_SIZE` bytes of little-endian
+/// coordinates (a.x, a.y, b.x.c0, b.x.c1, b.y.c0, b.y.c1, c.x, c.y), or
+/// `E::COMPRESSED_PROOF_SIZE` bytes of `CanonicalSerialize` compressed points (a, b, c).
+fn deserialize_proof<E: ProofCurve>(proof_data: &[u8]) -> Result<Proof<E>, ProgramError> {
+    match proof_data.len() {
+        len if len == E::PROOF_SIZE => E::deserialize_uncompressed_proof(proof_data),
+        len if len == E::COMPRESSED_PROOF_SIZE => deserialize_compressed_proof(proof_data),
+        len => {
+            msg!(
+                "Invalid proof length {}: expected {} bytes uncompressed or {} bytes compressed",
+                len,
+                E::PROOF_SIZE,
+                E::COMPRESSED_PROOF_SIZE
+            );
+            Err(TornadoError::InvalidProof.into())
+        }
+    }
+}
+
+/// Deserialize a proof from compressed points
+fn deserialize_compressed_proof<E: Pairing>(proof_data: &[u8]) -> Result<Proof<E>, ProgramError> {
+    Proof::<E>::deserialize_compressed(proof_data).map_err(|_| {
+        msg!("Invalid compressed proof point");
+        TornadoError::InvalidProof.into()
+    })
+}
+
+/// Split an uncompressed proof into its eight base field coordinates
+fn extract_coordinates<F: PrimeField>(proof_data: &[u8]) -> Result<[F; 8], ProgramError> {
+    let size = field_element_size::<F>();
+    if proof_data.len() != 8 * size {
+        return Err(TornadoError::InvalidProof.into());
+    }
+
+    let mut coordinates = [F::zero(); 8];
+    for (coordinate, bytes) in coordinates.iter_mut().zip(proof_data.chunks_exact(size)) {
+        *coordinate = extract_field_element(bytes)?;
+    }
+
+    Ok(coordinates)
+}
+
+/// Check a proof point is on the curve and in the prime-order subgroup
+///
+/// The pairing is only meaningful for points of the subgroup, and ark may
+/// misbehave on points that aren't on the curve at all.
+fn check_proof_point<P: SWCurveConfig>(point: Affine<P>) -> Result<Affine<P>, ProgramError> {
+    if !point.is_on_curve() || !point.is_in_correct_subgroup_assuming_on_curve() {
+        msg!("Proof point is not a valid group element");
         return Err(TornadoError::InvalidProof.into());
     }
     
-    // Convert bytes to field element
-    let mut repr = <Fr as PrimeField>::BigInt::default();
-    repr.read_le(data)
-        .map_err(|_| TornadoError::InvalidProof)?;
+    Ok(point)
+}
+
+/// Number of bytes encoding an element of the field `F`
+fn field_element_size<F: PrimeField>() -> usize {
+    (F::MODULUS_BIT_SIZE as usize).div_ceil(8)
+}
+
+/// Extract a field element from little-endian bytes
+fn extract_field_element<F: PrimeField>(data: &[u8]) -> Result<F, ProgramError> {
+    if data.len() != field_element_size::<F>() {
+        return Err(TornadoError::InvalidProof.into());
+    }
     
     // Create the field element
-    Fr::from_le_bytes_mod_order(data)
+    Ok(F::from_le_bytes_mod_order(data))
 }
 
-/// Deserialize public inputs from bytes
-fn deserialize_public_inputs(data: &[u8; 192]) -> Result<Vec<Fr>, ProgramError> {
-    let mut inputs = Vec::with_capacity(6);
+/// Parse a canonical big-endian field element, rejecting values >= the field order
+fn canonical_field_element<F: PrimeField>(bytes: &[u8; 32]) -> Option<F> {
+    let element = F::from_be_bytes_mod_order(bytes);
+    (element.into_bigint().to_bytes_be() == bytes[..]).then_some(element)
+}
+
+/// Reduce a pubkey to the field element the circuit uses for it
+///
+/// A pubkey is 256 bits wide, so its big-endian value can exceed the BN254
+/// scalar field order and is reduced modulo it. Every pubkey entering the
+/// public inputs goes through this function. Clients must apply the same
+/// reduction when generating proofs, or valid proofs are rejected; encoding
+/// the inputs with `WithdrawPublicInputs` does this for them.
+pub fn reduce_pubkey_to_field(address: &Pubkey) -> Fr {
+    Fr::from_be_bytes_mod_order(address.as_ref())
+}
+
+/// Added to the `fee` public input of a withdrawal paying its fee in lamports
+///
+/// Token fees are below `2^64`, so a proof of a token fee never verifies as a
+/// SOL fee of the same amount.
+pub const SOL_FEE_TAG: u128 = 1 << 64;
+
+/// The public inputs of a withdrawal proof
+///
+/// This is the single definition of the order and encoding of the circuit's
+/// public signals, for both the program and clients generating proofs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WithdrawPublicInputs {
+    /// The Merkle root the note is proven against
+    pub root: [u8; 32],
+    /// The nullifier hash of the note
+    pub nullifier_hash: [u8; 32],
+    /// The recipient address
+    pub recipient: Pubkey,
+    /// The relayer address
+    pub relayer: Pubkey,
+    /// The fee paid to the relayer
+    pub fee: u64,
+    /// Whether the fee is paid in lamports rather than in the pool's tokens
+    pub fee_in_sol: bool,
+    /// The refund paid to the recipient
+    pub refund: u64,
+    /// The Tornado instance withdrawn from, which the note's commitment is bound to
+    pub instance: Pubkey,
+}
+
+impl WithdrawPublicInputs {
+    /// Length of the encoded public inputs of the fixed-denomination circuit
+    pub const LEN: usize = NUM_PUBLIC_INPUTS * 32;
+    
+    /// Length of the encoded public inputs of the variable-amount circuit
+    pub const VARIABLE_AMOUNT_LEN: usize = NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS * 32;
+    
+    /// Length of the encoded public inputs of the partial withdrawal circuit
+    pub const PARTIAL_LEN: usize = NUM_PARTIAL_WITHDRAW_PUBLIC_INPUTS * 32;
+    
+    /// Encode the public inputs in the circuit's public signal order
+    ///
+    /// Each signal is a 32-byte big-endian BN254 scalar. Every BN254 scalar is
+    /// below the BLS12-381 scalar field order, so BLS12-381 circuits read the
+    /// same bytes as the same values. Integers such as the fee are widened to
+    /// a full scalar, so their value sits in the last 8 bytes of the slot.
+    ///
+    /// | Bytes     | Signal          | Encoding                        |
+    /// |-----------|-----------------|---------------------------------|
+    /// | 0..32     | `root`          | field element                   |
+    /// | 32..64    | `nullifierHash` | field element                   |
+    /// | 64..96    | `recipient`     | `reduce_pubkey_to_field`        |
+    /// | 96..128   | `relayer`       | `reduce_pubkey_to_field`        |
+    /// | 128..160  | `fee`           | integer, tagged if paid in SOL  |
+    /// | 160..192  | `refund`        | integer                         |
+    /// | 192..224  | `instance`      | `reduce_pubkey_to_field`        |
+    ///
+    /// The root and nullifier hash must already be canonical field elements:
+    /// otherwise two different nullifier hashes could verify as the same note.
+    /// The circuit checks the instance against the one hashed into the note's
+    /// commitment, so a note only withdraws from the pool it was deposited to.
+    /// A fee paid in lamports has `SOL_FEE_TAG` added, which sets a bit above
+    /// every `u64`, so a relayer can't take a fee the withdrawer agreed to in
+    /// one unit in the other.
+    pub fn to_bytes(&self) -> Result<[u8; Self::LEN], ProgramError> {
+        let root = canonical_field_element::<Fr>(&self.root).ok_or(TornadoError::InvalidMerkleRoot)?;
+        let nullifier_hash =
+            canonical_field_element::<Fr>(&self.nullifier_hash).ok_or(TornadoError::InvalidNullifierHash)?;
+        let fee = if self.fee_in_sol {
+            Fr::from(self.fee) + Fr::from(SOL_FEE_TAG)
+        } else {
+            Fr::from(self.fee)
+        };
+        let inputs = [
+            root,
+            nullifier_hash,
+            reduce_pubkey_to_field(&self.recipient),
+            reduce_pubkey_to_field(&self.relayer),
+            fee,
+            Fr::from(self.refund),
+            reduce_pubkey_to_field(&self.instance),
+        ];
+        
+        let mut public_inputs = [0u8; Self::LEN];
+        for (slot, input) in public_inputs.chunks_exact_mut(32).zip(inputs.iter()) {
+            slot.copy_from_slice(&input.into_bigint().to_bytes_be());
+        }
+        
+        Ok(public_inputs)
+    }
+    
+    /// Encode the public inputs of a withdrawal from a variable-amount instance
+    ///
+    /// The layout of `to_bytes`, followed by the withdrawn amount:
+    ///
+    /// | Bytes     | Signal          | Encoding                        |
+    /// |-----------|-----------------|---------------------------------|
+    /// | 0..224    | (as above)      |                                 |
+    /// | 224..256  | `amount`        | integer                         |
+    ///
+    /// The circuit proves that `amount` is the one committed in the deposited leaf.
+    pub fn to_variable_amount_bytes(&self, amount: u64) -> Result<[u8; Self::VARIABLE_AMOUNT_LEN], ProgramError> {
+        let mut public_inputs = [0u8; Self::VARIABLE_AMOUNT_LEN];
+        public_inputs[..Self::LEN].copy_from_slice(&self.to_bytes()?);
+        public_inputs[Self::LEN..].copy_from_slice(&Fr::from(amount).into_bigint().to_bytes_be());
+        
+        Ok(public_inputs)
+    }
     
-    for i in 0..6 {
-        let start = i * 32;
-        let end = start + 32;
-        let input = extract_field_element(&data[start..end])?;
+    /// Encode the public inputs of a partial withdrawal from a variable-amount instance
+    ///
+    /// The layout of `to_variable_amount_bytes` for the input note, followed by
+    /// the remainder re-deposited:
+    ///
+    /// | Bytes     | Signal             | Encoding                        |
+    /// |-----------|--------------------|---------------------------------|
+    /// | 0..224    | (as `to_bytes`)    |                                 |
+    /// | 224..256  | `inputAmount`      | integer                         |
+    /// | 256..288  | `outputAmount`     | integer                         |
+    /// | 288..320  | `outputCommitment` | field element                   |
+    ///
+    /// The circuit proves that `inputAmount` is the one committed in the spent
+    /// leaf. Binding the output commitment to the proof keeps a relayer from
+    /// redirecting the remainder to a note of its own.
+    pub fn to_partial_bytes(
+        &self,
+        input_amount: u64,
+        output_amount: u64,
+        output_commitment: &[u8; 32],
+    ) -> Result<[u8; Self::PARTIAL_LEN], ProgramError> {
+        let output_commitment =
+            canonical_field_element::<Fr>(output_commitment).ok_or(TornadoError::InvalidCommitment)?;
+        
+        let mut public_inputs = [0u8; Self::PARTIAL_LEN];
+        public_inputs[..Self::VARIABLE_AMOUNT_LEN].copy_from_slice(&self.to_variable_amount_bytes(input_amount)?);
+        public_inputs[Self::VARIABLE_AMOUNT_LEN..Self::VARIABLE_AMOUNT_LEN + 32]
+            .copy_from_slice(&Fr::from(output_amount).into_bigint().to_bytes_be());
+        public_inputs[Self::VARIABLE_AMOUNT_LEN + 32..].copy_from_slice(&output_commitment.into_bigint().to_bytes_be());
+        
+        Ok(public_inputs)
+    }
+}
+
+/// Deserialize public inputs encoded by `WithdrawPublicInputs` as scalars of the field `F`
+fn deserialize_public_inputs<F: PrimeField>(data: &[u8]) -> Result<Vec<F>, ProgramError> {
+    if !data.len().is_multiple_of(32) {
+        msg!("Invalid public inputs length: {}", data.len());
+        return Err(TornadoError::InvalidProof.into());
+    }
+    
+    let mut inputs = Vec::with_capacity(data.len() / 32);
+    
+    for slot in data.chunks_exact(32) {
+        let input = canonical_field_element(slot.try_into().unwrap())
+            .ok_or(TornadoError::InvalidProof)?;
         inputs.push(input);
     }
     
     Ok(inputs)
 }
 
-/// Get the hardcoded verifying key
-fn get_verifying_key() -> Result<VerifyingKey<Bn254>, ProgramError> {
-    // This would be the hardcoded verifying key from the trusted setup
-    // For simplicity, we're creating a dummy key here
-    // In a real implementation, this would be the actual verifying key
-    
-    // Alpha in G1
-    let alpha_g1 = G1Affine::new(
-        Fr::from(1),
-        Fr::from(2),
-    );
-    
-    // Beta in G2
-    let beta_g2 = G2Affine::new(
-        [Fr::from(3), Fr::from(4)],
-        [Fr::from(5), Fr::from(6)],
-    );
-    
-    // Gamma in G2
-    let gamma_g2 = G2Affine::new(
-        [Fr::from(7), Fr::from(8)],
-        [Fr::from(9), Fr::from(10)],
-    );
-    
-    // Delta in G2
-    let delta_g2 = G2Affine::new(
-        [Fr::from(11), Fr::from(12)],
-        [Fr::from(13), Fr::from(14)],
-    );
-    
-    // IC (7 elements for 6 public inputs + 1)
-    let mut ic = Vec::with_capacity(7);
-    for i in 0..7 {
-        ic.push(G1Affine::new(
-            Fr::from((i * 2 + 15) as u64),
-            Fr::from((i * 2 + 16) as u64),
-        ));
-    }
-    
-    Ok(VerifyingKey {
-        alpha_g1,
-        beta_g2,
-        gamma_g2,
-        delta_g2,
-        gamma_abc_g1: ic,
-    })
+/// Parse a verifying key over the curve `E` serialized with `CanonicalSerialize` (compressed)
+///
+/// The key must have one `gamma_abc_g1` point per public input plus one.
+pub fn parse_verifying_key<E: Pairing>(
+    vk_bytes: &[u8],
+    num_public_inputs: usize,
+) -> Result<VerifyingKey<E>, ProgramError> {
+    let vk = VerifyingKey::<E>::deserialize_compressed(vk_bytes).map_err(|e| {
+        msg!("Error deserializing verifying key: {:?}", e);
+        TornadoError::InvalidVerifyingKey
+    })?;
+    
+    // IC (one element per public input + 1)
+    if vk.gamma_abc_g1.len() != num_public_inputs + 1 {
+        msg!(
+            "Verifying key takes {} public inputs, expected {}",
+            vk.gamma_abc_g1.len().saturating_sub(1),
+            num_public_inputs
+        );
+        return Err(TornadoError::InvalidVerifyingKey.into());
+    }
+    
+    Ok(vk)
+}
+
+/// Load the verifying key from the data of a verifier account
+///
+/// The key was validated by `parse_verifying_key` when the verifier account was
+/// initialized, so the (expensive) curve and subgroup checks are skipped here.
+/// Its `gamma_abc_g1` length fixes the circuit's number of public inputs, which
+/// must be `num_public_inputs`.
+fn load_verifying_key<E: Pairing>(
+    verifier_data: &[u8],
+    num_public_inputs: usize,
+) -> Result<VerifyingKey<E>, ProgramError> {
+    let verifier_state = VerifierState::deserialize(&mut &verifier_data[..])
+        .map_err(|_| TornadoError::InvalidVerifyingKey)?;
+    if !verifier_state.is_initialized {
+        return Err(TornadoError::AccountNotInitialized.into());
+    }
+    
+    let vk = VerifyingKey::<E>::deserialize_compressed_unchecked(&verifier_state.verifying_key[..])
+        .map_err(|e| {
+            msg!("Error deserializing verifying key: {:?}", e);
+            TornadoError::InvalidVerifyingKey
+        })?;
+    if vk.gamma_abc_g1.len() != num_public_inputs + 1 {
+        msg!(
+            "Verifying key takes {} public inputs, got {}",
+            vk.gamma_abc_g1.len().saturating_sub(1),
+            num_public_inputs
+        );
+        return Err(TornadoError::InvalidVerifyingKey.into());
+    }
+    
+    Ok(vk)
+}
+
+/// The stages of `verify_tornado_proof`, exposed for the benchmarks in `benches/`
+#[cfg(all(feature = "bench", not(target_os = "solana")))]
+pub mod bench {
+    use ark_bn254::{Bn254, Fr};
+    use ark_groth16::{prepare_verifying_key, Groth16, PreparedVerifyingKey, Proof, VerifyingKey};
+    use solana_program::program_error::ProgramError;
+
+    /// Deserialize a BN254 proof in either encoding accepted by the withdraw instructions
+    pub fn deserialize_proof(proof_data: &[u8]) -> Result<Proof<Bn254>, ProgramError> {
+        super::deserialize_proof::<Bn254>(proof_data)
+    }
+
+    /// Deserialize public inputs encoded by `WithdrawPublicInputs`
+    pub fn deserialize_public_inputs(data: &[u8]) -> Result<Vec<Fr>, ProgramError> {
+        super::deserialize_public_inputs::<Fr>(data)
+    }
+
+    /// Load the verifying key stored in a verifier account
+    pub fn load_verifying_key(
+        verifier_data: &[u8],
+        num_public_inputs: usize,
+    ) -> Result<VerifyingKey<Bn254>, ProgramError> {
+        super::load_verifying_key::<Bn254>(verifier_data, num_public_inputs)
+    }
+
+    /// Precompute the pairing constants of a verifying key (ark path)
+    pub fn prepare(vk: &VerifyingKey<Bn254>) -> PreparedVerifyingKey<Bn254> {
+        prepare_verifying_key(vk)
+    }
+
+    /// Check a proof against a prepared key: the input MSM and the pairings (ark path)
+    pub fn verify_prepared(pvk: &PreparedVerifyingKey<Bn254>, proof: &Proof<Bn254>, inputs: &[Fr]) -> bool {
+        Groth16::<Bn254>::verify_proof(pvk, proof, inputs).unwrap_or(false)
+    }
+
+    /// Check a proof with the alt_bn128 syscalls, emulated off-chain (on-chain path)
+    pub fn verify_syscalls(vk: &VerifyingKey<Bn254>, proof: &Proof<Bn254>, inputs: &[Fr]) -> bool {
+        super::verify_groth16_syscalls(vk, proof, inputs).unwrap_or(false)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ark_bn254::{Bn254, Fr, G1Affine, G2Affine};
-    use ark_ec::pairing::Pairing;
-    use ark_ff::{Field, One, Zero};
-    use ark_groth16::{Proof, VerifyingKey};
-    use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+    use ark_ec::AffineRepr;
+    use ark_ff::{BigInt, Field, Zero};
+    use ark_relations::{
+        lc,
+        r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError, Variable},
+    };
+    use ark_serialize::CanonicalSerialize;
+    use borsh::BorshSerialize;
+    
+    // Circuit with the given public inputs, each constrained by `x * 1 = x`
+    struct PublicInputsCircuit<F: PrimeField> {
+        inputs: Vec<F>,
+    }
+    
+    impl<F: PrimeField> ConstraintSynthesizer<F> for PublicInputsCircuit<F> {
+        fn generate_constraints(self, cs: ConstraintSystemRef<F>) -> Result<(), SynthesisError> {
+            for input in self.inputs {
+                let x = cs.new_input_variable(|| Ok(input))?;
+                cs.enforce_constraint(lc!() + x, lc!() + Variable::One, lc!() + x)?;
+            }
+            Ok(())
+        }
+    }
+    
+    // Helper function to create a real verifying key and proof over the curve `E` for the given inputs
+    fn create_curve_proof<E: Pairing>(inputs: &[E::ScalarField]) -> (VerifyingKey<E>, Proof<E>) {
+        let mut rng = ark_std::test_rng();
+        let circuit = PublicInputsCircuit { inputs: inputs.to_vec() };
+        let pk = Groth16::<E>::generate_random_parameters_with_reduction(circuit, &mut rng)
+            .unwrap();
+        let circuit = PublicInputsCircuit { inputs: inputs.to_vec() };
+        let proof = Groth16::<E>::create_random_proof_with_reduction(circuit, &pk, &mut rng)
+            .unwrap();
+        (pk.vk, proof)
+    }
+    
+    // Helper function to create a real BN254 verifying key and proof for the given inputs
+    fn create_real_proof(inputs: &[Fr]) -> (VerifyingKey<Bn254>, Proof<Bn254>) {
+        create_curve_proof::<Bn254>(inputs)
+    }
+    
+    // Helper function to encode proof coordinates as little-endian bytes
+    fn encode_coordinates<F: PrimeField>(coordinates: [F; 8]) -> Vec<u8> {
+        coordinates.iter().flat_map(|f| f.into_bigint().to_bytes_le()).collect()
+    }
+    
+    // Helper function to encode a proof in the `PROOF_SIZE` format read by deserialize_proof
+    fn encode_proof(proof: &Proof<Bn254>) -> Vec<u8> {
+        encode_coordinates([
+            proof.a.x,
+            proof.a.y,
+            proof.b.x.c0,
+            proof.b.x.c1,
+            proof.b.y.c0,
+            proof.b.y.c1,
+            proof.c.x,
+            proof.c.y,
+        ])
+    }
+    
+    // Helper function to encode a BLS12-381 proof in the `BLS12_381_PROOF_SIZE` format
+    fn encode_bls12_381_proof(proof: &Proof<Bls12_381>) -> Vec<u8> {
+        encode_coordinates([
+            proof.a.x,
+            proof.a.y,
+            proof.b.x.c0,
+            proof.b.x.c1,
+            proof.b.y.c0,
+            proof.b.y.c1,
+            proof.c.x,
+            proof.c.y,
+        ])
+    }
     
     // Helper function to create a dummy proof
     fn create_dummy_proof() -> Vec<u8> {
-        // Create dummy field elements
-        let a_x = Fr::one();
-        let a_y = Fr::one();
-        let b_x_1 = Fr::one();
-        let b_x_2 = Fr::one();
-        let b_y_1 = Fr::one();
-        let b_y_2 = Fr::one();
-        let c_x = Fr::one();
-        let c_y = Fr::one();
-        
-        // Create G1 and G2 points
-        let a = G1Affine::new(a_x, a_y);
-        let b = G2Affine::new([b_x_1, b_x_2], [b_y_1, b_y_2]);
-        let c = G1Affine::new(c_x, c_y);
-        
-        // Create the proof
-        let proof = Proof { a, b, c };
-        
         // Serialize the proof components to bytes
         let mut proof_data = Vec::new();
         
@@ -219,25 +855,210 @@ mod tests {
     }
     
     // Helper function to create dummy public inputs
-    fn create_dummy_public_inputs() -> [u8; 192] {
-        let mut inputs = [0u8; 192];
+    fn create_dummy_public_inputs() -> [u8; WithdrawPublicInputs::LEN] {
+        let mut inputs = [0u8; WithdrawPublicInputs::LEN];
         // Set some non-zero values
-        for i in 0..6 {
+        for i in 0..NUM_PUBLIC_INPUTS {
             inputs[i * 32] = (i + 1) as u8;
         }
         inputs
     }
     
+    // Helper function to create a verifying key built from the curve generators
+    fn create_test_verifying_key(ic_len: usize) -> Vec<u8> {
+        let vk = VerifyingKey::<Bn254> {
+            alpha_g1: G1Affine::generator(),
+            beta_g2: G2Affine::generator(),
+            gamma_g2: G2Affine::generator(),
+            delta_g2: G2Affine::generator(),
+            gamma_abc_g1: vec![G1Affine::generator(); ic_len],
+        };
+        
+        let mut vk_bytes = Vec::new();
+        vk.serialize_compressed(&mut vk_bytes).unwrap();
+        vk_bytes
+    }
+    
+    // Helper function to create verifier account data
+    fn create_verifier_data(verifying_key: Vec<u8>) -> Vec<u8> {
+        VerifierState {
+            is_initialized: true,
+            verifying_key,
+        }
+        .try_to_vec()
+        .unwrap()
+    }
+    
     #[test]
     fn test_deserialize_proof() {
-        let proof_data = create_dummy_proof();
-        let result = deserialize_proof(&proof_data);
-        assert!(result.is_ok());
+        let inputs = deserialize_public_inputs::<Fr>(&create_dummy_public_inputs()).unwrap();
+        let (_, proof) = create_real_proof(&inputs);
+        assert_eq!(deserialize_proof::<Bn254>(&encode_proof(&proof)).unwrap(), proof);
         
-        // Test with invalid length
-        let invalid_proof = vec![0u8; 128]; // Too short
-        let result = deserialize_proof(&invalid_proof);
-        assert!(result.is_err());
+        // The dummy proof's points are not on the curve
+        assert_eq!(
+            deserialize_proof::<Bn254>(&create_dummy_proof()).unwrap_err(),
+            TornadoError::InvalidProof.into()
+        );
+        
+        // Test with lengths that match neither encoding, including the BLS12-381 sizes
+        for len in [
+            0,
+            1,
+            COMPRESSED_PROOF_SIZE - 1,
+            COMPRESSED_PROOF_SIZE + 1,
+            PROOF_SIZE - 1,
+            PROOF_SIZE + 1,
+            BLS12_381_COMPRESSED_PROOF_SIZE,
+            BLS12_381_PROOF_SIZE,
+            2 * PROOF_SIZE,
+        ] {
+            assert_eq!(
+                deserialize_proof::<Bn254>(&vec![0u8; len]).unwrap_err(),
+                TornadoError::InvalidProof.into()
+            );
+        }
+        
+        // BLS12-381 proofs have their own sizes
+        for len in [
+            0,
+            BLS12_381_COMPRESSED_PROOF_SIZE - 1,
+            BLS12_381_COMPRESSED_PROOF_SIZE + 1,
+            BLS12_381_PROOF_SIZE - 1,
+            BLS12_381_PROOF_SIZE + 1,
+        ] {
+            assert_eq!(
+                deserialize_proof::<Bls12_381>(&vec![0u8; len]).unwrap_err(),
+                TornadoError::InvalidProof.into()
+            );
+        }
+    }
+    
+    #[test]
+    fn test_deserialize_compressed_proof() {
+        let inputs = deserialize_public_inputs::<Fr>(&create_dummy_public_inputs()).unwrap();
+        let (vk, proof) = create_real_proof(&inputs);
+        
+        // Round trip through the compressed encoding
+        let mut proof_data = Vec::new();
+        proof.a.serialize_compressed(&mut proof_data).unwrap();
+        proof.b.serialize_compressed(&mut proof_data).unwrap();
+        proof.c.serialize_compressed(&mut proof_data).unwrap();
+        assert_eq!(proof_data.len(), COMPRESSED_PROOF_SIZE);
+        
+        let parsed = deserialize_proof::<Bn254>(&proof_data).unwrap();
+        assert_eq!(parsed, proof);
+        assert_eq!(parsed, deserialize_proof::<Bn254>(&encode_proof(&proof)).unwrap());
+        assert!(Bn254::verify_groth16(&vk, &parsed, &inputs).unwrap());
+        
+        // A point that doesn't decompress is rejected
+        let mut invalid_proof = proof_data.clone();
+        invalid_proof[..32].copy_from_slice(&[0xFF; 32]);
+        assert_eq!(
+            deserialize_proof::<Bn254>(&invalid_proof).unwrap_err(),
+            TornadoError::InvalidProof.into()
+        );
+    }
+    
+    #[test]
+    fn test_deserialize_bls12_381_proof() {
+        let public_inputs = create_dummy_public_inputs();
+        let inputs = deserialize_public_inputs::<bls12_381::Fr>(&public_inputs).unwrap();
+        let (vk, proof) = create_curve_proof::<Bls12_381>(&inputs);
+        
+        // Round trip through both encodings
+        let proof_data = encode_bls12_381_proof(&proof);
+        assert_eq!(proof_data.len(), BLS12_381_PROOF_SIZE);
+        assert_eq!(deserialize_proof::<Bls12_381>(&proof_data).unwrap(), proof);
+        let mut compressed_proof_data = Vec::new();
+        proof.serialize_compressed(&mut compressed_proof_data).unwrap();
+        assert_eq!(compressed_proof_data.len(), BLS12_381_COMPRESSED_PROOF_SIZE);
+        assert_eq!(deserialize_proof::<Bls12_381>(&compressed_proof_data).unwrap(), proof);
+        
+        // Off-curve points and BN254 lengths are rejected
+        let mut off_curve = proof_data.clone();
+        off_curve[48] ^= 1;
+        for invalid_proof in [off_curve, vec![0u8; PROOF_SIZE], vec![0u8; COMPRESSED_PROOF_SIZE]] {
+            assert_eq!(
+                deserialize_proof::<Bls12_381>(&invalid_proof).unwrap_err(),
+                TornadoError::InvalidProof.into()
+            );
+        }
+        
+        // The proof verifies against its key only as a BLS12-381 proof
+        let mut vk_bytes = Vec::new();
+        vk.serialize_compressed(&mut vk_bytes).unwrap();
+        assert_eq!(check_verifying_key(PROOF_SYSTEM_GROTH16, CURVE_BLS12_381, &vk_bytes, NUM_PUBLIC_INPUTS), Ok(()));
+        assert_eq!(
+            check_verifying_key(PROOF_SYSTEM_GROTH16, CURVE_BN254, &vk_bytes, NUM_PUBLIC_INPUTS),
+            Err(TornadoError::InvalidVerifyingKey.into())
+        );
+        let verifier_data = create_verifier_data(vk_bytes);
+        for proof_data in [&proof_data, &compressed_proof_data] {
+            assert_eq!(verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BLS12_381, None, &verifier_data, proof_data, &public_inputs), Ok(()));
+        }
+        assert_eq!(
+            verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs),
+            Err(TornadoError::InvalidProof.into())
+        );
+        
+        // Changing a public input invalidates the proof
+        let mut other_inputs = public_inputs;
+        other_inputs[0] ^= 1;
+        assert_eq!(
+            verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BLS12_381, None, &verifier_data, &proof_data, &other_inputs),
+            Err(TornadoError::InvalidProof.into())
+        );
+        
+        // Unknown curves are rejected
+        assert_eq!(
+            verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BLS12_381 + 1, None, &verifier_data, &proof_data, &public_inputs),
+            Err(TornadoError::UnsupportedCurve.into())
+        );
+        assert_eq!(
+            check_verifying_key(PROOF_SYSTEM_GROTH16, CURVE_BLS12_381 + 1, &[], NUM_PUBLIC_INPUTS),
+            Err(TornadoError::UnsupportedCurve.into())
+        );
+    }
+    
+    #[test]
+    fn test_deserialize_proof_invalid_points() {
+        let inputs = deserialize_public_inputs::<Fr>(&create_dummy_public_inputs()).unwrap();
+        let (_, proof) = create_real_proof(&inputs);
+        let proof_data = encode_proof(&proof);
+        let with_bytes = |range: std::ops::Range<usize>, bytes: &[u8]| {
+            let mut data = proof_data.clone();
+            data[range].copy_from_slice(bytes);
+            data
+        };
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        
+        // A G2 point on the curve but outside the prime-order subgroup
+        let off_subgroup = (1u64..)
+            .filter_map(|x| G2Affine::get_point_from_x_unchecked(Fq2::from(x), false))
+            .find(|point| !point.is_in_correct_subgroup_assuming_on_curve())
+            .unwrap();
+        assert!(off_subgroup.is_on_curve());
+        let off_subgroup_bytes: Vec<u8> = [off_subgroup.x.c0, off_subgroup.x.c1, off_subgroup.y.c0, off_subgroup.y.c1]
+            .iter()
+            .flat_map(|f| f.into_bigint().to_bytes_le())
+            .collect();
+        
+        for invalid_proof in [
+            // Off-curve A, B and C
+            with_bytes(32..64, &one),
+            with_bytes(160..192, &one),
+            with_bytes(224..256, &one),
+            // The identity has no affine encoding; zeros are off the curve
+            with_bytes(0..64, &[0u8; 64]),
+            with_bytes(64..192, &off_subgroup_bytes),
+        ] {
+            assert_eq!(
+                deserialize_proof::<Bn254>(&invalid_proof).unwrap_err(),
+                TornadoError::InvalidProof.into()
+            );
+        }
     }
     
     #[test]
@@ -245,52 +1066,543 @@ mod tests {
         // Test with valid data
         let mut data = [0u8; 32];
         data[0] = 1;
-        let result = extract_field_element(&data);
+        let result = extract_field_element::<Fr>(&data);
         assert!(result.is_ok());
         
         // Test with invalid length
         let invalid_data = [0u8; 16]; // Too short
-        let result = extract_field_element(&invalid_data);
+        let result = extract_field_element::<Fr>(&invalid_data);
         assert!(result.is_err());
     }
     
     #[test]
     fn test_deserialize_public_inputs() {
         let inputs = create_dummy_public_inputs();
-        let result = deserialize_public_inputs(&inputs);
+        let result = deserialize_public_inputs::<Fr>(&inputs);
         assert!(result.is_ok());
         
         let deserialized = result.unwrap();
-        assert_eq!(deserialized.len(), 6);
+        assert_eq!(deserialized.len(), NUM_PUBLIC_INPUTS);
         
         // Check that the values were correctly deserialized
-        for i in 0..6 {
-            assert!(!deserialized[i].is_zero());
+        for input in deserialized.iter() {
+            assert!(!input.is_zero());
+        }
+
+        // Values outside the field are rejected
+        let mut non_canonical = inputs;
+        non_canonical[32..64].copy_from_slice(&[0xFF; 32]);
+        assert_eq!(
+            deserialize_public_inputs::<Fr>(&non_canonical).unwrap_err(),
+            TornadoError::InvalidProof.into()
+        );
+    }
+
+    #[test]
+    fn test_withdraw_public_inputs_layout() {
+        fn hex_to_bytes(hex: &str) -> [u8; 32] {
+            core::array::from_fn(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+        }
+
+        let root = hex_to_bytes("2b0f6fc0179fa65b6f73627c0e1e84c7374d2eaec44c9a48f2571393ea77bcbb");
+        let nullifier_hash = hex_to_bytes("0000000000000000000000000000000000000000000000000000000000000009");
+        let recipient = Pubkey::new_from_array([0xFF; 32]);
+        let relayer = Pubkey::new_from_array([0x01; 32]);
+        let instance = Pubkey::new_from_array([0x02; 32]);
+
+        let withdraw_inputs = WithdrawPublicInputs {
+            root,
+            nullifier_hash,
+            recipient,
+            relayer,
+            fee: 1000,
+            fee_in_sol: false,
+            refund: 7,
+            instance,
+        };
+        let public_inputs = withdraw_inputs.to_bytes().unwrap();
+        assert_eq!(WithdrawPublicInputs::LEN, 224);
+        assert_eq!(public_inputs.len(), WithdrawPublicInputs::LEN);
+
+        // Public signals in circuit order: root, nullifierHash, recipient, relayer, fee, refund, instance
+        let expected = [
+            root,
+            nullifier_hash,
+            // 2^256 - 1 mod r
+            hex_to_bytes("0e0a77c19a07df2f666ea36f7879462e36fc76959f60cd29ac96341c4ffffffa"),
+            // Below the field order, so unchanged
+            [0x01; 32],
+            hex_to_bytes("00000000000000000000000000000000000000000000000000000000000003e8"),
+            hex_to_bytes("0000000000000000000000000000000000000000000000000000000000000007"),
+            [0x02; 32],
+        ];
+        for (slot, signal) in public_inputs.chunks_exact(32).zip(expected.iter()) {
+            assert_eq!(slot, signal);
+        }
+        assert_eq!(reduce_pubkey_to_field(&recipient), Fr::from_be_bytes_mod_order(&expected[2]));
+
+        // The encoding is what the verifier reads back
+        let inputs = deserialize_public_inputs::<Fr>(&public_inputs).unwrap();
+        assert_eq!(inputs[4], Fr::from(1000u64));
+        assert_eq!(inputs[5], Fr::from(7u64));
+
+        // A fee paid in lamports sets bit 64 of its slot, and nothing else changes
+        let sol_fee_inputs = WithdrawPublicInputs {
+            fee_in_sol: true,
+            ..withdraw_inputs
+        }
+        .to_bytes()
+        .unwrap();
+        assert_eq!(
+            sol_fee_inputs[128..160],
+            hex_to_bytes("00000000000000000000000000000000000000000000000100000000000003e8")
+        );
+        assert_eq!(sol_fee_inputs[..128], public_inputs[..128]);
+        assert_eq!(sol_fee_inputs[160..], public_inputs[160..]);
+
+        // A root or nullifier hash outside the field is rejected instead of reduced
+        let non_canonical_nullifier_hash = WithdrawPublicInputs {
+            nullifier_hash: [0xFF; 32],
+            ..withdraw_inputs
+        };
+        assert_eq!(
+            non_canonical_nullifier_hash.to_bytes().unwrap_err(),
+            TornadoError::InvalidNullifierHash.into()
+        );
+        let non_canonical_root = WithdrawPublicInputs {
+            root: [0xFF; 32],
+            ..withdraw_inputs
+        };
+        assert_eq!(
+            non_canonical_root.to_bytes().unwrap_err(),
+            TornadoError::InvalidMerkleRoot.into()
+        );
+    }
+    
+    #[test]
+    fn test_reduce_pubkey_to_field() {
+        // `k * modulus + offset` as a pubkey; 5 times the modulus still fits in 256 bits
+        let pubkey_at = |k: u64, offset: u64| {
+            let mut value = BigInt::from(offset);
+            for _ in 0..k {
+                assert!(!value.add_with_carry(&Fr::MODULUS));
+            }
+            Pubkey::new_from_array(value.to_bytes_be().try_into().unwrap())
+        };
+        
+        // Pubkeys below the modulus are kept as is
+        for offset in [0, 1, 2, u64::MAX] {
+            assert_eq!(reduce_pubkey_to_field(&pubkey_at(0, offset)), Fr::from(offset));
+        }
+        let mut modulus_minus_one = Fr::MODULUS;
+        assert!(!modulus_minus_one.sub_with_borrow(&BigInt::from(1u64)));
+        let below_modulus = Pubkey::new_from_array(modulus_minus_one.to_bytes_be().try_into().unwrap());
+        assert_eq!(reduce_pubkey_to_field(&below_modulus), -Fr::from(1u64));
+        
+        // Pubkeys at or above the modulus wrap around to the same elements
+        for k in 1..=5 {
+            for offset in [0, 1, 2, u64::MAX] {
+                assert_eq!(reduce_pubkey_to_field(&pubkey_at(k, offset)), Fr::from(offset));
+            }
+        }
+        
+        // The largest pubkey is 2^256 - 1 in the field
+        let largest = Pubkey::new_from_array([0xFF; 32]);
+        assert_eq!(reduce_pubkey_to_field(&largest), Fr::from(2u64).pow([256]) - Fr::from(1u64));
+        
+        // The reduction is deterministic, and congruent pubkeys encode the same public inputs
+        let inputs = |recipient: Pubkey| {
+            WithdrawPublicInputs {
+                root: [1u8; 32],
+                nullifier_hash: [2u8; 32],
+                recipient,
+                relayer: pubkey_at(5, 7),
+                fee: 1,
+                fee_in_sol: false,
+                refund: 0,
+                instance: largest,
+            }
+            .to_bytes()
+            .unwrap()
+        };
+        for k in 0..=5 {
+            let pubkey = pubkey_at(k, 42);
+            assert_eq!(reduce_pubkey_to_field(&pubkey), reduce_pubkey_to_field(&pubkey));
+            assert_eq!(inputs(pubkey), inputs(pubkey_at(0, 42)));
+            assert_eq!(inputs(pubkey)[64..96], Fr::from(42u64).into_bigint().to_bytes_be()[..]);
         }
     }
     
     #[test]
-    fn test_get_verifying_key() {
-        let result = get_verifying_key();
+    fn test_variable_amount_public_inputs() {
+        let withdraw_inputs = WithdrawPublicInputs {
+            root: [1u8; 32],
+            nullifier_hash: [2u8; 32],
+            recipient: Pubkey::new_unique(),
+            relayer: Pubkey::new_unique(),
+            fee: 1000,
+            fee_in_sol: false,
+            refund: 0,
+            instance: Pubkey::new_unique(),
+        };
+        
+        // The fixed-denomination layout followed by the amount
+        let public_inputs = withdraw_inputs.to_variable_amount_bytes(500_000).unwrap();
+        assert_eq!(public_inputs.len(), WithdrawPublicInputs::VARIABLE_AMOUNT_LEN);
+        assert_eq!(public_inputs[..WithdrawPublicInputs::LEN], withdraw_inputs.to_bytes().unwrap());
+        let inputs = deserialize_public_inputs::<Fr>(&public_inputs).unwrap();
+        assert_eq!(inputs.len(), NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS);
+        assert_eq!(inputs[7], Fr::from(500_000u64));
+        
+        // A proof for the eight inputs verifies only against an eight-input key
+        let (vk, proof) = create_real_proof(&inputs);
+        let mut vk_bytes = Vec::new();
+        vk.serialize_compressed(&mut vk_bytes).unwrap();
+        assert!(parse_verifying_key::<Bn254>(&vk_bytes, NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS).is_ok());
+        assert_eq!(
+            parse_verifying_key::<Bn254>(&vk_bytes, NUM_PUBLIC_INPUTS).unwrap_err(),
+            TornadoError::InvalidVerifyingKey.into()
+        );
+        let verifier_data = create_verifier_data(vk_bytes);
+        let proof_data = encode_proof(&proof);
+        assert_eq!(verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs), Ok(()));
+        assert_eq!(
+            verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs[..WithdrawPublicInputs::LEN]).unwrap_err(),
+            TornadoError::InvalidVerifyingKey.into()
+        );
+        
+        // A different amount invalidates the proof
+        let other_amount = withdraw_inputs.to_variable_amount_bytes(500_001).unwrap();
+        assert_eq!(
+            verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &other_amount).unwrap_err(),
+            TornadoError::InvalidProof.into()
+        );
+        
+        // Truncated inputs are rejected
+        assert_eq!(
+            deserialize_public_inputs::<Fr>(&public_inputs[..200]).unwrap_err(),
+            TornadoError::InvalidProof.into()
+        );
+    }
+    
+    #[test]
+    fn test_partial_withdraw_public_inputs() {
+        let withdraw_inputs = WithdrawPublicInputs {
+            root: [1u8; 32],
+            nullifier_hash: [2u8; 32],
+            recipient: Pubkey::new_unique(),
+            relayer: Pubkey::new_unique(),
+            fee: 1000,
+            fee_in_sol: false,
+            refund: 0,
+            instance: Pubkey::new_unique(),
+        };
+        let output_commitment = [3u8; 32];
+        
+        // The variable-amount layout of the input note, then the remainder
+        let public_inputs = withdraw_inputs.to_partial_bytes(500_000, 200_000, &output_commitment).unwrap();
+        assert_eq!(public_inputs.len(), WithdrawPublicInputs::PARTIAL_LEN);
+        assert_eq!(
+            public_inputs[..WithdrawPublicInputs::VARIABLE_AMOUNT_LEN],
+            withdraw_inputs.to_variable_amount_bytes(500_000).unwrap()
+        );
+        let inputs = deserialize_public_inputs::<Fr>(&public_inputs).unwrap();
+        assert_eq!(inputs.len(), NUM_PARTIAL_WITHDRAW_PUBLIC_INPUTS);
+        assert_eq!(inputs[8], Fr::from(200_000u64));
+        assert_eq!(public_inputs[288..], output_commitment);
+        
+        // A proof for the ten inputs doesn't verify with another remainder or output commitment
+        let (vk, proof) = create_real_proof(&inputs);
+        let mut vk_bytes = Vec::new();
+        vk.serialize_compressed(&mut vk_bytes).unwrap();
+        assert!(parse_verifying_key::<Bn254>(&vk_bytes, NUM_PARTIAL_WITHDRAW_PUBLIC_INPUTS).is_ok());
+        let verifier_data = create_verifier_data(vk_bytes);
+        let proof_data = encode_proof(&proof);
+        assert_eq!(verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs), Ok(()));
+        for other_inputs in [
+            withdraw_inputs.to_partial_bytes(500_000, 200_001, &output_commitment).unwrap(),
+            withdraw_inputs.to_partial_bytes(500_000, 200_000, &[4u8; 32]).unwrap(),
+        ] {
+            assert_eq!(
+                verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &other_inputs).unwrap_err(),
+                TornadoError::InvalidProof.into()
+            );
+        }
+        
+        // The output commitment must be a canonical field element
+        assert_eq!(
+            withdraw_inputs.to_partial_bytes(500_000, 200_000, &[0xFF; 32]).unwrap_err(),
+            TornadoError::InvalidCommitment.into()
+        );
+    }
+    
+    #[test]
+    fn test_proof_bound_to_instance() {
+        let withdraw_inputs = WithdrawPublicInputs {
+            root: [1u8; 32],
+            nullifier_hash: [2u8; 32],
+            recipient: Pubkey::new_unique(),
+            relayer: Pubkey::new_unique(),
+            fee: 0,
+            fee_in_sol: false,
+            refund: 0,
+            instance: Pubkey::new_unique(),
+        };
+        let public_inputs = withdraw_inputs.to_bytes().unwrap();
+        let (vk, proof) = create_real_proof(&deserialize_public_inputs::<Fr>(&public_inputs).unwrap());
+        let mut vk_bytes = Vec::new();
+        vk.serialize_compressed(&mut vk_bytes).unwrap();
+        let verifier_data = create_verifier_data(vk_bytes);
+        let proof_data = encode_proof(&proof);
+        assert_eq!(verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs), Ok(()));
+        
+        // A proof for a note of one instance doesn't verify against another
+        let other_instance_inputs = WithdrawPublicInputs {
+            instance: Pubkey::new_unique(),
+            ..withdraw_inputs
+        }
+        .to_bytes()
+        .unwrap();
+        assert_eq!(
+            verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &other_instance_inputs),
+            Err(TornadoError::InvalidProof.into())
+        );
+    }
+    
+    #[test]
+    fn test_verify_tornado_proof_vk_hash() {
+        let withdraw_inputs = WithdrawPublicInputs {
+            root: [1u8; 32],
+            nullifier_hash: [2u8; 32],
+            recipient: Pubkey::new_unique(),
+            relayer: Pubkey::new_unique(),
+            fee: 0,
+            fee_in_sol: false,
+            refund: 0,
+            instance: Pubkey::new_unique(),
+        };
+        let public_inputs = withdraw_inputs.to_bytes().unwrap();
+        let (vk, proof) = create_real_proof(&deserialize_public_inputs::<Fr>(&public_inputs).unwrap());
+        let mut vk_bytes = Vec::new();
+        vk.serialize_compressed(&mut vk_bytes).unwrap();
+        let vk_hash = hash_verifying_key(&vk_bytes);
+        let verifier_data = create_verifier_data(vk_bytes.clone());
+        let proof_data = encode_proof(&proof);
+        assert_eq!(
+            verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, Some(&vk_hash), &verifier_data, &proof_data, &public_inputs),
+            Ok(())
+        );
+        
+        // A verifier account holding any other key is rejected before the proof is checked
+        let mut tampered_vk_bytes = vk_bytes;
+        let last = tampered_vk_bytes.len() - 1;
+        tampered_vk_bytes[last] ^= 1;
+        for other_verifier_data in [
+            create_verifier_data(tampered_vk_bytes),
+            create_verifier_data(create_test_verifying_key(NUM_PUBLIC_INPUTS)),
+        ] {
+            assert_eq!(
+                verify_tornado_proof(
+                    PROOF_SYSTEM_GROTH16,
+                    CURVE_BN254,
+                    Some(&vk_hash),
+                    &other_verifier_data,
+                    &proof_data,
+                    &public_inputs,
+                ),
+                Err(TornadoError::VerifierKeyMismatch.into())
+            );
+        }
+    }
+    
+    #[test]
+    fn test_load_verifying_key() {
+        let verifier_data = create_verifier_data(create_test_verifying_key(NUM_PUBLIC_INPUTS + 1));
+        let result = load_verifying_key::<Bn254>(&verifier_data, NUM_PUBLIC_INPUTS);
         assert!(result.is_ok());
         
         let vk = result.unwrap();
-        assert_eq!(vk.gamma_abc_g1.len(), 7); // 6 public inputs + 1
+        assert_eq!(vk.gamma_abc_g1.len(), NUM_PUBLIC_INPUTS + 1);
+        assert_eq!(vk.alpha_g1, G1Affine::generator());
+        
+        // Trailing account space after the state is ignored
+        let mut padded_data = verifier_data.clone();
+        padded_data.extend_from_slice(&[0u8; 64]);
+        assert!(load_verifying_key::<Bn254>(&padded_data, NUM_PUBLIC_INPUTS).is_ok());
+        
+        // Test with the wrong number of IC points
+        let verifier_data = create_verifier_data(create_test_verifying_key(3));
+        assert_eq!(
+            load_verifying_key::<Bn254>(&verifier_data, NUM_PUBLIC_INPUTS).unwrap_err(),
+            TornadoError::InvalidVerifyingKey.into()
+        );
+        
+        // Test with garbage key bytes
+        let verifier_data = create_verifier_data(vec![0xFF; 64]);
+        assert_eq!(
+            load_verifying_key::<Bn254>(&verifier_data, NUM_PUBLIC_INPUTS).unwrap_err(),
+            TornadoError::InvalidVerifyingKey.into()
+        );
+        
+        // Test with an uninitialized verifier account
+        let verifier_data = vec![0u8; 128];
+        assert_eq!(
+            load_verifying_key::<Bn254>(&verifier_data, NUM_PUBLIC_INPUTS).unwrap_err(),
+            TornadoError::AccountNotInitialized.into()
+        );
     }
     
     #[test]
     fn test_verify_tornado_proof() {
+        let verifier_data = create_verifier_data(create_test_verifying_key(NUM_PUBLIC_INPUTS + 1));
         let proof_data = create_dummy_proof();
         let public_inputs = create_dummy_public_inputs();
         
-        // This should fail because we're using dummy values
-        // In a real scenario, we would use a valid proof and inputs
-        let result = verify_tornado_proof(&proof_data, &public_inputs);
-        assert!(result.is_err());
+        // A well-formed proof that doesn't verify is rejected
+        let result = verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs);
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
         
         // Test with invalid proof data
-        let invalid_proof = vec![0u8; 128]; // Too short
-        let result = verify_tornado_proof(&invalid_proof, &public_inputs);
-        assert!(result.is_err());
+        let invalid_proof = vec![0u8; PROOF_SIZE - 1];
+        let result = verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &invalid_proof, &public_inputs);
+        assert_eq!(result, Err(TornadoError::InvalidProof.into()));
+    }
+    
+    #[test]
+    fn test_verify_groth16_syscalls() {
+        let inputs = [1u64, 2, 3, 4, 5, 6].map(Fr::from);
+        let (vk, proof) = create_real_proof(&inputs);
+        
+        // Both backends accept a valid proof
+        assert!(verify_groth16_syscalls(&vk, &proof, &inputs).unwrap());
+        assert!(Bn254::verify_groth16(&vk, &proof, &inputs).unwrap());
+        
+        // Both backends reject the proof for different public inputs
+        let mut wrong_inputs = inputs;
+        wrong_inputs[5] = Fr::from(7u64);
+        assert!(!verify_groth16_syscalls(&vk, &proof, &wrong_inputs).unwrap());
+        assert!(!Bn254::verify_groth16(&vk, &proof, &wrong_inputs).unwrap());
+        
+        // Mismatched number of public inputs
+        assert_eq!(
+            verify_groth16_syscalls(&vk, &proof, &inputs[..5]).unwrap_err(),
+            TornadoError::InvalidVerifyingKey.into()
+        );
+    }
+    
+    #[test]
+    fn test_verify_tornado_proof_with_real_proof() {
+        let mut public_inputs = create_dummy_public_inputs();
+        let inputs = deserialize_public_inputs::<Fr>(&public_inputs).unwrap();
+        let (vk, proof) = create_real_proof(&inputs);
+        
+        let mut vk_bytes = Vec::new();
+        vk.serialize_compressed(&mut vk_bytes).unwrap();
+        let verifier_data = create_verifier_data(vk_bytes);
+        let proof_data = encode_proof(&proof);
+        
+        assert_eq!(verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs), Ok(()));
+        
+        // Changing a public input invalidates the proof
+        public_inputs[0] ^= 1;
+        assert_eq!(
+            verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs).unwrap_err(),
+            TornadoError::InvalidProof.into()
+        );
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_select_proof_system() {
+        let public_inputs = create_dummy_public_inputs();
+        let inputs = deserialize_public_inputs::<Fr>(&public_inputs).unwrap();
+        let (vk, proof) = create_real_proof(&inputs);
+        
+        let mut vk_bytes = Vec::new();
+        vk.serialize_compressed(&mut vk_bytes).unwrap();
+        let verifier_data = create_verifier_data(vk_bytes.clone());
+        let proof_data = encode_proof(&proof);
+        
+        // Groth16 over BN254 dispatches to the BN254 implementation
+        let groth16_bn254 = select_proof_system(PROOF_SYSTEM_GROTH16, CURVE_BN254).unwrap();
+        assert_eq!(groth16_bn254.check_verifying_key(&vk_bytes, NUM_PUBLIC_INPUTS), Ok(()));
+        assert_eq!(groth16_bn254.verify(&proof_data, &public_inputs, &verifier_data), Ok(()));
+        assert_eq!(Groth16Bn254.verify(&proof_data, &public_inputs, &verifier_data), Ok(()));
+        
+        // Groth16 over BLS12-381 dispatches to the BLS12-381 implementation,
+        // which can't parse the BN254 key or proof
+        let groth16_bls12_381 = select_proof_system(PROOF_SYSTEM_GROTH16, CURVE_BLS12_381).unwrap();
+        assert_eq!(
+            groth16_bls12_381.check_verifying_key(&vk_bytes, NUM_PUBLIC_INPUTS),
+            Err(TornadoError::InvalidVerifyingKey.into())
+        );
+        assert_eq!(
+            groth16_bls12_381.verify(&proof_data, &public_inputs, &verifier_data),
+            Groth16Bls12_381.verify(&proof_data, &public_inputs, &verifier_data)
+        );
+        assert!(groth16_bls12_381.verify(&proof_data, &public_inputs, &verifier_data).is_err());
+        
+        // PLONK is reserved but not implemented, and unknown proof systems are rejected
+        for proof_system in [PROOF_SYSTEM_PLONK, PROOF_SYSTEM_PLONK + 1] {
+            assert_eq!(
+                select_proof_system(proof_system, CURVE_BN254).err(),
+                Some(TornadoError::UnsupportedProofSystem.into())
+            );
+            assert_eq!(
+                verify_tornado_proof(proof_system, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs),
+                Err(TornadoError::UnsupportedProofSystem.into())
+            );
+            assert_eq!(
+                check_verifying_key(proof_system, CURVE_BN254, &vk_bytes, NUM_PUBLIC_INPUTS),
+                Err(TornadoError::UnsupportedProofSystem.into())
+            );
+        }
+        
+        // Unknown curves are rejected for a known proof system
+        assert_eq!(
+            select_proof_system(PROOF_SYSTEM_GROTH16, CURVE_BLS12_381 + 1).err(),
+            Some(TornadoError::UnsupportedCurve.into())
+        );
+    }
+    
+    #[test]
+    fn test_verify_tornado_proof_input_counts() {
+        // The circuit's number of public inputs comes from its verifying key
+        for num_inputs in [6, 8] {
+            let mut public_inputs = vec![0u8; num_inputs * 32];
+            for i in 0..num_inputs {
+                public_inputs[i * 32 + 31] = (i + 1) as u8;
+            }
+            let inputs = deserialize_public_inputs::<Fr>(&public_inputs).unwrap();
+            assert_eq!(inputs.len(), num_inputs);
+            let (vk, proof) = create_real_proof(&inputs);
+            
+            let mut vk_bytes = Vec::new();
+            vk.serialize_compressed(&mut vk_bytes).unwrap();
+            assert_eq!(check_verifying_key(PROOF_SYSTEM_GROTH16, CURVE_BN254, &vk_bytes, num_inputs), Ok(()));
+            assert_eq!(
+                check_verifying_key(PROOF_SYSTEM_GROTH16, CURVE_BN254, &vk_bytes, num_inputs + 1),
+                Err(TornadoError::InvalidVerifyingKey.into())
+            );
+            let verifier_data = create_verifier_data(vk_bytes);
+            let proof_data = encode_proof(&proof);
+            assert_eq!(
+                verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs),
+                Ok(())
+            );
+            
+            // One input fewer or more than the key takes is rejected
+            for other_inputs in [&public_inputs[..(num_inputs - 1) * 32], &[public_inputs.as_slice(), &[0u8; 32]].concat()] {
+                assert_eq!(
+                    verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, other_inputs),
+                    Err(TornadoError::InvalidVerifyingKey.into())
+                );
+            }
+            
+            // So are inputs that aren't a whole number of 32-byte values
+            assert_eq!(
+                verify_tornado_proof(PROOF_SYSTEM_GROTH16, CURVE_BN254, None, &verifier_data, &proof_data, &public_inputs[1..]),
+                Err(TornadoError::InvalidProof.into())
+            );
+        }
+    }
+}
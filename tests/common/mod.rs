@@ -0,0 +1,151 @@
+//! Helpers shared by the integration tests
+
+// Each test crate uses only some of these
+#![allow(dead_code)]
+
+use ark_bn254::{Bn254, G1Affine, G2Affine};
+use ark_ec::AffineRepr;
+use ark_groth16::VerifyingKey;
+use ark_serialize::CanonicalSerialize;
+use borsh::BorshSerialize;
+use solana_program::{pubkey::Pubkey, system_instruction};
+use solana_program_test::{BanksClient, BanksClientError, ProgramTest};
+use solana_sdk::{
+    account::Account,
+    hash::Hash,
+    program_pack::Pack,
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+};
+use tornado_svm::{
+    instruction::initialize,
+    merkle_tree::HASH_ALGO_MIMC,
+    state::{MerkleTree, TornadoInstance, MAX_FEE_BPS, ROOT_HISTORY_SIZE},
+    utils::{find_merkle_tree_address, find_vault_authority_address},
+    verifier::{CURVE_BN254, NUM_PUBLIC_INPUTS, PROOF_SYSTEM_GROTH16},
+};
+
+// Verifying key from the trusted setup (generator points stand in for it here)
+pub fn create_test_verifying_key(num_public_inputs: usize) -> Vec<u8> {
+    let verifying_key = VerifyingKey::<Bn254> {
+        alpha_g1: G1Affine::generator(),
+        beta_g2: G2Affine::generator(),
+        gamma_g2: G2Affine::generator(),
+        delta_g2: G2Affine::generator(),
+        gamma_abc_g1: vec![G1Affine::generator(); num_public_inputs + 1],
+    };
+    let mut verifying_key_bytes = Vec::new();
+    verifying_key.serialize_compressed(&mut verifying_key_bytes).unwrap();
+    verifying_key_bytes
+}
+
+// Create the instance account and initialize it as a fixed-denomination SOL instance
+#[allow(clippy::too_many_arguments)]
+pub async fn initialize_instance(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: Hash,
+    program_id: &Pubkey,
+    tornado_instance: &Keypair,
+    denomination: u64,
+    merkle_tree_height: u8,
+    strict_tx: bool,
+) -> Result<(), BanksClientError> {
+    let rent = banks_client.get_rent().await.unwrap();
+    let create_instance_ix = system_instruction::create_account(
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        rent.minimum_balance(TornadoInstance::LEN),
+        TornadoInstance::LEN as u64,
+        program_id,
+    );
+
+    let initialize_ix = initialize(
+        program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        denomination,
+        merkle_tree_height,
+        ROOT_HISTORY_SIZE as u8,
+        0,
+        None,
+        false,
+        MAX_FEE_BPS,
+        HASH_ALGO_MIMC,
+        CURVE_BN254,
+        strict_tx,
+        PROOF_SYSTEM_GROTH16,
+        create_test_verifying_key(NUM_PUBLIC_INPUTS),
+    )
+    .unwrap();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[create_instance_ix, initialize_ix],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[payer, tornado_instance], recent_blockhash);
+
+    banks_client.process_transaction(transaction).await
+}
+
+// Add an initialized instance with an empty Merkle tree, returning the tree address
+pub fn add_instance(program_test: &mut ProgramTest, program_id: &Pubkey, tornado_instance: &Pubkey) -> Pubkey {
+    let (merkle_tree, merkle_tree_bump) = find_merkle_tree_address(program_id, tornado_instance);
+    let height = 8;
+
+    let instance = TornadoInstance {
+        version: TornadoInstance::VERSION,
+        is_initialized: true,
+        denomination: 100_000_000,
+        merkle_tree_height: height,
+        merkle_tree,
+        verifier: Pubkey::new_unique(),
+        token_mint: None,
+        admin: Pubkey::new_unique(),
+        paused: false,
+        variable_amount: false,
+        max_fee_bps: MAX_FEE_BPS,
+        curve: CURVE_BN254,
+        max_root_age: 0,
+        strict_tx: false,
+        compliance_list: None,
+        proof_system: PROOF_SYSTEM_GROTH16,
+        vault_authority_bump: find_vault_authority_address(program_id, tornado_instance).1,
+        vk_hash: [0; 32],
+        deposit_fee: 0,
+        treasury: Pubkey::default(),
+        min_anonymity_set: 0,
+        withdraw_delay_secs: 0,
+        merkle_tree_bump,
+        max_sol_fee: 0,
+    };
+    let mut instance_data = vec![0u8; TornadoInstance::LEN];
+    instance.pack_into_slice(&mut instance_data);
+    program_test.add_account(
+        *tornado_instance,
+        Account {
+            lamports: 1_000_000_000,
+            data: instance_data,
+            owner: *program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    // Empty Merkle tree
+    let tree = MerkleTree::new(height, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+    let mut tree_data = tree.try_to_vec().unwrap();
+    tree_data.resize(MerkleTree::get_account_size_bounded(height, ROOT_HISTORY_SIZE as u8), 0);
+    program_test.add_account(
+        merkle_tree,
+        Account {
+            lamports: 1_000_000_000,
+            data: tree_data,
+            owner: *program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    merkle_tree
+}
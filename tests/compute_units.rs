@@ -0,0 +1,234 @@
+//! Compute-unit regression tests for the on-chain proof verification
+//!
+//! These run against the SBF build of the program: `cargo test-sbf --features test-sbf`.
+#![cfg(feature = "test-sbf")]
+
+use ark_bn254::{Bn254, Fr};
+use ark_ff::{BigInteger, PrimeField};
+use ark_groth16::{Groth16, Proof};
+use ark_relations::{
+    lc,
+    r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError, Variable},
+};
+use ark_serialize::CanonicalSerialize;
+use borsh::BorshSerialize;
+use solana_program::{program_pack::Pack, pubkey::Pubkey};
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+};
+
+use tornado_svm::{
+    instruction::withdraw,
+    merkle_tree::HASH_ALGO_MIMC,
+    state::{MerkleTree, TornadoInstance, VerifierState, MAX_FEE_BPS, ROOT_HISTORY_SIZE},
+    utils::{find_merkle_tree_address, find_vault_authority_address, find_verifier_address},
+    verifier::{hash_verifying_key, WithdrawPublicInputs, CURVE_BN254, NUM_PUBLIC_INPUTS, PROOF_SYSTEM_GROTH16},
+};
+
+/// Upper bound on the compute units a withdrawal may consume
+const MAX_WITHDRAW_COMPUTE_UNITS: u64 = 200_000;
+
+// Circuit with one public input per withdraw input, each constrained by `x * 1 = x`
+struct PublicInputsCircuit {
+    inputs: [Fr; NUM_PUBLIC_INPUTS],
+}
+
+impl ConstraintSynthesizer<Fr> for PublicInputsCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        for input in self.inputs {
+            let x = cs.new_input_variable(|| Ok(input))?;
+            cs.enforce_constraint(lc!() + x, lc!() + Variable::One, lc!() + x)?;
+        }
+        Ok(())
+    }
+}
+
+// Encode a proof in the 256-byte format expected by the withdraw instruction
+fn encode_proof(proof: &Proof<Bn254>) -> Vec<u8> {
+    [
+        proof.a.x,
+        proof.a.y,
+        proof.b.x.c0,
+        proof.b.x.c1,
+        proof.b.y.c0,
+        proof.b.y.c1,
+        proof.c.x,
+        proof.c.y,
+    ]
+    .iter()
+    .flat_map(|f| f.into_bigint().to_bytes_le())
+    .collect()
+}
+
+#[tokio::test]
+async fn test_withdraw_proof_verification_compute_units() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("tornado_svm", program_id, None);
+    program_test.prefer_bpf(true);
+
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, merkle_tree_bump) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let recipient = Pubkey::new_unique();
+    let relayer = Pubkey::new_unique();
+    let (verifier, _) = find_verifier_address(&program_id, &tornado_instance.pubkey());
+    let denomination = 100_000_000;
+
+    let root = [7u8; 32];
+    let nullifier_hash = [9u8; 32];
+
+    // Public inputs in the same layout the processor builds them
+    let public_inputs = WithdrawPublicInputs {
+        root,
+        nullifier_hash,
+        recipient,
+        relayer,
+        fee: 0,
+        fee_in_sol: false,
+        refund: 0,
+        instance: tornado_instance.pubkey(),
+    }
+    .to_bytes()
+    .unwrap();
+    let inputs: [Fr; NUM_PUBLIC_INPUTS] =
+        core::array::from_fn(|i| Fr::from_be_bytes_mod_order(&public_inputs[i * 32..(i + 1) * 32]));
+
+    // Generate a real proof for the public inputs
+    let mut rng = ark_std::test_rng();
+    let pk = Groth16::<Bn254>::generate_random_parameters_with_reduction(
+        PublicInputsCircuit { inputs },
+        &mut rng,
+    )
+    .unwrap();
+    let proof = Groth16::<Bn254>::create_random_proof_with_reduction(
+        PublicInputsCircuit { inputs },
+        &pk,
+        &mut rng,
+    )
+    .unwrap();
+    let mut verifying_key = Vec::new();
+    pk.vk.serialize_compressed(&mut verifying_key).unwrap();
+
+    // Instance account
+    let instance = TornadoInstance {
+        version: TornadoInstance::VERSION,
+        is_initialized: true,
+        denomination,
+        merkle_tree_height: 20,
+        merkle_tree,
+        verifier,
+        token_mint: None,
+        admin: Pubkey::new_unique(),
+        paused: false,
+        variable_amount: false,
+        max_fee_bps: MAX_FEE_BPS,
+        curve: CURVE_BN254,
+        max_root_age: 0,
+        strict_tx: false,
+        compliance_list: None,
+        proof_system: PROOF_SYSTEM_GROTH16,
+        vault_authority_bump: find_vault_authority_address(&program_id, &tornado_instance.pubkey()).1,
+        vk_hash: hash_verifying_key(&verifying_key),
+        deposit_fee: 0,
+        treasury: Pubkey::default(),
+        min_anonymity_set: 0,
+        withdraw_delay_secs: 0,
+        merkle_tree_bump,
+        max_sol_fee: 0,
+    };
+    let mut instance_data = vec![0u8; TornadoInstance::LEN];
+    instance.serialize(&mut &mut instance_data[..]).unwrap();
+    program_test.add_account(
+        tornado_instance.pubkey(),
+        Account {
+            lamports: 10 * denomination,
+            data: instance_data,
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    // Merkle tree account with the root in its history
+    let mut roots = vec![[0u8; 32]; ROOT_HISTORY_SIZE];
+    roots[0] = root;
+    let tree = MerkleTree {
+        version: MerkleTree::VERSION,
+        is_initialized: true,
+        height: 20,
+        hash_algo: HASH_ALGO_MIMC,
+        next_index: 1,
+        current_root_index: 0,
+        nullifier_count: 0,
+        roots,
+        root_timestamps: vec![0; ROOT_HISTORY_SIZE],
+        filled_subtrees: vec![[0u8; 32]; 20],
+        nullifier_hashes: vec![],
+    };
+    program_test.add_account(
+        merkle_tree,
+        Account {
+            lamports: 1_000_000_000,
+            data: tree.try_to_vec().unwrap(),
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    // Verifier account
+    let verifier_state = VerifierState {
+        is_initialized: true,
+        verifying_key,
+    };
+    program_test.add_account(
+        verifier,
+        Account {
+            lamports: 1_000_000_000,
+            data: verifier_state.try_to_vec().unwrap(),
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let withdraw_ix = withdraw(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        &recipient,
+        &relayer,
+        encode_proof(&proof),
+        root,
+        nullifier_hash,
+        0,
+        0,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+
+    let simulation = banks_client.simulate_transaction(transaction).await.unwrap();
+    let details = simulation.simulation_details.unwrap();
+
+    assert!(
+        details
+            .logs
+            .iter()
+            .any(|log| log.contains("Proof verification successful")),
+        "proof was not verified: {:?}",
+        details.logs
+    );
+    assert!(
+        details.units_consumed < MAX_WITHDRAW_COMPUTE_UNITS,
+        "withdraw consumed {} compute units",
+        details.units_consumed
+    );
+}
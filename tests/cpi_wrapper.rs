@@ -0,0 +1,200 @@
+//! A program depositing into and withdrawing from the pool through CPI
+//!
+//! The wrapper program pays from a PDA of its own, signing with its seeds.
+//! Withdrawals need a proof, so this runs with the mock verifier:
+//! `cargo test --features mock-verifier --test cpi_wrapper`.
+#![cfg(feature = "mock-verifier")]
+
+mod common;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, program_error::ProgramError, pubkey::Pubkey, system_program,
+};
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account,
+    instruction::{AccountMeta, Instruction},
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+};
+
+use tornado_svm::{
+    instruction::{deposit, invoke_deposit, invoke_withdraw, withdraw},
+    merkle_tree::latest_root_with_index,
+    state::MerkleTree,
+    utils::{derive_note_fields, find_commitment_address, find_merkle_tree_address, find_nullifier_address},
+    verifier::MOCK_PROOF,
+};
+
+use common::initialize_instance;
+
+// The seeds of the wrapper's PDA paying for its deposits and withdrawals
+const VAULT_SEED: &[u8] = b"vault";
+
+#[derive(BorshSerialize, BorshDeserialize)]
+enum WrapperInstruction {
+    /// Accounts: the `Deposit` accounts with the vault as payer, then the Tornado program
+    Deposit { commitment: [u8; 32] },
+    /// Accounts: the `Withdraw` accounts with the vault as payer, then the Tornado program
+    Withdraw {
+        proof: Vec<u8>,
+        root: [u8; 32],
+        nullifier_hash: [u8; 32],
+    },
+}
+
+// Program that forwards deposits and withdrawals to the Tornado program, signing for its vault
+fn wrapper_process_instruction(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let (tornado_program, tornado_accounts) = accounts.split_last().ok_or(ProgramError::NotEnoughAccountKeys)?;
+    let (vault, vault_bump) = Pubkey::find_program_address(&[VAULT_SEED], program_id);
+    if tornado_accounts.first().map(|payer| payer.key) != Some(&vault) {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let signer_seeds: &[&[u8]] = &[VAULT_SEED, &[vault_bump]];
+
+    match WrapperInstruction::try_from_slice(data)? {
+        WrapperInstruction::Deposit { commitment } => {
+            invoke_deposit(tornado_program.key, tornado_accounts, commitment, &[signer_seeds])
+        }
+        WrapperInstruction::Withdraw {
+            proof,
+            root,
+            nullifier_hash,
+        } => invoke_withdraw(
+            tornado_program.key,
+            tornado_accounts,
+            proof,
+            root,
+            nullifier_hash,
+            0,
+            0,
+            &[signer_seeds],
+        ),
+    }
+}
+
+// Call the wrapper with the accounts of a Tornado instruction paid by the vault
+fn wrap(wrapper_program_id: &Pubkey, tornado_ix: Instruction, wrapper_instruction: WrapperInstruction) -> Instruction {
+    // The wrapper signs for the vault, so the transaction doesn't
+    let mut accounts: Vec<_> = tornado_ix
+        .accounts
+        .into_iter()
+        .map(|meta| AccountMeta { is_signer: false, ..meta })
+        .collect();
+    accounts.push(AccountMeta::new_readonly(tornado_ix.program_id, false));
+    Instruction {
+        program_id: *wrapper_program_id,
+        accounts,
+        data: wrapper_instruction.try_to_vec().unwrap(),
+    }
+}
+
+#[tokio::test]
+async fn test_deposit_and_withdraw_through_cpi() {
+    let program_id = Pubkey::new_unique();
+    let wrapper_program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    program_test.add_program("wrapper", wrapper_program_id, processor!(wrapper_process_instruction));
+
+    // The vault is a System Program account without data, so it can pay
+    let (vault, _) = Pubkey::find_program_address(&[VAULT_SEED], &wrapper_program_id);
+    program_test.add_account(
+        vault,
+        Account {
+            lamports: 1_000_000_000,
+            data: vec![],
+            owner: system_program::id(),
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let denomination = 100_000_000;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+
+    // The wrapper deposits from its vault
+    let note = ([1u8; 32], [2u8; 32]);
+    let (commitment, nullifier_hash) = derive_note_fields(&note.0, &note.1, &tornado_instance.pubkey()).unwrap();
+    let deposit_ix = deposit(
+        &program_id,
+        &vault,
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        commitment,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let wrapper_ix = wrap(&wrapper_program_id, deposit_ix, WrapperInstruction::Deposit { commitment });
+    let mut transaction = Transaction::new_with_payer(&[wrapper_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let (commitment_marker, _) = find_commitment_address(&program_id, &tornado_instance.pubkey(), &commitment);
+    let marker_rent = banks_client.get_account(commitment_marker).await.unwrap().unwrap().lamports;
+    let vault_balance = banks_client.get_balance(vault).await.unwrap();
+    assert_eq!(vault_balance, 1_000_000_000 - denomination - marker_rent);
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::from_account_data(&merkle_tree_account.data).unwrap();
+    assert_eq!(tree.next_index, 1);
+
+    // It withdraws the note to a recipient, its vault paying as relayer
+    let recipient = Pubkey::new_unique();
+    let root = latest_root_with_index(&tree).0;
+    let withdraw_ix = withdraw(
+        &program_id,
+        &vault,
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        &recipient,
+        &vault,
+        MOCK_PROOF.to_vec(),
+        root,
+        nullifier_hash,
+        0,
+        0,
+        None,
+        None,
+    )
+    .unwrap();
+    let wrapper_ix = wrap(
+        &wrapper_program_id,
+        withdraw_ix,
+        WrapperInstruction::Withdraw {
+            proof: MOCK_PROOF.to_vec(),
+            root,
+            nullifier_hash,
+        },
+    );
+    let mut transaction = Transaction::new_with_payer(&[wrapper_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(banks_client.get_balance(recipient).await.unwrap(), denomination);
+    let (nullifier_marker, _) = find_nullifier_address(&program_id, &tornado_instance.pubkey(), &nullifier_hash);
+    let nullifier_rent = banks_client.get_account(nullifier_marker).await.unwrap().unwrap().lamports;
+    assert_eq!(banks_client.get_balance(vault).await.unwrap(), vault_balance - nullifier_rent);
+}
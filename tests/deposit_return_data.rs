@@ -0,0 +1,134 @@
+//! Deposit return data read by a program depositing through CPI
+
+mod common;
+
+use borsh::BorshDeserialize;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program::{get_return_data, invoke, set_return_data},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use solana_program_test::*;
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    signature::{Keypair, Signer},
+    system_program,
+    transaction::Transaction,
+};
+
+use tornado_svm::{
+    instruction::{deposit, DepositReturnData},
+    merkle_tree::latest_root_with_index,
+    state::MerkleTree,
+    utils::find_commitment_address,
+};
+
+use common::add_instance;
+
+// Program that deposits the commitment in its instruction data through CPI,
+// then checks the return data against the Merkle tree and returns it in turn.
+//
+// Accounts: payer, instance, Merkle tree, commitment marker, System Program, Tornado program
+fn depositor_process_instruction(_program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let payer = next_account_info(account_info_iter)?;
+    let tornado_instance = next_account_info(account_info_iter)?;
+    let merkle_tree = next_account_info(account_info_iter)?;
+    let commitment_marker = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let tornado_program = next_account_info(account_info_iter)?;
+
+    let commitment: [u8; 32] = data.try_into().map_err(|_| ProgramError::InvalidInstructionData)?;
+    let deposit_ix = deposit(
+        tornado_program.key,
+        payer.key,
+        tornado_instance.key,
+        merkle_tree.key,
+        commitment,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )?;
+    invoke(
+        &deposit_ix,
+        &[
+            payer.clone(),
+            tornado_instance.clone(),
+            merkle_tree.clone(),
+            commitment_marker.clone(),
+            system_program_info.clone(),
+        ],
+    )?;
+
+    // The return data was set by the Tornado program and matches the tree
+    let (returning_program, return_data) = get_return_data().ok_or(ProgramError::InvalidAccountData)?;
+    if returning_program != *tornado_program.key {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let deposited = DepositReturnData::try_from_slice(&return_data)?;
+    let tree = MerkleTree::from_account_data(&merkle_tree.data.borrow())?;
+    if (deposited.root, deposited.leaf_index + 1) != (latest_root_with_index(&tree).0, tree.next_index) {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    set_return_data(&return_data);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deposit_return_data_through_cpi() {
+    let program_id = Pubkey::new_unique();
+    let depositor_program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    program_test.add_program("depositor", depositor_program_id, processor!(depositor_process_instruction));
+
+    let tornado_instance = Keypair::new();
+    let merkle_tree = add_instance(&mut program_test, &program_id, &tornado_instance.pubkey());
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    for (leaf_index, commitment) in [[1u8; 32], [2u8; 32]].into_iter().enumerate() {
+        let (commitment_marker, _) = find_commitment_address(&program_id, &tornado_instance.pubkey(), &commitment);
+        let depositor_ix = Instruction {
+            program_id: depositor_program_id,
+            accounts: vec![
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new(tornado_instance.pubkey(), false),
+                AccountMeta::new(merkle_tree, false),
+                AccountMeta::new(commitment_marker, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(program_id, false),
+            ],
+            data: commitment.to_vec(),
+        };
+        let mut transaction = Transaction::new_with_payer(&[depositor_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+
+        // The depositor forwards what the deposit returned
+        let simulation = banks_client.simulate_transaction(transaction.clone()).await.unwrap();
+        assert!(simulation.result.unwrap().is_ok());
+        let return_data = simulation.simulation_details.unwrap().return_data.unwrap();
+        assert_eq!(return_data.program_id, depositor_program_id);
+        let returned = DepositReturnData::try_from_slice(&return_data.data).unwrap();
+
+        // It is the leaf and root the deposit left in the tree
+        banks_client.process_transaction(transaction).await.unwrap();
+        let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+        let tree = MerkleTree::from_account_data(&merkle_tree_account.data).unwrap();
+        assert_eq!(
+            returned,
+            DepositReturnData {
+                leaf_index: leaf_index as u32,
+                root: latest_root_with_index(&tree).0,
+            }
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Event log tests
+//!
+//! `sol_log_data` is only recorded in the transaction logs by the SBF runtime,
+//! so these run against the SBF build of the program: `cargo test-sbf --features test-sbf`.
+#![cfg(feature = "test-sbf")]
+
+mod common;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use borsh::BorshDeserialize;
+use solana_program::{program_pack::Pack, pubkey::Pubkey};
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+};
+
+use tornado_svm::{
+    events::TornadoEvent,
+    instruction::{deposit, get_root, is_spent},
+    merkle_tree::latest_root_with_index,
+    state::{MerkleTree, NullifierMarker},
+    utils::find_nullifier_address,
+};
+
+use common::add_instance;
+
+// Find the event logged by a simulated transaction
+fn find_event(logs: &[String]) -> TornadoEvent {
+    // The event is logged base64-encoded as `Program data: ...`
+    let event_data = logs
+        .iter()
+        .find_map(|log| log.strip_prefix("Program data: "))
+        .unwrap_or_else(|| panic!("no event was logged: {:?}", logs));
+    TornadoEvent::from_log_data(&BASE64_STANDARD.decode(event_data).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn test_deposit_event() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("tornado_svm", program_id, None);
+    program_test.prefer_bpf(true);
+
+    let tornado_instance = Keypair::new();
+    let merkle_tree = add_instance(&mut program_test, &program_id, &tornado_instance.pubkey());
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let commitment = [3u8; 32];
+    let deposit_ix = deposit(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        commitment,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+
+    let simulation = banks_client.simulate_transaction(transaction).await.unwrap();
+    let details = simulation.simulation_details.unwrap();
+
+    match find_event(&details.logs) {
+        TornadoEvent::Deposit(deposit_event) => {
+            assert_eq!(deposit_event.commitment, commitment);
+            assert_eq!(deposit_event.leaf_index, 0);
+        }
+        event => panic!("unexpected event: {:?}", event),
+    }
+}
+
+#[tokio::test]
+async fn test_root_event() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("tornado_svm", program_id, None);
+    program_test.prefer_bpf(true);
+
+    let tornado_instance = Keypair::new();
+    let merkle_tree = add_instance(&mut program_test, &program_id, &tornado_instance.pubkey());
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // Make several deposits
+    for i in 1..=3u8 {
+        let deposit_ix = deposit(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            [i; 32],
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+    }
+
+    let get_root_ix = get_root(&program_id, &tornado_instance.pubkey(), &merkle_tree).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[get_root_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+
+    let simulation = banks_client.simulate_transaction(transaction).await.unwrap();
+    let details = simulation.simulation_details.unwrap();
+
+    // The logged root is the latest one in the tree
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap();
+    match find_event(&details.logs) {
+        TornadoEvent::Root(root_event) => {
+            assert_eq!(root_event.root_index, 3);
+            assert_eq!((root_event.root, root_event.root_index), latest_root_with_index(&tree));
+        }
+        event => panic!("unexpected event: {:?}", event),
+    }
+}
+
+#[tokio::test]
+async fn test_nullifier_status_event() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("tornado_svm", program_id, None);
+    program_test.prefer_bpf(true);
+
+    let tornado_instance = Keypair::new();
+    let merkle_tree = add_instance(&mut program_test, &program_id, &tornado_instance.pubkey());
+
+    // The marker a withdrawal creates for a spent nullifier hash
+    let spent_nullifier_hash = [5u8; 32];
+    let (nullifier_marker, _) =
+        find_nullifier_address(&program_id, &tornado_instance.pubkey(), &spent_nullifier_hash);
+    let mut marker_data = vec![0u8; NullifierMarker::LEN];
+    NullifierMarker::pack(NullifierMarker { is_initialized: true }, &mut marker_data).unwrap();
+    program_test.add_account(
+        nullifier_marker,
+        Account {
+            lamports: 1_000_000_000,
+            data: marker_data,
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    for (nullifier_hash, spent) in [(spent_nullifier_hash, true), ([6u8; 32], false)] {
+        let is_spent_ix = is_spent(&program_id, &tornado_instance.pubkey(), &merkle_tree, nullifier_hash).unwrap();
+        let mut transaction = Transaction::new_with_payer(&[is_spent_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+
+        let simulation = banks_client.simulate_transaction(transaction).await.unwrap();
+        let details = simulation.simulation_details.unwrap();
+
+        match find_event(&details.logs) {
+            TornadoEvent::NullifierStatus(status_event) => {
+                assert_eq!(status_event.nullifier_hash, nullifier_hash);
+                assert_eq!(status_event.spent, spent);
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! MiMC test vectors
+//!
+//! Expected hashes are circomlib's `MiMCSponge(2, 220, 1)` with key 0, the hasher
+//! of Tornado's `MerkleTreeWithHistory.sol`. The inputs cover zero, small values,
+//! limb boundaries and the top of the field; a commitment or root hashed by the
+//! program has to match the circuit bit for bit.
+
+use tornado_svm::merkle_tree::{hash_left_right, FIELD_SIZE, HASH_ALGO_MIMC, ZEROS, ZERO_VALUE};
+
+// The largest field element, `FIELD_SIZE - 1`
+const FIELD_MAX: &str = "30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000000";
+
+// (left, right, hash) as big-endian hex
+const VECTORS: [(&str, &str, &str); 12] = [
+    (
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        "2d9fea8398a61ea1997e7d748364c0fdb49412c4dbabc1578375ade642e85581",
+    ),
+    (
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        "0000000000000000000000000000000000000000000000000000000000000001",
+        "2e06737045248389b5af4dd98d5e43b1d1bc1ee6a218f31df3c54782ba5fb5a5",
+    ),
+    (
+        "0000000000000000000000000000000000000000000000000000000000000001",
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        "1da263d3a84800d345556c801d614888a7a0f0e112c97da2b9a66bfd97befd17",
+    ),
+    (
+        "0000000000000000000000000000000000000000000000000000000000000001",
+        "0000000000000000000000000000000000000000000000000000000000000002",
+        "2bcea035a1251603f1ceaf73cd4ae89427c47075bb8e3a944039ff1e3d6d2a6f",
+    ),
+    (
+        "0000000000000000000000000000000000000000000000000000000000000002",
+        "0000000000000000000000000000000000000000000000000000000000000001",
+        "1d853bcd317d6b6a6cde9a9969137327f7c756222d099cfdbc1cc6e70e17fbd6",
+    ),
+    (
+        "00000000000000000000000000000000000000000000000000000000000000ff",
+        "0000000000000000000000000000000000000000000000000000000000000100",
+        "1716d8e36e4437ce665f8e94cd11384c73cf26fb853b4b60d5d2e75321e3be92",
+    ),
+    (
+        "0000000000000000000000000000000000000000000000010000000000000000",
+        "0000000000000000000000000000000100000000000000000000000000000000",
+        "14187e8b06b4d39fe885320562d7a97e8cc11cfce3b1dffc54ba687b32708910",
+    ),
+    (
+        FIELD_MAX,
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        "260e5f6e3daaeb9218cc0d9518e413cb99f2b2edf47aa57e9ebb86a12d589148",
+    ),
+    (
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        FIELD_MAX,
+        "2637d2b859277b1b2b51f08a4ba35743b3bb20f48dbbb3c0427acc8208414db5",
+    ),
+    (
+        FIELD_MAX,
+        FIELD_MAX,
+        "1fd83c8be03ba8308e5b75dc0d5ebfd128370f533ff92ffe6c75cd967bab3c38",
+    ),
+    (
+        "30644e72e131a029b85045b68181585d2833e84879b9709143e1f593efffffff",
+        "0000000000000000000000000000000000000000000000000000000000000001",
+        "083aff72bc57d8c38d959784beb8c563ff0f2bd7c5a94ca65c2b6164815c15c9",
+    ),
+    (
+        "2fe54c60d3acabf3343a35b6eba15db4821b340f76e741e2249685ed4899af6c",
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        "08938f1d0a15f2344d18a403e1cba6bf9856d0354dfcfb2d3619c8e54d2c71c3",
+    ),
+];
+
+fn from_hex(hex: &str) -> [u8; 32] {
+    core::array::from_fn(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+}
+
+#[test]
+fn test_mimc_vectors() {
+    for (left, right, expected) in VECTORS {
+        assert_eq!(
+            hash_left_right(HASH_ALGO_MIMC, &from_hex(left), &from_hex(right)).unwrap(),
+            from_hex(expected),
+            "MiMC({}, {})",
+            left,
+            right
+        );
+    }
+}
+
+#[test]
+fn test_mimc_zero_chain() {
+    // `zeros(i)` from Tornado's MerkleTreeWithHistory.sol
+    assert_eq!(from_hex(VECTORS[11].0), ZERO_VALUE);
+    for level in 0..ZEROS.len() - 1 {
+        assert_eq!(
+            hash_left_right(HASH_ALGO_MIMC, &ZEROS[level], &ZEROS[level]).unwrap(),
+            ZEROS[level + 1],
+            "zero value mismatch at level {}",
+            level + 1
+        );
+    }
+}
+
+#[test]
+fn test_mimc_rejects_non_canonical_inputs() {
+    // Inputs are not reduced: the field size itself and anything above are rejected
+    let one = from_hex(VECTORS[1].1);
+    for input in [FIELD_SIZE, [0xff; 32]] {
+        assert!(hash_left_right(HASH_ALGO_MIMC, &input, &one).is_err());
+        assert!(hash_left_right(HASH_ALGO_MIMC, &one, &input).is_err());
+    }
+}
@@ -0,0 +1,1337 @@
+//! End-to-end deposit and withdrawal tests using the mock verifier
+//!
+//! Real proofs can't be produced here, so these accept `MOCK_PROOF` instead:
+//! `cargo test --features mock-verifier`.
+#![cfg(feature = "mock-verifier")]
+
+mod common;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{clock::Clock, program_pack::Pack, pubkey::Pubkey, rent::Rent, system_instruction};
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account,
+    compute_budget::ComputeBudgetInstruction,
+    hash::Hash,
+    instruction::{AccountMeta, InstructionError},
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+
+use tornado_svm::{
+    error::TornadoError,
+    instruction::{
+        close_instance, deposit, deposit_variable, initialize, migrate, set_compliance_list, set_min_anonymity_set,
+        set_partial_verifier, set_withdraw_delay, simulate_withdraw, withdraw, withdraw_batch, withdraw_partial,
+        withdraw_stealth, withdraw_variable, WithdrawRequest,
+    },
+    merkle_tree::{insert_leaf, latest_root_with_index, HASH_ALGO_MIMC},
+    state::{
+        CommitmentMarker, ComplianceList, MerkleTree, MerkleTreeV1, Registry, TornadoInstance, TornadoInstanceV1,
+        VerifierState, MAX_FEE_BPS, ROOT_HISTORY_SIZE,
+    },
+    stealth::{derive_stealth_address, StealthKeys},
+    utils::{
+        compute_amount_commitment, find_commitment_address, find_compliance_list_address, find_merkle_tree_address,
+        find_nullifier_address, find_registry_address, find_verifier_address, derive_note_fields, pool_balance_invariant,
+    },
+    verifier::{
+        CURVE_BN254, MOCK_PROOF, NUM_PARTIAL_WITHDRAW_PUBLIC_INPUTS, NUM_PUBLIC_INPUTS, NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS,
+        PROOF_SYSTEM_GROTH16,
+    },
+};
+
+use common::{create_test_verifying_key, initialize_instance};
+
+#[tokio::test]
+async fn test_tornado_flow() {
+    // Create program test environment
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+
+    // Create accounts
+    let payer = Keypair::new();
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let recipient = Keypair::new();
+    let relayer = Keypair::new();
+
+    // Add accounts to the test environment
+    program_test.add_account(
+        payer.pubkey(),
+        Account {
+            lamports: 1_000_000_000,
+            data: vec![],
+            owner: solana_program::system_program::id(),
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    // Start the test environment
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // Initialize the Tornado instance
+    let denomination = 100_000_000; // 0.1 SOL
+    let merkle_tree_height = 8;
+
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        merkle_tree_height,
+        false,
+    )
+    .await
+    .unwrap();
+
+    // Generate a nullifier and secret
+    let nullifier = [1u8; 32];
+    let secret = [2u8; 32];
+
+    // Compute the commitment and the nullifier hash revealed on withdrawal
+    let (commitment, nullifier_hash) = derive_note_fields(&nullifier, &secret, &tornado_instance.pubkey()).unwrap();
+
+    // Deposit
+    let instance_balance = banks_client.get_balance(tornado_instance.pubkey()).await.unwrap();
+    let deposit_ix = deposit(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        commitment,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[deposit_ix],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(),
+        instance_balance + denomination
+    );
+
+    // Get the Merkle root
+    let merkle_tree_account = banks_client
+        .get_account(merkle_tree)
+        .await
+        .unwrap()
+        .unwrap();
+    let merkle_tree_data = MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap();
+    let root = merkle_tree_data.roots[merkle_tree_data.current_root_index as usize];
+
+    // Withdraw with the sentinel proof accepted by the mock verifier
+    let withdraw_ix = withdraw(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        &recipient.pubkey(),
+        &relayer.pubkey(),
+        MOCK_PROOF.to_vec(),
+        root,
+        nullifier_hash,
+        0, // No fee
+        0, // No refund
+        None, // No fee quote
+        None,
+    )
+    .unwrap();
+
+    let mut transaction = Transaction::new_with_payer(
+        std::slice::from_ref(&withdraw_ix),
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // Verify that the recipient received the funds and the instance released them
+    let recipient_account = banks_client
+        .get_account(recipient.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(recipient_account.lamports, denomination);
+    assert_eq!(
+        banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(),
+        instance_balance
+    );
+
+    // Verify that the nullifier hash is marked as spent
+    let (nullifier_marker, _) =
+        find_nullifier_address(&program_id, &tornado_instance.pubkey(), &nullifier_hash);
+    let nullifier_marker_account = banks_client
+        .get_account(nullifier_marker)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(nullifier_marker_account.owner, program_id);
+
+    // Replaying the withdrawal is rejected as a double spend
+    let recent_blockhash = banks_client.get_new_latest_blockhash(&recent_blockhash).await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[withdraw_ix],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::NullifierAlreadySpent as u32)
+        )
+    );
+}
+
+// Deposit the note `(nullifier, secret)` into the instance
+async fn deposit_note(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: Hash,
+    program_id: &Pubkey,
+    tornado_instance: &Pubkey,
+    (nullifier, secret): &([u8; 32], [u8; 32]),
+) {
+    let (merkle_tree, _) = find_merkle_tree_address(program_id, tornado_instance);
+    let (commitment, _) = derive_note_fields(nullifier, secret, tornado_instance).unwrap();
+    let deposit_ix = deposit(
+        program_id,
+        &payer.pubkey(),
+        tornado_instance,
+        &merkle_tree,
+        commitment,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+    transaction.sign(&[payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+}
+
+// Withdraw the note `(nullifier, secret)` against the latest root, paying the payer as relayer
+async fn withdraw_note(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: Hash,
+    program_id: &Pubkey,
+    tornado_instance: &Pubkey,
+    (nullifier, secret): &([u8; 32], [u8; 32]),
+    fee: u64,
+) {
+    let (merkle_tree, _) = find_merkle_tree_address(program_id, tornado_instance);
+    let tree = get_merkle_tree(banks_client, &merkle_tree).await;
+    let root = tree.roots[tree.current_root_index as usize];
+    let (_, nullifier_hash) = derive_note_fields(nullifier, secret, tornado_instance).unwrap();
+    let withdraw_ix = withdraw(
+        program_id,
+        &payer.pubkey(),
+        tornado_instance,
+        &merkle_tree,
+        &Keypair::new().pubkey(),
+        &payer.pubkey(),
+        MOCK_PROOF.to_vec(),
+        root,
+        nullifier_hash,
+        fee,
+        0,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+}
+
+async fn get_merkle_tree(banks_client: &mut BanksClient, merkle_tree: &Pubkey) -> MerkleTree {
+    let merkle_tree_account = banks_client.get_account(*merkle_tree).await.unwrap().unwrap();
+    MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap()
+}
+
+#[tokio::test]
+async fn test_pool_balance_invariant() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let denomination = 100_000_000;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let rent = banks_client.get_rent().await.unwrap().minimum_balance(TornadoInstance::LEN);
+
+    // Deposits arrive through the System Program
+    let (deposits, withdrawals) = (3u8, 2u8);
+    let notes: Vec<_> = (1..=deposits).map(|i| ([i; 32], [i + 10; 32])).collect();
+    for note in &notes {
+        deposit_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), note).await;
+    }
+    assert_eq!(
+        banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(),
+        rent + u64::from(deposits) * denomination
+    );
+
+    // Withdrawals move the lamports out of the program-owned instance directly
+    for note in &notes[..withdrawals as usize] {
+        withdraw_note(
+            &mut banks_client,
+            &payer,
+            recent_blockhash,
+            &program_id,
+            &tornado_instance.pubkey(),
+            note,
+            denomination / 100,
+        )
+        .await;
+    }
+
+    // Fees come out of the withdrawn denomination, not the rest of the pool
+    let balance = banks_client.get_balance(tornado_instance.pubkey()).await.unwrap();
+    assert_eq!(balance, rent + u64::from(deposits - withdrawals) * denomination);
+
+    // The instance's counts account for exactly that balance
+    let instance_account = banks_client.get_account(tornado_instance.pubkey()).await.unwrap().unwrap();
+    let instance = TornadoInstance::unpack(&instance_account.data).unwrap();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+    assert_eq!(
+        pool_balance_invariant(&instance, &tree, balance, rent),
+        Ok(u64::from(deposits - withdrawals) * denomination)
+    );
+}
+
+#[tokio::test]
+async fn test_merkle_tree_counts() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        100_000_000,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+
+    // Interleave deposits and withdrawals: (is_deposit, note)
+    let notes: Vec<_> = (1..=3u8).map(|i| ([i; 32], [i + 10; 32])).collect();
+    let steps = [(true, 0), (true, 1), (false, 0), (true, 2), (false, 1), (false, 2)];
+    let (mut deposits, mut withdrawals) = (0u64, 0u64);
+    for (is_deposit, note) in steps {
+        if is_deposit {
+            deposit_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), &notes[note])
+                .await;
+            deposits += 1;
+        } else {
+            withdraw_note(
+                &mut banks_client,
+                &payer,
+                recent_blockhash,
+                &program_id,
+                &tornado_instance.pubkey(),
+                &notes[note],
+                0,
+            )
+            .await;
+            withdrawals += 1;
+        }
+
+        let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+        assert_eq!(u64::from(tree.next_index), deposits);
+        assert_eq!(tree.nullifier_count, withdrawals);
+    }
+}
+
+#[tokio::test]
+async fn test_close_migrated_instance() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+
+    // Unversioned accounts holding two deposits, the first withdrawn before
+    // nullifier markers existed and listed in `nullifier_hashes`
+    let tornado_instance = Keypair::new();
+    let admin = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let (verifier, _) = find_verifier_address(&program_id, &tornado_instance.pubkey());
+    let (registry, _) = find_registry_address(&program_id);
+    let denomination = 100_000_000;
+    let height = 8;
+    let notes = [([1u8; 32], [11u8; 32]), ([2u8; 32], [12u8; 32])];
+    let mut tree = MerkleTree::new(height, ROOT_HISTORY_SIZE as u8, HASH_ALGO_MIMC).unwrap();
+    for (index, (nullifier, secret)) in notes.iter().enumerate() {
+        let (commitment, _) = derive_note_fields(nullifier, secret, &tornado_instance.pubkey()).unwrap();
+        insert_leaf(
+            &commitment,
+            index as u32,
+            height,
+            HASH_ALGO_MIMC,
+            &mut tree.filled_subtrees,
+            &mut tree.roots,
+            &mut tree.current_root_index,
+        )
+        .unwrap();
+    }
+    let (_, spent_nullifier_hash) = derive_note_fields(&notes[0].0, &notes[0].1, &tornado_instance.pubkey()).unwrap();
+    let legacy_instance = TornadoInstanceV1 {
+        is_initialized: true,
+        denomination,
+        merkle_tree_height: height,
+        merkle_tree,
+        verifier,
+        token_mint: None,
+        admin: admin.pubkey(),
+        paused: false,
+        variable_amount: false,
+    };
+    let legacy_tree = MerkleTreeV1 {
+        is_initialized: true,
+        height,
+        next_index: 2,
+        current_root_index: tree.current_root_index,
+        roots: tree.roots,
+        filled_subtrees: tree.filled_subtrees,
+        nullifier_hashes: vec![spent_nullifier_hash],
+    };
+    let verifier_state = VerifierState {
+        is_initialized: true,
+        verifying_key: create_test_verifying_key(NUM_PUBLIC_INPUTS),
+    };
+    let registry_state = Registry {
+        is_initialized: true,
+        instances: vec![],
+    };
+    let rent = Rent::default();
+    for (address, data, pooled) in [
+        (tornado_instance.pubkey(), legacy_instance.try_to_vec().unwrap(), denomination),
+        (merkle_tree, legacy_tree.try_to_vec().unwrap(), 0),
+        (verifier, verifier_state.try_to_vec().unwrap(), 0),
+        (registry, registry_state.try_to_vec().unwrap(), 0),
+    ] {
+        program_test.add_account(
+            address,
+            Account {
+                lamports: rent.minimum_balance(data.len()) + pooled,
+                data,
+                owner: program_id,
+                executable: false,
+                rent_epoch: 0,
+            },
+        );
+    }
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let migrate_ix = migrate(&program_id, &payer.pubkey(), &tornado_instance.pubkey(), &merkle_tree, &verifier).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[migrate_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The migrated tree counts the legacy withdrawal
+    let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+    assert_eq!((tree.next_index, tree.nullifier_count), (2, 1));
+
+    // The instance can't be closed while the second note is unspent
+    let receiver = Pubkey::new_unique();
+    let close_tx = |recent_blockhash: Hash| {
+        let close_ix = close_instance(&program_id, &admin.pubkey(), &tornado_instance.pubkey(), &receiver, None).unwrap();
+        let mut transaction = Transaction::new_with_payer(&[close_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &admin], recent_blockhash);
+        transaction
+    };
+    assert_eq!(
+        banks_client.process_transaction(close_tx(recent_blockhash)).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::InstanceNotEmpty as u32)
+        )
+    );
+
+    // Once the rest is withdrawn, the instance closes
+    withdraw_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), &notes[1], 0)
+        .await;
+    let recent_blockhash = banks_client.get_new_latest_blockhash(&recent_blockhash).await.unwrap();
+    banks_client.process_transaction(close_tx(recent_blockhash)).await.unwrap();
+    for address in [tornado_instance.pubkey(), merkle_tree, verifier] {
+        assert!(banks_client.get_account(address).await.unwrap().is_none());
+    }
+    assert!(banks_client.get_balance(receiver).await.unwrap() > 0);
+}
+
+#[tokio::test]
+async fn test_min_anonymity_set() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        100_000_000,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+
+    // The admin requires three deposits before any withdrawal
+    let min_anonymity_set = 3;
+    let set_ix = set_min_anonymity_set(&program_id, &payer.pubkey(), &tornado_instance.pubkey(), min_anonymity_set).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let notes: Vec<_> = (1..=min_anonymity_set as u8).map(|i| ([i; 32], [i + 10; 32])).collect();
+    for note in &notes[..notes.len() - 1] {
+        deposit_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), note).await;
+    }
+
+    // One deposit short of the minimum, withdrawals are rejected
+    let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+    assert_eq!(tree.next_index, min_anonymity_set - 1);
+    let (_, nullifier_hash) = derive_note_fields(&notes[0].0, &notes[0].1, &tornado_instance.pubkey()).unwrap();
+    let withdraw_ix = withdraw(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        &Keypair::new().pubkey(),
+        &payer.pubkey(),
+        MOCK_PROOF.to_vec(),
+        latest_root_with_index(&tree).0,
+        nullifier_hash,
+        0,
+        0,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::AnonymitySetTooSmall as u32)
+        )
+    );
+
+    // Once the tree holds exactly the minimum, the same note can be withdrawn
+    let last_note = &notes[notes.len() - 1];
+    deposit_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), last_note).await;
+    withdraw_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), &notes[0], 0)
+        .await;
+    let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+    assert_eq!((tree.next_index, tree.nullifier_count), (min_anonymity_set, 1));
+}
+
+#[tokio::test]
+async fn test_withdraw_delay() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let mut context = program_test.start_with_context().await;
+    let payer = context.payer.insecure_clone();
+    let recent_blockhash = context.last_blockhash;
+
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    initialize_instance(
+        &mut context.banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        100_000_000,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+
+    // The admin time-locks withdrawals for an hour
+    let withdraw_delay_secs = 3600;
+    let set_ix = set_withdraw_delay(&program_id, &payer.pubkey(), &tornado_instance.pubkey(), withdraw_delay_secs).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+
+    // The deposit's root is stamped with the time it was inserted at
+    let note = ([1u8; 32], [2u8; 32]);
+    deposit_note(&mut context.banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), &note).await;
+    let mut clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    let tree = get_merkle_tree(&mut context.banks_client, &merkle_tree).await;
+    let inserted_at = tree.root_timestamps[tree.current_root_index as usize];
+    assert_eq!(inserted_at, clock.unix_timestamp);
+
+    // Withdrawals against the root fail until the delay has passed, up to the last second
+    let (_, nullifier_hash) = derive_note_fields(&note.0, &note.1, &tornado_instance.pubkey()).unwrap();
+    for now in [inserted_at, inserted_at + i64::from(withdraw_delay_secs) - 1] {
+        clock.unix_timestamp = now;
+        context.set_sysvar(&clock);
+        let withdraw_ix = withdraw(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            &Keypair::new().pubkey(),
+            &payer.pubkey(),
+            MOCK_PROOF.to_vec(),
+            latest_root_with_index(&tree).0,
+            nullifier_hash,
+            0,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_eq!(
+            context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::Custom(TornadoError::WithdrawTooEarly as u32))
+        );
+    }
+
+    // Once it has, the note is withdrawn on time
+    clock.unix_timestamp = inserted_at + i64::from(withdraw_delay_secs);
+    context.set_sysvar(&clock);
+    withdraw_note(&mut context.banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), &note, 0)
+        .await;
+    let tree = get_merkle_tree(&mut context.banks_client, &merkle_tree).await;
+    assert_eq!(tree.nullifier_count, 1);
+}
+
+#[tokio::test]
+async fn test_simulate_withdraw() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let denomination = 100_000_000;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let note = ([1u8; 32], [2u8; 32]);
+    deposit_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), &note).await;
+
+    // A valid withdrawal passes every check
+    let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+    let root = tree.roots[tree.current_root_index as usize];
+    let (_, nullifier_hash) = derive_note_fields(&note.0, &note.1, &tornado_instance.pubkey()).unwrap();
+    let simulate_ix = simulate_withdraw(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        &Keypair::new().pubkey(),
+        &payer.pubkey(),
+        MOCK_PROOF.to_vec(),
+        root,
+        nullifier_hash,
+        denomination / 100,
+        0,
+        None,
+    )
+    .unwrap();
+    let instance_balance = banks_client.get_balance(tornado_instance.pubkey()).await.unwrap();
+    let mut transaction = Transaction::new_with_payer(std::slice::from_ref(&simulate_ix), Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // Nothing was spent or paid out
+    let (nullifier_marker, _) =
+        find_nullifier_address(&program_id, &tornado_instance.pubkey(), &nullifier_hash);
+    assert!(banks_client.get_account(nullifier_marker).await.unwrap().is_none());
+    assert_eq!(
+        banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(),
+        instance_balance
+    );
+    assert_eq!(get_merkle_tree(&mut banks_client, &merkle_tree).await, tree);
+
+    // Once the note is withdrawn, the simulation reports the double spend
+    withdraw_note(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance.pubkey(),
+        &note,
+        0,
+    )
+    .await;
+    let recent_blockhash = banks_client.get_new_latest_blockhash(&recent_blockhash).await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[simulate_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::NullifierAlreadySpent as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_strict_tx_withdraw() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let denomination = 100_000_000;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        8,
+        true,
+    )
+    .await
+    .unwrap();
+    let note = ([1u8; 32], [2u8; 32]);
+    deposit_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), &note).await;
+
+    let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+    let root = tree.roots[tree.current_root_index as usize];
+    let (_, nullifier_hash) = derive_note_fields(&note.0, &note.1, &tornado_instance.pubkey()).unwrap();
+    let recipient = Keypair::new();
+    let withdraw_ix = withdraw(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        &recipient.pubkey(),
+        &payer.pubkey(),
+        MOCK_PROOF.to_vec(),
+        root,
+        nullifier_hash,
+        denomination / 100,
+        0,
+        None,
+        None,
+    )
+    .unwrap();
+
+    // A relayer can't bundle anything with the withdrawal, such as a sweep of the recipient's funds
+    let sweep_ix = system_instruction::transfer(&recipient.pubkey(), &payer.pubkey(), denomination / 2);
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix.clone(), sweep_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &recipient], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::UnexpectedInstructions as u32)
+        )
+    );
+
+    // Compute budget instructions are allowed
+    let compute_budget_ix = ComputeBudgetInstruction::set_compute_unit_limit(1_400_000);
+    let mut transaction = Transaction::new_with_payer(&[compute_budget_ix, withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        banks_client.get_balance(recipient.pubkey()).await.unwrap(),
+        denomination - denomination / 100
+    );
+}
+
+#[tokio::test]
+async fn test_compliance_list_withdraw() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let denomination = 100_000_000;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let notes = [([1u8; 32], [2u8; 32]), ([3u8; 32], [4u8; 32])];
+    for note in &notes {
+        deposit_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), note).await;
+    }
+
+    // Withdraw a note to the recipient as a self-withdrawal
+    let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+    let root = tree.roots[tree.current_root_index as usize];
+    let withdraw_tx = |note: &([u8; 32], [u8; 32]), recipient: &Pubkey| {
+        let (_, nullifier_hash) = derive_note_fields(&note.0, &note.1, &tornado_instance.pubkey()).unwrap();
+        let withdraw_ix = withdraw(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            recipient,
+            recipient,
+            MOCK_PROOF.to_vec(),
+            root,
+            nullifier_hash,
+            0,
+            0,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        transaction
+    };
+    let set_list_tx = |recipients: Option<Vec<Pubkey>>| {
+        let set_ix = set_compliance_list(&program_id, &payer.pubkey(), &tornado_instance.pubkey(), recipients).unwrap();
+        let mut transaction = Transaction::new_with_payer(&[set_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        transaction
+    };
+
+    // The list must be sorted without duplicates
+    let mut listed = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+    listed.sort();
+    let blocked = listed[1];
+    let allowed = Pubkey::new_unique();
+    assert_eq!(
+        banks_client
+            .process_transaction(set_list_tx(Some(vec![listed[1], listed[0]])))
+            .await
+            .unwrap_err()
+            .unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidInstructionData)
+    );
+    banks_client.process_transaction(set_list_tx(Some(listed.clone()))).await.unwrap();
+    let (compliance_list, _) = find_compliance_list_address(&program_id, &tornado_instance.pubkey());
+    let instance_account = banks_client.get_account(tornado_instance.pubkey()).await.unwrap().unwrap();
+    assert_eq!(
+        TornadoInstance::unpack(&instance_account.data).unwrap().compliance_list,
+        Some(compliance_list)
+    );
+    let list_account = banks_client.get_account(compliance_list).await.unwrap().unwrap();
+    assert_eq!(ComplianceList::deserialize(&mut &list_account.data[..]).unwrap().recipients, listed);
+
+    // A listed recipient is blocked, anyone else is paid
+    assert_eq!(
+        banks_client.process_transaction(withdraw_tx(&notes[0], &blocked)).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(TornadoError::RecipientBlocked as u32))
+    );
+    banks_client.process_transaction(withdraw_tx(&notes[0], &allowed)).await.unwrap();
+    assert_eq!(banks_client.get_balance(allowed).await.unwrap(), denomination);
+
+    // Once the list is disabled, the blocked recipient can be paid too
+    banks_client.process_transaction(set_list_tx(None)).await.unwrap();
+    banks_client.process_transaction(withdraw_tx(&notes[1], &blocked)).await.unwrap();
+    assert_eq!(banks_client.get_balance(blocked).await.unwrap(), denomination);
+}
+
+#[tokio::test]
+async fn test_withdraw_partial() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // A variable-amount instance accepting up to 1 SOL per deposit
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let rent = banks_client.get_rent().await.unwrap();
+    let create_instance_ix = system_instruction::create_account(
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        rent.minimum_balance(TornadoInstance::LEN),
+        TornadoInstance::LEN as u64,
+        &program_id,
+    );
+    let initialize_ix = initialize(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        1_000_000_000,
+        8,
+        ROOT_HISTORY_SIZE as u8,
+        0,
+        None,
+        true,
+        MAX_FEE_BPS,
+        HASH_ALGO_MIMC,
+        CURVE_BN254,
+        false,
+        PROOF_SYSTEM_GROTH16,
+        create_test_verifying_key(NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS),
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[create_instance_ix, initialize_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &tornado_instance], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // Nothing can be withdrawn partially until the admin sets the partial withdrawal verifier, once,
+    // before the first deposit
+    let error = |error: TornadoError| TransactionError::InstructionError(0, InstructionError::Custom(error as u32));
+    let (empty_root, _) = latest_root_with_index(&get_merkle_tree(&mut banks_client, &merkle_tree).await);
+    let withdraw_ix = withdraw_partial(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        &payer.pubkey(),
+        &payer.pubkey(),
+        MOCK_PROOF.to_vec(),
+        empty_root,
+        [5u8; 32],
+        0,
+        1,
+        0,
+        [8u8; 32],
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        error(TornadoError::InvalidAccountData)
+    );
+    let set_verifier_tx = |num_public_inputs| {
+        let set_ix = set_partial_verifier(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            create_test_verifying_key(num_public_inputs),
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[set_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        transaction
+    };
+    assert_eq!(
+        banks_client
+            .process_transaction(set_verifier_tx(NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS))
+            .await
+            .unwrap_err()
+            .unwrap(),
+        error(TornadoError::InvalidVerifyingKey)
+    );
+    banks_client.process_transaction(set_verifier_tx(NUM_PARTIAL_WITHDRAW_PUBLIC_INPUTS)).await.unwrap();
+    assert_eq!(
+        banks_client.process_transaction(set_verifier_tx(NUM_PUBLIC_INPUTS)).await.unwrap_err().unwrap(),
+        error(TornadoError::AccountAlreadyInitialized)
+    );
+
+    // Deposit a note of 0.6 SOL and another of 0.25 SOL
+    let input_amount = 600_000_000;
+    let output_amount = 250_000_000;
+    for (commitment, amount) in [([1u8; 32], input_amount), ([3u8; 32], output_amount)] {
+        let deposit_ix = deposit_variable(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            commitment,
+            amount,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+    }
+
+    // Withdraw 0.35 SOL of the first note to the recipient, re-depositing 0.25 SOL under `output_commitment`
+    let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+    let (root, _) = latest_root_with_index(&tree);
+    let nullifier_hash = [5u8; 32];
+    let recipient = Keypair::new().pubkey();
+    let partial_tx = |output_amount, output_commitment| {
+        let withdraw_ix = withdraw_partial(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            &recipient,
+            &recipient,
+            MOCK_PROOF.to_vec(),
+            root,
+            nullifier_hash,
+            0,
+            input_amount,
+            output_amount,
+            output_commitment,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        transaction
+    };
+
+    // The key can't be swapped in under notes already deposited
+    let new_blockhash = banks_client.get_new_latest_blockhash(&recent_blockhash).await.unwrap();
+    let set_ix = set_partial_verifier(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        create_test_verifying_key(NUM_PARTIAL_WITHDRAW_PUBLIC_INPUTS),
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], new_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        error(TornadoError::InstanceNotEmpty)
+    );
+
+    // The remainder must be a strictly smaller, non-zero part of the note
+    for output_amount in [0, input_amount] {
+        assert_eq!(
+            banks_client.process_transaction(partial_tx(output_amount, [9u8; 32])).await.unwrap_err().unwrap(),
+            error(TornadoError::InvalidAmount)
+        );
+    }
+
+    // The payer must sign, and every account the withdrawal writes to must be writable
+    let partial_ix = withdraw_partial(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        &recipient,
+        &recipient,
+        MOCK_PROOF.to_vec(),
+        root,
+        nullifier_hash,
+        0,
+        input_amount,
+        output_amount,
+        [9u8; 32],
+    )
+    .unwrap();
+    let mut unsigned_ix = partial_ix.clone();
+    unsigned_ix.accounts[0] = AccountMeta::new(Keypair::new().pubkey(), false);
+    let mut transaction = Transaction::new_with_payer(&[unsigned_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature)
+    );
+    for index in [1, 2, 4, 5] {
+        let mut read_only_ix = partial_ix.clone();
+        read_only_ix.accounts[index].is_writable = false;
+        let mut transaction = Transaction::new_with_payer(&[read_only_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_eq!(
+            banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+            error(TornadoError::AccountNotWritable)
+        );
+    }
+
+    // A remainder whose leaf already exists fails the whole withdrawal, leaving the note unspent
+    let (nullifier_marker, _) = find_nullifier_address(&program_id, &tornado_instance.pubkey(), &nullifier_hash);
+    assert_eq!(
+        banks_client.process_transaction(partial_tx(output_amount, [3u8; 32])).await.unwrap_err().unwrap(),
+        error(TornadoError::CommitmentAlreadyExists)
+    );
+    assert!(banks_client.get_account(nullifier_marker).await.unwrap().is_none());
+    assert_eq!(get_merkle_tree(&mut banks_client, &merkle_tree).await, tree);
+
+    // The note is spent, only the withdrawn part leaves the pool and the remainder is the next leaf
+    let instance_balance = banks_client.get_balance(tornado_instance.pubkey()).await.unwrap();
+    let output_commitment = [4u8; 32];
+    banks_client.process_transaction(partial_tx(output_amount, output_commitment)).await.unwrap();
+    assert_eq!(banks_client.get_balance(recipient).await.unwrap(), input_amount - output_amount);
+    assert_eq!(
+        banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(),
+        instance_balance - (input_amount - output_amount)
+    );
+    assert!(banks_client.get_account(nullifier_marker).await.unwrap().is_some());
+    let output_leaf = compute_amount_commitment(&output_commitment, output_amount).unwrap();
+    let (commitment_marker, _) = find_commitment_address(&program_id, &tornado_instance.pubkey(), &output_leaf);
+    let marker_account = banks_client.get_account(commitment_marker).await.unwrap().unwrap();
+    assert_eq!(CommitmentMarker::unpack(&marker_account.data).unwrap().leaf_index, 2);
+    let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+    assert_eq!((tree.next_index, tree.nullifier_count), (3, 1));
+
+    // The spent note can't be withdrawn again
+    assert_eq!(
+        banks_client.process_transaction(partial_tx(output_amount, [6u8; 32])).await.unwrap_err().unwrap(),
+        error(TornadoError::NullifierAlreadySpent)
+    );
+
+    // The remainder is a note like any other deposit of its amount
+    let other_recipient = Keypair::new().pubkey();
+    let withdraw_ix = withdraw_variable(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        &other_recipient,
+        &other_recipient,
+        MOCK_PROOF.to_vec(),
+        latest_root_with_index(&tree).0,
+        [7u8; 32],
+        0,
+        0,
+        output_amount,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(banks_client.get_balance(other_recipient).await.unwrap(), output_amount);
+}
+
+#[tokio::test]
+async fn test_withdraw_batch() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let denomination = 100_000_000;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let notes: Vec<_> = (1..=4u8).map(|i| ([i; 32], [i + 10; 32])).collect();
+    for note in &notes {
+        deposit_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), note).await;
+    }
+    let tree = get_merkle_tree(&mut banks_client, &merkle_tree).await;
+    let root = tree.roots[tree.current_root_index as usize];
+
+    // Each withdrawal pays its own recipient, with the payer relaying for a fee
+    let fee = denomination / 100;
+    let recipients: Vec<_> = notes.iter().map(|_| Keypair::new().pubkey()).collect();
+    let request = |note: usize| {
+        let (nullifier, secret) = &notes[note];
+        let (_, nullifier_hash) = derive_note_fields(nullifier, secret, &tornado_instance.pubkey()).unwrap();
+        WithdrawRequest {
+            proof: MOCK_PROOF.to_vec(),
+            root,
+            nullifier_hash,
+            recipient: recipients[note],
+            relayer: payer.pubkey(),
+            fee,
+        }
+    };
+    let withdraw_batch_ix = |requests: Vec<WithdrawRequest>| {
+        withdraw_batch(&program_id, &payer.pubkey(), &tornado_instance.pubkey(), &merkle_tree, requests).unwrap()
+    };
+
+    // A successful batch settles every withdrawal
+    let mut transaction = Transaction::new_with_payer(&[withdraw_batch_ix(vec![request(0), request(1)])], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    for (note, recipient) in recipients[..2].iter().enumerate() {
+        assert_eq!(banks_client.get_balance(*recipient).await.unwrap(), denomination - fee);
+        let (nullifier_marker, _) =
+            find_nullifier_address(&program_id, &tornado_instance.pubkey(), &request(note).nullifier_hash);
+        assert!(banks_client.get_account(nullifier_marker).await.unwrap().is_some());
+    }
+    assert_eq!(get_merkle_tree(&mut banks_client, &merkle_tree).await.nullifier_count, 2);
+
+    // A nullifier hash can't be spent twice within a batch
+    let mut transaction = Transaction::new_with_payer(&[withdraw_batch_ix(vec![request(2), request(2)])], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::NullifierAlreadySpent as u32)
+        )
+    );
+
+    // A batch whose second withdrawal spends a spent nullifier rolls back the
+    // first, and reports the index of the second
+    withdraw_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), &notes[3], 0)
+        .await;
+    let instance_balance = banks_client.get_balance(tornado_instance.pubkey()).await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[withdraw_batch_ix(vec![request(2), request(3)])], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    let simulation = banks_client.simulate_transaction(transaction.clone()).await.unwrap();
+    let details = simulation.simulation_details.unwrap();
+    assert!(details.logs.iter().any(|log| log.contains("Withdrawal 1 of the batch is invalid")), "{:?}", details.logs);
+    assert_eq!(details.return_data.unwrap().data, 1u32.to_le_bytes());
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::NullifierAlreadySpent as u32)
+        )
+    );
+    assert_eq!(banks_client.get_balance(recipients[2]).await.unwrap(), 0);
+    let (nullifier_marker, _) = find_nullifier_address(&program_id, &tornado_instance.pubkey(), &request(2).nullifier_hash);
+    assert!(banks_client.get_account(nullifier_marker).await.unwrap().is_none());
+    assert_eq!(banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(), instance_balance);
+    assert_eq!(get_merkle_tree(&mut banks_client, &merkle_tree).await.nullifier_count, 3);
+}
+
+#[tokio::test]
+async fn test_withdraw_stealth() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let denomination = 100_000_000;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let note = ([1u8; 32], [2u8; 32]);
+    deposit_note(&mut banks_client, &payer, recent_blockhash, &program_id, &tornado_instance.pubkey(), &note).await;
+
+    // The withdrawer pays a one-time address derived from the recipient's meta-address
+    let recipient_keys = StealthKeys::new(&[3u8; 32], &[4u8; 32]);
+    let (stealth_address, ephemeral_pubkey) =
+        derive_stealth_address(&recipient_keys.meta_address(), &[5u8; 32]).unwrap();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let root = latest_root_with_index(&get_merkle_tree(&mut banks_client, &merkle_tree).await).0;
+    let (_, nullifier_hash) = derive_note_fields(&note.0, &note.1, &tornado_instance.pubkey()).unwrap();
+    let fee = denomination / 100;
+    let withdraw_ix = withdraw_stealth(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        &stealth_address,
+        &payer.pubkey(),
+        MOCK_PROOF.to_vec(),
+        root,
+        nullifier_hash,
+        ephemeral_pubkey,
+        fee,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The recipient finds the payment and holds the key of the address
+    assert_eq!(banks_client.get_balance(stealth_address).await.unwrap(), denomination - fee);
+    assert!(recipient_keys.scan(&stealth_address, &ephemeral_pubkey).is_some());
+    let (nullifier_marker, _) = find_nullifier_address(&program_id, &tornado_instance.pubkey(), &nullifier_hash);
+    assert!(banks_client.get_account(nullifier_marker).await.unwrap().is_some());
+}
@@ -0,0 +1,753 @@
+//! Token withdrawal tests paying into the recipient's associated token account
+
+use ark_bn254::{Bn254, Fr};
+use ark_ff::{BigInteger, PrimeField};
+use ark_groth16::{Groth16, ProvingKey};
+use ark_relations::{
+    lc,
+    r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError, Variable},
+};
+use ark_serialize::CanonicalSerialize;
+use borsh::BorshSerialize;
+use solana_program::{
+    program_option::COption, program_pack::Pack, pubkey::Pubkey, rent::Rent, system_instruction,
+};
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account,
+    hash::Hash,
+    instruction::{Instruction, InstructionError},
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+use spl_associated_token_account::get_associated_token_address;
+use spl_token::state::{Account as TokenAccount, AccountState, Mint};
+
+use tornado_svm::{
+    error::TornadoError,
+    instruction::{set_max_sol_fee, withdraw_token},
+    merkle_tree::HASH_ALGO_MIMC,
+    state::{MerkleTree, NullifierMarker, TornadoInstance, VerifierState, MAX_FEE_BPS, ROOT_HISTORY_SIZE},
+    utils::{
+        find_merkle_tree_address, find_nullifier_address, find_vault_authority_address, find_verifier_address,
+        get_vault_address,
+    },
+    verifier::{hash_verifying_key, WithdrawPublicInputs, CURVE_BN254, NUM_PUBLIC_INPUTS, PROOF_SYSTEM_GROTH16},
+};
+
+// Circuit with one public input per withdraw input, each constrained by `x * 1 = x`
+#[derive(Clone)]
+struct PublicInputsCircuit {
+    inputs: [Fr; NUM_PUBLIC_INPUTS],
+}
+
+impl ConstraintSynthesizer<Fr> for PublicInputsCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        for input in self.inputs {
+            let x = cs.new_input_variable(|| Ok(input))?;
+            cs.enforce_constraint(lc!() + x, lc!() + Variable::One, lc!() + x)?;
+        }
+        Ok(())
+    }
+}
+
+// Prove the withdrawal public inputs, encoded in the 256-byte instruction format
+#[allow(clippy::too_many_arguments)]
+fn prove(
+    pk: &ProvingKey<Bn254>,
+    tornado_instance: &Pubkey,
+    nullifier_hash: &[u8; 32],
+    recipient: &Pubkey,
+    relayer: &Pubkey,
+    fee: u64,
+    refund: u64,
+    fee_in_sol: bool,
+) -> Vec<u8> {
+    let public_inputs = WithdrawPublicInputs {
+        root: ROOT,
+        nullifier_hash: *nullifier_hash,
+        recipient: *recipient,
+        relayer: *relayer,
+        fee,
+        fee_in_sol,
+        refund,
+        instance: *tornado_instance,
+    }
+    .to_bytes()
+    .unwrap();
+    let inputs: [Fr; NUM_PUBLIC_INPUTS] =
+        core::array::from_fn(|i| Fr::from_be_bytes_mod_order(&public_inputs[i * 32..(i + 1) * 32]));
+    let proof = Groth16::<Bn254>::create_random_proof_with_reduction(
+        PublicInputsCircuit { inputs },
+        pk,
+        &mut ark_std::test_rng(),
+    )
+    .unwrap();
+
+    [
+        proof.a.x,
+        proof.a.y,
+        proof.b.x.c0,
+        proof.b.x.c1,
+        proof.b.y.c0,
+        proof.b.y.c1,
+        proof.c.x,
+        proof.c.y,
+    ]
+    .iter()
+    .flat_map(|f| f.into_bigint().to_bytes_le())
+    .collect()
+}
+
+const ROOT: [u8; 32] = [7u8; 32];
+const DENOMINATION: u64 = 1_000_000;
+const FEE: u64 = 1_000;
+// Relayer fees are capped at 0.5% of the denomination (5_000)
+const MAX_FEE_BPS_CAP: u16 = 50;
+
+// A token instance whose Merkle tree has `ROOT` in its history
+struct TokenInstance {
+    program_id: Pubkey,
+    tornado_instance: Pubkey,
+    merkle_tree: Pubkey,
+    token_mint: Pubkey,
+    vault: Pubkey,
+    mint_authority: Keypair,
+    admin: Keypair,
+    pk: ProvingKey<Bn254>,
+}
+
+impl TokenInstance {
+    // Build a withdrawal relayed by the payer, who collects the fee in its associated token account
+    fn withdraw_ix(
+        &self,
+        payer: &Pubkey,
+        nullifier_hash: [u8; 32],
+        recipient: &Pubkey,
+        fee: u64,
+        refund: u64,
+    ) -> Instruction {
+        self.build_withdraw_ix(payer, nullifier_hash, recipient, fee, refund, false)
+    }
+
+    // Build a withdrawal relayed by the payer, who collects the fee in lamports from the instance
+    fn sol_fee_withdraw_ix(
+        &self,
+        payer: &Pubkey,
+        nullifier_hash: [u8; 32],
+        recipient: &Pubkey,
+        fee: u64,
+        refund: u64,
+    ) -> Instruction {
+        self.build_withdraw_ix(payer, nullifier_hash, recipient, fee, refund, true)
+    }
+
+    fn build_withdraw_ix(
+        &self,
+        payer: &Pubkey,
+        nullifier_hash: [u8; 32],
+        recipient: &Pubkey,
+        fee: u64,
+        refund: u64,
+        fee_in_sol: bool,
+    ) -> Instruction {
+        withdraw_token(
+            &self.program_id,
+            payer,
+            &self.tornado_instance,
+            &self.merkle_tree,
+            &self.token_mint,
+            recipient,
+            payer,
+            &get_associated_token_address(payer, &self.token_mint),
+            prove(&self.pk, &self.tornado_instance, &nullifier_hash, recipient, payer, fee, refund, fee_in_sol),
+            ROOT,
+            nullifier_hash,
+            fee,
+            refund,
+            fee_in_sol,
+        )
+        .unwrap()
+    }
+
+    fn nullifier_marker(&self, nullifier_hash: &[u8; 32]) -> Pubkey {
+        find_nullifier_address(&self.program_id, &self.tornado_instance, nullifier_hash).0
+    }
+}
+
+// Start a test validator with a token instance whose vault holds `vault_amount`
+async fn setup(vault_amount: u64) -> (BanksClient, Keypair, Hash, TokenInstance) {
+    setup_with_fee_cap(vault_amount, MAX_FEE_BPS_CAP).await
+}
+
+// Same as `setup`, with relayer fees capped at `max_fee_bps`
+async fn setup_with_fee_cap(
+    vault_amount: u64,
+    max_fee_bps: u16,
+) -> (BanksClient, Keypair, Hash, TokenInstance) {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+
+    let tornado_instance = Keypair::new().pubkey();
+    let token_mint = Pubkey::new_unique();
+    let mint_authority = Keypair::new();
+    let admin = Keypair::new();
+    let (merkle_tree, merkle_tree_bump) = find_merkle_tree_address(&program_id, &tornado_instance);
+    let (verifier, _) = find_verifier_address(&program_id, &tornado_instance);
+    let (vault_authority, vault_authority_bump) = find_vault_authority_address(&program_id, &tornado_instance);
+    let vault = get_vault_address(&program_id, &tornado_instance, &token_mint);
+    let rent = Rent::default();
+
+    // Proving key for the public-input circuit
+    let pk = Groth16::<Bn254>::generate_random_parameters_with_reduction(
+        PublicInputsCircuit {
+            inputs: [Fr::from(0u64); NUM_PUBLIC_INPUTS],
+        },
+        &mut ark_std::test_rng(),
+    )
+    .unwrap();
+    let mut verifying_key = Vec::new();
+    pk.vk.serialize_compressed(&mut verifying_key).unwrap();
+
+    // Token mint and the vault holding the deposits
+    let mut mint_data = vec![0u8; Mint::LEN];
+    Mint::pack(
+        Mint {
+            mint_authority: COption::Some(mint_authority.pubkey()),
+            supply: vault_amount,
+            decimals: 6,
+            is_initialized: true,
+            freeze_authority: COption::None,
+        },
+        &mut mint_data,
+    )
+    .unwrap();
+    program_test.add_account(
+        token_mint,
+        Account {
+            lamports: rent.minimum_balance(Mint::LEN),
+            data: mint_data,
+            owner: spl_token::id(),
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+    let mut vault_data = vec![0u8; TokenAccount::LEN];
+    TokenAccount::pack(
+        TokenAccount {
+            mint: token_mint,
+            owner: vault_authority,
+            amount: vault_amount,
+            delegate: COption::None,
+            state: AccountState::Initialized,
+            is_native: COption::None,
+            delegated_amount: 0,
+            close_authority: COption::None,
+        },
+        &mut vault_data,
+    )
+    .unwrap();
+    program_test.add_account(
+        vault,
+        Account {
+            lamports: rent.minimum_balance(TokenAccount::LEN),
+            data: vault_data,
+            owner: spl_token::id(),
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    // Token instance, Merkle tree with the root in its history, and verifier
+    let instance = TornadoInstance {
+        version: TornadoInstance::VERSION,
+        is_initialized: true,
+        denomination: DENOMINATION,
+        merkle_tree_height: 20,
+        merkle_tree,
+        verifier,
+        token_mint: Some(token_mint),
+        admin: admin.pubkey(),
+        paused: false,
+        variable_amount: false,
+        max_fee_bps,
+        curve: CURVE_BN254,
+        max_root_age: 0,
+        strict_tx: false,
+        compliance_list: None,
+        proof_system: PROOF_SYSTEM_GROTH16,
+        vault_authority_bump,
+        vk_hash: hash_verifying_key(&verifying_key),
+        deposit_fee: 0,
+        treasury: Pubkey::default(),
+        min_anonymity_set: 0,
+        withdraw_delay_secs: 0,
+        merkle_tree_bump,
+        max_sol_fee: 0,
+    };
+    let mut instance_data = vec![0u8; TornadoInstance::LEN];
+    instance.pack_into_slice(&mut instance_data);
+    program_test.add_account(
+        tornado_instance,
+        Account {
+            lamports: rent.minimum_balance(TornadoInstance::LEN),
+            data: instance_data,
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+    let mut roots = vec![[0u8; 32]; ROOT_HISTORY_SIZE];
+    roots[0] = ROOT;
+    let tree = MerkleTree {
+        version: MerkleTree::VERSION,
+        is_initialized: true,
+        height: 20,
+        hash_algo: HASH_ALGO_MIMC,
+        next_index: 1,
+        current_root_index: 0,
+        nullifier_count: 0,
+        roots,
+        root_timestamps: vec![0; ROOT_HISTORY_SIZE],
+        filled_subtrees: vec![[0u8; 32]; 20],
+        nullifier_hashes: vec![],
+    };
+    program_test.add_account(
+        merkle_tree,
+        Account {
+            lamports: 1_000_000_000,
+            data: tree.try_to_vec().unwrap(),
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+    let verifier_state = VerifierState {
+        is_initialized: true,
+        verifying_key,
+    };
+    program_test.add_account(
+        verifier,
+        Account {
+            lamports: 1_000_000_000,
+            data: verifier_state.try_to_vec().unwrap(),
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // The payer relays the withdrawals and collects the fee in its own token account
+    let create_relayer_ata_ix = spl_associated_token_account::instruction::create_associated_token_account(
+        &payer.pubkey(),
+        &payer.pubkey(),
+        &token_mint,
+        &spl_token::id(),
+    );
+    let mut transaction = Transaction::new_with_payer(&[create_relayer_ata_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    (
+        banks_client,
+        payer,
+        recent_blockhash,
+        TokenInstance {
+            program_id,
+            tornado_instance,
+            merkle_tree,
+            token_mint,
+            vault,
+            mint_authority,
+            admin,
+            pk,
+        },
+    )
+}
+
+fn token_balance(account: Account) -> u64 {
+    TokenAccount::unpack(&account.data).unwrap().amount
+}
+
+#[tokio::test]
+async fn test_withdraw_token_to_associated_token_account() {
+    let (mut banks_client, payer, recent_blockhash, instance) = setup(10 * DENOMINATION).await;
+    let withdraw_tx = |nullifier_hash: [u8; 32], recipient: &Pubkey, fee: u64, refund: u64| {
+        let withdraw_ix = instance.withdraw_ix(&payer.pubkey(), nullifier_hash, recipient, fee, refund);
+        let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        transaction
+    };
+    let relayer_token_account = get_associated_token_address(&payer.pubkey(), &instance.token_mint);
+
+    let recipient = Pubkey::new_unique();
+    let recipient_token_account = get_associated_token_address(&recipient, &instance.token_mint);
+    let token_account_rent = Rent::default().minimum_balance(TokenAccount::LEN);
+    assert!(banks_client.get_account(recipient_token_account).await.unwrap().is_none());
+
+    // A missing associated token account is created out of the refund
+    let refund = token_account_rent + 1_000_000;
+    banks_client
+        .process_transaction(withdraw_tx([1u8; 32], &recipient, FEE, refund))
+        .await
+        .unwrap();
+    let recipient_token = banks_client.get_account(recipient_token_account).await.unwrap().unwrap();
+    assert_eq!(recipient_token.lamports, token_account_rent);
+    assert_eq!(token_balance(recipient_token), DENOMINATION - FEE);
+    assert_eq!(banks_client.get_balance(recipient).await.unwrap(), 1_000_000);
+    let relayer_token = banks_client.get_account(relayer_token_account).await.unwrap().unwrap();
+    assert_eq!(token_balance(relayer_token), FEE);
+
+    // An existing associated token account is reused and the full refund paid out
+    banks_client
+        .process_transaction(withdraw_tx([2u8; 32], &recipient, FEE, 500_000))
+        .await
+        .unwrap();
+    let recipient_token = banks_client.get_account(recipient_token_account).await.unwrap().unwrap();
+    assert_eq!(token_balance(recipient_token), 2 * (DENOMINATION - FEE));
+    assert_eq!(banks_client.get_balance(recipient).await.unwrap(), 1_500_000);
+
+    // The refund must cover the rent of a new associated token account
+    let new_recipient = Pubkey::new_unique();
+    let result = banks_client
+        .process_transaction(withdraw_tx([3u8; 32], &new_recipient, FEE, token_account_rent - 1))
+        .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::InsufficientFunds as u32)
+        )
+    );
+
+    // Any token account other than the recipient's associated one is rejected
+    let mut withdraw_ix = instance.withdraw_ix(&payer.pubkey(), [4u8; 32], &recipient, FEE, 0);
+    withdraw_ix.accounts[6].pubkey = relayer_token_account;
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::InvalidTokenAccount as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_withdraw_token_fee_cap() {
+    let (mut banks_client, payer, recent_blockhash, instance) = setup(10 * DENOMINATION).await;
+    let recipient = Pubkey::new_unique();
+    let refund = Rent::default().minimum_balance(TokenAccount::LEN) + 1_000_000;
+    let withdraw_tx = |nullifier_hash: [u8; 32], fee: u64| {
+        let withdraw_ix = instance.withdraw_ix(&payer.pubkey(), nullifier_hash, &recipient, fee, refund);
+        let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        transaction
+    };
+    let relayer_token_account = get_associated_token_address(&payer.pubkey(), &instance.token_mint);
+    let max_fee = DENOMINATION * MAX_FEE_BPS_CAP as u64 / 10_000;
+
+    // A fee above the cap is rejected even with a valid proof
+    assert_eq!(
+        banks_client
+            .process_transaction(withdraw_tx([6u8; 32], max_fee + 1))
+            .await
+            .unwrap_err()
+            .unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::InvalidFee as u32)
+        )
+    );
+    assert!(banks_client
+        .get_account(instance.nullifier_marker(&[6u8; 32]))
+        .await
+        .unwrap()
+        .is_none());
+
+    // A fee within the cap is paid to the relayer
+    banks_client
+        .process_transaction(withdraw_tx([7u8; 32], FEE))
+        .await
+        .unwrap();
+    let relayer_token = banks_client.get_account(relayer_token_account).await.unwrap().unwrap();
+    assert_eq!(token_balance(relayer_token), FEE);
+}
+
+#[tokio::test]
+async fn test_withdraw_token_whole_denomination_fee() {
+    let (mut banks_client, payer, recent_blockhash, instance) =
+        setup_with_fee_cap(10 * DENOMINATION, MAX_FEE_BPS).await;
+    let recipient = Pubkey::new_unique();
+    let recipient_token_account = get_associated_token_address(&recipient, &instance.token_mint);
+    let relayer_token_account = get_associated_token_address(&payer.pubkey(), &instance.token_mint);
+    let refund = Rent::default().minimum_balance(TokenAccount::LEN);
+
+    // With an uncapped fee the relayer can take the whole denomination
+    let withdraw_ix = instance.withdraw_ix(&payer.pubkey(), [8u8; 32], &recipient, DENOMINATION, refund);
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The recipient gets nothing and the relayer everything
+    let recipient_token = banks_client.get_account(recipient_token_account).await.unwrap().unwrap();
+    assert_eq!(token_balance(recipient_token), 0);
+    let relayer_token = banks_client.get_account(relayer_token_account).await.unwrap().unwrap();
+    assert_eq!(token_balance(relayer_token), DENOMINATION);
+    let vault = banks_client.get_account(instance.vault).await.unwrap().unwrap();
+    assert_eq!(token_balance(vault), 9 * DENOMINATION);
+
+    // A fee above the denomination is still rejected
+    let withdraw_ix = instance.withdraw_ix(&payer.pubkey(), [9u8; 32], &recipient, DENOMINATION + 1, 0);
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::InvalidFee as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_failed_transfer_leaves_nullifier_unspent() {
+    // The vault can pay the recipient but not the relayer's fee as well
+    let (mut banks_client, payer, recent_blockhash, instance) = setup(DENOMINATION - 1).await;
+    let nullifier_hash = [5u8; 32];
+    let recipient = Pubkey::new_unique();
+    let recipient_token_account = get_associated_token_address(&recipient, &instance.token_mint);
+    let token_account_rent = Rent::default().minimum_balance(TokenAccount::LEN);
+
+    // The nullifier marker, the new token account and the recipient's transfer are all rolled back
+    let withdraw_ix = instance.withdraw_ix(&payer.pubkey(), nullifier_hash, &recipient, FEE, token_account_rent + 1_000_000);
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(spl_token::error::TokenError::InsufficientFunds as u32)
+        )
+    );
+    let nullifier_marker = instance.nullifier_marker(&nullifier_hash);
+    assert!(banks_client.get_account(nullifier_marker).await.unwrap().is_none());
+    assert!(banks_client.get_account(recipient_token_account).await.unwrap().is_none());
+    assert_eq!(banks_client.get_balance(recipient).await.unwrap(), 0);
+    let vault = banks_client.get_account(instance.vault).await.unwrap().unwrap();
+    assert_eq!(token_balance(vault), DENOMINATION - 1);
+
+    // Once the vault is topped up, the same note can still be withdrawn
+    let mint_ix = spl_token::instruction::mint_to(
+        &spl_token::id(),
+        &instance.token_mint,
+        &instance.vault,
+        &instance.mint_authority.pubkey(),
+        &[],
+        1,
+    )
+    .unwrap();
+    let withdraw_ix = instance.withdraw_ix(&payer.pubkey(), nullifier_hash, &recipient, FEE, token_account_rent + 2_000_000);
+    let mut transaction = Transaction::new_with_payer(&[mint_ix, withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &instance.mint_authority], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let nullifier_marker_account = banks_client.get_account(nullifier_marker).await.unwrap().unwrap();
+    assert_eq!(nullifier_marker_account.owner, instance.program_id);
+    let recipient_token = banks_client.get_account(recipient_token_account).await.unwrap().unwrap();
+    assert_eq!(token_balance(recipient_token), DENOMINATION - FEE);
+    assert_eq!(banks_client.get_balance(recipient).await.unwrap(), 2_000_000);
+    let vault = banks_client.get_account(instance.vault).await.unwrap().unwrap();
+    assert_eq!(token_balance(vault), 0);
+}
+
+#[tokio::test]
+async fn test_withdraw_token_signer_and_writable_accounts() {
+    let (mut banks_client, payer, recent_blockhash, instance) = setup(10 * DENOMINATION).await;
+    let nullifier_hash = [10u8; 32];
+    let recipient = Pubkey::new_unique();
+
+    // A payer that doesn't sign can't fund the nullifier marker
+    let other_payer = Keypair::new().pubkey();
+    let mut withdraw_ix = instance.withdraw_ix(&other_payer, nullifier_hash, &recipient, 0, 0);
+    withdraw_ix.accounts[0].is_signer = false;
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature)
+    );
+
+    // Every account the withdrawal writes to must be writable
+    let withdraw_ix = instance.withdraw_ix(&payer.pubkey(), nullifier_hash, &recipient, FEE, 0);
+    for index in [1, 2, 4, 5, 6, 7, 8] {
+        let mut read_only_ix = withdraw_ix.clone();
+        read_only_ix.accounts[index].is_writable = false;
+        let mut transaction = Transaction::new_with_payer(&[read_only_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_eq!(
+            banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(TornadoError::AccountNotWritable as u32)
+            )
+        );
+    }
+    assert!(banks_client
+        .get_account(instance.nullifier_marker(&nullifier_hash))
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_withdraw_token_fee_in_sol() {
+    let (mut banks_client, payer, recent_blockhash, instance) = setup(10 * DENOMINATION).await;
+    let recipient = Pubkey::new_unique();
+    let recipient_token_account = get_associated_token_address(&recipient, &instance.token_mint);
+    let relayer_token_account = get_associated_token_address(&payer.pubkey(), &instance.token_mint);
+    let rent = Rent::default();
+    let refund = rent.minimum_balance(TokenAccount::LEN) + 1_000_000;
+    // Above the token fee cap, which doesn't apply to fees paid in SOL
+    let sol_fee = 50_000;
+    let max_sol_fee = 2 * sol_fee;
+
+    // Only the admin can cap fees paid in SOL
+    let set_ix =
+        set_max_sol_fee(&instance.program_id, &payer.pubkey(), &instance.tornado_instance, max_sol_fee).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(TornadoError::Unauthorized as u32))
+    );
+    let set_ix = set_max_sol_fee(
+        &instance.program_id,
+        &instance.admin.pubkey(),
+        &instance.tornado_instance,
+        max_sol_fee,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &instance.admin], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The instance holds no SOL above its rent-exempt minimum yet
+    let withdraw_ix = instance.sol_fee_withdraw_ix(&payer.pubkey(), [8u8; 32], &recipient, sol_fee, refund);
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::InsufficientFunds as u32)
+        )
+    );
+    assert!(banks_client
+        .get_account(instance.nullifier_marker(&[8u8; 32]))
+        .await
+        .unwrap()
+        .is_none());
+
+    // Anyone can fund the instance's SOL fees
+    let fund_ix = system_instruction::transfer(&payer.pubkey(), &instance.tornado_instance, 10 * sol_fee);
+    let mut transaction = Transaction::new_with_payer(&[fund_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // A fee above the cap fails even though the instance could pay it
+    let withdraw_ix = instance.sol_fee_withdraw_ix(&payer.pubkey(), [9u8; 32], &recipient, max_sol_fee + 1, refund);
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(TornadoError::InvalidFee as u32))
+    );
+
+    // The proof commits to the fee's unit: a relayer can't flip it on a valid proof
+    for proven_in_sol in [false, true] {
+        let withdraw_ix = withdraw_token(
+            &instance.program_id,
+            &payer.pubkey(),
+            &instance.tornado_instance,
+            &instance.merkle_tree,
+            &instance.token_mint,
+            &recipient,
+            &payer.pubkey(),
+            &relayer_token_account,
+            prove(
+                &instance.pk,
+                &instance.tornado_instance,
+                &[9u8; 32],
+                &recipient,
+                &payer.pubkey(),
+                FEE,
+                refund,
+                proven_in_sol,
+            ),
+            ROOT,
+            [9u8; 32],
+            FEE,
+            refund,
+            !proven_in_sol,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_eq!(
+            banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::Custom(TornadoError::InvalidProof as u32))
+        );
+    }
+
+    // Token pool with a SOL fee: the recipient gets the whole denomination and the relayer the lamports
+    let instance_lamports = banks_client.get_balance(instance.tornado_instance).await.unwrap();
+    let payer_lamports = banks_client.get_balance(payer.pubkey()).await.unwrap();
+    let withdraw_ix = instance.sol_fee_withdraw_ix(&payer.pubkey(), [9u8; 32], &recipient, sol_fee, refund);
+    let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    let transaction_fee = banks_client
+        .get_fee_for_message(transaction.message().clone())
+        .await
+        .unwrap()
+        .unwrap();
+    banks_client.process_transaction(transaction).await.unwrap();
+    let recipient_token = banks_client.get_account(recipient_token_account).await.unwrap().unwrap();
+    assert_eq!(token_balance(recipient_token), DENOMINATION);
+    let relayer_token = banks_client.get_account(relayer_token_account).await.unwrap().unwrap();
+    assert_eq!(token_balance(relayer_token), 0);
+    assert_eq!(
+        banks_client.get_balance(instance.tornado_instance).await.unwrap(),
+        instance_lamports - sol_fee
+    );
+    // The relayer also paid for the nullifier marker and the refund
+    assert_eq!(
+        banks_client.get_balance(payer.pubkey()).await.unwrap(),
+        payer_lamports - transaction_fee - rent.minimum_balance(NullifierMarker::LEN) - refund + sol_fee
+    );
+
+    // Token pool with a token fee: the instance's SOL is left alone
+    banks_client
+        .process_transaction({
+            let withdraw_ix = instance.withdraw_ix(&payer.pubkey(), [10u8; 32], &recipient, FEE, 500_000);
+            let mut transaction = Transaction::new_with_payer(&[withdraw_ix], Some(&payer.pubkey()));
+            transaction.sign(&[&payer], recent_blockhash);
+            transaction
+        })
+        .await
+        .unwrap();
+    let recipient_token = banks_client.get_account(recipient_token_account).await.unwrap().unwrap();
+    assert_eq!(token_balance(recipient_token), 2 * DENOMINATION - FEE);
+    let relayer_token = banks_client.get_account(relayer_token_account).await.unwrap().unwrap();
+    assert_eq!(token_balance(relayer_token), FEE);
+    assert_eq!(
+        banks_client.get_balance(instance.tornado_instance).await.unwrap(),
+        instance_lamports - sol_fee
+    );
+}
@@ -1,156 +1,1503 @@
 //! Tests for the Tornado Cash Privacy Solution
 
+mod common;
+
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::{
-    hash::Hash,
-    program_pack::Pack,
-    pubkey::Pubkey,
-    system_instruction,
-};
+use solana_program::{clock::Clock, program_pack::Pack, pubkey::Pubkey, rent::Rent, system_instruction};
 use solana_program_test::*;
 use solana_sdk::{
     account::Account,
+    instruction::InstructionError,
     signature::{Keypair, Signer},
-    transaction::Transaction,
+    transaction::{Transaction, TransactionError},
 };
 
 use tornado_svm::{
-    instruction::{deposit, initialize, withdraw},
-    state::{MerkleTree, TornadoInstance},
-    utils::{compute_commitment, compute_nullifier_hash},
+    error::TornadoError,
+    instruction::{
+        close_instance, deposit, deposit_batch, deposit_variable, get_root, initialize, migrate, set_deposit_fee,
+        MAX_DEPOSIT_BATCH_SIZE, MAX_ENCRYPTED_NOTE_SIZE,
+    },
+    merkle_tree::{get_zero_value, HASH_ALGO_MIMC, HASH_ALGO_POSEIDON, ZERO_VALUE},
+    off_chain::TreeBuilder,
+    state::{
+        CommitmentMarker, EncryptedNote, MerkleTree, MerkleTreeV1, Registry, RegistryEntry, TornadoInstance,
+        TornadoInstanceV1, VerifierState, MAX_DEPOSIT_FEE_BPS, MAX_FEE_BPS, ROOT_HISTORY_SIZE,
+    },
+    utils::{
+        compute_amount_commitment, compute_commitment, find_commitment_address, find_encrypted_note_address,
+        find_merkle_tree_address, find_registry_address, find_vault_authority_address, find_verifier_address,
+    },
+    verifier::{
+        hash_verifying_key, CURVE_BN254, NUM_PUBLIC_INPUTS, NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS, PROOF_SYSTEM_GROTH16,
+    },
 };
 
+use common::{create_test_verifying_key, initialize_instance};
+
 #[tokio::test]
-async fn test_tornado_flow() {
-    // Create program test environment
+async fn test_initialize_and_deposit() {
     let program_id = Pubkey::new_unique();
-    let mut program_test = ProgramTest::new(
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // Initialize without creating the Merkle tree account beforehand
+    let tornado_instance = Keypair::new();
+    let denomination = 100_000_000;
+    let merkle_tree_height = 8;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        merkle_tree_height,
+        false,
+    )
+    .await
+    .unwrap();
+
+    // The Merkle tree PDA was created and holds an empty tree
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    assert_eq!(merkle_tree_account.owner, program_id);
+    assert_eq!(
+        merkle_tree_account.data.len(),
+        MerkleTree::get_account_size_bounded(merkle_tree_height, ROOT_HISTORY_SIZE as u8)
+    );
+    let tree = MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap();
+    assert!(tree.is_initialized);
+    assert_eq!(tree.next_index, 0);
+    assert_eq!(tree.roots[0], get_zero_value(HASH_ALGO_MIMC, merkle_tree_height as usize).unwrap());
+
+    // Deposit straight away
+    let commitment = compute_commitment(&[1u8; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap();
+    let deposit_ix = deposit(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        commitment,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The commitment was inserted and a new root recorded
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap();
+    assert_eq!(tree.next_index, 1);
+    assert_eq!(tree.current_root_index, 1);
+    assert_ne!(tree.roots[1], tree.roots[0]);
+
+    // The commitment marker records the leaf index
+    let (commitment_marker, _) =
+        find_commitment_address(&program_id, &tornado_instance.pubkey(), &commitment);
+    let marker_account = banks_client.get_account(commitment_marker).await.unwrap().unwrap();
+    let marker = CommitmentMarker::unpack(&marker_account.data).unwrap();
+    assert_eq!(marker.leaf_index, 0);
+
+    // The deposit was credited to the instance
+    let instance_account = banks_client
+        .get_account(tornado_instance.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let rent = banks_client.get_rent().await.unwrap();
+    assert_eq!(
+        instance_account.lamports,
+        rent.minimum_balance(TornadoInstance::LEN) + denomination
+    );
+
+    // The latest root can be read back without a signature from the instance
+    let get_root_ix = get_root(&program_id, &tornado_instance.pubkey(), &merkle_tree).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[get_root_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_minimum_tree_height() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // A tree of height 1 holds two leaves
+    let tornado_instance = Keypair::new();
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        100_000_000,
+        1,
+        false,
+    )
+    .await
+    .unwrap();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+
+    for i in 0..3u8 {
+        let commitment = compute_commitment(&[i; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap();
+        let deposit_ix = deposit(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            commitment,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+
+        // The deposit reports how many more the tree can take, and a full
+        // tree fails before the deposit is transferred
+        let simulation = banks_client.simulate_transaction(transaction.clone()).await.unwrap();
+        let logs = simulation.simulation_details.unwrap().logs;
+        if i < 2 {
+            let message = format!("Leaf index: {}, remaining capacity: {}", i, 1 - i);
+            assert!(logs.iter().any(|log| log.contains(&message)), "{:?}", logs);
+        } else {
+            let system_program = solana_program::system_program::id().to_string();
+            assert!(!logs.iter().any(|log| log.contains(&system_program)), "{:?}", logs);
+        }
+
+        let payer_balance = banks_client.get_balance(payer.pubkey()).await.unwrap();
+        let fee = banks_client
+            .get_fee_for_message(transaction.message.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        let result = banks_client.process_transaction(transaction).await;
+        if i < 2 {
+            result.unwrap();
+        } else {
+            // The payer only pays the transaction fee
+            assert_eq!(banks_client.get_balance(payer.pubkey()).await.unwrap(), payer_balance - fee);
+            assert_eq!(
+                result.unwrap_err().unwrap(),
+                TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(TornadoError::MerkleTreeFull as u32)
+                )
+            );
+        }
+    }
+
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap();
+    assert_eq!(tree.next_index, 2);
+}
+
+#[tokio::test]
+async fn test_deposit_encrypted_note() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        100_000_000,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+
+    // A note above the size cap is rejected
+    let commitment = compute_commitment(&[1u8; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap();
+    let deposit_ix = deposit(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        commitment,
+        Some(vec![7u8; MAX_ENCRYPTED_NOTE_SIZE + 1]),
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::InvalidInstructionData as u32)
+        )
+    );
+
+    // The note is stored with the deposit, as given
+    let ciphertext: Vec<u8> = (0..MAX_ENCRYPTED_NOTE_SIZE).map(|i| i as u8).collect();
+    let deposit_ix = deposit(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        commitment,
+        Some(ciphertext.clone()),
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let (encrypted_note, _) =
+        find_encrypted_note_address(&program_id, &tornado_instance.pubkey(), &commitment);
+    let note_account = banks_client.get_account(encrypted_note).await.unwrap().unwrap();
+    assert_eq!(note_account.owner, program_id);
+    let rent = banks_client.get_rent().await.unwrap();
+    assert_eq!(
+        note_account.lamports,
+        rent.minimum_balance(EncryptedNote::get_account_size(ciphertext.len()))
+    );
+    let note = EncryptedNote::try_from_slice(&note_account.data).unwrap();
+    assert!(note.is_initialized);
+    assert_eq!(note.ciphertext, ciphertext);
+}
+
+#[tokio::test]
+async fn test_deposit_expected_index() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        100_000_000,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+
+    // The first commitment takes the leaf the depositor expected, the second
+    // is rejected at a leaf already taken and accepted at the next one
+    let first = compute_commitment(&[1u8; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap();
+    let second = compute_commitment(&[3u8; 32], &[4u8; 32], &tornado_instance.pubkey()).unwrap();
+    for (commitment, expected_index, accepted) in [(first, 0, true), (second, 0, false), (second, 1, true)] {
+        let deposit_ix = deposit(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            commitment,
+            None,
+            Some(expected_index),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        let result = banks_client.process_transaction(transaction).await;
+        if accepted {
+            result.unwrap();
+        } else {
+            assert_eq!(
+                result.unwrap_err().unwrap(),
+                TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(TornadoError::UnexpectedLeafIndex as u32)
+                )
+            );
+        }
+    }
+
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::from_account_data(&merkle_tree_account.data).unwrap();
+    assert_eq!(tree.next_index, 2);
+}
+
+#[tokio::test]
+async fn test_deposit_zero_commitment() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
         "tornado_svm",
         program_id,
         processor!(tornado_svm::process_instruction),
     );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
 
-    // Create accounts
-    let payer = Keypair::new();
     let tornado_instance = Keypair::new();
-    let merkle_tree = Keypair::new();
-    let recipient = Keypair::new();
-    let relayer = Keypair::new();
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        100_000_000,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+
+    // Zero and the empty-leaf value are rejected, a real commitment is accepted
+    let commitment = compute_commitment(&[1u8; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap();
+    for (commitment, accepted) in [([0u8; 32], false), (ZERO_VALUE, false), (commitment, true)] {
+        let deposit_ix = deposit(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            commitment,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        let result = banks_client.process_transaction(transaction).await;
+        if accepted {
+            result.unwrap();
+        } else {
+            assert_eq!(
+                result.unwrap_err().unwrap(),
+                TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(TornadoError::InvalidCommitment as u32)
+                )
+            );
+        }
+    }
+
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::from_account_data(&merkle_tree_account.data).unwrap();
+    assert_eq!(tree.next_index, 1);
+}
 
-    // Add accounts to the test environment
+#[tokio::test]
+async fn test_sponsored_deposit() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+
+    // The funding account holds the denomination but pays no fees
+    let funding_account = Keypair::new();
+    let funding_balance = 1_000_000_000;
     program_test.add_account(
-        payer.pubkey(),
+        funding_account.pubkey(),
         Account {
-            lamports: 1_000_000_000,
-            data: vec![],
-            owner: solana_program::system_program::id(),
-            executable: false,
-            rent_epoch: 0,
+            lamports: funding_balance,
+            ..Account::default()
         },
     );
-
-    // Start the test environment
     let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
 
-    // Initialize the Tornado instance
-    let denomination = 100_000_000; // 1 SOL
-    let merkle_tree_height = 20;
+    let tornado_instance = Keypair::new();
+    let denomination = 100_000_000;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let instance_balance = banks_client.get_balance(tornado_instance.pubkey()).await.unwrap();
 
-    let initialize_ix = initialize(
+    // The funding account must sign the deposit it funds
+    let commitment = compute_commitment(&[1u8; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap();
+    let mut deposit_ix = deposit(
         &program_id,
         &payer.pubkey(),
         &tornado_instance.pubkey(),
+        &merkle_tree,
+        commitment,
+        None,
+        None,
+        None,
+        None,
+        Some(&funding_account.pubkey()),
+    )
+    .unwrap();
+    deposit_ix.accounts[5].is_signer = false;
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix.clone()], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature)
+    );
+
+    // The sponsor submits the deposit and the funding account provides the denomination
+    deposit_ix.accounts[5].is_signer = true;
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &funding_account], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(
+        banks_client.get_balance(funding_account.pubkey()).await.unwrap(),
+        funding_balance - denomination
+    );
+    assert_eq!(
+        banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(),
+        instance_balance + denomination
+    );
+
+    // The sponsor paid the rent of the commitment marker
+    let (commitment_marker, _) =
+        find_commitment_address(&program_id, &tornado_instance.pubkey(), &commitment);
+    let marker_account = banks_client.get_account(commitment_marker).await.unwrap().unwrap();
+    assert_eq!(CommitmentMarker::unpack(&marker_account.data).unwrap().leaf_index, 0);
+}
+
+#[tokio::test]
+async fn test_deposit_fee() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+
+    // The treasury already exists, so fees below its rent-exempt minimum can be paid to it
+    let treasury = Pubkey::new_unique();
+    let treasury_balance = 1_000_000_000;
+    program_test.add_account(
+        treasury,
+        Account {
+            lamports: treasury_balance,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let denomination = 100_000_000;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
         denomination,
-        merkle_tree_height,
+        8,
+        false,
     )
+    .await
     .unwrap();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let instance_balance = banks_client.get_balance(tornado_instance.pubkey()).await.unwrap();
+    let deposit_ix = |nullifier: u8, treasury: Option<&Pubkey>| {
+        let commitment = compute_commitment(&[nullifier; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap();
+        deposit(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            commitment,
+            None,
+            None,
+            None,
+            treasury,
+            None,
+        )
+        .unwrap()
+    };
 
-    let mut transaction = Transaction::new_with_payer(
-        &[initialize_ix],
-        Some(&payer.pubkey()),
+    // A new instance charges no fee, so deposits don't pass the treasury
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix(1, None)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(),
+        instance_balance + denomination
     );
-    transaction.sign(&[&payer, &tornado_instance], recent_blockhash);
+    assert_eq!(banks_client.get_balance(treasury).await.unwrap(), treasury_balance);
 
+    // Only the admin can set a fee
+    let deposit_fee = 1_000_000;
+    let other_admin = Keypair::new();
+    let set_fee_ix =
+        set_deposit_fee(&program_id, &other_admin.pubkey(), &tornado_instance.pubkey(), deposit_fee, &treasury).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_fee_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &other_admin], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(TornadoError::Unauthorized as u32))
+    );
+
+    // The fee is capped at `MAX_DEPOSIT_FEE_BPS` of the denomination
+    let max_deposit_fee = denomination * MAX_DEPOSIT_FEE_BPS as u64 / MAX_FEE_BPS as u64;
+    let set_fee_ix =
+        set_deposit_fee(&program_id, &payer.pubkey(), &tornado_instance.pubkey(), max_deposit_fee + 1, &treasury)
+            .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_fee_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(TornadoError::InvalidFee as u32))
+    );
+
+    let set_fee_ix =
+        set_deposit_fee(&program_id, &payer.pubkey(), &tornado_instance.pubkey(), deposit_fee, &treasury).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_fee_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
     banks_client.process_transaction(transaction).await.unwrap();
+    let instance_account = banks_client.get_account(tornado_instance.pubkey()).await.unwrap().unwrap();
+    let instance = TornadoInstance::unpack(&instance_account.data).unwrap();
+    assert_eq!((instance.deposit_fee, instance.treasury), (deposit_fee, treasury));
 
-    // Generate a nullifier and secret
-    let nullifier = [1u8; 32];
-    let secret = [2u8; 32];
+    // A deposit must then pay the instance's treasury, and no other account
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix(2, None)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys)
+    );
+    let mut transaction =
+        Transaction::new_with_payer(&[deposit_ix(2, Some(&Pubkey::new_unique()))], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(TornadoError::InvalidAccountData as u32))
+    );
 
-    // Compute the commitment
-    let commitment = compute_commitment(&nullifier, &secret);
+    // The payer funds both the denomination and the fee
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix(2, Some(&treasury))], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(),
+        instance_balance + 2 * denomination
+    );
+    assert_eq!(banks_client.get_balance(treasury).await.unwrap(), treasury_balance + deposit_fee);
 
-    // Deposit
-    let deposit_ix = deposit(
+    // A batch pays the fee once per commitment
+    let commitments = [3u8, 4].map(|nullifier| {
+        compute_commitment(&[nullifier; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap()
+    });
+    let deposit_ix = deposit_batch(
         &program_id,
         &payer.pubkey(),
         &tornado_instance.pubkey(),
-        &merkle_tree.pubkey(),
-        commitment,
+        &merkle_tree,
+        commitments.to_vec(),
+        Some(deposit_fee),
+        Some(&treasury),
     )
     .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(),
+        instance_balance + 4 * denomination
+    );
+    assert_eq!(banks_client.get_balance(treasury).await.unwrap(), treasury_balance + 3 * deposit_fee);
 
-    let mut transaction = Transaction::new_with_payer(
-        &[deposit_ix],
-        Some(&payer.pubkey()),
+    // Deposits signed with the current fee as their maximum...
+    let commitment = compute_commitment(&[5u8; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap();
+    let capped_deposit_ix = |max_deposit_fee| {
+        deposit(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            commitment,
+            None,
+            None,
+            Some(max_deposit_fee),
+            Some(&treasury),
+            None,
+        )
+        .unwrap()
+    };
+    let capped_batch_ix = deposit_batch(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        vec![commitment],
+        Some(deposit_fee),
+        Some(&treasury),
+    )
+    .unwrap();
+
+    // ...fail if the admin front-runs them by raising the fee, taking nothing
+    let raised_fee = 2 * deposit_fee;
+    let set_fee_ix =
+        set_deposit_fee(&program_id, &payer.pubkey(), &tornado_instance.pubkey(), raised_fee, &treasury).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_fee_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    for deposit_ix in [capped_deposit_ix(deposit_fee), capped_batch_ix] {
+        let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_eq!(
+            banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::Custom(TornadoError::InvalidFee as u32))
+        );
+    }
+    assert_eq!(
+        banks_client.get_balance(tornado_instance.pubkey()).await.unwrap(),
+        instance_balance + 4 * denomination
     );
+    assert_eq!(banks_client.get_balance(treasury).await.unwrap(), treasury_balance + 3 * deposit_fee);
+
+    // A depositor accepting the raised fee pays it
+    let mut transaction = Transaction::new_with_payer(&[capped_deposit_ix(raised_fee)], Some(&payer.pubkey()));
     transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        banks_client.get_balance(treasury).await.unwrap(),
+        treasury_balance + 3 * deposit_fee + raised_fee
+    );
+}
 
+#[tokio::test]
+async fn test_initialize_with_prefunded_accounts() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let rent = banks_client.get_rent().await.unwrap();
+
+    // Lamports sent to the Merkle tree and verifier addresses ahead of time don't block initialization
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let (verifier, _) = find_verifier_address(&program_id, &tornado_instance.pubkey());
+    let prefund_ixs = [
+        system_instruction::transfer(&payer.pubkey(), &merkle_tree, 1_000_000),
+        system_instruction::transfer(&payer.pubkey(), &verifier, 1_000_000),
+    ];
+    let mut transaction = Transaction::new_with_payer(&prefund_ixs, Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
     banks_client.process_transaction(transaction).await.unwrap();
 
-    // Compute the nullifier hash
-    let nullifier_hash = compute_nullifier_hash(&nullifier);
+    let merkle_tree_height = 8;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        100_000_000,
+        merkle_tree_height,
+        false,
+    )
+    .await
+    .unwrap();
+
+    // The prefunded accounts were topped up to exactly their rent-exempt minimum
+    let merkle_tree_size = MerkleTree::get_account_size_bounded(merkle_tree_height, ROOT_HISTORY_SIZE as u8);
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    assert_eq!(merkle_tree_account.owner, program_id);
+    assert_eq!(merkle_tree_account.data.len(), merkle_tree_size);
+    assert_eq!(merkle_tree_account.lamports, rent.minimum_balance(merkle_tree_size));
+    let tree = MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap();
+    assert!(tree.is_initialized);
+    let verifier_account = banks_client.get_account(verifier).await.unwrap().unwrap();
+    assert_eq!(verifier_account.owner, program_id);
+    assert!(rent.is_exempt(verifier_account.lamports, verifier_account.data.len()));
+}
+
+#[tokio::test]
+async fn test_migrate_v1_accounts() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+
+    // An instance and Merkle tree stored in the unversioned layouts, with their verifier
+    let tornado_instance = Keypair::new();
+    let (merkle_tree, merkle_tree_bump) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let verifier = Pubkey::new_unique();
+    let height = 8;
+    let legacy_instance = TornadoInstanceV1 {
+        is_initialized: true,
+        denomination: 100_000_000,
+        merkle_tree_height: height,
+        merkle_tree,
+        verifier,
+        token_mint: None,
+        admin: Pubkey::new_unique(),
+        paused: false,
+        variable_amount: false,
+    };
+    let mut roots = vec![[0u8; 32]; ROOT_HISTORY_SIZE];
+    roots[0] = get_zero_value(HASH_ALGO_MIMC, height as usize).unwrap();
+    let legacy_tree = MerkleTreeV1 {
+        is_initialized: true,
+        height,
+        next_index: 0,
+        current_root_index: 0,
+        roots,
+        filled_subtrees: (0..height as usize).map(|i| get_zero_value(HASH_ALGO_MIMC, i).unwrap()).collect(),
+        nullifier_hashes: vec![],
+    };
+    let verifying_key = create_test_verifying_key(NUM_PUBLIC_INPUTS);
+    let verifier_state = VerifierState {
+        is_initialized: true,
+        verifying_key: verifying_key.clone(),
+    };
+    let rent = Rent::default();
+    for (address, data) in [
+        (tornado_instance.pubkey(), legacy_instance.try_to_vec().unwrap()),
+        (merkle_tree, legacy_tree.try_to_vec().unwrap()),
+        (verifier, verifier_state.try_to_vec().unwrap()),
+    ] {
+        program_test.add_account(
+            address,
+            Account {
+                lamports: rent.minimum_balance(data.len()),
+                data,
+                owner: program_id,
+                executable: false,
+                rent_epoch: 0,
+            },
+        );
+    }
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let deposit_tx = |commitment: [u8; 32]| {
+        let deposit_ix = deposit(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            commitment,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        transaction
+    };
+    // Deposits are rejected until the accounts are migrated
+    let commitment = compute_commitment(&[1u8; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap();
+    assert_eq!(
+        banks_client.process_transaction(deposit_tx(commitment)).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::UnsupportedStateVersion as u32)
+        )
+    );
+
+    let migrate_ix = migrate(&program_id, &payer.pubkey(), &tornado_instance.pubkey(), &merkle_tree, &verifier).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[migrate_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
 
-    // Get the Merkle root
-    let merkle_tree_account = banks_client
-        .get_account(merkle_tree.pubkey())
+    // Both accounts keep their fields and are grown to the current sizes, and
+    // the instance records the hash of its verifying key
+    let instance_account = banks_client
+        .get_account(tornado_instance.pubkey())
         .await
         .unwrap()
         .unwrap();
-    let merkle_tree_data = MerkleTree::try_from_slice(&merkle_tree_account.data).unwrap();
-    let root = merkle_tree_data.roots[merkle_tree_data.current_root_index as usize];
+    assert_eq!(instance_account.data.len(), TornadoInstance::LEN);
+    assert!(instance_account.lamports >= rent.minimum_balance(TornadoInstance::LEN));
+    let instance = TornadoInstance::unpack(&instance_account.data).unwrap();
+    let (_, vault_authority_bump) = find_vault_authority_address(&program_id, &tornado_instance.pubkey());
+    assert_eq!(
+        instance,
+        TornadoInstance {
+            vault_authority_bump,
+            vk_hash: hash_verifying_key(&verifying_key),
+            merkle_tree_bump,
+            ..TornadoInstance::from(legacy_instance)
+        }
+    );
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    assert_eq!(
+        merkle_tree_account.data.len(),
+        MerkleTree::get_account_size_bounded(height, ROOT_HISTORY_SIZE as u8)
+    );
+    let tree = MerkleTree::from_account_data(&merkle_tree_account.data).unwrap();
+    // The legacy roots count as inserted at the migration
+    let clock = banks_client.get_sysvar::<Clock>().await.unwrap();
+    assert_eq!(
+        tree,
+        MerkleTree {
+            root_timestamps: vec![clock.unix_timestamp; ROOT_HISTORY_SIZE],
+            ..MerkleTree::from(legacy_tree)
+        }
+    );
+    assert_eq!(tree.version, MerkleTree::VERSION);
 
-    // Generate a dummy proof (in a real scenario, this would be a valid zkSNARK proof)
-    let proof = vec![0u8; 256];
+    // Deposits go through after the migration
+    let commitment = compute_commitment(&[3u8; 32], &[4u8; 32], &tornado_instance.pubkey()).unwrap();
+    banks_client.process_transaction(deposit_tx(commitment)).await.unwrap();
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::from_account_data(&merkle_tree_account.data).unwrap();
+    assert_eq!(tree.next_index, 1);
+}
 
-    // Withdraw
-    let withdraw_ix = withdraw(
+#[tokio::test]
+async fn test_variable_amount_deposit() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // A variable-amount instance accepting up to 1 SOL per deposit
+    let tornado_instance = Keypair::new();
+    let max_amount = 1_000_000_000;
+    let rent = banks_client.get_rent().await.unwrap();
+    let create_instance_ix = system_instruction::create_account(
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        rent.minimum_balance(TornadoInstance::LEN),
+        TornadoInstance::LEN as u64,
+        &program_id,
+    );
+    let initialize_ix = initialize(
         &program_id,
         &payer.pubkey(),
         &tornado_instance.pubkey(),
-        &merkle_tree.pubkey(),
-        &recipient.pubkey(),
-        &relayer.pubkey(),
-        proof,
-        root,
-        nullifier_hash,
-        0, // No fee
-        0, // No refund
+        max_amount,
+        8,
+        ROOT_HISTORY_SIZE as u8,
+        0,
+        None,
+        true,
+        MAX_FEE_BPS,
+        HASH_ALGO_MIMC,
+        CURVE_BN254,
+        false,
+        PROOF_SYSTEM_GROTH16,
+        create_test_verifying_key(NUM_VARIABLE_AMOUNT_PUBLIC_INPUTS),
     )
     .unwrap();
-
     let mut transaction = Transaction::new_with_payer(
-        &[withdraw_ix],
+        &[create_instance_ix, initialize_ix],
         Some(&payer.pubkey()),
     );
+    transaction.sign(&[&payer, &tornado_instance], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let instance_account = banks_client
+        .get_account(tornado_instance.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let instance = TornadoInstance::unpack(&instance_account.data).unwrap();
+    assert!(instance.variable_amount);
+
+    // Deposit part of the maximum
+    let commitment = compute_commitment(&[1u8; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap();
+    let amount = 300_000_000;
+    let deposit_ix = deposit_variable(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        commitment,
+        amount,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The leaf binding the amount was inserted
+    let leaf = compute_amount_commitment(&commitment, amount).unwrap();
+    let (commitment_marker, _) =
+        find_commitment_address(&program_id, &tornado_instance.pubkey(), &leaf);
+    let marker_account = banks_client.get_account(commitment_marker).await.unwrap().unwrap();
+    assert_eq!(CommitmentMarker::unpack(&marker_account.data).unwrap().leaf_index, 0);
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap();
+    assert_eq!(tree.next_index, 1);
+    assert_eq!(tree.filled_subtrees[0], leaf);
+
+    // Only the deposited amount was credited to the instance
+    let instance_account = banks_client
+        .get_account(tornado_instance.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        instance_account.lamports,
+        rent.minimum_balance(TornadoInstance::LEN) + amount
+    );
+
+    // A fixed-denomination deposit is rejected
+    let deposit_ix = deposit(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        &merkle_tree,
+        commitment,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
     transaction.sign(&[&payer], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::InvalidInstructionData as u32)
+        )
+    );
+}
 
+#[tokio::test]
+async fn test_poseidon_instance() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // An instance whose tree is hashed with Poseidon
+    let tornado_instance = Keypair::new();
+    let merkle_tree_height = 8;
+    let rent = banks_client.get_rent().await.unwrap();
+    let create_instance_ix = system_instruction::create_account(
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        rent.minimum_balance(TornadoInstance::LEN),
+        TornadoInstance::LEN as u64,
+        &program_id,
+    );
+    let initialize_ix = initialize(
+        &program_id,
+        &payer.pubkey(),
+        &tornado_instance.pubkey(),
+        100_000_000,
+        merkle_tree_height,
+        ROOT_HISTORY_SIZE as u8,
+        0,
+        None,
+        false,
+        MAX_FEE_BPS,
+        HASH_ALGO_POSEIDON,
+        CURVE_BN254,
+        false,
+        PROOF_SYSTEM_GROTH16,
+        create_test_verifying_key(NUM_PUBLIC_INPUTS),
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[create_instance_ix, initialize_ix],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &tornado_instance], recent_blockhash);
     banks_client.process_transaction(transaction).await.unwrap();
 
-    // Verify that the recipient received the funds
-    let recipient_account = banks_client
-        .get_account(recipient.pubkey())
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap();
+    assert_eq!(tree.hash_algo, HASH_ALGO_POSEIDON);
+    assert_eq!(tree.roots[0], get_zero_value(HASH_ALGO_POSEIDON, merkle_tree_height as usize).unwrap());
+
+    // Deposit a few commitments
+    let commitments: Vec<[u8; 32]> = (1..=3u8)
+        .map(|i| compute_commitment(&[i; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap())
+        .collect();
+    let deposit_ixs: Vec<_> = commitments
+        .iter()
+        .map(|commitment| {
+            deposit(
+                &program_id,
+                &payer.pubkey(),
+                &tornado_instance.pubkey(),
+                &merkle_tree,
+                *commitment,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap()
+        })
+        .collect();
+    let mut transaction = Transaction::new_with_payer(&deposit_ixs, Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The on-chain root is the Poseidon root of the deposits, not the MiMC one
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap();
+    let root = tree.roots[tree.current_root_index as usize];
+    assert_eq!(
+        root,
+        TreeBuilder::from_leaves(merkle_tree_height, HASH_ALGO_POSEIDON, &commitments)
+            .unwrap()
+            .root()
+            .unwrap()
+    );
+    assert_ne!(
+        root,
+        TreeBuilder::from_leaves(merkle_tree_height, HASH_ALGO_MIMC, &commitments)
+            .unwrap()
+            .root()
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_deposit_batch() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let tornado_instance = Keypair::new();
+    let denomination = 100_000_000;
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        denomination,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+
+    let commitments: Vec<[u8; 32]> = (0..6u8)
+        .map(|i| compute_commitment(&[i; 32], &[2u8; 32], &tornado_instance.pubkey()).unwrap())
+        .collect();
+    let deposit_batch_tx = |commitments: &[[u8; 32]]| {
+        let deposit_ix = deposit_batch(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            commitments.to_vec(),
+            None,
+            None,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        transaction
+    };
+
+    // Deposit four commitments at once
+    banks_client
+        .process_transaction(deposit_batch_tx(&commitments[..4]))
+        .await
+        .unwrap();
+
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    let tree = MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap();
+    assert_eq!(tree.next_index, 4);
+    assert_eq!(tree.current_root_index, 4);
+    for (i, commitment) in commitments[..4].iter().enumerate() {
+        let (commitment_marker, _) =
+            find_commitment_address(&program_id, &tornado_instance.pubkey(), commitment);
+        let marker_account = banks_client.get_account(commitment_marker).await.unwrap().unwrap();
+        assert_eq!(CommitmentMarker::unpack(&marker_account.data).unwrap().leaf_index, i as u32);
+    }
+
+    // The whole batch was paid for in one transfer
+    let rent = banks_client.get_rent().await.unwrap();
+    let instance_account = banks_client
+        .get_account(tornado_instance.pubkey())
         .await
         .unwrap()
         .unwrap();
-    assert_eq!(recipient_account.lamports, denomination);
+    assert_eq!(
+        instance_account.lamports,
+        rent.minimum_balance(TornadoInstance::LEN) + 4 * denomination
+    );
+
+    // A batch whose third commitment was already deposited is rejected as a whole
+    let result = banks_client
+        .process_transaction(deposit_batch_tx(&[commitments[4], commitments[5], commitments[0]]))
+        .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::CommitmentAlreadyExists as u32)
+        )
+    );
+    let merkle_tree_account = banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+    assert_eq!(
+        MerkleTree::deserialize(&mut &merkle_tree_account.data[..]).unwrap(),
+        tree
+    );
+    for commitment in &commitments[4..] {
+        let (commitment_marker, _) =
+            find_commitment_address(&program_id, &tornado_instance.pubkey(), commitment);
+        assert!(banks_client.get_account(commitment_marker).await.unwrap().is_none());
+    }
 
-    // Verify that the nullifier hash is marked as spent
-    let merkle_tree_account = banks_client
-        .get_account(merkle_tree.pubkey())
+    // Oversized batches are rejected
+    let oversized: Vec<[u8; 32]> = (0..=MAX_DEPOSIT_BATCH_SIZE as u8)
+        .map(|i| compute_commitment(&[i; 32], &[3u8; 32], &tornado_instance.pubkey()).unwrap())
+        .collect();
+    let result = banks_client.process_transaction(deposit_batch_tx(&oversized)).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::InvalidInstructionData as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_instance_registry() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // Initialize two instances with different denominations
+    let small_instance = Keypair::new();
+    let large_instance = Keypair::new();
+    for (tornado_instance, denomination) in
+        [(&small_instance, 100_000_000), (&large_instance, 1_000_000_000)]
+    {
+        initialize_instance(
+            &mut banks_client,
+            &payer,
+            recent_blockhash,
+            &program_id,
+            tornado_instance,
+            denomination,
+            8,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    // Both are listed in the registry, in initialization order
+    let (registry_address, _) = find_registry_address(&program_id);
+    let registry_account = banks_client
+        .get_account(registry_address)
         .await
         .unwrap()
         .unwrap();
-    let merkle_tree_data = MerkleTree::try_from_slice(&merkle_tree_account.data).unwrap();
-    assert!(merkle_tree_data.nullifier_hashes.contains(&nullifier_hash));
-}
\ No newline at end of file
+    assert_eq!(registry_account.owner, program_id);
+    assert_eq!(registry_account.data.len(), Registry::get_account_size(2));
+    let registry = Registry::deserialize(&mut &registry_account.data[..]).unwrap();
+    let vk_hash = hash_verifying_key(&create_test_verifying_key(NUM_PUBLIC_INPUTS));
+    assert_eq!(
+        registry.instances,
+        vec![
+            RegistryEntry {
+                denomination: 100_000_000,
+                instance: small_instance.pubkey(),
+                token_mint: None,
+                admin: payer.pubkey(),
+                vk_hash,
+            },
+            RegistryEntry {
+                denomination: 1_000_000_000,
+                instance: large_instance.pubkey(),
+                token_mint: None,
+                admin: payer.pubkey(),
+                vk_hash,
+            },
+        ]
+    );
+    assert_eq!(
+        registry.find_instance(1_000_000_000, None, &vk_hash).unwrap().instance,
+        large_instance.pubkey()
+    );
+    assert!(registry.find_instance(1_000_000_000, Some(Pubkey::new_unique()), &vk_hash).is_none());
+    assert!(registry.find_instance(1_000_000_000, None, &[0u8; 32]).is_none());
+
+    // Anyone can register another pool for the same denomination and mint,
+    // which doesn't take the slot of the first: clients tell them apart by admin
+    let squatter = Keypair::new();
+    let fund_ix = system_instruction::transfer(&payer.pubkey(), &squatter.pubkey(), 1_000_000_000);
+    let mut transaction = Transaction::new_with_payer(&[fund_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let squatter_instance = Keypair::new();
+    initialize_instance(
+        &mut banks_client,
+        &squatter,
+        recent_blockhash,
+        &program_id,
+        &squatter_instance,
+        100_000_000,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+
+    let registry_account = banks_client.get_account(registry_address).await.unwrap().unwrap();
+    let registry = Registry::deserialize(&mut &registry_account.data[..]).unwrap();
+    let pools: Vec<_> = registry.find_instances(100_000_000, None).collect();
+    assert_eq!(
+        pools.iter().map(|entry| (entry.instance, entry.admin)).collect::<Vec<_>>(),
+        vec![
+            (small_instance.pubkey(), payer.pubkey()),
+            (squatter_instance.pubkey(), squatter.pubkey()),
+        ]
+    );
+    assert_eq!(
+        registry.find_instance(100_000_000, None, &vk_hash).unwrap().instance,
+        small_instance.pubkey()
+    );
+}
+
+#[tokio::test]
+async fn test_close_instance() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // One instance stays empty, the other gets a deposit
+    let empty_instance = Keypair::new();
+    let funded_instance = Keypair::new();
+    for (tornado_instance, denomination) in
+        [(&empty_instance, 100_000_000), (&funded_instance, 1_000_000_000)]
+    {
+        initialize_instance(
+            &mut banks_client,
+            &payer,
+            recent_blockhash,
+            &program_id,
+            tornado_instance,
+            denomination,
+            8,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+    let (funded_tree, _) = find_merkle_tree_address(&program_id, &funded_instance.pubkey());
+    let deposit_ix = deposit(
+        &program_id,
+        &payer.pubkey(),
+        &funded_instance.pubkey(),
+        &funded_tree,
+        [1u8; 32],
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // An instance with an unspent deposit can't be closed
+    let receiver = Pubkey::new_unique();
+    let close_ix = close_instance(&program_id, &payer.pubkey(), &funded_instance.pubkey(), &receiver, None).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[close_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    let result = banks_client.process_transaction(transaction).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(TornadoError::InstanceNotEmpty as u32)
+        )
+    );
+
+    // A lamport sent to the empty instance by anyone doesn't keep it open
+    let dust_ix = system_instruction::transfer(&payer.pubkey(), &empty_instance.pubkey(), 1);
+    let mut transaction = Transaction::new_with_payer(&[dust_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The empty instance is closed and its accounts' lamports go to the receiver
+    let (empty_tree, _) = find_merkle_tree_address(&program_id, &empty_instance.pubkey());
+    let (empty_verifier, _) = find_verifier_address(&program_id, &empty_instance.pubkey());
+    let mut reclaimed = 0;
+    for address in [empty_instance.pubkey(), empty_tree, empty_verifier] {
+        reclaimed += banks_client.get_account(address).await.unwrap().unwrap().lamports;
+    }
+    let close_ix = close_instance(&program_id, &payer.pubkey(), &empty_instance.pubkey(), &receiver, None).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[close_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(banks_client.get_balance(receiver).await.unwrap(), reclaimed);
+    for address in [empty_instance.pubkey(), empty_tree, empty_verifier] {
+        assert!(banks_client.get_account(address).await.unwrap().is_none());
+    }
+
+    // The closed instance is no longer registered
+    let (registry_address, _) = find_registry_address(&program_id);
+    let registry_account = banks_client.get_account(registry_address).await.unwrap().unwrap();
+    let registry = Registry::deserialize(&mut &registry_account.data[..]).unwrap();
+    assert_eq!(registry.find_instances(100_000_000, None).count(), 0);
+    initialize_instance(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &Keypair::new(),
+        100_000_000,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_truncated_account_data() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "tornado_svm",
+        program_id,
+        processor!(tornado_svm::process_instruction),
+    );
+    let mut context = program_test.start_with_context().await;
+    let payer = context.payer.insecure_clone();
+    let recent_blockhash = context.last_blockhash;
+
+    let tornado_instance = Keypair::new();
+    initialize_instance(
+        &mut context.banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        &tornado_instance,
+        100_000_000,
+        8,
+        false,
+    )
+    .await
+    .unwrap();
+    let (merkle_tree, _) = find_merkle_tree_address(&program_id, &tornado_instance.pubkey());
+    let instance_account = context.banks_client.get_account(tornado_instance.pubkey()).await.unwrap().unwrap();
+    let merkle_tree_account = context.banks_client.get_account(merkle_tree).await.unwrap().unwrap();
+
+    // Each account is cut short in turn, keeping its version byte, and the
+    // deposit fails naming the account that didn't deserialize
+    for (address, account, message) in [
+        (merkle_tree, &merkle_tree_account, "Failed to deserialize the Merkle tree account"),
+        (tornado_instance.pubkey(), &instance_account, "Tornado instance account has 16 bytes"),
+    ] {
+        let truncated = Account {
+            data: account.data[..16].to_vec(),
+            ..account.clone()
+        };
+        context.set_account(&address, &truncated.into());
+
+        let deposit_ix = deposit(
+            &program_id,
+            &payer.pubkey(),
+            &tornado_instance.pubkey(),
+            &merkle_tree,
+            [1u8; 32],
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut transaction = Transaction::new_with_payer(&[deposit_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        let simulation = context.banks_client.simulate_transaction(transaction).await.unwrap();
+        assert_eq!(
+            simulation.result.unwrap().unwrap_err(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(TornadoError::InvalidAccountData as u32)
+            )
+        );
+        let logs = simulation.simulation_details.unwrap().logs;
+        assert!(logs.iter().any(|log| log.contains(message)), "{:?}", logs);
+
+        context.set_account(&address, &account.clone().into());
+    }
+}